@@ -16,7 +16,8 @@ BIN=release/bin
 LIB=release/lib
 
 cp $HOMEBREW_PREFIX/bin/krunkit $BIN
-install_name_tool -change /opt/homebrew/opt/libkrun-efi/lib/libkrun-efi.dylib @rpath/libkrun-efi.dylib $BIN/krunkit
+# krunkit loads libkrun-efi with dlopen, so it has no load command to rewrite. The rpath is still
+# needed for the dlopen'd library to resolve its own @rpath dependencies.
 install_name_tool -add_rpath $RPATH $BIN/krunkit
 codesign --remove-signature $BIN/krunkit
 
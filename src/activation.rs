@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cleanup::{self, Resource},
+    cmdline::{args_parse, val_parse},
+    vm::VmHandle,
+};
+
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Time given to the forward address to accept connections once the VM is started, as the
+/// guest boots.
+const FORWARD_CONNECT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Interval at which connecting to the forward address is retried.
+const FORWARD_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Address of a socket, as tcp://host:port or unix:///path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SocketUri {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for SocketUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            if !address.contains(':') {
+                return Err(anyhow!("socket URI {s} has no port"));
+            }
+            return Ok(Self::Tcp(address.to_string()));
+        }
+
+        match s.strip_prefix("unix://") {
+            Some(path) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(anyhow!(
+                "invalid socket URI {s} (expected tcp://host:port or unix:///path)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SocketUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "tcp://{address}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for SocketUri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Socket whose first connection starts the VM.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivationConfig {
+    /// Socket krunkit listens on.
+    pub listen: SocketUri,
+
+    /// Socket connections are forwarded to once the VM is started. Without it, connections are
+    /// closed once they have started the VM.
+    pub forward: Option<SocketUri>,
+}
+
+impl FromStr for ActivationConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut listen = None;
+        let mut forward = None;
+
+        for arg in args_parse(s.to_string(), "activate-on", None)? {
+            match arg.split_once('=').map(|(label, _)| label) {
+                Some("listen") => {
+                    listen = Some(
+                        SocketUri::from_str(&val_parse(&arg, "listen")?)
+                            .context("activate-on listen argument invalid")?,
+                    )
+                }
+                Some("forward") => {
+                    forward = Some(
+                        SocketUri::from_str(&val_parse(&arg, "forward")?)
+                            .context("activate-on forward argument invalid")?,
+                    )
+                }
+                _ => return Err(anyhow!("invalid activate-on argument: {arg}")),
+            }
+        }
+
+        let listen = listen.ok_or(anyhow!("activate-on listen argument not found"))?;
+        if forward.as_ref() == Some(&listen) {
+            return Err(anyhow!(
+                "activate-on cannot forward connections to {listen}"
+            ));
+        }
+
+        Ok(Self { listen, forward })
+    }
+}
+
+impl fmt::Display for ActivationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "listen={}", self.listen)?;
+        if let Some(forward) = &self.forward {
+            write!(f, ",forward={forward}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection accepted on, or made to, a socket URI.
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn connect(uri: &SocketUri) -> io::Result<Self> {
+        match uri {
+            SocketUri::Tcp(address) => TcpStream::connect(address).map(Self::Tcp),
+            SocketUri::Unix(path) => UnixStream::connect(path).map(Self::Unix),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+
+    fn shutdown_write(&self) {
+        let _ = match self {
+            Self::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            Self::Unix(stream) => stream.shutdown(Shutdown::Write),
+        };
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Listen on the activation socket, and accept connections on a new thread for the lifetime of
+/// krunkit. The VM awaits activation, and the first connection starts it. Each connection is then
+/// forwarded, or closed if there is no forward address.
+pub fn activation_listener(
+    vm: Arc<VmHandle>,
+    config: &ActivationConfig,
+) -> Result<(), anyhow::Error> {
+    let mut accept: Box<dyn FnMut() -> io::Result<Connection> + Send> = match &config.listen {
+        SocketUri::Tcp(address) => {
+            let listener = TcpListener::bind(address).context(format!(
+                "unable to listen on activation socket {}",
+                config.listen
+            ))?;
+            Box::new(move || listener.accept().map(|(stream, _)| Connection::Tcp(stream)))
+        }
+        SocketUri::Unix(path) => {
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path).context(format!(
+                "unable to listen on activation socket {}",
+                config.listen
+            ))?;
+            cleanup::register(Resource::File(path.clone()));
+            Box::new(move || {
+                listener
+                    .accept()
+                    .map(|(stream, _)| Connection::Unix(stream))
+            })
+        }
+    };
+    vm.await_activation();
+    println!(
+        "Waiting for a connection on {} to start the VM",
+        config.listen
+    );
+
+    let listen = config.listen.clone();
+    let forward = config.forward.clone();
+    thread::spawn(move || loop {
+        let connection = match accept() {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("Unable to accept connection on activation socket: {e}");
+                continue;
+            }
+        };
+
+        if vm.activate() {
+            println!("Connection on {listen}, starting the VM");
+        }
+
+        // Without a forward address, the connection only starts the VM.
+        if let Some(forward) = &forward {
+            let vm = vm.clone();
+            let forward = forward.clone();
+            thread::spawn(move || {
+                if let Err(e) = forward_connection(&vm, connection, &forward) {
+                    println!("Unable to forward connection to {forward}: {e:#}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Forward a connection to the forward address once it accepts connections, until either side
+/// closes it.
+fn forward_connection(
+    vm: &VmHandle,
+    client: Connection,
+    forward: &SocketUri,
+) -> Result<(), anyhow::Error> {
+    let deadline = Instant::now() + FORWARD_CONNECT_TIMEOUT;
+    let server = loop {
+        match Connection::connect(forward) {
+            Ok(server) => break server,
+            Err(_) if Instant::now() < deadline && !vm.wait_exited(FORWARD_RETRY_INTERVAL) => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut client_reader = client.try_clone()?;
+    let mut server_writer = server.try_clone()?;
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        server_writer.shutdown_write();
+    });
+
+    let (mut server_reader, mut client_writer) = (server, client);
+    let _ = io::copy(&mut server_reader, &mut client_writer);
+    client_writer.shutdown_write();
+    let _ = upstream.join();
+
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn activation_config_parse() {
+        use super::*;
+
+        assert_eq!(
+            ActivationConfig::from_str("listen=tcp://127.0.0.1:2200,forward=tcp://127.0.0.1:2222")
+                .unwrap(),
+            ActivationConfig {
+                listen: SocketUri::Tcp(String::from("127.0.0.1:2200")),
+                forward: Some(SocketUri::Tcp(String::from("127.0.0.1:2222"))),
+            }
+        );
+
+        let config = ActivationConfig::from_str("listen=unix:///tmp/vm.sock").unwrap();
+        assert_eq!(
+            config.listen,
+            SocketUri::Unix(PathBuf::from("/tmp/vm.sock"))
+        );
+        assert_eq!(config.forward, None);
+        assert_eq!(config.to_string(), "listen=unix:///tmp/vm.sock");
+
+        assert!(ActivationConfig::from_str("forward=tcp://127.0.0.1:2222").is_err());
+        assert!(ActivationConfig::from_str("listen=tcp://127.0.0.1").is_err());
+        assert!(ActivationConfig::from_str("listen=/tmp/vm.sock").is_err());
+        assert!(ActivationConfig::from_str("listen=unix:///a,forward=unix:///a").is_err());
+    }
+}
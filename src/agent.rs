@@ -0,0 +1,713 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::{args_parse, read_pidfile, val_parse},
+    libkrun::{self, libkrun},
+    statedir,
+    virtio::KrunContextSet,
+};
+
+use std::{
+    env,
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Time to wait for the guest agent to respond to a command.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of bytes read from or written to a guest file in a single guest agent command.
+const AGENT_READ_CHUNK: usize = 64 * 1024;
+
+/// Time to wait for the guest to freeze its filesystems, which includes running the guest agent's
+/// fsfreeze hook scripts. Shorter than the time macOS waits for sleep to be acknowledged.
+const AGENT_FREEZE_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Interval at which the status of a program run in the guest is polled.
+const AGENT_EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time to wait for an error from the guest agent for commands that do not respond on success.
+const AGENT_NO_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Configuration of the guest agent channel. The guest is expected to run qemu-guest-agent
+/// listening on the configured vsock port (for example, qemu-ga -m vsock-listen -p 3:1026).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestAgentConfig {
+    /// vsock port the guest agent listens on.
+    pub port: u32,
+}
+
+impl FromStr for GuestAgentConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = args_parse(s.to_string(), "guest-agent", Some(1))?;
+
+        let port = u32::from_str(&val_parse(&args[0], "port")?)
+            .context("guest agent port argument invalid")?;
+
+        Ok(Self { port })
+    }
+}
+
+impl GuestAgentConfig {
+    /// Path of the host UNIX socket proxied to the guest agent's vsock port: agent.sock in the
+    /// state directory, or a socket in the temporary directory named after the process ID, so
+    /// that the socket of a running instance can be located from its pidfile.
+    pub fn socket_path(&self) -> PathBuf {
+        statedir::socket_path("agent").unwrap_or_else(|| agent_socket_path(process::id()))
+    }
+}
+
+/// Path of the lock file serializing access to the guest agent behind the given socket.
+pub fn agent_lock_path(socket: &Path) -> PathBuf {
+    socket.with_extension("lock")
+}
+
+/// Path of the guest agent socket of the krunkit instance with the given PID.
+pub fn agent_socket_path(pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("krunkit-agent-{pid}.sock"))
+}
+
+/// Client of the guest agent of the running krunkit instance with the given pidfile.
+pub fn instance_agent(pidfile: &Path) -> Result<GuestAgent, anyhow::Error> {
+    let pid = read_pidfile(pidfile)?;
+
+    // The socket is next to the pidfile if the instance has a state directory.
+    let path = match pidfile.parent().map(|dir| dir.join("agent.sock")) {
+        Some(path) if path.exists() => path,
+        _ => agent_socket_path(pid),
+    };
+    if !path.exists() {
+        return Err(anyhow!(
+            "krunkit instance {pid} has no guest agent channel (see --guest-agent)"
+        ));
+    }
+
+    Ok(GuestAgent::new(path))
+}
+
+/// Have libkrun listen for host connections on the agent socket, forwarding each to the guest
+/// agent's vsock port.
+impl KrunContextSet for GuestAgentConfig {
+    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+        let path = self.socket_path();
+        let path_cstr = CString::new(path.as_os_str().as_bytes())
+            .context("unable to convert guest agent socket path into C string")?;
+
+        let krun = libkrun();
+        let add_vsock_port2 = krun.require(
+            krun.krun_add_vsock_port2,
+            "krun_add_vsock_port2",
+            "the guest agent",
+        )?;
+        libkrun::check(add_vsock_port2(id, self.port, path_cstr.as_ptr(), true)).context(
+            format!(
+                "unable to add guest agent vsock port {} for path {}",
+                self.port,
+                path.display()
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// A client of the qemu-guest-agent running in the guest.
+#[derive(Clone, Debug)]
+pub struct GuestAgent {
+    path: PathBuf,
+}
+
+impl GuestAgent {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Execute a guest agent command, returning its result. Each command is sent on a new
+    /// connection, so a response can never be confused with one to an earlier command. The guest
+    /// agent serves one connection at a time, so commands wait for the agent lock first.
+    pub fn execute(&self, command: &str, arguments: Option<Value>) -> Result<Value, anyhow::Error> {
+        self.execute_timeout(command, arguments, AGENT_TIMEOUT)
+    }
+
+    /// Execute a guest agent command, waiting for its result for at most the given time.
+    fn execute_timeout(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, anyhow::Error> {
+        let line = self.request(command, arguments, timeout)?;
+
+        response(command, &line)
+    }
+
+    /// Send a guest agent command that does not return a response on success (such as
+    /// guest-shutdown). An error is only reported if the agent responds with one.
+    pub fn send(&self, command: &str, arguments: Option<Value>) -> Result<(), anyhow::Error> {
+        let line = match self.request(command, arguments, AGENT_NO_RESPONSE_TIMEOUT) {
+            Ok(line) => line,
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(is_timeout) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        match serde_json::from_str::<Value>(&line) {
+            Ok(response) if response.get("error").is_some() => Err(anyhow!(
+                "guest agent {command} failed: {}",
+                response["error"]["desc"]
+                    .as_str()
+                    .unwrap_or("unknown error")
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Take the lock serializing access to the guest agent, which is released when the returned
+    /// file is closed. krunkit's own threads as well as the exec and cp subcommands connect to
+    /// the agent, so the lock is a file lock next to the socket. If `wait` is false, None is
+    /// returned instead of waiting for another command to complete.
+    fn lock(&self, wait: bool) -> Result<Option<File>, anyhow::Error> {
+        let path = agent_lock_path(&self.path);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .context(format!(
+                "unable to open guest agent lock {}",
+                path.display()
+            ))?;
+
+        let operation = match wait {
+            true => libc::LOCK_EX,
+            false => libc::LOCK_EX | libc::LOCK_NB,
+        };
+        while unsafe { libc::flock(file.as_raw_fd(), operation) } < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EWOULDBLOCK) if !wait => return Ok(None),
+                _ => return Err(e).context("unable to lock guest agent"),
+            }
+        }
+
+        Ok(Some(file))
+    }
+
+    /// Send a command to the guest agent once no other command is in flight, and read a line of
+    /// response. An empty line is returned if the agent closed the connection without responding.
+    fn request(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<String, anyhow::Error> {
+        let _lock = self.lock(true)?;
+
+        self.exchange(command, arguments, timeout)
+    }
+
+    /// Send a command to the guest agent and read a line of response, without taking the lock.
+    fn exchange(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<String, anyhow::Error> {
+        let mut stream = UnixStream::connect(&self.path).context(format!(
+            "unable to connect to guest agent socket {}",
+            self.path.display()
+        ))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        stream
+            .write_all(format!("{request}\n").as_bytes())
+            .context("unable to send guest agent command")?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+
+        Ok(line)
+    }
+
+    /// Read the entire contents of a file in the guest.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let mut contents = Vec::new();
+        self.copy_from_guest(path, &mut contents, |_| ())?;
+
+        Ok(contents)
+    }
+
+    /// Replace the contents of a file in the guest.
+    pub fn write_file(&self, path: &str, mut contents: &[u8]) -> Result<(), anyhow::Error> {
+        self.copy_to_guest(path, &mut contents, |_| ())?;
+
+        Ok(())
+    }
+
+    /// Copy a file in the guest to a writer, a chunk at a time. The number of bytes copied so far
+    /// is reported after each chunk. Returns the size of the file.
+    pub fn copy_from_guest(
+        &self,
+        path: &str,
+        writer: &mut impl Write,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64, anyhow::Error> {
+        let handle = self.execute(
+            "guest-file-open",
+            Some(json!({ "path": path, "mode": "r" })),
+        )?;
+
+        let mut copied = 0;
+        let result = loop {
+            let chunk = match self.execute(
+                "guest-file-read",
+                Some(json!({ "handle": handle, "count": AGENT_READ_CHUNK })),
+            ) {
+                Ok(chunk) => chunk,
+                Err(e) => break Err(e),
+            };
+
+            let buf = match STANDARD.decode(chunk["buf-b64"].as_str().unwrap_or("")) {
+                Ok(buf) => buf,
+                Err(e) => break Err(anyhow!("invalid guest file contents: {e}")),
+            };
+            if let Err(e) = writer.write_all(&buf) {
+                break Err(e.into());
+            }
+            copied += buf.len() as u64;
+            progress(copied);
+
+            if chunk["eof"].as_bool().unwrap_or(true) {
+                break Ok(copied);
+            }
+        };
+
+        self.execute("guest-file-close", Some(json!({ "handle": handle })))?;
+
+        result.context(format!("unable to read guest file {path}"))
+    }
+
+    /// Replace the contents of a file in the guest with those of a reader, a chunk at a time. The
+    /// number of bytes copied so far is reported after each chunk. Returns the size of the file.
+    pub fn copy_to_guest(
+        &self,
+        path: &str,
+        reader: &mut impl Read,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64, anyhow::Error> {
+        let handle = self.execute(
+            "guest-file-open",
+            Some(json!({ "path": path, "mode": "w" })),
+        )?;
+
+        let mut buf = vec![0u8; AGENT_READ_CHUNK];
+        let mut copied = 0;
+        let result = loop {
+            let sz = match reader.read(&mut buf) {
+                Ok(0) => break Ok(copied),
+                Ok(sz) => sz,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e.into()),
+            };
+
+            if let Err(e) = self.execute(
+                "guest-file-write",
+                Some(json!({ "handle": handle, "buf-b64": STANDARD.encode(&buf[..sz]) })),
+            ) {
+                break Err(e);
+            }
+            copied += sz as u64;
+            progress(copied);
+        };
+
+        self.execute("guest-file-close", Some(json!({ "handle": handle })))?;
+
+        result.context(format!("unable to write guest file {path}"))
+    }
+
+    /// Check that the guest agent responds, waiting for at most the given time. Returns false
+    /// without pinging if another command is in flight, as the agent would not respond before it
+    /// completes.
+    pub fn ping(&self, timeout: Duration) -> Result<bool, anyhow::Error> {
+        let Some(_lock) = self.lock(false)? else {
+            return Ok(false);
+        };
+        response("guest-ping", &self.exchange("guest-ping", None, timeout)?)?;
+
+        Ok(true)
+    }
+
+    /// Set the guest's clock to the given time.
+    pub fn set_time(&self, time: SystemTime) -> Result<(), anyhow::Error> {
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .context("host time before UNIX epoch")?
+            .as_nanos();
+
+        self.execute("guest-set-time", Some(json!({ "time": nanos as u64 })))?;
+
+        Ok(())
+    }
+
+    /// Read the guest's clock.
+    pub fn get_time(&self) -> Result<SystemTime, anyhow::Error> {
+        let nanos = self
+            .execute("guest-get-time", None)?
+            .as_u64()
+            .ok_or(anyhow!("invalid guest time"))?;
+
+        Ok(UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+
+    /// Run a program in the guest and wait for it to exit, returning its exit code.
+    pub fn exec(&self, path: &str, args: &[&str]) -> Result<i64, anyhow::Error> {
+        let status = self.exec_status(path, args, false, Some(AGENT_TIMEOUT))?;
+
+        Ok(status["exitcode"].as_i64().unwrap_or(-1))
+    }
+
+    /// Run a program in the guest and wait for it to exit (for at most the timeout, if any),
+    /// capturing its output. The guest agent only returns the output once the program has exited.
+    pub fn exec_output(
+        &self,
+        path: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> Result<ExecOutput, anyhow::Error> {
+        let status = self.exec_status(path, args, true, timeout)?;
+
+        let data = |name: &str| -> Result<Vec<u8>, anyhow::Error> {
+            let data = status[name].as_str().unwrap_or("");
+            STANDARD
+                .decode(data)
+                .context(format!("invalid guest {name}"))
+        };
+
+        Ok(ExecOutput {
+            exit_code: status["exitcode"].as_i64(),
+            signal: status["signal"].as_i64(),
+            stdout: data("out-data")?,
+            stderr: data("err-data")?,
+            truncated: status["out-truncated"].as_bool().unwrap_or(false)
+                || status["err-truncated"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// Run a program in the guest, returning its status once it has exited.
+    fn exec_status(
+        &self,
+        path: &str,
+        args: &[&str],
+        capture_output: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Value, anyhow::Error> {
+        let pid = self.execute(
+            "guest-exec",
+            Some(json!({ "path": path, "arg": args, "capture-output": capture_output })),
+        )?["pid"]
+            .as_i64()
+            .ok_or(anyhow!("guest-exec did not return a PID"))?;
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let status = self.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+            if status["exited"].as_bool().unwrap_or(false) {
+                return Ok(status);
+            }
+
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(anyhow!("{path} did not exit in the guest"));
+            }
+            thread::sleep(AGENT_EXEC_POLL_INTERVAL);
+        }
+    }
+
+    /// Freeze the guest's filesystems, flushing pending writes to disk. The guest agent runs its
+    /// fsfreeze hook scripts first, allowing applications such as databases to quiesce. Returns
+    /// the number of filesystems frozen once the guest confirms.
+    pub fn freeze_filesystems(&self) -> Result<u64, anyhow::Error> {
+        let frozen = self.execute_timeout("guest-fsfreeze-freeze", None, AGENT_FREEZE_TIMEOUT)?;
+
+        Ok(frozen.as_u64().unwrap_or(0))
+    }
+
+    /// Thaw the guest's filesystems. Returns the number of filesystems thawed.
+    pub fn thaw_filesystems(&self) -> Result<u64, anyhow::Error> {
+        let thawed = self.execute("guest-fsfreeze-thaw", None)?;
+
+        Ok(thawed.as_u64().unwrap_or(0))
+    }
+
+    /// Network interfaces of the guest and their IP addresses, loopback interfaces excluded.
+    pub fn network_interfaces(&self) -> Result<Vec<GuestInterface>, anyhow::Error> {
+        let interfaces = self.execute("guest-network-get-interfaces", None)?;
+
+        Ok(interfaces
+            .as_array()
+            .map(|interfaces| {
+                interfaces
+                    .iter()
+                    .map(GuestInterface::from)
+                    .filter(|interface| interface.name != "lo")
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Gather filesystem usage, load average, and memory statistics from the guest.
+    pub fn stats(&self) -> Result<GuestStats, anyhow::Error> {
+        let filesystems = self
+            .execute("guest-get-fsinfo", None)?
+            .as_array()
+            .map(|fs| fs.iter().map(FilesystemStats::from).collect())
+            .unwrap_or_default();
+
+        let loadavg = String::from_utf8_lossy(&self.read_file("/proc/loadavg")?).to_string();
+        let meminfo = String::from_utf8_lossy(&self.read_file("/proc/meminfo")?).to_string();
+
+        Ok(GuestStats {
+            filesystems,
+            load: LoadStats::from_str(&loadavg)?,
+            memory: MemoryStats::from_str(&meminfo)?,
+        })
+    }
+}
+
+/// Result of a program run in the guest.
+#[derive(Clone, Debug)]
+pub struct ExecOutput {
+    /// Exit code, if the program exited normally.
+    pub exit_code: Option<i64>,
+
+    /// Signal the program was terminated by, if any.
+    pub signal: Option<i64>,
+
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+
+    /// The guest agent truncated the output, which it limits in size.
+    pub truncated: bool,
+}
+
+/// Result of a guest agent command from its response line.
+fn response(command: &str, line: &str) -> Result<Value, anyhow::Error> {
+    if line.is_empty() {
+        return Err(anyhow!("no response from guest agent to {command}"));
+    }
+
+    let mut response: Value =
+        serde_json::from_str(line).context(format!("invalid guest agent response to {command}"))?;
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!(
+            "guest agent {command} failed: {}",
+            error["desc"].as_str().unwrap_or("unknown error")
+        ));
+    }
+
+    Ok(response["return"].take())
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Resource usage reported by the guest.
+#[derive(Clone, Debug, Serialize)]
+pub struct GuestStats {
+    pub filesystems: Vec<FilesystemStats>,
+    pub load: LoadStats,
+    pub memory: MemoryStats,
+}
+
+/// Usage of a mounted guest filesystem.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilesystemStats {
+    pub mountpoint: String,
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    pub used_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+impl From<&Value> for FilesystemStats {
+    fn from(fs: &Value) -> Self {
+        Self {
+            mountpoint: fs["mountpoint"].as_str().unwrap_or("").to_string(),
+            fs_type: fs["type"].as_str().unwrap_or("").to_string(),
+            used_bytes: fs["used-bytes"].as_u64(),
+            total_bytes: fs["total-bytes"].as_u64(),
+        }
+    }
+}
+
+/// A network interface of the guest, as reported by the guest agent.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestInterface {
+    pub name: String,
+    pub mac_address: Option<String>,
+
+    /// Addresses of the interface, in CIDR notation.
+    pub ip_addresses: Vec<String>,
+}
+
+impl From<&Value> for GuestInterface {
+    fn from(interface: &Value) -> Self {
+        let ip_addresses = interface["ip-addresses"]
+            .as_array()
+            .map(|addresses| {
+                addresses
+                    .iter()
+                    .filter_map(|a| {
+                        let address = a["ip-address"].as_str()?;
+                        Some(match a["prefix"].as_u64() {
+                            Some(prefix) => format!("{address}/{prefix}"),
+                            None => address.to_string(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            name: interface["name"].as_str().unwrap_or("").to_string(),
+            mac_address: interface["hardware-address"].as_str().map(String::from),
+            ip_addresses,
+        }
+    }
+}
+
+/// Guest load average, parsed from /proc/loadavg.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoadStats {
+    pub load1: f64,
+    pub load5: f64,
+    pub load15: f64,
+}
+
+impl FromStr for LoadStats {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let loads: Vec<f64> = s
+            .split_whitespace()
+            .take(3)
+            .map(f64::from_str)
+            .collect::<Result<_, _>>()
+            .context("invalid guest load average")?;
+        if loads.len() != 3 {
+            return Err(anyhow!("invalid guest load average"));
+        }
+
+        Ok(Self {
+            load1: loads[0],
+            load5: loads[1],
+            load15: loads[2],
+        })
+    }
+}
+
+/// Guest memory usage, parsed from /proc/meminfo.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl FromStr for MemoryStats {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Each value is reported in kB, for example "MemTotal:        8039932 kB".
+        let field = |name: &str| -> Result<u64, anyhow::Error> {
+            s.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|v| v.split_whitespace().next())
+                .and_then(|v| u64::from_str(v).ok())
+                .map(|kb| kb * 1024)
+                .ok_or_else(|| anyhow!("{name} not found in guest memory info"))
+        };
+
+        Ok(Self {
+            total_bytes: field("MemTotal")?,
+            free_bytes: field("MemFree")?,
+            available_bytes: field("MemAvailable")?,
+        })
+    }
+}
+
+mod tests {
+    #[test]
+    fn guest_stats_parse() {
+        use super::*;
+
+        let load = LoadStats::from_str("0.52 0.58 0.59 2/187 4321\n").unwrap();
+        assert_eq!(
+            load,
+            LoadStats {
+                load1: 0.52,
+                load5: 0.58,
+                load15: 0.59
+            }
+        );
+
+        let memory = MemoryStats::from_str(
+            "MemTotal:        8039932 kB\nMemFree:         6162340 kB\nMemAvailable:    7420864 kB\n",
+        )
+        .unwrap();
+        assert_eq!(memory.total_bytes, 8039932 * 1024);
+        assert_eq!(memory.free_bytes, 6162340 * 1024);
+        assert_eq!(memory.available_bytes, 7420864 * 1024);
+
+        assert!(MemoryStats::from_str("MemTotal: 1 kB\n").is_err());
+    }
+
+    #[test]
+    fn guest_agent_lock() {
+        use super::*;
+
+        let socket = env::temp_dir().join(format!("krunkit-agent-test-{}.sock", process::id()));
+        let agent = GuestAgent::new(socket.clone());
+
+        let lock = agent.lock(false).unwrap();
+        assert!(lock.is_some());
+        assert!(agent.lock(false).unwrap().is_none());
+        assert!(agent
+            .ping(Duration::from_secs(1))
+            .is_ok_and(|pinged| !pinged));
+
+        drop(lock);
+        assert!(agent.lock(false).unwrap().is_some());
+
+        std::fs::remove_file(agent_lock_path(&socket)).unwrap();
+    }
+}
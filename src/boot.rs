@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+/// Time at which krunkit started, from which boot phases are measured.
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Boot phases reached so far, in order.
+static PHASES: Mutex<Vec<BootPhase>> = Mutex::new(Vec::new());
+
+/// A phase of krunkit's startup and of the VM's boot.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootPhase {
+    pub name: String,
+
+    /// Milliseconds since krunkit started at which the phase was reached.
+    pub since_start_ms: u64,
+
+    /// Milliseconds since the previous phase was reached.
+    pub duration_ms: u64,
+}
+
+/// Start measuring boot phases. Called as early as possible once krunkit starts.
+pub fn start() {
+    START.get_or_init(Instant::now);
+}
+
+/// Record that a boot phase was reached, writing it to the log. Each phase is only recorded the
+/// first time it is reached.
+pub fn mark(name: &str) {
+    let since_start_ms = START.get_or_init(Instant::now).elapsed().as_millis() as u64;
+    let mut phases = PHASES.lock().unwrap();
+
+    if phases.iter().any(|p| p.name == name) {
+        return;
+    }
+
+    let previous_ms = phases.last().map(|p| p.since_start_ms).unwrap_or(0);
+    let phase = BootPhase {
+        name: name.to_string(),
+        since_start_ms,
+        duration_ms: since_start_ms - previous_ms,
+    };
+    println!(
+        "Boot phase {} reached after {} ms (+{} ms)",
+        phase.name, phase.since_start_ms, phase.duration_ms
+    );
+
+    phases.push(phase);
+}
+
+/// Retrieve the boot phases reached so far, in order.
+pub fn phases() -> Vec<BootPhase> {
+    PHASES.lock().unwrap().clone()
+}
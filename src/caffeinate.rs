@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: Apache-2.0
+
+/// Keeps the host awake while the VM is running: prevents idle system sleep and App Nap, which
+/// would otherwise stall long-running work in the guest. Released when dropped.
+pub struct Caffeinate {
+    #[cfg(target_os = "macos")]
+    inner: macos::Assertions,
+}
+
+impl Caffeinate {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            #[cfg(target_os = "macos")]
+            inner: macos::Assertions::take("krunkit VM running")?,
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::{
+        ffi::{c_char, c_void, CStr, CString},
+        mem, ptr,
+    };
+
+    use anyhow::{anyhow, Context};
+
+    type CfStringRef = *const c_void;
+    type Id = *mut c_void;
+    type Sel = *mut c_void;
+
+    const CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+    const IOPM_ASSERTION_LEVEL_ON: u32 = 255;
+
+    /// NSActivityUserInitiated: disables App Nap and idle system sleep for the process.
+    const NS_ACTIVITY_USER_INITIATED: u64 = 0x00FF_FFFF | (1 << 20);
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFStringCreateWithCString(
+            alloc: *const c_void,
+            c_str: *const c_char,
+            encoding: u32,
+        ) -> CfStringRef;
+        fn CFRelease(cf: *const c_void);
+    }
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IOPMAssertionCreateWithName(
+            assertion_type: CfStringRef,
+            level: u32,
+            name: CfStringRef,
+            assertion_id: *mut u32,
+        ) -> i32;
+        fn IOPMAssertionRelease(assertion_id: u32) -> i32;
+    }
+
+    #[link(name = "Foundation", kind = "framework")]
+    extern "C" {}
+
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const c_char) -> Id;
+        fn sel_registerName(name: *const c_char) -> Sel;
+        fn objc_msgSend();
+    }
+
+    /// A power assertion preventing idle system sleep, and a process activity disabling App Nap.
+    pub struct Assertions {
+        assertion_id: u32,
+        process_info: Id,
+        activity: Id,
+    }
+
+    impl Assertions {
+        pub fn take(reason: &str) -> Result<Self, anyhow::Error> {
+            let reason = CString::new(reason).context("invalid power assertion reason")?;
+
+            let assertion_id = unsafe {
+                let assertion_type = cf_string(c"PreventUserIdleSystemSleep".as_ptr());
+                let name = cf_string(reason.as_ptr());
+
+                let mut assertion_id = 0;
+                let ret = IOPMAssertionCreateWithName(
+                    assertion_type,
+                    IOPM_ASSERTION_LEVEL_ON,
+                    name,
+                    &mut assertion_id,
+                );
+
+                CFRelease(assertion_type);
+                CFRelease(name);
+
+                if ret != 0 {
+                    return Err(anyhow!("unable to create power assertion (error {ret:#x})"));
+                }
+
+                assertion_id
+            };
+
+            let (process_info, activity) = unsafe {
+                let process_info =
+                    msg_send(objc_getClass(c"NSProcessInfo".as_ptr()), c"processInfo");
+                let reason = msg_send_ptr(
+                    objc_getClass(c"NSString".as_ptr()),
+                    c"stringWithUTF8String:",
+                    reason.as_ptr() as Id,
+                );
+
+                let begin: extern "C" fn(Id, Sel, u64, Id) -> Id =
+                    mem::transmute(objc_msgSend as unsafe extern "C" fn());
+                let activity = begin(
+                    process_info,
+                    sel_registerName(c"beginActivityWithOptions:reason:".as_ptr()),
+                    NS_ACTIVITY_USER_INITIATED,
+                    reason,
+                );
+
+                (process_info, msg_send(activity, c"retain"))
+            };
+
+            Ok(Self {
+                assertion_id,
+                process_info,
+                activity,
+            })
+        }
+    }
+
+    impl Drop for Assertions {
+        fn drop(&mut self) {
+            unsafe {
+                IOPMAssertionRelease(self.assertion_id);
+
+                if !self.activity.is_null() {
+                    msg_send_ptr(self.process_info, c"endActivity:", self.activity);
+                    msg_send(self.activity, c"release");
+                }
+            }
+        }
+    }
+
+    unsafe fn cf_string(s: *const c_char) -> CfStringRef {
+        CFStringCreateWithCString(ptr::null(), s, CF_STRING_ENCODING_UTF8)
+    }
+
+    unsafe fn msg_send(receiver: Id, selector: &CStr) -> Id {
+        let f: extern "C" fn(Id, Sel) -> Id =
+            mem::transmute(objc_msgSend as unsafe extern "C" fn());
+
+        f(receiver, sel_registerName(selector.as_ptr()))
+    }
+
+    unsafe fn msg_send_ptr(receiver: Id, selector: &CStr, arg: Id) -> Id {
+        let f: extern "C" fn(Id, Sel, Id) -> Id =
+            mem::transmute(objc_msgSend as unsafe extern "C" fn());
+
+        f(receiver, sel_registerName(selector.as_ptr()), arg)
+    }
+}
@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    libkrun::{self, GuestArch, Libkrun},
+    preflight,
+    sandbox::{self, SandboxMode},
+    status::RESTFUL_ENDPOINTS,
+};
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Serialize;
+
+/// Arguments of the capabilities subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct CapabilitiesArgs {
+    /// Print the report as JSON.
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+}
+
+/// What this krunkit build can do on this host, given the libkrun it loads.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Capabilities {
+    krunkit_version: &'static str,
+    host: HostReport,
+    libkrun: LibkrunReport,
+
+    /// Architectures of the guests that can be run.
+    guest_archs: Vec<GuestArch>,
+
+    devices: Vec<Capability>,
+    features: Vec<Capability>,
+
+    /// Endpoints of the restful service, as "METHOD path".
+    restful_endpoints: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostReport {
+    os_version: Option<String>,
+    arch: GuestArch,
+
+    /// Problems preventing VMs from running on the host, such as a missing entitlement.
+    problems: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LibkrunReport {
+    /// Path of the loaded library, if it could be loaded.
+    path: Option<PathBuf>,
+
+    /// Reason the library could not be loaded.
+    error: Option<String>,
+
+    /// Optional functions of libkrun, along with whether the library provides them.
+    functions: Vec<FunctionReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct FunctionReport {
+    name: &'static str,
+    available: bool,
+}
+
+/// A device or feature, along with whether it is supported.
+#[derive(Debug, Serialize)]
+struct Capability {
+    name: &'static str,
+    supported: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'static str>,
+}
+
+impl Capability {
+    fn new(name: &'static str, supported: bool) -> Self {
+        Self {
+            name,
+            supported,
+            note: None,
+        }
+    }
+
+    fn note(mut self, note: &'static str) -> Self {
+        self.note = Some(note);
+        self
+    }
+}
+
+/// Print the capabilities of krunkit on this host.
+pub fn capabilities(args: &CapabilitiesArgs) -> Result<(), anyhow::Error> {
+    let report = report();
+
+    match args.json {
+        true => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("unable to serialize capabilities")?
+        ),
+        false => print_report(&report),
+    }
+
+    Ok(())
+}
+
+fn report() -> Capabilities {
+    let (krun, error) = match libkrun::load() {
+        Ok(krun) => (Some(krun), None),
+        Err(e) => (None, Some(format!("{e:#}"))),
+    };
+
+    let host = HostReport {
+        os_version: sysinfo::System::long_os_version(),
+        arch: GuestArch::host(),
+        problems: preflight::host_problems()
+            .into_iter()
+            .map(|p| p.message)
+            .collect(),
+    };
+
+    let libkrun = LibkrunReport {
+        path: krun.map(|k| k.path.clone()),
+        error,
+        functions: krun
+            .map(Libkrun::optional_functions)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, available)| FunctionReport { name, available })
+            .collect(),
+    };
+
+    Capabilities {
+        krunkit_version: env!("CARGO_PKG_VERSION"),
+        host,
+        libkrun,
+        guest_archs: krun.map(|_| vec![GuestArch::host()]).unwrap_or_default(),
+        devices: devices(krun),
+        features: features(krun),
+        restful_endpoints: RESTFUL_ENDPOINTS.to_vec(),
+    }
+}
+
+fn devices(krun: Option<&Libkrun>) -> Vec<Capability> {
+    let loaded = krun.is_some();
+    let unixgram = krun.is_some_and(|k| k.krun_add_net_unixgram.is_some());
+    let gpu = krun.is_some_and(|k| k.krun_set_gpu_options2.is_some());
+
+    vec![
+        Capability::new("virtio-blk", loaded).note("raw and qcow2 images"),
+        Capability::new("virtio-rng", loaded),
+        Capability::new("virtio-serial", loaded),
+        Capability::new("virtio-vsock", loaded),
+        match unixgram {
+            true => Capability::new("virtio-net", loaded).note("unixgram API, multiple devices"),
+            false => {
+                Capability::new("virtio-net", loaded).note("legacy gvproxy API, single device")
+            }
+        },
+        Capability::new("virtio-fs", loaded),
+        Capability::new("virtio-gpu", gpu).note("Venus, enabled by default when supported"),
+        Capability::new("virtio-input", false).note("accepted for vfkit compatibility, ignored"),
+    ]
+}
+
+fn features(krun: Option<&Libkrun>) -> Vec<Capability> {
+    let vsock_port2 = krun.is_some_and(|k| k.krun_add_vsock_port2.is_some());
+    let unixstream = krun.is_some_and(|k| k.krun_add_net_unixstream.is_some());
+    let oem_strings = krun.is_some_and(|k| k.krun_set_smbios_oem_strings.is_some());
+    let macos = cfg!(target_os = "macos");
+
+    vec![
+        Capability::new("guest-agent", vsock_port2),
+        Capability::new("timesync", vsock_port2),
+        Capability::new("net-unixstream", unixstream).note("virtio-net unixStreamPath"),
+        Capability::new("oem-strings", oem_strings),
+        Capability::new("secrets", oem_strings),
+        Capability::new("keychain-secrets", macos && oem_strings),
+        Capability::new("ignition", krun.is_some()),
+        Capability::new("guest-ready", krun.is_some()),
+        Capability::new("sandbox", sandbox::check(SandboxMode::Strict).is_ok()),
+        Capability::new("restful-privsep", true),
+    ]
+}
+
+fn print_report(report: &Capabilities) {
+    println!("krunkit {}", report.krunkit_version);
+    println!(
+        "host: {} ({})",
+        report.host.os_version.as_deref().unwrap_or("unknown OS"),
+        report.host.arch
+    );
+    for problem in &report.host.problems {
+        println!("  problem: {problem}");
+    }
+
+    match (&report.libkrun.path, &report.libkrun.error) {
+        (Some(path), _) => println!("libkrun: {}", path.display()),
+        (None, error) => println!("libkrun: not loaded: {}", error.as_deref().unwrap_or("")),
+    }
+    for function in &report.libkrun.functions {
+        println!("  {:<32}{}", function.name, yes_no(function.available));
+    }
+
+    let archs: Vec<String> = report.guest_archs.iter().map(|a| a.to_string()).collect();
+    println!("guest architectures: {}", archs.join(", "));
+
+    for (title, capabilities) in [("devices", &report.devices), ("features", &report.features)] {
+        println!("{title}:");
+        for capability in capabilities {
+            match capability.note {
+                Some(note) => println!(
+                    "  {:<32}{} ({note})",
+                    capability.name,
+                    yes_no(capability.supported)
+                ),
+                None => println!("  {:<32}{}", capability.name, yes_no(capability.supported)),
+            }
+        }
+    }
+
+    println!("restful endpoints:");
+    for endpoint in &report.restful_endpoints {
+        println!("  {endpoint}");
+    }
+}
+
+fn yes_no(supported: bool) -> &'static str {
+    match supported {
+        true => "yes",
+        false => "no",
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs, io, panic,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+    thread,
+};
+
+/// Host resources created by krunkit, released in reverse order of creation when krunkit exits.
+static RESOURCES: Mutex<Vec<Resource>> = Mutex::new(Vec::new());
+
+/// A host resource created by krunkit which must not outlive it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resource {
+    /// A file (such as the pidfile or a UNIX socket), removed on exit.
+    File(PathBuf),
+
+    /// A helper process, terminated on exit.
+    Process(libc::pid_t),
+}
+
+impl Resource {
+    fn release(&self) {
+        match self {
+            Self::File(path) => {
+                if let Err(e) = fs::remove_file(path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        println!("Error removing {}: {e}", path.display());
+                    }
+                }
+            }
+            Self::Process(pid) => {
+                if unsafe { libc::kill(*pid, libc::SIGTERM) } < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.raw_os_error() != Some(libc::ESRCH) {
+                        println!("Error terminating process {pid}: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The registry may be used from a panic hook, so ignore poisoning.
+fn resources() -> MutexGuard<'static, Vec<Resource>> {
+    RESOURCES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Track a resource to release when krunkit exits.
+pub fn register(resource: Resource) {
+    let mut resources = resources();
+
+    if !resources.contains(&resource) {
+        resources.push(resource);
+    }
+}
+
+/// Stop tracking a resource, for example once a helper process has exited on its own.
+pub fn unregister(resource: &Resource) {
+    resources().retain(|r| r != resource);
+}
+
+/// Release every tracked resource, most recently created first.
+pub fn run() {
+    let released: Vec<Resource> = resources().drain(..).rev().collect();
+
+    for resource in released {
+        resource.release();
+    }
+}
+
+/// Release all resources if the main thread panics, as krunkit exits without returning from
+/// main. Panics on other threads do not terminate krunkit, and are left to the default hook.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        if thread::current().name() == Some("main") {
+            run();
+        }
+    }));
+}
+
+mod tests {
+    #[test]
+    fn cleanup_registry() {
+        use super::*;
+
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("krunkit-cleanup-test-{}-1", std::process::id()));
+        let second = dir.join(format!("krunkit-cleanup-test-{}-2", std::process::id()));
+        fs::write(&first, "").unwrap();
+        fs::write(&second, "").unwrap();
+
+        register(Resource::File(first.clone()));
+        register(Resource::File(second.clone()));
+        register(Resource::File(second.clone()));
+        unregister(&Resource::File(second.clone()));
+        run();
+
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert!(resources().is_empty());
+
+        fs::remove_file(second).unwrap();
+    }
+}
@@ -1,16 +1,62 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{status::RestfulUriAddr, virtio::VirtioDeviceConfig};
+use crate::{
+    activation::ActivationConfig,
+    agent::GuestAgentConfig,
+    capabilities::CapabilitiesArgs,
+    config::label_parse,
+    copy::CpArgs,
+    diagnose::DiagnoseArgs,
+    exec::ExecArgs,
+    health::{HeartbeatConfig, UnresponsivePolicy},
+    helper::HelperConfig,
+    hook::HookConfig,
+    ignition::{GuestReadyConfig, IgnitionConfig},
+    image::ImageArgs,
+    import::ImportArgs,
+    libkrun::GuestArch,
+    logfilter::LogFilter,
+    lowpower::LowPowerPolicy,
+    oem::OemString,
+    otel::OtelEndpoint,
+    priority::QosClass,
+    privsep::RestfulProxyArgs,
+    quota::CpuQuota,
+    sandbox::SandboxMode,
+    secret::{SecretConfig, SecretSource},
+    statedir::name_parse,
+    status::{RestfulAccess, RestfulUri},
+    thermal::ThermalPolicy,
+    timesync::{HostSleepPolicy, TimeCorrection, TimesyncConfig},
+    virtio::VirtioDeviceConfig,
+    vm::RestartPolicy,
+};
 
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    ffi::OsString,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
 
 /// Command line arguments to configure a krun VM.
 #[derive(Clone, Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args {
+    /// Config file of additional options, with an option (and its value, separated by a space)
+    /// per line, as written by krunkit import.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Profile of the config file to apply on top of its base options.
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Number of vCPUs for the VM.
     #[arg(long)]
     pub cpus: u8,
@@ -19,6 +65,10 @@ pub struct Args {
     #[arg(long)]
     pub memory: u32,
 
+    /// Architecture of the guest (aarch64, x86_64). Defaults to the host's architecture.
+    #[arg(long, default_value_t = GuestArch::host())]
+    pub arch: GuestArch,
+
     /// Bootloader configuration.
     #[arg(long)]
     pub bootloader: Option<bootloader::Config>,
@@ -27,21 +77,279 @@ pub struct Args {
     #[arg(long = "device")]
     pub devices: Vec<VirtioDeviceConfig>,
 
+    /// Helper processes (such as gvproxy) to run for the lifetime of the VM, started in order.
+    #[arg(long = "helper")]
+    pub helpers: Vec<HelperConfig>,
+
+    /// DNS servers advertised to the guest by the network backend started with --helper (passt).
+    #[arg(long = "dns")]
+    pub dns: Vec<IpAddr>,
+
+    /// Search domains advertised to the guest by the network backend started with --helper.
+    #[arg(long = "dns-search")]
+    pub dns_search: Vec<String>,
+
+    /// Scripts to run on the host at points of the VM's lifecycle
+    /// (pre-start=<path>,post-start=<path>,post-stop=<path>).
+    #[arg(long)]
+    pub hook: Option<HookConfig>,
+
     /// URI of the status/shutdown listener.
     #[arg(long = "restful-uri")]
-    pub restful_uri: Option<RestfulUriAddr>,
+    pub restful_uri: Option<RestfulUri>,
+
+    /// Allow the restful service to listen on addresses other than loopback addresses, exposing
+    /// it (unauthenticated) to the network.
+    #[arg(long = "restful-insecure-bind", default_value_t = false)]
+    pub restful_insecure_bind: bool,
+
+    /// Requests clients of the restful service are allowed to make (status-only, full-control).
+    #[arg(long = "restful-access", default_value = "full-control")]
+    pub restful_access: RestfulAccess,
+
+    /// Bearer token clients of the restful service must present, read from a file (@file) or the
+    /// macOS Keychain (keychain:item).
+    #[arg(long = "restful-token")]
+    pub restful_token: Option<SecretSource>,
+
+    /// Handle the connections and requests of restful service clients in a separate, unprivileged
+    /// process.
+    #[arg(long = "restful-privsep", default_value_t = false)]
+    pub restful_privsep: bool,
+
+    /// Guest agent (qemu-guest-agent) channel configuration.
+    #[arg(long = "guest-agent")]
+    pub guest_agent: Option<GuestAgentConfig>,
+
+    /// Channel dedicated to synchronizing the guest's clock, if not done through the guest agent.
+    #[arg(long)]
+    pub timesync: Option<TimesyncConfig>,
+
+    /// Ignition config to serve to the guest on vsock port 1024, as with vfkit.
+    #[arg(long)]
+    pub ignition: Option<IgnitionConfig>,
+
+    /// Channel the guest reports it has booted on (port=N), with a "Ready" message.
+    #[arg(long = "guest-ready")]
+    pub guest_ready: Option<GuestReadyConfig>,
 
     /// GUI option for compatibility with vfkit (ignored).
     #[arg(long, default_value_t = false)]
     pub gui: bool,
 
-    /// SMBIOS OEM String
+    /// SMBIOS OEM String, given as the string, as a key=value pair (with keys prefixed with
+    /// io.containers. unless namespaced), or with the string or value read from a file (@file).
     #[arg(long = "oem-string")]
-    pub oem_strings: Option<Vec<String>>,
+    pub oem_strings: Option<Vec<OemString>>,
+
+    /// Secret to provision into the guest as a systemd credential, read from a file
+    /// (name=@file). The value is passed in an SMBIOS OEM string.
+    #[arg(long = "secret")]
+    pub secrets: Vec<SecretConfig>,
 
     /// Log level for libkrun (0=off, 1=error, 2=warn, 3=info, 4=debug, 5 or higher=trace)
     #[arg(long = "krun-log-level", default_value_t = 0)]
     pub krun_log_level: u32,
+
+    /// Per-module log filter for libkrun, in env_logger syntax (for example,
+    /// "devices::virtio::net=trace,vmm=warn"). Other modules are logged at --krun-log-level.
+    #[arg(long = "krun-log-filter")]
+    pub krun_log_filter: Option<LogFilter>,
+
+    /// OpenTelemetry collector to export traces to, with OTLP over HTTP (for example,
+    /// http://localhost:4318).
+    #[arg(long = "otel-endpoint")]
+    pub otel_endpoint: Option<OtelEndpoint>,
+
+    /// Name of the VM, used to locate its state directory if --state-dir is not specified.
+    #[arg(long, value_parser = name_parse)]
+    pub name: Option<String>,
+
+    /// Metadata label to tag the VM with (key=value), reported by the restful service. A key given
+    /// again replaces the previous value.
+    #[arg(long = "label", value_parser = label_parse)]
+    pub labels: Vec<(String, String)>,
+
+    /// Directory of the VM's runtime files (pidfile, sockets, crash file, EFI variable store).
+    /// Defaults to a directory named after --name in the user's application support directory.
+    #[arg(long = "state-dir")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Path of a file to write the krunkit process ID to. Removed once the VM exits.
+    #[arg(long)]
+    pub pidfile: Option<PathBuf>,
+
+    /// Run in the background, detached from the terminal. krunkit returns once the VM is about to
+    /// run.
+    #[arg(long, default_value_t = false)]
+    pub daemonize: bool,
+
+    /// Path of a file to write krunkit's output to when daemonized (defaults to the system log).
+    #[arg(long = "log-file")]
+    pub log_file: Option<PathBuf>,
+
+    /// Path of a UNIX datagram socket to send an sd_notify(3) READY message to once the VM is
+    /// about to run.
+    #[arg(long = "notify-socket")]
+    pub notify_socket: Option<PathBuf>,
+
+    /// File descriptor to write a READY message to (and then close) once the VM is about to run.
+    #[arg(long = "ready-fd")]
+    pub ready_fd: Option<i32>,
+
+    /// Start the VM only once a connection arrives on a socket (listen=tcp://host:port or
+    /// unix:///path), optionally forwarding connections to another socket (forward=...).
+    #[arg(long = "activate-on")]
+    pub activate_on: Option<ActivationConfig>,
+
+    /// Behavior when the VM terminates abnormally (no, on-failure[:max-retries]).
+    #[arg(long, default_value = "no")]
+    pub restart: RestartPolicy,
+
+    /// Shut the VM down gracefully once it has run for this long (for example, 90s, 30m, 2h).
+    #[arg(long = "max-runtime", value_parser = duration_parse)]
+    pub max_runtime: Option<Duration>,
+
+    /// Shut the VM down gracefully once its vCPUs have been idle for this long (for example,
+    /// 30m).
+    #[arg(long = "idle-timeout", value_parser = duration_parse)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Interval at which the host-side resource usage of the VM (CPU time of krunkit and its
+    /// vCPUs, resident and dirty memory) is sampled.
+    #[arg(long = "stats-interval", value_parser = duration_parse, default_value = "5s")]
+    pub stats_interval: Duration,
+
+    /// Path of a CSV file to append each sample of the host-side resource usage to.
+    #[arg(long = "stats-log")]
+    pub stats_log: Option<PathBuf>,
+
+    /// Path of a file to save the most recent guest console output to if the guest kernel panics.
+    #[arg(long = "crash-file")]
+    pub crash_file: Option<PathBuf>,
+
+    /// Maximum size of the console output saved to the crash file, in KiB.
+    #[arg(long = "crash-file-size", default_value_t = 64)]
+    pub crash_file_size: usize,
+
+    /// Behavior when the host goes to sleep (ignore, suspend).
+    #[arg(long = "on-host-sleep", default_value = "ignore")]
+    pub on_host_sleep: HostSleepPolicy,
+
+    /// How the guest's clock is corrected when resynchronized with the host's: step, or
+    /// slew[:threshold] to have chrony gradually correct offsets up to the threshold (default 1s).
+    #[arg(long = "time-correction", default_value = "step")]
+    pub time_correction: TimeCorrection,
+
+    /// Behavior when the host is under thermal pressure: ignore, or background[:level] to run the
+    /// VM at background priority while the pressure is at least the level (default heavy).
+    #[arg(long = "thermal-policy", default_value = "ignore")]
+    pub thermal_policy: ThermalPolicy,
+
+    /// Behavior while the host is in Low Power Mode (ignore, background).
+    #[arg(long = "low-power-policy", default_value = "ignore")]
+    pub low_power_policy: LowPowerPolicy,
+
+    /// QoS class of the vCPU and I/O threads (background, utility, userinitiated,
+    /// userinteractive).
+    #[arg(long)]
+    pub qos: Option<QosClass>,
+
+    /// Scheduling priority (nice value, from -20 to 19) of the vCPU and I/O threads. Negative
+    /// values require root privileges.
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19)
+    )]
+    pub nice: Option<i32>,
+
+    /// Host CPU time the vCPUs may use, in percent of a host CPU (for example, 200% for two host
+    /// CPUs).
+    #[arg(long = "cpu-quota")]
+    pub cpu_quota: Option<CpuQuota>,
+
+    /// Path of a file in the guest to write the host's power state (power source and battery
+    /// charge) to as JSON whenever it changes. Requires --guest-agent.
+    #[arg(long = "host-power-file")]
+    pub host_power_file: Option<PathBuf>,
+
+    /// Periodically ping the guest agent to check the guest's health: interval=DURATION (default
+    /// 10s), threshold=N consecutive missed heartbeats after which the guest is unresponsive
+    /// (default 3). Requires --guest-agent.
+    #[arg(long)]
+    pub heartbeat: Option<HeartbeatConfig>,
+
+    /// Behavior when the guest becomes unresponsive to heartbeats (log, restart, stop).
+    #[arg(long = "on-unresponsive", default_value = "log")]
+    pub on_unresponsive: UnresponsivePolicy,
+
+    /// Prevent the host from idle sleeping, and krunkit from being throttled by App Nap, while the
+    /// VM is running.
+    #[arg(long, default_value_t = false)]
+    pub caffeinate: bool,
+
+    /// Restrictions applied to krunkit once the VM is about to run (off, strict). In strict mode,
+    /// file access is limited to the disks, shares, sockets, and files given on the command line.
+    #[arg(long, default_value = "off")]
+    pub sandbox: SandboxMode,
+
+    /// Start the VM even if the host does not have enough free memory for its RAM, or free disk
+    /// space to grow its disks, warning instead of failing.
+    #[arg(long, default_value_t = false)]
+    pub force: bool,
+
+    /// Print the resolved VM configuration as JSON and exit without running the VM.
+    #[arg(long = "print-config", default_value_t = false)]
+    pub print_config: bool,
+}
+
+/// Command line arguments of a krunkit subcommand, which acts on an existing krunkit instance
+/// rather than running a VM.
+#[derive(Clone, Debug, Parser)]
+#[command(name = "krunkit", version)]
+pub struct CommandArgs {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// krunkit subcommands.
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Copy a file between the host and the guest of a running krunkit instance, through its guest
+    /// agent channel.
+    Cp(CpArgs),
+
+    /// Collect the configuration, logs, and state of a running krunkit instance, along with host
+    /// information, into a tarball to attach to bug reports.
+    Diagnose(DiagnoseArgs),
+
+    /// Report the devices, features, and restful endpoints supported by krunkit on this host,
+    /// given the libkrun it loads.
+    Capabilities(CapabilitiesArgs),
+
+    /// Run a command in the guest of a running krunkit instance through its guest agent channel,
+    /// exiting with the command's exit code.
+    Exec(ExecArgs),
+
+    /// Manage disk images.
+    Image(ImageArgs),
+
+    /// Convert a vfkit or libvirt VM definition into a krunkit config file (see --config).
+    Import(ImportArgs),
+
+    /// Serve the restful service on behalf of a krunkit instance (see --restful-privsep).
+    #[command(hide = true)]
+    RestfulProxy(RestfulProxyArgs),
+}
+
+/// Read the process ID of a krunkit instance from its pidfile.
+pub fn read_pidfile(path: &Path) -> Result<u32> {
+    fs::read_to_string(path)
+        .context(format!("unable to read pidfile {}", path.display()))?
+        .trim()
+        .parse::<u32>()
+        .context("pidfile does not contain a process ID")
 }
 
 /// Parse a string into a vector of substrings, all of which are separated by commas.
@@ -87,15 +395,224 @@ pub fn val_parse(s: &str, label: &str) -> Result<String> {
     }
 }
 
+/// Insert the options of the config file given with --config, if any, after the --config option
+/// in the command line arguments, with those of the profile given with --profile applied.
+pub fn expand_config_file(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let profile = option_value(&args, "--profile")?.map(|(_, profile)| profile);
+
+    let Some((end, path)) = option_value(&args, "--config")? else {
+        return match profile {
+            Some(_) => Err(anyhow!("--profile requires --config")),
+            None => Ok(args),
+        };
+    };
+
+    let contents =
+        fs::read_to_string(&path).context(format!("unable to read config file {path}"))?;
+    let options = config_file_parse(&contents, profile.as_deref())
+        .context(format!("invalid config file {path}"))?;
+
+    let mut expanded = args[..end].to_vec();
+    expanded.extend(options.into_iter().map(OsString::from));
+    expanded.extend_from_slice(&args[end..]);
+
+    Ok(expanded)
+}
+
+/// Find the value of an option in the command line arguments, given either as "--option value"
+/// or as "--option=value", along with the index of the argument following it.
+fn option_value(args: &[OsString], option: &str) -> Result<Option<(usize, String)>> {
+    let prefix = format!("{option}=");
+
+    for (i, arg) in args.iter().enumerate() {
+        let Some(arg) = arg.to_str() else {
+            continue;
+        };
+
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Ok(Some((i + 1, value.to_string())));
+        }
+        if arg == option {
+            let value = args
+                .get(i + 1)
+                .ok_or(anyhow!("expected a value for {option}"))?;
+            return Ok(Some((i + 2, value.to_string_lossy().to_string())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// An option of a config file, with its value, if any.
+type ConfigOption = (String, Option<String>);
+
+/// Parse the contents of a config file into arguments. Empty lines and lines starting with # are
+/// ignored, and the value of an option is the rest of its line, which may contain spaces.
+///
+/// The options following a [profiles.NAME] line make up the NAME profile, which is only applied
+/// if selected. A profile adds devices (and other options that can be repeated) to the base
+/// options before the first profile, removes the devices starting with the value of
+/// --remove-device, and replaces the base options it repeats.
+pub fn config_file_parse(contents: &str, profile: Option<&str>) -> Result<Vec<String>> {
+    let mut options: Vec<ConfigOption> = Vec::new();
+    let mut profiles: Vec<(String, Vec<ConfigOption>)> = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let name = line
+                .strip_prefix("[profiles.")
+                .and_then(|l| l.strip_suffix(']'))
+                .filter(|name| !name.is_empty())
+                .ok_or(anyhow!("invalid section {line} (expected [profiles.NAME])"))?;
+            if profiles.iter().any(|(n, _)| n == name) {
+                return Err(anyhow!("profile {name} defined more than once"));
+            }
+            profiles.push((name.to_string(), Vec::new()));
+            continue;
+        }
+
+        let option = match line.split_once(' ') {
+            Some((option, value)) => (option.to_string(), Some(value.trim().to_string())),
+            None => (line.to_string(), None),
+        };
+        match profiles.last_mut() {
+            Some((_, profile)) => profile.push(option),
+            None if option.0 == "--remove-device" => {
+                return Err(anyhow!("--remove-device is only allowed in profiles"))
+            }
+            None => options.push(option),
+        }
+    }
+
+    if let Some(name) = profile {
+        let Some((_, profile)) = profiles.iter().find(|(n, _)| n == name) else {
+            let names: Vec<&str> = profiles.iter().map(|(n, _)| n.as_str()).collect();
+            return Err(anyhow!(
+                "profile {name} not found (available profiles: {})",
+                match names.is_empty() {
+                    true => String::from("none"),
+                    false => names.join(", "),
+                }
+            ));
+        };
+
+        for (option, value) in profile {
+            profile_apply(&mut options, option, value.as_deref())
+                .context(format!("invalid profile {name}"))?;
+        }
+    }
+
+    Ok(options
+        .into_iter()
+        .flat_map(|(option, value)| [Some(option), value])
+        .flatten()
+        .collect())
+}
+
+/// Apply an option of a profile to the options of a config file.
+fn profile_apply(options: &mut Vec<ConfigOption>, option: &str, value: Option<&str>) -> Result<()> {
+    if option == "--remove-device" {
+        let device = value.ok_or(anyhow!("expected --remove-device argument to be a device"))?;
+
+        // A device is matched by its full configuration, or by its leading sub-arguments.
+        let matches = |v: &Option<String>| {
+            v.as_deref().is_some_and(|v| {
+                v == device || v.strip_prefix(device).is_some_and(|r| r.starts_with(','))
+            })
+        };
+        let count = options.len();
+        options.retain(|(o, v)| !(o == "--device" && matches(v)));
+        if options.len() == count {
+            return Err(anyhow!("no device matching {device} to remove"));
+        }
+
+        return Ok(());
+    }
+
+    let repeatable = Args::command()
+        .get_arguments()
+        .find(|arg| option.strip_prefix("--") == arg.get_long())
+        .is_some_and(|arg| matches!(arg.get_action(), ArgAction::Append | ArgAction::Count));
+    if !repeatable {
+        options.retain(|(o, _)| o != option);
+    }
+    options.push((option.to_string(), value.map(String::from)));
+
+    Ok(())
+}
+
+/// Parse a size in bytes, optionally suffixed with a binary unit (K, M, G, or T, optionally
+/// followed by iB), for example 100G.
+pub fn size_parse(s: &str) -> Result<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let shift = match unit.to_uppercase().trim_end_matches("IB") {
+        "" if !unit.is_empty() => return Err(anyhow!("invalid size unit in {s}")),
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(anyhow!("invalid size unit in {s}")),
+    };
+    let n = u64::from_str(num).context(format!("invalid size: {s}"))?;
+
+    n.checked_mul(1 << shift)
+        .ok_or(anyhow!("size too large: {s}"))
+}
+
+/// Parse a duration made of one or more numbers suffixed with a unit (h, m, s, or ms), for example
+/// 1h30m. A number without a unit is a number of seconds.
+pub fn duration_parse(s: &str) -> Result<Duration> {
+    if let Ok(secs) = u64::from_str(s) {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut millis: u64 = 0;
+    let mut num = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            'h' => 3_600_000,
+            'm' if chars.next_if_eq(&'s').is_some() => 1,
+            'm' => 60_000,
+            's' => 1000,
+            _ => return Err(anyhow!("invalid duration unit '{c}' in {s}")),
+        };
+        let n = u64::from_str(&num).context(format!("invalid duration: {s}"))?;
+        millis = n
+            .checked_mul(unit)
+            .and_then(|n| millis.checked_add(n))
+            .ok_or(anyhow!("duration too long: {s}"))?;
+        num.clear();
+    }
+
+    if !num.is_empty() || s.is_empty() {
+        return Err(anyhow!("invalid duration: {s}"));
+    }
+
+    Ok(Duration::from_millis(millis))
+}
+
 /// A wrapper of all data associated with the bootloader argument.
 mod bootloader {
     use super::*;
 
     #[derive(Clone, Debug)]
     pub struct Config {
-        fw: BootloaderFw,
-        vstore: PathBuf,
-        action: Action,
+        pub fw: BootloaderFw,
+        pub vstore: PathBuf,
+        pub action: Action,
     }
 
     impl FromStr for Config {
@@ -172,14 +689,169 @@ mod bootloader {
 }
 
 mod tests {
+    #[test]
+    fn duration_parse_units() {
+        use super::*;
+
+        assert_eq!(duration_parse("45").unwrap(), Duration::from_secs(45));
+        assert_eq!(duration_parse("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration_parse("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(duration_parse("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(duration_parse("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(duration_parse("1m30s").unwrap(), Duration::from_secs(90));
+        assert!(duration_parse("").is_err());
+        assert!(duration_parse("2d").is_err());
+        assert!(duration_parse("1h30").is_err());
+        assert!(duration_parse("99999999999999999999h").is_err());
+        assert!(duration_parse("9999999999999999h").is_err());
+        assert!(duration_parse("5000000000000h5000000000000h").is_err());
+        assert!(duration_parse("h").is_err());
+    }
+
+    #[test]
+    fn size_parse_units() {
+        use super::*;
+
+        assert_eq!(size_parse("4096").unwrap(), 4096);
+        assert_eq!(size_parse("100G").unwrap(), 100 << 30);
+        assert_eq!(size_parse("512MiB").unwrap(), 512 << 20);
+        assert_eq!(size_parse("2t").unwrap(), 2 << 40);
+        assert!(size_parse("").is_err());
+        assert!(size_parse("10GB").is_err());
+        assert!(size_parse("G").is_err());
+        assert!(size_parse("20000000T").is_err());
+    }
+
+    #[test]
+    fn device_socket_policy_parse() {
+        use super::*;
+
+        let net = VirtioDeviceConfig::from_str(
+            "virtio-net,unixSocketPath=/tmp/gv.sock,mac=5a:94:ef:e4:0c:ee,wait-for-socket=30s,optional=true",
+        )
+        .unwrap();
+        let VirtioDeviceConfig::Net(config) = &net else {
+            panic!("expected virtio-net device");
+        };
+        assert_eq!(config.socket_policy.wait_for_socket_secs, Some(30));
+        assert!(config.socket_policy.optional);
+        assert_eq!(
+            net.to_string(),
+            "virtio-net,unixSocketPath=/tmp/gv.sock,mac=5A:94:EF:E4:0C:EE,wait-for-socket=30s,optional=true"
+        );
+
+        let vsock = VirtioDeviceConfig::from_str(
+            "virtio-vsock,port=1024,socketURL=/tmp/v.sock,listen,optional=true",
+        )
+        .unwrap();
+        let VirtioDeviceConfig::Vsock(config) = vsock else {
+            panic!("expected virtio-vsock device");
+        };
+        assert_eq!(config.socket_policy.wait_for_socket_secs, None);
+        assert!(config.socket_policy.optional);
+
+        assert!(VirtioDeviceConfig::from_str(
+            "virtio-net,unixSocketPath=/tmp/gv.sock,mac=5a:94:ef:e4:0c:ee,optional=maybe"
+        )
+        .is_err());
+
+        let stream = VirtioDeviceConfig::from_str(
+            "virtio-net,unixStreamPath=/tmp/passt.sock,mac=5a:94:ef:e4:0c:ee",
+        )
+        .unwrap();
+        let VirtioDeviceConfig::Net(config) = &stream else {
+            panic!("expected virtio-net device");
+        };
+        assert_eq!(config.api, crate::virtio::NetApi::Unixstream);
+        assert_eq!(
+            stream.to_string(),
+            "virtio-net,unixStreamPath=/tmp/passt.sock,mac=5A:94:EF:E4:0C:EE"
+        );
+    }
+
+    #[test]
+    fn config_file_parse_lines() {
+        use super::*;
+
+        let contents = "# imported\n--cpus 2\n\n  --device virtio-blk,path=/Users/user/VM Disks/a.img,format=raw\n--gui\n";
+        assert_eq!(
+            config_file_parse(contents, None).unwrap(),
+            vec![
+                "--cpus",
+                "2",
+                "--device",
+                "virtio-blk,path=/Users/user/VM Disks/a.img,format=raw",
+                "--gui"
+            ]
+        );
+    }
+
+    #[test]
+    fn config_file_profiles() {
+        use super::*;
+
+        let contents = "--cpus 2\n--device virtio-rng\n--device virtio-gpu,width=800,height=600\n--device virtio-fs,sharedDir=/Users/user/src,mountTag=src\n\n[profiles.ci]\n--cpus 4\n--remove-device virtio-gpu\n--device virtio-serial,logFilePath=/tmp/ci.log\n\n[profiles.gui]\n--gui\n";
+
+        assert_eq!(
+            config_file_parse(contents, Some("ci")).unwrap(),
+            vec![
+                "--device",
+                "virtio-rng",
+                "--device",
+                "virtio-fs,sharedDir=/Users/user/src,mountTag=src",
+                "--cpus",
+                "4",
+                "--device",
+                "virtio-serial,logFilePath=/tmp/ci.log"
+            ]
+        );
+        assert_eq!(
+            config_file_parse(contents, None).unwrap().len(),
+            config_file_parse(contents, Some("gui")).unwrap().len() - 1
+        );
+        assert!(config_file_parse(contents, Some("release")).is_err());
+        assert!(
+            config_file_parse("[profiles.ci]\n--remove-device virtio-net\n", Some("ci")).is_err()
+        );
+        assert!(config_file_parse("--remove-device virtio-rng\n", None).is_err());
+        assert!(config_file_parse("[ci]\n", None).is_err());
+    }
+
+    #[test]
+    fn config_file_expand() {
+        use super::*;
+
+        let path = std::env::temp_dir().join(format!("krunkit-config-{}", std::process::id()));
+        fs::write(&path, "--cpus 2\n\n[profiles.ci]\n--cpus 4\n").unwrap();
+
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+        let config = path.to_string_lossy().to_string();
+        let config_eq = format!("--config={config}");
+
+        assert_eq!(
+            expand_config_file(args(&["krunkit", "--config", &config, "--memory", "512"])).unwrap(),
+            args(&["krunkit", "--config", &config, "--cpus", "2", "--memory", "512"])
+        );
+        assert_eq!(
+            expand_config_file(args(&["krunkit", &config_eq, "--profile=ci"])).unwrap(),
+            args(&["krunkit", &config_eq, "--cpus", "4", "--profile=ci"])
+        );
+        assert_eq!(
+            expand_config_file(args(&["krunkit", "--profile", "ci", &config_eq])).unwrap(),
+            args(&["krunkit", "--profile", "ci", &config_eq, "--cpus", "4"])
+        );
+        assert!(expand_config_file(args(&["krunkit", "--profile=ci"])).is_err());
+        assert!(expand_config_file(args(&["krunkit", "--config"])).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
     #[cfg(target_os = "macos")]
     #[test]
     fn mac_cmdline_ordering_argtest() {
         use super::*;
         use crate::virtio::*;
 
-        use std::net::Ipv4Addr;
-
         use mac_address::MacAddress;
 
         let cmdline = vec![
@@ -274,6 +946,7 @@ mod tests {
                 PathBuf::from_str("/Users/user/net.sock").unwrap()
             );
             assert_eq!(net.mac_address, MacAddress::new([0, 0, 0, 0, 0, 0]));
+            assert_eq!(net.api, NetApi::Unixgram);
         } else {
             panic!("expected virtio-net device as 6th device config argument");
         }
@@ -342,8 +1015,13 @@ mod tests {
 
         let restful_uri = args.restful_uri.expect("restful-uri argument not found");
 
-        assert_eq!(restful_uri.ip_addr, Ipv4Addr::new(127, 0, 0, 1));
-        assert_eq!(restful_uri.port, 49573);
+        assert_eq!(
+            restful_uri,
+            RestfulUri::Tcp {
+                host: String::from("127.0.0.1"),
+                port: 49573
+            }
+        );
 
         assert_eq!(args.gui, true);
     }
@@ -0,0 +1,356 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    activation::ActivationConfig,
+    agent::GuestAgentConfig,
+    cmdline::Args,
+    health::{HeartbeatConfig, UnresponsivePolicy},
+    helper::HelperConfig,
+    hook::HookConfig,
+    ignition::{GuestReadyConfig, IgnitionConfig},
+    libkrun::GuestArch,
+    logfilter::LogFilter,
+    lowpower::LowPowerPolicy,
+    oem::OemString,
+    otel::OtelEndpoint,
+    priority::QosClass,
+    quota::CpuQuota,
+    sandbox::SandboxMode,
+    secret::{SecretConfig, SecretSource},
+    statedir,
+    status::{RestfulAccess, RestfulUri},
+    thermal::ThermalPolicy,
+    timesync::{HostSleepPolicy, TimeCorrection, TimesyncConfig},
+    virtio::VirtioDeviceConfig,
+    vm::RestartPolicy,
+};
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+/// The fully-resolved configuration of a krun VM. This is what is printed with --print-config
+/// and reported by the restful service's inspect endpoint.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VmConfig {
+    /// Name of the VM.
+    pub name: Option<String>,
+
+    /// Metadata labels the VM is tagged with.
+    pub labels: BTreeMap<String, String>,
+
+    /// Directory of the VM's runtime files.
+    pub state_dir: Option<PathBuf>,
+
+    /// Number of vCPUs.
+    pub cpus: u8,
+
+    /// Amount of RAM (MiB).
+    pub memory_mib: u32,
+
+    /// Architecture of the guest.
+    pub arch: GuestArch,
+
+    /// Amount of memory available for the GPU's host-visible shared memory region (bytes).
+    pub vram_bytes: u64,
+
+    /// Bootloader configuration, including the path of the EFI variable store.
+    pub bootloader: Option<BootloaderReport>,
+
+    /// virtio devices, in the order they were configured.
+    pub devices: Vec<DeviceReport>,
+
+    /// URI of the status/shutdown listener. Once the listener is bound, this is the address
+    /// actually being listened on.
+    pub restful_uri: RestfulUri,
+
+    /// The restful service may listen on addresses other than loopback addresses.
+    pub restful_insecure_bind: bool,
+
+    /// Requests clients of the restful service are allowed to make.
+    pub restful_access: RestfulAccess,
+
+    /// Where the bearer token of the restful service is read from.
+    pub restful_token: Option<SecretSource>,
+
+    /// Requests of restful service clients are handled in a separate, unprivileged process.
+    pub restful_privsep: bool,
+
+    /// Helper processes run for the lifetime of the VM.
+    pub helpers: Vec<HelperConfig>,
+
+    /// Scripts run on the host at points of the VM's lifecycle.
+    pub hook: Option<HookConfig>,
+
+    /// Guest agent channel configuration.
+    pub guest_agent: Option<GuestAgentConfig>,
+
+    /// Timesync channel configuration.
+    pub timesync: Option<TimesyncConfig>,
+
+    /// Ignition config served to the guest.
+    pub ignition: Option<IgnitionConfig>,
+
+    /// Channel the guest reports it has booted on.
+    pub guest_ready: Option<GuestReadyConfig>,
+
+    /// SMBIOS OEM strings (files they are read from are reported by path).
+    pub oem_strings: Vec<OemString>,
+
+    /// Secrets provisioned into the guest (names and sources only).
+    pub secrets: Vec<SecretConfig>,
+
+    /// Socket whose first connection starts the VM, if any.
+    pub activate_on: Option<ActivationConfig>,
+
+    /// Behavior when the VM terminates abnormally.
+    pub restart: RestartPolicy,
+
+    /// Path of a file to save the most recent console output to if the guest kernel panics.
+    pub crash_file: Option<PathBuf>,
+
+    /// Maximum size of the console output saved to the crash file, in KiB.
+    pub crash_file_size_kib: usize,
+
+    /// Behavior when the host goes to sleep.
+    pub on_host_sleep: HostSleepPolicy,
+
+    /// How the guest's clock is corrected when resynchronized with the host's.
+    pub time_correction: TimeCorrection,
+
+    /// Behavior when the host is under thermal pressure.
+    pub thermal_policy: ThermalPolicy,
+
+    /// Behavior while the host is in Low Power Mode.
+    pub low_power_policy: LowPowerPolicy,
+
+    /// QoS class of the vCPU and I/O threads.
+    pub qos: Option<QosClass>,
+
+    /// Nice value of the vCPU and I/O threads.
+    pub nice: Option<i32>,
+
+    /// Host CPU time the vCPUs may use.
+    pub cpu_quota: Option<CpuQuota>,
+
+    /// Path of a file in the guest the host's power state is written to.
+    pub host_power_file: Option<PathBuf>,
+
+    /// Heartbeats sent to the guest agent to check the guest's health, if any.
+    pub heartbeat: Option<HeartbeatConfig>,
+
+    /// Behavior when the guest becomes unresponsive to heartbeats.
+    pub on_unresponsive: UnresponsivePolicy,
+
+    /// Prevent host idle sleep and App Nap while the VM is running.
+    pub caffeinate: bool,
+
+    /// Restrictions applied to krunkit once the VM is about to run.
+    pub sandbox: SandboxMode,
+
+    /// Seconds after which the VM is shut down.
+    pub max_runtime_secs: Option<u64>,
+
+    /// Seconds of vCPU idleness after which the VM is shut down.
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Milliseconds between samples of the host-side resource usage.
+    pub stats_interval_ms: u64,
+
+    /// Path of the CSV file samples of the host-side resource usage are appended to.
+    pub stats_log: Option<PathBuf>,
+
+    /// Log level for libkrun.
+    pub krun_log_level: u32,
+
+    /// Per-module log filter for libkrun.
+    pub krun_log_filter: Option<LogFilter>,
+
+    /// OpenTelemetry collector traces are exported to.
+    pub otel_endpoint: Option<OtelEndpoint>,
+}
+
+/// Bootloader configuration report.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootloaderReport {
+    pub firmware: String,
+    pub variable_store: String,
+    pub action: String,
+}
+
+/// A virtio device with the identifier it is reported under.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceReport {
+    /// Identifier of the device, composed of the device label and its index among the devices of
+    /// the same type (for example, virtio-blk-1 is the second virtio-blk device).
+    pub id: String,
+
+    #[serde(flatten)]
+    pub config: VirtioDeviceConfig,
+}
+
+impl From<&Args> for VmConfig {
+    fn from(args: &Args) -> Self {
+        let mut devices: Vec<DeviceReport> = Vec::with_capacity(args.devices.len());
+        for device in &args.devices {
+            let label = device.label();
+            let index = devices.iter().filter(|d| d.config.label() == label).count();
+
+            devices.push(DeviceReport {
+                id: format!("{label}-{index}"),
+                config: device.clone(),
+            });
+        }
+
+        let bootloader = args.bootloader.as_ref().map(|b| BootloaderReport {
+            firmware: format!("{:?}", b.fw).to_lowercase(),
+            variable_store: b.vstore.display().to_string(),
+            action: format!("{:?}", b.action).to_lowercase(),
+        });
+
+        Self {
+            name: args.name.clone(),
+            labels: args.labels.iter().cloned().collect(),
+            state_dir: statedir::path().cloned(),
+            cpus: args.cpus,
+            memory_mib: args.memory,
+            arch: args.arch,
+            vram_bytes: vram_size(args.memory),
+            bootloader,
+            devices,
+            restful_uri: args.restful_uri.clone().unwrap_or_default(),
+            restful_insecure_bind: args.restful_insecure_bind,
+            restful_access: args.restful_access,
+            restful_token: args.restful_token.clone(),
+            restful_privsep: args.restful_privsep,
+            helpers: args.helpers.clone(),
+            hook: args.hook.clone(),
+            guest_agent: args.guest_agent.clone(),
+            timesync: args.timesync.clone(),
+            ignition: args.ignition.clone(),
+            guest_ready: args.guest_ready.clone(),
+            oem_strings: args.oem_strings.clone().unwrap_or_default(),
+            secrets: args.secrets.clone(),
+            activate_on: args.activate_on.clone(),
+            restart: args.restart,
+            crash_file: args.crash_file.clone(),
+            crash_file_size_kib: args.crash_file_size,
+            on_host_sleep: args.on_host_sleep,
+            time_correction: args.time_correction,
+            thermal_policy: args.thermal_policy,
+            low_power_policy: args.low_power_policy,
+            qos: args.qos,
+            nice: args.nice,
+            cpu_quota: args.cpu_quota,
+            host_power_file: args.host_power_file.clone(),
+            heartbeat: args.heartbeat.clone(),
+            on_unresponsive: args.on_unresponsive,
+            caffeinate: args.caffeinate,
+            sandbox: args.sandbox,
+            max_runtime_secs: args.max_runtime.map(|d| d.as_secs()),
+            idle_timeout_secs: args.idle_timeout.map(|d| d.as_secs()),
+            stats_interval_ms: args.stats_interval.as_millis() as u64,
+            stats_log: args.stats_log.clone(),
+            krun_log_level: args.krun_log_level,
+            krun_log_filter: args.krun_log_filter.clone(),
+            otel_endpoint: args.otel_endpoint.clone(),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Concise summary of the configuration logged as the VM boots, so that its output includes
+    /// the facts needed to investigate a problem.
+    pub fn banner(&self) -> String {
+        let mut lines = vec![
+            match &self.name {
+                Some(name) => format!("krunkit {} starting VM {name}:", env!("CARGO_PKG_VERSION")),
+                None => format!("krunkit {} starting VM:", env!("CARGO_PKG_VERSION")),
+            },
+            format!(
+                "  {} vCPU(s), {} MiB RAM, {} MiB VRAM, {} guest",
+                self.cpus,
+                self.memory_mib,
+                self.vram_bytes / (1024 * 1024),
+                self.arch
+            ),
+        ];
+
+        // The EFI firmware is bundled in libkrun-efi.
+        lines.push(match &self.bootloader {
+            Some(b) => format!(
+                "  firmware: libkrun-efi ({}), variable store {} ({})",
+                b.firmware, b.variable_store, b.action
+            ),
+            None => String::from("  firmware: libkrun-efi"),
+        });
+
+        lines.extend(
+            self.devices
+                .iter()
+                .map(|d| format!("  device {}: {}", d.id, d.config)),
+        );
+        lines.push(format!("  restful service: {}", self.restful_uri));
+        if let Some(dir) = &self.state_dir {
+            lines.push(format!("  state directory: {}", dir.display()));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Parse a metadata label given as key=value. Keys are made of letters, digits, '.', '_', '-' and
+/// '/' (for example, io.podman/project), and values may be empty.
+pub fn label_parse(s: &str) -> Result<(String, String), anyhow::Error> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(anyhow!("label {s} is not a key=value pair"));
+    };
+
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+    {
+        return Err(anyhow!(
+            "invalid label key {key} (only letters, digits, '.', '_', '-' and '/' are allowed)"
+        ));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Size of the GPU's shared memory region for a VM with the given amount of RAM (MiB).
+fn vram_size(memory: u32) -> u64 {
+    let sys = sysinfo::System::new_all();
+
+    // Limit RAM + VRAM to 64 GB (36 bit IPA address limit) minus 2 GB (start address plus
+    // rounding).
+    let rounded_mem = ((memory as u64) / 1024 + 1) * 1024;
+    std::cmp::min(
+        63488u64.saturating_sub(rounded_mem) * 1024 * 1024,
+        sys.total_memory(),
+    )
+}
+
+mod tests {
+    #[test]
+    fn vm_label_parse() {
+        use super::*;
+
+        assert_eq!(
+            label_parse("io.podman/project=web").unwrap(),
+            (String::from("io.podman/project"), String::from("web"))
+        );
+        assert_eq!(
+            label_parse("purpose=ci=nightly").unwrap(),
+            (String::from("purpose"), String::from("ci=nightly"))
+        );
+        assert_eq!(label_parse("owner=").unwrap().1, "");
+        assert!(label_parse("owner").is_err());
+        assert!(label_parse("=web").is_err());
+        assert!(label_parse("my project=web").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent::{GuestAgent, GuestInterface},
+    config::VmConfig,
+    helper::HelperConfig,
+    virtio::VirtioDeviceConfig,
+};
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+/// Port gvproxy forwards to the guest's SSH server if not specified with -ssh-port.
+const GVPROXY_DEFAULT_SSH_PORT: u16 = 2222;
+
+/// Port of the guest's SSH server.
+const SSH_PORT: u32 = 22;
+
+/// How to reach the guest over the network and SSH.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Connection {
+    /// Network interfaces of the guest, as reported by the guest agent.
+    pub interfaces: Vec<Interface>,
+
+    /// Why the interfaces could not be retrieved from the guest agent, if they could not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interfaces_error: Option<String>,
+
+    pub ssh: SshConnection,
+}
+
+/// A network interface of the guest, with the virtio-net device backing it, if any.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Interface {
+    #[serde(flatten)]
+    pub guest: GuestInterface,
+
+    /// Identifier of the virtio-net device with the interface's MAC address.
+    pub device: Option<String>,
+}
+
+/// Ways to reach the guest's SSH server from the host.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshConnection {
+    /// Host port forwarded to the guest's SSH server by a network helper.
+    pub port_forward: Option<SshPortForward>,
+
+    /// UNIX socket connected to the guest's SSH server over vsock.
+    pub vsock: Option<SshVsock>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshPortForward {
+    pub host: String,
+    pub port: u16,
+
+    /// Name of the helper forwarding the port.
+    pub helper: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshVsock {
+    pub port: u32,
+    pub socket: PathBuf,
+}
+
+/// Gather how to reach the guest from the interfaces the guest agent reports (if configured) and
+/// the configuration of the devices and helpers.
+pub fn connection(config: &VmConfig, agent: Option<&GuestAgent>) -> Connection {
+    let (guest, interfaces_error) = match agent.map(|a| a.network_interfaces()) {
+        Some(Ok(interfaces)) => (interfaces, None),
+        Some(Err(e)) => (Vec::new(), Some(format!("{e:#}"))),
+        None => (Vec::new(), Some(String::from("no guest agent configured"))),
+    };
+
+    let interfaces = guest
+        .into_iter()
+        .map(|guest| {
+            let device = config.devices.iter().find_map(|d| match &d.config {
+                VirtioDeviceConfig::Net(net)
+                    if guest.mac_address.as_deref().is_some_and(|mac| {
+                        mac.eq_ignore_ascii_case(&net.mac_address.to_string())
+                    }) =>
+                {
+                    Some(d.id.clone())
+                }
+                _ => None,
+            });
+
+            Interface { guest, device }
+        })
+        .collect();
+
+    let vsock = config.devices.iter().find_map(|d| match &d.config {
+        VirtioDeviceConfig::Vsock(vsock) if vsock.port == SSH_PORT => Some(SshVsock {
+            port: vsock.port,
+            socket: vsock.socket_url.clone(),
+        }),
+        _ => None,
+    });
+
+    Connection {
+        interfaces,
+        interfaces_error,
+        ssh: SshConnection {
+            port_forward: config.helpers.iter().find_map(ssh_port_forward),
+            vsock,
+        },
+    }
+}
+
+/// SSH port forwarded by a helper. Only gvproxy is known to forward one, from a loopback port
+/// (-ssh-port, 2222 by default, or -1 to disable it) to port 22 of the guest.
+fn ssh_port_forward(helper: &HelperConfig) -> Option<SshPortForward> {
+    let program = Path::new(&helper.command[0]).file_name()?;
+    if program != "gvproxy" {
+        return None;
+    }
+
+    let mut port = GVPROXY_DEFAULT_SSH_PORT.to_string();
+    let mut args = helper.command[1..].iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.trim_start_matches('-');
+        if flag == "ssh-port" {
+            port = args.next()?.clone();
+        } else if let Some(value) = flag.strip_prefix("ssh-port=") {
+            port = value.to_string();
+        }
+    }
+
+    Some(SshPortForward {
+        host: String::from("127.0.0.1"),
+        port: port.parse().ok()?,
+        helper: helper.name.clone(),
+    })
+}
+
+mod tests {
+    #[test]
+    fn gvproxy_ssh_port_forward() {
+        use super::*;
+
+        use std::str::FromStr;
+
+        let forward = |command: &str| {
+            ssh_port_forward(&HelperConfig::from_str(&format!("command={command}")).unwrap())
+        };
+
+        assert_eq!(
+            forward("/opt/podman/bin/gvproxy -listen-vfkit unixgram:///tmp/gv.sock"),
+            Some(SshPortForward {
+                host: String::from("127.0.0.1"),
+                port: 2222,
+                helper: String::from("gvproxy"),
+            })
+        );
+        assert_eq!(
+            forward("gvproxy -ssh-port 50022 -listen-vfkit unixgram:///tmp/gv.sock")
+                .map(|f| f.port),
+            Some(50022)
+        );
+        assert_eq!(
+            forward("gvproxy --ssh-port=50023").map(|f| f.port),
+            Some(50023)
+        );
+        assert_eq!(forward("gvproxy -ssh-port -1"), None);
+        assert_eq!(forward("/usr/bin/passt --vhost-user"), None);
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{boot, virtio::VirtioDeviceConfig};
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Maximum number of lines of console output kept in memory.
+pub const CONSOLE_BUFFER_LINES: usize = 1000;
+
+/// Interval in which the console log file is checked for new output.
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An in-memory ring buffer of the most recent lines of guest console output.
+#[derive(Debug, Default)]
+pub struct ConsoleBuffer {
+    inner: Mutex<ConsoleLines>,
+}
+
+#[derive(Debug, Default)]
+struct ConsoleLines {
+    /// Complete lines, oldest first.
+    lines: VecDeque<String>,
+
+    /// Output following the last newline.
+    partial: Vec<u8>,
+}
+
+impl ConsoleBuffer {
+    /// Append console output to the buffer, dropping the oldest lines if full. Returns the lines
+    /// completed by the output.
+    pub fn push(&self, output: &[u8]) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut completed = Vec::new();
+
+        inner.partial.extend_from_slice(output);
+        while let Some(idx) = inner.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = inner.partial.drain(..=idx).collect();
+            if inner.lines.len() == CONSOLE_BUFFER_LINES {
+                inner.lines.pop_front();
+            }
+            let line = String::from_utf8_lossy(&line)
+                .trim_end_matches(['\r', '\n'])
+                .to_string();
+            inner.lines.push_back(line.clone());
+            completed.push(line);
+        }
+
+        completed
+    }
+
+    /// Retrieve (at most) the last n lines of console output, including output that has not yet
+    /// been terminated with a newline.
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut lines: Vec<String> = inner.lines.iter().cloned().collect();
+        if !inner.partial.is_empty() {
+            lines.push(String::from_utf8_lossy(&inner.partial).to_string());
+        }
+
+        let skip = lines.len().saturating_sub(n);
+        lines.split_off(skip)
+    }
+}
+
+/// Follow the console log file that libkrun writes the guest's output to, and store each line of
+/// output in the console buffer, passing it to the line handler as well. Only output written after
+/// this is called is stored.
+pub fn console_tail<F>(path: PathBuf, buffer: Arc<ConsoleBuffer>, on_line: F)
+where
+    F: Fn(&str) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut offset = file_len(&path);
+        let mut first_output = true;
+        let mut buf = [0u8; 4096];
+
+        loop {
+            thread::sleep(CONSOLE_POLL_INTERVAL);
+
+            let Ok(mut file) = File::open(&path) else {
+                continue;
+            };
+
+            // The file was truncated (for example, recreated by libkrun). Start from the
+            // beginning.
+            let len = file_len(&path);
+            if len < offset {
+                offset = 0;
+            }
+            if len == offset || file.seek(SeekFrom::Start(offset)).is_err() {
+                continue;
+            }
+
+            while let Ok(sz) = file.read(&mut buf) {
+                if sz == 0 {
+                    break;
+                }
+                if first_output {
+                    boot::mark("firstConsoleOutput");
+                    first_output = false;
+                }
+
+                for line in buffer.push(&buf[..sz]) {
+                    on_line(&line);
+                }
+                offset += sz as u64;
+            }
+        }
+    });
+}
+
+/// Path of the file the guest's console output is written to, if the VM has a virtio-serial
+/// device. libkrun only writes the console to the log file of the last virtio-serial device
+/// configured.
+pub fn console_log_path<'a>(
+    devices: impl DoubleEndedIterator<Item = &'a VirtioDeviceConfig>,
+) -> Option<PathBuf> {
+    devices.rev().find_map(|d| match d {
+        VirtioDeviceConfig::Serial(serial) => Some(serial.log_file_path.clone()),
+        _ => None,
+    })
+}
+
+/// Length of a file, or 0 if it cannot be read.
+pub fn file_len(path: &Path) -> u64 {
+    path.metadata().map(|m| m.len()).unwrap_or(0)
+}
+
+mod tests {
+    #[test]
+    fn console_buffer_tail() {
+        use super::*;
+
+        let buffer = ConsoleBuffer::default();
+        buffer.push(b"first\r\nsec");
+        buffer.push(b"ond\nthi");
+
+        assert_eq!(buffer.tail(2), vec!["second", "thi"]);
+        assert_eq!(buffer.tail(10), vec!["first", "second", "thi"]);
+
+        for i in 0..CONSOLE_BUFFER_LINES {
+            buffer.push(format!("{i}\n").as_bytes());
+        }
+        let lines = buffer.tail(CONSOLE_BUFFER_LINES + 1);
+        assert_eq!(lines.len(), CONSOLE_BUFFER_LINES);
+        assert_eq!(lines[0], "thi0");
+    }
+}
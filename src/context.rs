@@ -3,25 +3,48 @@
 use super::*;
 
 use crate::{
-    status::{get_shutdown_eventfd, status_listener},
-    virtio::KrunContextSet,
+    activation::activation_listener,
+    agent::{agent_lock_path, GuestAgent},
+    boot,
+    caffeinate::Caffeinate,
+    cleanup::{self, Resource},
+    console::{console_log_path, console_tail, ConsoleBuffer},
+    crash::{self, CrashPolicy},
+    daemon::DaemonReady,
+    events::EventKind,
+    grow::{filesystem_grower, grow_disks},
+    health::heartbeat_monitor,
+    hook::HookPoint,
+    hostpower::power_state_propagator,
+    ignition::{guest_ready_listener, serve_ignition, IGNITION_VSOCK_PORT},
+    libkrun::{self, libkrun},
+    limits::{idle_monitor, max_runtime_monitor},
+    logfilter::LOG_LEVEL_INFO,
+    lowpower::low_power_monitor,
+    notify::ReadyNotify,
+    oem, otel, priority,
+    quota::cpu_quota_limiter,
+    sandbox::{self, SandboxMode},
+    shares::share_watcher,
+    signal::signal_listener,
+    state::{StateHistory, VmState},
+    stats::stats_sampler,
+    status::{get_shutdown_eventfd, status_listener, RestfulUri},
+    thermal::thermal_monitor,
+    timesync::{
+        clock_resync_scheduler, power_monitor, GuestClock, TimeCorrection, TimesyncProtocol,
+    },
+    virtio::{KrunContextSet, VirtioDeviceConfig},
+    vm::{self, ExitReason, VmHandle},
 };
 
-use std::ffi::{c_char, CString};
-use std::{convert::TryFrom, ptr, thread};
+use std::ffi::CString;
+use std::{
+    convert::TryFrom, fmt, fs, path::PathBuf, process, ptr, sync::Arc, thread, time::Duration,
+};
 
 use anyhow::{anyhow, Context};
 
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_create_ctx() -> i32;
-    fn krun_set_log_level(level: u32) -> i32;
-    fn krun_set_gpu_options2(ctx_id: u32, virgl_flags: u32, shm_size: u64) -> i32;
-    fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32;
-    fn krun_set_smbios_oem_strings(ctx_id: u32, oem_strings: *const *const c_char) -> i32;
-    fn krun_start_enter(ctx_id: u32) -> i32;
-}
-
 const VIRGLRENDERER_VENUS: u32 = 1 << 6;
 const VIRGLRENDERER_NO_VIRGL: u32 = 1 << 7;
 
@@ -29,25 +52,52 @@ const VIRGLRENDERER_NO_VIRGL: u32 = 1 << 7;
 pub struct KrunContext {
     id: u32,
     args: Args,
+    config: VmConfig,
+    restful_token: Option<String>,
+
+    /// Lifecycle state of the VM, from its configuration on.
+    state: Arc<StateHistory>,
+
+    /// Disk images grown at startup (see grow-to).
+    grown_disks: Vec<PathBuf>,
 }
 
 /// Create a krun context from the command line arguments.
 impl TryFrom<Args> for KrunContext {
     type Error = anyhow::Error;
 
-    fn try_from(args: Args) -> Result<Self, Self::Error> {
-        // Start by setting up the desired log level for libkrun.
-        unsafe { krun_set_log_level(args.krun_log_level) };
+    fn try_from(mut args: Args) -> Result<Self, Self::Error> {
+        let state = Arc::new(StateHistory::default());
 
-        // Create a new context in libkrun. Store identifier to later use to configure VM
-        // resources and devices.
-        let id = unsafe { krun_create_ctx() };
-        if id < 0 {
-            return Err(anyhow!("unable to create libkrun context"));
+        // Reject devices and services sharing a resource before anything is set up.
+        check_resources(&args)?;
+
+        // Start by loading libkrun and setting up the desired log level (and per-module filter).
+        let krun = libkrun::load()?;
+        krun.check_arch(args.arch)?;
+        if let Some(filter) = &args.krun_log_filter {
+            filter.apply(args.krun_log_level);
         }
+        unsafe { (krun.krun_set_log_level)(args.krun_log_level) };
+
+        // Wait for the sockets of network backends started concurrently with krunkit, dropping the
+        // optional devices whose socket is missing.
+        preflight::await_sockets(&mut args);
 
-        // Safe to unwrap, as it's already ensured that id >= 0.
-        let id = u32::try_from(id).unwrap();
+        // Report problems with the host's configuration, or with access to the files the VM uses,
+        // before libkrun fails to create the context or to start the VM. The restful token and
+        // secrets are read at the same time.
+        let inputs = prepare(&args)?;
+        boot::mark("preflightChecked");
+
+        // Grow the disks before libkrun opens them.
+        let grown_disks = grow_disks(&args.devices)?;
+
+        // Create a new context in libkrun. Store identifier to later use to configure VM
+        // resources and devices.
+        let id = libkrun::check(unsafe { (krun.krun_create_ctx)() })
+            .context("unable to create libkrun context")?;
+        boot::mark("contextCreated");
 
         // Set the krun VM's number of vCPUs and amount of memory allocated.
         //
@@ -67,62 +117,566 @@ impl TryFrom<Args> for KrunContext {
             ));
         }
 
-        if unsafe { krun_set_vm_config(id, args.cpus, args.memory) } < 0 {
-            return Err(anyhow!("unable to set krun vCPU/RAM configuration"));
+        libkrun::check(unsafe { (krun.krun_set_vm_config)(id, args.cpus, args.memory) })
+            .context("unable to set krun vCPU/RAM configuration")?;
+
+        // Summarize the configuration at the info log level, on stderr so that it is not mixed
+        // into output consumed from stdout.
+        let config = VmConfig::from(&args);
+        if args.krun_log_level >= LOG_LEVEL_INFO {
+            eprintln!("{}", config.banner());
         }
 
-        // Temporarily enable GPU by default
+        // Temporarily enable GPU by default, if supported by libkrun.
         let virgl_flags = VIRGLRENDERER_VENUS | VIRGLRENDERER_NO_VIRGL;
-        let sys = sysinfo::System::new_all();
-        // Limit RAM + VRAM to 64 GB (36 bit IPA address limit) minus 2 GB (start address plus rounding).
-        let rounded_mem = ((args.memory as u64) / 1024 + 1) * 1024;
-        let vram = std::cmp::min((63488 - rounded_mem) * 1024 * 1024, sys.total_memory());
-        if unsafe { krun_set_gpu_options2(id, virgl_flags, vram) } < 0 {
-            return Err(anyhow!("unable to set krun vCPU/RAM configuration"));
+        if let Some(set_gpu_options2) = krun.krun_set_gpu_options2 {
+            libkrun::check(unsafe { set_gpu_options2(id, virgl_flags, config.vram_bytes) })
+                .context("unable to set krun GPU options")?;
+        }
+
+        // The legacy gvproxy API configures a single network interface.
+        let legacy_nets = args
+            .devices
+            .iter()
+            .filter(|d| matches!(d, VirtioDeviceConfig::Net(net) if net.uses_legacy_api()))
+            .count();
+        if legacy_nets > 1 {
+            return Err(anyhow!(
+                "only one virtio-net device can use the legacy gvproxy API (gvproxySocket, or libkrun without krun_add_net_unixgram)"
+            ));
         }
 
         // Configure each virtio device to include in the VM.
-        for device in &args.devices {
+        for (device, report) in args.devices.iter().zip(&config.devices) {
             unsafe { device.krun_ctx_set(id)? }
+            boot::mark(&format!("deviceConfigured:{}", report.id));
         }
 
-        set_smbios_oem_strings(id, &args.oem_strings)?;
+        if let (Some(timesync), TimeCorrection::Slew { .. }) =
+            (&args.timesync, args.time_correction)
+        {
+            if timesync.protocol != TimesyncProtocol::Qga {
+                return Err(anyhow!(
+                    "slewing the guest clock requires the qga timesync protocol"
+                ));
+            }
+        }
+
+        config.restful_uri.check_bind(args.restful_insecure_bind)?;
+        if let Some(uri) = &args.restful_uri {
+            unsafe { uri.krun_ctx_set(id)? }
+        }
 
-        Ok(Self { id, args })
+        if let Some(agent) = &args.guest_agent {
+            unsafe { agent.krun_ctx_set(id)? }
+            cleanup::register(Resource::File(agent.socket_path()));
+            cleanup::register(Resource::File(agent_lock_path(&agent.socket_path())));
+        }
+
+        if args.host_power_file.is_some() && args.guest_agent.is_none() {
+            return Err(anyhow!("--host-power-file requires --guest-agent"));
+        }
+
+        if args.heartbeat.is_some() && args.guest_agent.is_none() {
+            return Err(anyhow!("--heartbeat requires --guest-agent"));
+        }
+
+        if let Some(timesync) = &args.timesync {
+            unsafe { timesync.krun_ctx_set(id)? }
+            cleanup::register(Resource::File(timesync.socket_path()));
+        }
+
+        if let Some(ignition) = &args.ignition {
+            unsafe { ignition.krun_ctx_set(id)? }
+        }
+
+        if let Some(ready) = &args.guest_ready {
+            unsafe { ready.krun_ctx_set(id)? }
+        }
+
+        sandbox::check(args.sandbox)?;
+        if args.sandbox == SandboxMode::Strict
+            && args.hook.as_ref().is_some_and(|h| h.runs_after_start())
+        {
+            return Err(anyhow!(
+                "post-start and post-stop hooks cannot be used with --sandbox strict, as they run once it is applied"
+            ));
+        }
+
+        // Secrets are delivered as OEM strings as well, so that they are only read once the VM is
+        // being configured and never appear on the command line.
+        let mut oem_strings = inputs.oem_strings;
+        oem_strings.extend(inputs.secrets);
+        set_smbios_oem_strings(id, &oem_strings)?;
+        boot::mark("contextConfigured");
+
+        Ok(Self {
+            id,
+            args,
+            config,
+            restful_token: inputs.restful_token,
+            state,
+            grown_disks,
+        })
     }
 }
 
 impl KrunContext {
-    /// Spawn a thread to listen for shutdown requests and run the workload. If behaving properly,
-    /// the main thread will never return from this function.
-    pub fn run(&self) -> Result<(), anyhow::Error> {
+    /// Spawn a thread to listen for shutdown requests and run the workload. libkrun exits the
+    /// process once the VM stops, so this only returns the reason for the VM to have exited if it
+    /// was stopped before it ran, or could not be run. If the VM is to be restarted, the krunkit
+    /// process is replaced by a new instance.
+    pub fn run(&self, daemon: Option<DaemonReady>) -> Result<ExitReason, anyhow::Error> {
+        self.state
+            .set(VmState::Starting, "VM configured, starting services");
+
+        // Keep the most recent guest console output in memory.
+        let console_path = console_log_path(self.args.devices.iter());
+        let console = console_path
+            .as_ref()
+            .map(|_| Arc::new(ConsoleBuffer::default()));
+
         // Get the krun shutdown file descriptor and listen to shutdown requests on a new thread.
-        let shutdown_eventfd = unsafe { get_shutdown_eventfd(self.id) };
-        let uri = self.args.restful_uri.clone();
+        let agent = self
+            .args
+            .guest_agent
+            .as_ref()
+            .map(|a| GuestAgent::new(a.socket_path()));
+        let clock = GuestClock::new(self.args.timesync.as_ref(), agent.as_ref());
+        let vm = Arc::new(VmHandle::new(
+            unsafe { get_shutdown_eventfd(self.id) },
+            console.clone(),
+            agent,
+            clock,
+            self.state.clone(),
+        ));
+
+        // Watch the console output for guest kernel crashes. On a panic, the VM is restarted or
+        // krunkit exits.
+        if let (Some(path), Some(console)) = (console_path, console) {
+            let policy = CrashPolicy {
+                crash_file: self.args.crash_file.clone(),
+                crash_file_size: self.args.crash_file_size,
+                restart: self.args.restart,
+            };
+            let console_vm = vm.clone();
+            console_tail(path, console, move |line| {
+                crash::watch_line(&console_vm, &policy, line)
+            });
+        }
+        let config = self.config.clone();
+
+        let listener_vm = vm.clone();
+        let token = self.restful_token.clone();
+        thread::spawn(move || status_listener(listener_vm, config, token).unwrap());
+
+        // Shut the VM down when krunkit is asked to terminate.
+        signal_listener(vm.clone());
+
+        if let Some(quota) = self.args.cpu_quota {
+            cpu_quota_limiter(vm.clone(), quota)?;
+        }
+
+        stats_sampler(
+            vm.clone(),
+            self.args.stats_interval,
+            self.args.cpus,
+            self.args.stats_log.as_deref(),
+        )?;
 
-        thread::spawn(move || status_listener(shutdown_eventfd, uri).unwrap());
+        // Apply the host sleep policy as the host sleeps and wakes.
+        let networks = self
+            .args
+            .devices
+            .iter()
+            .filter_map(|d| match d {
+                VirtioDeviceConfig::Net(net) => Some(net.unix_socket_path.clone()),
+                _ => None,
+            })
+            .collect();
+        power_monitor(
+            vm.clone(),
+            self.args.on_host_sleep,
+            self.args.time_correction,
+            networks,
+        );
+
+        if let Some(interval) = self.args.timesync.as_ref().and_then(|t| t.interval_secs) {
+            clock_resync_scheduler(
+                vm.clone(),
+                Duration::from_secs(interval),
+                self.args.time_correction,
+            );
+        }
+
+        share_watcher(vm.clone(), &self.args.devices);
+        thermal_monitor(vm.clone(), self.args.thermal_policy);
+        low_power_monitor(vm.clone(), self.args.low_power_policy);
+
+        if let Some(path) = &self.args.host_power_file {
+            power_state_propagator(vm.clone(), path.clone());
+        }
 
-        // Run the workload.
-        if unsafe { krun_start_enter(self.id) } < 0 {
-            return Err(anyhow!("unable to begin running krun workload"));
+        // Serve the Ignition config, and wait for the guest to report it has booted, for
+        // provisioning tools such as podman machine.
+        if let Some(ignition) = &self.args.ignition {
+            serve_ignition(ignition)?;
         }
 
+        if self.args.guest_ready.is_some() {
+            guest_ready_listener(vm.clone())?;
+        }
+
+        // Let the pre-start hook set up what the VM needs on the host, including what helpers
+        // need.
+        if let Some(hook) = &self.args.hook {
+            hook.run(HookPoint::PreStart, &self.config, None)?;
+
+            // Let the post-stop hook tear down what the VM needed, whether or not it is
+            // restarted.
+            let (hook, config) = (hook.clone(), self.config.clone());
+            vm.on_exit(Box::new(move |reason| {
+                if let Err(e) = hook.run(HookPoint::PostStop, &config, Some(reason)) {
+                    println!("Error running hook: {e:#}");
+                }
+            }));
+        }
+
+        // Start the helper processes serving the VM before it runs.
+        vm.helpers.start(&vm, &self.args.helpers)?;
+        if !self.args.helpers.is_empty() {
+            boot::mark("helpersStarted");
+        }
+
+        if let Some(pidfile) = &self.args.pidfile {
+            fs::write(pidfile, format!("{}\n", process::id()))
+                .context(format!("unable to write pidfile {}", pidfile.display()))?;
+            cleanup::register(Resource::File(pidfile.clone()));
+        }
+
+        // With --activate-on, the VM only starts once a connection arrives. A restarted instance
+        // starts it right away.
+        if let Some(activation) = self.args.activate_on.as_ref().filter(|_| !vm::restarted()) {
+            activation_listener(vm.clone(), activation)?;
+        }
+
+        // The VM is about to run. Notify any waiting orchestrator and, if daemonized, allow the
+        // parent process to exit.
+        let ready = ReadyNotify {
+            socket: self.args.notify_socket.clone(),
+            fd: self.args.ready_fd,
+        };
+        ready.notify()?;
+
+        if let Some(daemon) = daemon {
+            daemon.notify()?;
+        }
+
+        // An instance replacing one whose VM terminated abnormally waits before starting it
+        // again, unless it is stopped in the meantime.
+        let failures = vm::failures();
+        if let Some(delay) = self.args.restart.backoff(failures).filter(|_| failures > 0) {
+            vm.events.publish(
+                EventKind::Restarting,
+                format!("restarting VM in {} second(s)", delay.as_secs()),
+            );
+
+            if !vm.restart_delay(delay) {
+                return Ok(self.stopped_unstarted(&vm));
+            }
+        }
+
+        if !vm.wait_activated() {
+            return Ok(self.stopped_unstarted(&vm));
+        }
+
+        // Shut the VM down once it has run or been idle for too long.
+        if let Some(max_runtime) = self.args.max_runtime {
+            max_runtime_monitor(vm.clone(), max_runtime);
+        }
+
+        if let Some(idle_timeout) = self.args.idle_timeout {
+            idle_monitor(vm.clone(), idle_timeout);
+        }
+
+        if !self.grown_disks.is_empty() {
+            filesystem_grower(vm.clone(), self.grown_disks.clone());
+        }
+
+        if let Some(heartbeat) = &self.args.heartbeat {
+            heartbeat_monitor(vm.clone(), heartbeat.clone(), self.args.on_unresponsive);
+        }
+
+        match failures {
+            0 => vm.events.publish(EventKind::Started, "VM started"),
+            n => vm.events.publish(
+                EventKind::Started,
+                format!("VM started after {n} consecutive failure(s)"),
+            ),
+        }
+
+        // Keep the host awake while the VM runs, if requested. Released once the VM has exited and
+        // krunkit is about to exit (or be replaced to restart the VM).
+        let _caffeinate = match self.args.caffeinate {
+            true => Some(Caffeinate::new()?),
+            false => None,
+        };
+
+        // Threads libkrun creates to run the VM inherit the QoS class and nice value of this one.
+        priority::apply(self.args.qos, self.args.nice)?;
+
+        // Everything krunkit needs has been opened, so restrict it to what the VM was configured
+        // with. A restarted instance inherits the sandbox of the instance it replaced.
+        if !vm::restarted() {
+            sandbox::apply(self.args.sandbox, &self.args)?;
+        }
+
+        // Run the workload. libkrun loads the firmware and starts the vCPUs from here on.
+        boot::mark("vmStarting");
+        otel::export_startup(&boot::phases());
+        vm.set_started();
+        self.state.set(VmState::Running, "vCPUs starting");
+        if let Some(hook) = self.args.hook.clone() {
+            let config = self.config.clone();
+            thread::spawn(move || {
+                if let Err(e) = hook.run(HookPoint::PostStart, &config, None) {
+                    println!("Error running hook: {e:#}");
+                }
+            });
+        }
+        // libkrun exits the process once the VM stops, so this only returns if it could not run
+        // the VM.
+        let ret = unsafe { (libkrun().krun_start_enter)(self.id) };
+        vm.set_exited();
+
+        if ret < 0 {
+            println!("Unable to run the VM: {}", libkrun::describe_error(ret));
+            vm.fail(ExitReason::Failed, self.args.restart);
+
+            return Ok(ExitReason::Failed);
+        }
+
+        let reason = vm.exit_reason();
+        vm.prepare_exit(reason);
+
+        Ok(reason)
+    }
+
+    /// Tear down what was set up for a VM that was stopped while awaiting activation, before it
+    /// ever ran.
+    fn stopped_unstarted(&self, vm: &VmHandle) -> ExitReason {
+        println!("VM stopped before it was activated");
+        vm.set_exited();
+
+        let reason = vm.exit_reason();
+        vm.prepare_exit(reason);
+
+        reason
+    }
+}
+
+/// Values read before the VM is configured.
+struct Inputs {
+    restful_token: Option<String>,
+
+    /// OEM strings given with --oem-string, with those given as files read.
+    oem_strings: Vec<String>,
+
+    /// OEM strings delivering the secrets to the guest.
+    secrets: Vec<String>,
+}
+
+/// Run the preflight checks while reading the restful token, OEM strings, and secrets. Each of
+/// them can be slow (opening disk images on network volumes, waiting for the keychain), so they
+/// run concurrently, and every failure is reported rather than only the first.
+fn prepare(args: &Args) -> Result<Inputs, anyhow::Error> {
+    let mut errors = Vec::new();
+
+    let inputs = thread::scope(|s| {
+        let preflight = s.spawn(|| preflight::check(args));
+        let token = s.spawn(|| {
+            args.restful_token
+                .as_ref()
+                .map(|t| t.read_string().context("unable to read restful token"))
+                .transpose()
+        });
+        let oem_strings: Vec<_> = args
+            .oem_strings
+            .iter()
+            .flatten()
+            .map(|oem| s.spawn(|| oem.resolve()))
+            .collect();
+        let secrets: Vec<_> = args
+            .secrets
+            .iter()
+            .map(|secret| s.spawn(|| secret.oem_string()))
+            .collect();
+
+        joined(preflight.join(), &mut errors);
+        let restful_token = joined(token.join(), &mut errors).flatten();
+        let oem_strings = oem_strings
+            .into_iter()
+            .filter_map(|oem| joined(oem.join(), &mut errors))
+            .collect();
+        let secrets = secrets
+            .into_iter()
+            .filter_map(|secret| joined(secret.join(), &mut errors))
+            .collect();
+
+        Inputs {
+            restful_token,
+            oem_strings,
+            secrets,
+        }
+    });
+
+    match errors.len() {
+        0 => Ok(inputs),
+        1 => Err(errors.remove(0)),
+        _ => {
+            let errors: Vec<String> = errors.iter().map(|e| format!("{e:#}")).collect();
+            Err(anyhow!("{}", errors.join("\n")))
+        }
+    }
+}
+
+/// Result of a step run on its own thread, recording its failure.
+fn joined<T>(
+    result: thread::Result<Result<T, anyhow::Error>>,
+    errors: &mut Vec<anyhow::Error>,
+) -> Option<T> {
+    match result {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            errors.push(e);
+            None
+        }
+        Err(_) => {
+            errors.push(anyhow!("configuration step panicked"));
+            None
+        }
+    }
+}
+
+/// A host or guest resource that can only be used by one device or service.
+#[derive(Clone, Debug, PartialEq)]
+enum Claim {
+    /// A UNIX socket on the host.
+    Socket(PathBuf),
+
+    /// A disk image, which would be corrupted if attached twice.
+    DiskImage(PathBuf),
+
+    /// Identifier of a disk in libkrun.
+    BlockId(String),
+
+    /// Tag a shared directory is mounted with in the guest.
+    MountTag(PathBuf),
+
+    /// Guest vsock port, which only one device or service can listen on.
+    VsockPort(u32),
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(path) => write!(f, "socket {}", path.display()),
+            Self::DiskImage(path) => write!(f, "disk image {}", path.display()),
+            Self::BlockId(id) => write!(f, "block ID {id}"),
+            Self::MountTag(tag) => write!(f, "mount tag {}", tag.display()),
+            Self::VsockPort(port) => write!(f, "vsock port {port}"),
+        }
+    }
+}
+
+/// Resources claimed by the devices and services of the VM, along with the option claiming each.
+#[derive(Debug, Default)]
+struct ResourceRegistry {
+    claims: Vec<(Claim, String)>,
+}
+
+impl ResourceRegistry {
+    /// Record a resource claimed by an option, failing if another option already claimed it.
+    fn claim(&mut self, claim: Claim, owner: impl Into<String>) -> Result<(), anyhow::Error> {
+        let owner = owner.into();
+
+        if let Some((_, other)) = self.claims.iter().find(|(c, _)| *c == claim) {
+            return Err(anyhow!("{claim} used by both {other} and {owner}"));
+        }
+        self.claims.push((claim, owner));
+
         Ok(())
     }
 }
 
-fn set_smbios_oem_strings(
-    ctx_id: u32,
-    oem_strings: &Option<Vec<String>>,
-) -> Result<(), anyhow::Error> {
-    let Some(oem_strings) = oem_strings else {
-        return Ok(());
-    };
+/// Ensure that no socket, disk image, block ID, mount tag, or vsock port is used by more than one
+/// device or service, before libkrun fails with a less specific error (or two devices silently
+/// share a resource).
+fn check_resources(args: &Args) -> Result<(), anyhow::Error> {
+    let mut registry = ResourceRegistry::default();
+
+    for device in &args.devices {
+        let owner = format!("--device {device}");
+        let claims = match device {
+            VirtioDeviceConfig::Blk(blk) => vec![
+                Claim::DiskImage(blk.path.clone()),
+                Claim::BlockId(blk.block_id().to_string()),
+            ],
+            VirtioDeviceConfig::Vsock(vsock) => vec![
+                Claim::VsockPort(vsock.port),
+                Claim::Socket(vsock.socket_url.clone()),
+            ],
+            VirtioDeviceConfig::Net(net) => vec![Claim::Socket(net.unix_socket_path.clone())],
+            VirtioDeviceConfig::Fs(fs) => vec![Claim::MountTag(fs.mount_tag.clone())],
+            _ => Vec::new(),
+        };
+
+        for claim in claims {
+            registry.claim(claim, owner.as_str())?;
+        }
+    }
+
+    // The restful service, guest agent, timesync, Ignition, and guest ready channels may use vsock
+    // ports as well.
+    if let Some(uri) = &args.restful_uri {
+        match uri {
+            RestfulUri::Vsock { port } => {
+                registry.claim(Claim::VsockPort(*port), "--restful-uri")?
+            }
+            _ => {
+                if let Some(path) = uri.socket_path() {
+                    registry.claim(Claim::Socket(path), "--restful-uri")?;
+                }
+            }
+        }
+    }
+
+    if let Some(agent) = &args.guest_agent {
+        registry.claim(Claim::VsockPort(agent.port), "--guest-agent")?;
+    }
+
+    if let Some(timesync) = &args.timesync {
+        registry.claim(Claim::VsockPort(timesync.port), "--timesync")?;
+    }
 
-    if oem_strings.len() > u8::MAX as usize {
-        return Err(anyhow!("invalid number of SMBIOS OEM strings"));
+    if args.ignition.is_some() {
+        registry.claim(Claim::VsockPort(IGNITION_VSOCK_PORT), "--ignition")?;
     }
 
+    if let Some(ready) = &args.guest_ready {
+        registry.claim(Claim::VsockPort(ready.port), "--guest-ready")?;
+    }
+
+    Ok(())
+}
+
+fn set_smbios_oem_strings(ctx_id: u32, oem_strings: &[String]) -> Result<(), anyhow::Error> {
+    if oem_strings.is_empty() {
+        return Ok(());
+    }
+
+    oem::check_size(oem_strings)?;
+
     let mut cstr_vec = Vec::with_capacity(oem_strings.len());
     for s in oem_strings {
         let cs = CString::new(s.as_str()).context("invalid SMBIOS OEM string")?;
@@ -132,9 +686,66 @@ fn set_smbios_oem_strings(
     // libkrun requires an NULL terminator to indicate the end of the array
     ptr_vec.push(ptr::null());
 
-    let ret = unsafe { krun_set_smbios_oem_strings(ctx_id, ptr_vec.as_ptr()) };
-    if ret < 0 {
-        return Err(anyhow!("unable to set SMBIOS OEM Strings"));
-    }
+    let krun = libkrun();
+    let set_smbios_oem_strings = krun.require(
+        krun.krun_set_smbios_oem_strings,
+        "krun_set_smbios_oem_strings",
+        "SMBIOS OEM strings",
+    )?;
+    libkrun::check(unsafe { set_smbios_oem_strings(ctx_id, ptr_vec.as_ptr()) })
+        .context("unable to set SMBIOS OEM Strings")?;
+
     Ok(())
 }
+
+mod tests {
+    #[test]
+    fn resource_collisions() {
+        use super::*;
+
+        use clap::Parser;
+
+        let args = |extra: &[&str]| {
+            let mut cmdline = vec!["krunkit", "--cpus", "2", "--memory", "1024"];
+            cmdline.extend_from_slice(extra);
+            Args::parse_from(cmdline)
+        };
+
+        assert!(check_resources(&args(&[
+            "--device",
+            "virtio-blk,path=/Users/user/root.img,format=raw",
+            "--device",
+            "virtio-blk,path=/Users/user/data.img,format=raw",
+        ]))
+        .is_ok());
+
+        let e = check_resources(&args(&[
+            "--device",
+            "virtio-blk,path=/Users/user/a/disk.img,format=raw",
+            "--device",
+            "virtio-blk,path=/Users/user/b/disk.img,format=qcow2",
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "block ID disk.img used by both --device virtio-blk,path=/Users/user/a/disk.img,format=raw and --device virtio-blk,path=/Users/user/b/disk.img,format=qcow2"
+        );
+
+        let e = check_resources(&args(&[
+            "--device",
+            "virtio-vsock,port=1024,socketURL=/Users/user/vsock.sock,listen",
+            "--ignition",
+            "/Users/user/config.ign",
+        ]))
+        .unwrap_err();
+        assert!(e.to_string().ends_with("and --ignition"));
+
+        assert!(check_resources(&args(&[
+            "--device",
+            "virtio-fs,sharedDir=/Users/user/a,mountTag=share",
+            "--device",
+            "virtio-fs,sharedDir=/Users/user/b,mountTag=share",
+        ]))
+        .is_err());
+    }
+}
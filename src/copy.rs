@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent::instance_agent;
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+
+/// Arguments of the cp subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct CpArgs {
+    /// pidfile of the krunkit instance to copy the file to or from.
+    #[arg(long)]
+    pub pidfile: PathBuf,
+
+    /// Do not report the progress of the copy.
+    #[arg(long, short, default_value_t = false)]
+    pub quiet: bool,
+
+    /// File to copy (host:path or vm:path).
+    pub source: CopyPath,
+
+    /// Destination of the copy (host:path or vm:path). If it is a directory (or, in the guest,
+    /// ends with /), the file is copied into it with the same name.
+    pub destination: CopyPath,
+}
+
+/// A path on the host or in the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CopyPath {
+    Host(PathBuf),
+    Guest(String),
+}
+
+impl FromStr for CopyPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("host", path)) if !path.is_empty() => Ok(Self::Host(PathBuf::from(path))),
+            Some(("vm", path)) if !path.is_empty() => Ok(Self::Guest(path.to_string())),
+            _ => Err(anyhow!("expected a path of the form host:path or vm:path")),
+        }
+    }
+}
+
+impl fmt::Display for CopyPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Host(path) => write!(f, "host:{}", path.display()),
+            Self::Guest(path) => write!(f, "vm:{path}"),
+        }
+    }
+}
+
+/// Copy a file between the host and the guest of a running krunkit instance, through its guest
+/// agent channel.
+pub fn cp(args: &CpArgs) -> Result<(), anyhow::Error> {
+    let agent = instance_agent(&args.pidfile)?;
+    let mut progress = Progress {
+        quiet: args.quiet,
+        total: None,
+    };
+
+    let copied = match (&args.source, &args.destination) {
+        (CopyPath::Host(source), CopyPath::Guest(destination)) => {
+            let mut file =
+                File::open(source).context(format!("unable to open {}", source.display()))?;
+            progress.total = file.metadata().ok().map(|m| m.len());
+
+            let destination = match destination.ends_with('/') {
+                true => format!("{destination}{}", file_name(source)?),
+                false => destination.clone(),
+            };
+
+            agent.copy_to_guest(&destination, &mut file, |n| progress.report(n))?
+        }
+        (CopyPath::Guest(source), CopyPath::Host(destination)) => {
+            let destination = match destination.is_dir() {
+                true => destination.join(file_name(Path::new(source))?),
+                false => destination.clone(),
+            };
+            let mut file = File::create(&destination)
+                .context(format!("unable to create {}", destination.display()))?;
+
+            agent.copy_from_guest(source, &mut file, |n| progress.report(n))?
+        }
+        _ => {
+            return Err(anyhow!(
+                "one path must be on the host (host:path) and the other in the guest (vm:path)"
+            ))
+        }
+    };
+
+    progress.finish();
+    println!(
+        "Copied {} to {} ({})",
+        args.source,
+        args.destination,
+        format_bytes(copied)
+    );
+
+    Ok(())
+}
+
+fn file_name(path: &Path) -> Result<String, anyhow::Error> {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or(anyhow!("{} has no file name", path.display()))
+}
+
+/// Progress of a copy, reported on standard error.
+pub struct Progress {
+    pub quiet: bool,
+
+    /// Size of the file being copied, if known.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub fn report(&self, copied: u64) {
+        if self.quiet {
+            return;
+        }
+
+        let _ = match self.total {
+            Some(total) if total > 0 => write!(
+                io::stderr(),
+                "\r{} of {} ({}%)",
+                format_bytes(copied),
+                format_bytes(total),
+                copied * 100 / total
+            ),
+            _ => write!(io::stderr(), "\r{}", format_bytes(copied)),
+        };
+    }
+
+    pub fn finish(&self) {
+        if !self.quiet {
+            eprintln!();
+        }
+    }
+}
+
+/// Format a number of bytes with a binary unit, for example 1.5 MiB.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+mod tests {
+    #[test]
+    fn copy_path_parse() {
+        use super::*;
+
+        assert_eq!(
+            CopyPath::from_str("host:/Users/user/notes.txt").unwrap(),
+            CopyPath::Host(PathBuf::from("/Users/user/notes.txt"))
+        );
+        assert_eq!(
+            CopyPath::from_str("vm:/etc/os-release").unwrap(),
+            CopyPath::Guest(String::from("/etc/os-release"))
+        );
+        assert!(CopyPath::from_str("/etc/os-release").is_err());
+        assert!(CopyPath::from_str("vm:").is_err());
+
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536 * 1024), "1.5 MiB");
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    console::{ConsoleBuffer, CONSOLE_BUFFER_LINES},
+    events::EventKind,
+    vm::{ExitReason, RestartPolicy, VmHandle},
+};
+
+use std::{fs, path::PathBuf, process, sync::Arc, thread, time::Duration};
+
+/// Time given to the guest kernel to finish writing a panic report (such as the stack trace) to
+/// the console before acting on the panic.
+const PANIC_REPORT_DELAY: Duration = Duration::from_secs(2);
+
+/// Console output signatures of a guest kernel panic.
+const PANIC_SIGNATURES: [&str; 1] = ["Kernel panic - not syncing"];
+
+/// Console output signatures of a guest kernel oops, after which the guest may keep running.
+const OOPS_SIGNATURES: [&str; 5] = [
+    "Internal error: Oops",
+    "Oops: ",
+    "BUG: unable to handle",
+    "Unable to handle kernel",
+    "kernel BUG at",
+];
+
+/// Kind of guest kernel crash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrashKind {
+    Oops,
+    Panic,
+}
+
+/// Identify a guest kernel crash from a line of console output.
+pub fn detect(line: &str) -> Option<CrashKind> {
+    if PANIC_SIGNATURES.iter().any(|s| line.contains(s)) {
+        Some(CrashKind::Panic)
+    } else if OOPS_SIGNATURES.iter().any(|s| line.contains(s)) {
+        Some(CrashKind::Oops)
+    } else {
+        None
+    }
+}
+
+/// Actions taken when the guest kernel panics.
+#[derive(Clone, Debug, Default)]
+pub struct CrashPolicy {
+    /// Path of a file to save the most recent console output to.
+    pub crash_file: Option<PathBuf>,
+
+    /// Maximum size of the console output saved, in KiB.
+    pub crash_file_size: usize,
+
+    /// Whether the VM is restarted once it panicked.
+    pub restart: RestartPolicy,
+}
+
+/// Check a line of guest console output for a kernel crash, publishing an event and applying the
+/// crash policy if found.
+pub fn watch_line(vm: &Arc<VmHandle>, policy: &CrashPolicy, line: &str) {
+    match detect(line) {
+        Some(CrashKind::Oops) => vm
+            .events
+            .publish(EventKind::GuestOops, format!("guest kernel oops: {line}")),
+        Some(CrashKind::Panic) => {
+            // Only act on the first panic.
+            if !vm.set_guest_panicked() {
+                return;
+            }
+            vm.events.publish(
+                EventKind::GuestPanicked,
+                format!("guest kernel panic: {line}"),
+            );
+
+            let vm = vm.clone();
+            let policy = policy.clone();
+            thread::spawn(move || {
+                thread::sleep(PANIC_REPORT_DELAY);
+
+                if let (Some(path), Some(console)) = (&policy.crash_file, &vm.console) {
+                    match save_crash_file(path, policy.crash_file_size, console) {
+                        Ok(()) => println!("Saved guest console output to {}", path.display()),
+                        Err(e) => println!("Unable to write crash file {}: {e}", path.display()),
+                    }
+                }
+
+                // Unless the VM is restarted, exit with the status indicating the guest panicked,
+                // which stopping it through libkrun would not.
+                if vm.fail(ExitReason::GuestPanicked, policy.restart) {
+                    process::exit(ExitReason::GuestPanicked.exit_code());
+                }
+            });
+        }
+        None => (),
+    }
+}
+
+/// Write (at most) the last size_kib KiB of console output to a file.
+fn save_crash_file(
+    path: &PathBuf,
+    size_kib: usize,
+    console: &ConsoleBuffer,
+) -> Result<(), std::io::Error> {
+    let mut output = console.tail(CONSOLE_BUFFER_LINES).join("\n");
+    output.push('\n');
+
+    let max = size_kib * 1024;
+    let mut start = output.len().saturating_sub(max);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+
+    fs::write(path, &output[start..])
+}
+
+mod tests {
+    #[test]
+    fn crash_detect() {
+        use super::*;
+
+        assert_eq!(
+            detect("[   12.345] Kernel panic - not syncing: Attempted to kill init!"),
+            Some(CrashKind::Panic)
+        );
+        assert_eq!(
+            detect("[    3.210] Internal error: Oops: 0000000096000004 [#1] SMP"),
+            Some(CrashKind::Oops)
+        );
+        assert_eq!(
+            detect("[    3.210] BUG: unable to handle page fault for address: 0000000000000008"),
+            Some(CrashKind::Oops)
+        );
+        assert_eq!(detect("[    1.000] Run /sbin/init as init process"), None);
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    env,
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
+    process, thread,
+};
+
+use anyhow::{anyhow, Context};
+
+/// Set in the environment of the daemonized process, so that it is not daemonized again when it
+/// replaces itself to restart the VM.
+const DAEMONIZED_ENV: &str = "KRUNKIT_DAEMONIZED";
+
+/// Used by a daemonized krunkit process to report to the process that started it that the VM is
+/// about to run.
+pub struct DaemonReady(File);
+
+impl DaemonReady {
+    /// Report readiness, allowing the parent process to exit successfully.
+    pub fn notify(mut self) -> Result<(), anyhow::Error> {
+        self.0
+            .write_all(b"1")
+            .context("unable to report readiness to parent process")
+    }
+}
+
+/// Detach krunkit from the terminal and continue running in a forked child process. The parent
+/// process does not return: it exits successfully once the child reports readiness, and with an
+/// error if the child exits before doing so.
+///
+/// The child's output is written to the log file if given, or to the system log otherwise.
+///
+/// This must be called before any threads are created.
+pub fn daemonize(log_file: Option<&Path>) -> Result<Option<DaemonReady>, anyhow::Error> {
+    if env::var_os(DAEMONIZED_ENV).is_some() {
+        return Ok(None);
+    }
+
+    let mut fds: [RawFd; 2] = [-1; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(anyhow!(
+            "unable to create readiness pipe: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(anyhow!(
+            "unable to fork krunkit process: {}",
+            io::Error::last_os_error()
+        )),
+        0 => {
+            drop(read_end);
+            env::set_var(DAEMONIZED_ENV, "1");
+
+            if unsafe { libc::setsid() } < 0 {
+                return Err(anyhow!(
+                    "unable to create new session: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+            redirect_output(log_file)?;
+
+            Ok(Some(DaemonReady(write_end)))
+        }
+        _ => {
+            drop(write_end);
+
+            let mut buf = [0u8; 1];
+            match read_end.read(&mut buf) {
+                Ok(1) => process::exit(0),
+                _ => {
+                    eprintln!(
+                        "krunkit exited before the VM started, see the krunkit log for details"
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Redirect standard input from /dev/null, and standard output and error to the log file (or the
+/// system log).
+fn redirect_output(log_file: Option<&Path>) -> Result<(), anyhow::Error> {
+    let null = File::open("/dev/null").context("unable to open /dev/null")?;
+    dup2(&null, libc::STDIN_FILENO)?;
+
+    let output = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("unable to open log file {}", path.display()))?,
+        None => syslog_pipe()?,
+    };
+    dup2(&output, libc::STDOUT_FILENO)?;
+    dup2(&output, libc::STDERR_FILENO)?;
+
+    Ok(())
+}
+
+/// Create a pipe in which each line written is forwarded to the system log (the unified logging
+/// system on macOS).
+fn syslog_pipe() -> Result<File, anyhow::Error> {
+    let mut fds: [RawFd; 2] = [-1; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(anyhow!(
+            "unable to create log pipe: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let (read_end, write_end) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    thread::spawn(move || {
+        // openlog() keeps a reference to the identifier, so it must never be freed.
+        let ident: &'static CString = Box::leak(Box::new(CString::new("krunkit").unwrap()));
+        unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+
+        for line in BufReader::new(read_end).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let Ok(msg) = CString::new(line) else {
+                continue;
+            };
+
+            unsafe { libc::syslog(libc::LOG_NOTICE, c"%s".as_ptr(), msg.as_ptr()) };
+        }
+    });
+
+    Ok(write_end)
+}
+
+fn dup2(file: &File, fd: RawFd) -> Result<(), anyhow::Error> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(anyhow!(
+            "unable to redirect file descriptor {fd}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,346 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::{read_pidfile, Args},
+    config::VmConfig,
+    libkrun,
+    status::RestfulUri,
+    virtio::VirtioDeviceConfig,
+};
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use serde_json::{json, Value};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Amount of each log file included in a bundle, from its end.
+const DIAGNOSE_LOG_TAIL: u64 = 256 * 1024;
+
+/// Number of lines of console output requested from the restful service.
+const DIAGNOSE_CONSOLE_LINES: usize = 1000;
+
+/// Time given to the restful service to respond to each request.
+const DIAGNOSE_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Restful service endpoints included in a bundle, with the file each response is saved to.
+const DIAGNOSE_ENDPOINTS: [(&str, &str); 5] = [
+    ("/vm/inspect", "inspect.json"),
+    ("/vm/events", "events.json"),
+    ("/vm/stats/boot", "boot.json"),
+    ("/vm/guest/stats", "guest-stats.json"),
+    ("/vm/operations", "operations.json"),
+];
+
+/// Arguments of the diagnose subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct DiagnoseArgs {
+    /// pidfile of the krunkit instance to collect diagnostics from.
+    #[arg(long)]
+    pub pidfile: PathBuf,
+
+    /// Path of the bundle to write (defaults to krunkit-diagnose-<pid>-<time>.tar.gz in the
+    /// current directory).
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// A bundle of diagnostics being collected in a staging directory. Items that cannot be collected
+/// are recorded rather than failing the whole bundle, as a bug report is most needed when the VM
+/// is misbehaving.
+struct Bundle {
+    dir: PathBuf,
+    errors: Vec<String>,
+}
+
+impl Bundle {
+    fn write(&mut self, name: &str, contents: impl AsRef<[u8]>) {
+        if let Err(e) = fs::write(self.dir.join(name), contents) {
+            self.errors.push(format!("{name}: {e}"));
+        }
+    }
+
+    fn write_json(&mut self, name: &str, value: &Value) {
+        match serde_json::to_string_pretty(value) {
+            Ok(json) => self.write(name, json),
+            Err(e) => self.errors.push(format!("{name}: {e}")),
+        }
+    }
+
+    fn write_result(&mut self, name: &str, result: Result<Vec<u8>, anyhow::Error>) {
+        match result {
+            Ok(contents) => self.write(name, contents),
+            Err(e) => self.errors.push(format!("{name}: {e:#}")),
+        }
+    }
+}
+
+/// Collect the configuration, logs, and state of a running krunkit instance, along with
+/// information about the host, into a gzipped tarball for bug reports.
+pub fn diagnose(args: &DiagnoseArgs) -> Result<(), anyhow::Error> {
+    let pid = read_pidfile(&args.pidfile)?;
+
+    let mut sys = System::new_all();
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[Pid::from_u32(pid)]),
+        ProcessRefreshKind::everything(),
+    );
+    let process = sys
+        .process(Pid::from_u32(pid))
+        .ok_or(anyhow!("krunkit process {pid} is not running"))?;
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let name = format!("krunkit-diagnose-{pid}-{time}");
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{name}.tar.gz")));
+
+    let staging = env::temp_dir().join(format!("{name}.d"));
+    let dir = staging.join(&name);
+    fs::create_dir_all(&dir).context(format!(
+        "unable to create staging directory {}",
+        dir.display()
+    ))?;
+
+    let mut bundle = Bundle {
+        dir,
+        errors: Vec::new(),
+    };
+
+    bundle.write_json("host.json", &host_info(&sys));
+
+    let cmd: Vec<String> = process
+        .cmd()
+        .iter()
+        .map(|a| a.to_string_lossy().to_string())
+        .collect();
+    let cwd = process.cwd().map(Path::to_path_buf).unwrap_or_default();
+    bundle.write_json(
+        "process.json",
+        &json!({
+            "pid": pid,
+            "commandLine": cmd,
+            "cwd": cwd,
+            "startTime": process.start_time(),
+            "runTimeSecs": process.run_time(),
+            "memoryBytes": process.memory(),
+        }),
+    );
+
+    // The effective configuration is resolved from the instance's command line, as it is when the
+    // instance starts.
+    match Args::try_parse_from(&cmd) {
+        Ok(vm_args) => {
+            let config = VmConfig::from(&vm_args);
+            match serde_json::to_value(&config) {
+                Ok(value) => bundle.write_json("config.json", &value),
+                Err(e) => bundle.errors.push(format!("config.json: {e}")),
+            }
+
+            collect_devices(&mut bundle, &config, &cwd);
+            collect_logs(&mut bundle, &vm_args, &cwd);
+            collect_restful(&mut bundle, &config);
+        }
+        Err(e) => bundle
+            .errors
+            .push(format!("unable to parse the command line of krunkit: {e}")),
+    }
+
+    let errors = bundle.errors.join("\n");
+    bundle.write("errors.txt", errors);
+
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&output)
+        .arg("-C")
+        .arg(&staging)
+        .arg(&name)
+        .status();
+    let _ = fs::remove_dir_all(&staging);
+
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => return Err(anyhow!("unable to create bundle: tar {status}")),
+        Err(e) => return Err(anyhow!("unable to execute tar: {e}")),
+    }
+
+    println!("Diagnostic bundle written to {}", output.display());
+    if !bundle.errors.is_empty() {
+        println!(
+            "{} item(s) could not be collected, see errors.txt in the bundle",
+            bundle.errors.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Information about the host and the krunkit and libkrun builds installed on it.
+fn host_info(sys: &System) -> Value {
+    json!({
+        "osName": System::name(),
+        "osVersion": System::long_os_version(),
+        "kernelVersion": System::kernel_version(),
+        "arch": System::cpu_arch(),
+        "cpus": sys.cpus().len(),
+        "memoryBytes": sys.total_memory(),
+        "availableMemoryBytes": sys.available_memory(),
+        "uptimeSecs": System::uptime(),
+        "krunkitVersion": env!("CARGO_PKG_VERSION"),
+        "libkrun": libkrun::load().ok().map(|k| &k.path),
+    })
+}
+
+/// Record the state of the host files backing each device, such as the size of disk images.
+fn collect_devices(bundle: &mut Bundle, config: &VmConfig, cwd: &Path) {
+    let devices: Vec<Value> = config
+        .devices
+        .iter()
+        .map(|device| {
+            let path = match &device.config {
+                VirtioDeviceConfig::Blk(blk) => Some(&blk.path),
+                VirtioDeviceConfig::Serial(serial) => Some(&serial.log_file_path),
+                VirtioDeviceConfig::Vsock(vsock) => Some(&vsock.socket_url),
+                VirtioDeviceConfig::Net(net) => Some(&net.unix_socket_path),
+                VirtioDeviceConfig::Fs(fs) => Some(&fs.shared_dir),
+                _ => None,
+            };
+            let Some(path) = path else {
+                return json!({ "id": device.id });
+            };
+
+            match fs::metadata(cwd.join(path)) {
+                Ok(metadata) => json!({
+                    "id": device.id,
+                    "path": path,
+                    "sizeBytes": metadata.len(),
+                    "allocatedBytes": metadata.blocks() * 512,
+                    "mode": format!("{:o}", metadata.mode()),
+                    "modified": metadata.mtime(),
+                }),
+                Err(e) => json!({ "id": device.id, "path": path, "error": e.to_string() }),
+            }
+        })
+        .collect();
+
+    bundle.write_json("devices.json", &Value::Array(devices));
+}
+
+/// Include the end of the serial console log, krunkit's own log, and the crash file, if any.
+fn collect_logs(bundle: &mut Bundle, args: &Args, cwd: &Path) {
+    for device in &args.devices {
+        if let VirtioDeviceConfig::Serial(serial) = device {
+            let result = read_tail(&cwd.join(&serial.log_file_path));
+            bundle.write_result("serial.log", result);
+        }
+    }
+
+    if let Some(path) = &args.log_file {
+        bundle.write_result("krunkit.log", read_tail(&cwd.join(path)));
+    }
+
+    if let Some(path) = &args.crash_file {
+        let path = cwd.join(path);
+        if path.exists() {
+            bundle.write_result("crash.log", read_tail(&path));
+        }
+    }
+}
+
+/// Include the responses of the restful service, if it is reachable from the host.
+fn collect_restful(bundle: &mut Bundle, config: &VmConfig) {
+    let RestfulUri::Tcp { host, port } = &config.restful_uri else {
+        bundle.errors.push(String::from(
+            "restful service is only reachable from the guest",
+        ));
+        return;
+    };
+    if *port == 0 {
+        bundle.errors.push(String::from(
+            "restful service listens on a port chosen at startup",
+        ));
+        return;
+    }
+
+    let token = match config.restful_token.as_ref().map(|t| t.read_string()) {
+        Some(Ok(token)) => Some(token),
+        Some(Err(e)) => {
+            bundle.errors.push(format!("restful token: {e:#}"));
+            return;
+        }
+        None => None,
+    };
+
+    let console = format!("/vm/console?lines={DIAGNOSE_CONSOLE_LINES}");
+    let endpoints = DIAGNOSE_ENDPOINTS
+        .iter()
+        .map(|(path, name)| (path.to_string(), *name))
+        .chain([(console, "console.json")]);
+
+    for (path, name) in endpoints {
+        let result = http_get(host, *port, &path, token.as_deref()).context(format!("GET {path}"));
+        bundle.write_result(name, result);
+    }
+}
+
+/// Send a GET request to the restful service and return the body of the response.
+fn http_get(
+    host: &str,
+    port: u16,
+    path: &str,
+    token: Option<&str>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut stream = TcpStream::connect((host, port)).context("unable to connect")?;
+    stream.set_read_timeout(Some(DIAGNOSE_REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(DIAGNOSE_REQUEST_TIMEOUT))?;
+
+    let authorization = token
+        .map(|t| format!("Authorization: Bearer {t}\r\n"))
+        .unwrap_or_default();
+    stream.write_all(
+        format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}:{port}\r\n{authorization}Connection: close\r\n\r\n"
+        )
+        .as_bytes(),
+    )?;
+
+    // The service closes the connection once it has responded.
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let body = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| &response[i + 4..])
+        .ok_or(anyhow!("malformed response"))?;
+
+    // Some responses are NUL-terminated.
+    Ok(body.strip_suffix(b"\0").unwrap_or(body).to_vec())
+}
+
+/// Read (at most) the last DIAGNOSE_LOG_TAIL bytes of a file.
+fn read_tail(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    let mut file = File::open(path).context(format!("unable to open {}", path.display()))?;
+
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(DIAGNOSE_LOG_TAIL)))?;
+
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    Ok(contents)
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::operation::now;
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use serde::Serialize;
+
+/// Maximum number of events kept. Once reached, the oldest event is dropped when a new one is
+/// published.
+const EVENTS_MAX: usize = 1000;
+
+/// Kind of a VM lifecycle event.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    /// The VM is about to run.
+    Started,
+
+    /// The VM is being shut down by krunkit.
+    Stopping,
+
+    /// The VM exited normally.
+    Stopped,
+
+    /// The VM terminated abnormally.
+    Failed,
+
+    /// The VM is being started again after exiting.
+    Restarting,
+
+    /// The guest reported that it has booted.
+    GuestReady,
+
+    /// The guest kernel reported an oops.
+    GuestOops,
+
+    /// The guest kernel panicked.
+    GuestPanicked,
+
+    /// A helper process exited while the VM was running.
+    HelperExited,
+
+    /// The host is about to sleep.
+    HostSleep,
+
+    /// The host woke from sleep.
+    HostWake,
+
+    /// The thermal pressure of the host changed.
+    ThermalPressure,
+
+    /// The host entered or left Low Power Mode.
+    LowPowerMode,
+
+    /// A network backend was found dead and restarted.
+    NetBackendRestarted,
+
+    /// A network backend was found dead and could not be re-established.
+    NetBackendFailed,
+
+    /// The guest extended its filesystem into disks grown at startup.
+    DiskGrown,
+
+    /// The guest agent missed the threshold of consecutive heartbeats.
+    GuestUnresponsive,
+
+    /// The guest agent responded to a heartbeat after missing some.
+    GuestRecovered,
+
+    /// A host directory shared through virtio-fs was moved, removed, replaced, or put back.
+    SharedDirChanged,
+}
+
+/// An event published by krunkit about the VM.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: u64,
+
+    /// Seconds since the UNIX epoch at which the event was published.
+    pub time: u64,
+
+    pub kind: EventKind,
+
+    /// Human-readable description of the event.
+    pub message: String,
+}
+
+/// Ordered record of the events published since krunkit started.
+#[derive(Debug, Default)]
+pub struct Events {
+    inner: Mutex<EventsInner>,
+}
+
+#[derive(Debug, Default)]
+struct EventsInner {
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+impl Events {
+    /// Publish an event, also writing it to the log.
+    pub fn publish(&self, kind: EventKind, message: impl Into<String>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.next_id += 1;
+        let event = Event {
+            id: inner.next_id,
+            time: now(),
+            kind,
+            message: message.into(),
+        };
+        println!("Event: {}", event.message);
+
+        if inner.events.len() == EVENTS_MAX {
+            inner.events.pop_front();
+        }
+        inner.events.push_back(event);
+    }
+
+    /// Retrieve the events published after the event with the given ID, oldest first.
+    pub fn since(&self, id: u64) -> Vec<Event> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.events.iter().filter(|e| e.id > id).cloned().collect()
+    }
+}
@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{agent::instance_agent, cmdline::duration_parse};
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+
+/// Arguments of the exec subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct ExecArgs {
+    /// pidfile of the krunkit instance to run the command in.
+    #[arg(long)]
+    pub pidfile: PathBuf,
+
+    /// Fail if the command does not exit within this long (for example, 30s, 5m).
+    #[arg(long, value_parser = duration_parse)]
+    pub timeout: Option<Duration>,
+
+    /// Command to run in the guest, with its arguments. The program is searched for in the guest
+    /// agent's PATH.
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// Run a command in the guest of a running krunkit instance through its guest agent channel,
+/// writing its output to krunkit's, and return the exit status to exit krunkit with.
+pub fn exec(args: &ExecArgs) -> Result<i32, anyhow::Error> {
+    let agent = instance_agent(&args.pidfile)?;
+
+    let (path, arguments) = args.command.split_first().unwrap();
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    let output = agent.exec_output(path, &arguments, args.timeout)?;
+
+    io::stdout().write_all(&output.stdout)?;
+    io::stderr().write_all(&output.stderr)?;
+    if output.truncated {
+        eprintln!("krunkit: output of {path} was truncated by the guest agent");
+    }
+
+    // As with shells, a command terminated by a signal exits with 128 plus the signal number.
+    let status = match (output.exit_code, output.signal) {
+        (_, Some(signal)) => 128 + signal,
+        (Some(code), None) => code,
+        (None, None) => 1,
+    };
+
+    Ok(status.clamp(0, 255) as i32)
+}
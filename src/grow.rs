@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    copy::format_bytes,
+    events::EventKind,
+    image::detect_format,
+    qcow2,
+    virtio::{DiskImageFormat, VirtioDeviceConfig},
+    vm::VmHandle,
+};
+
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+/// Time given to the guest agent to start responding once the VM runs.
+const AGENT_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Interval at which the guest agent is polled until it responds.
+const AGENT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time given to each cloud-init module to extend the partition or filesystem.
+const GROW_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// cloud-init modules run in the guest to extend the root partition, then its filesystem, into
+/// the space added to its disk.
+const CLOUD_INIT_MODULES: [&str; 2] = ["growpart", "resizefs"];
+
+/// Grow the disk images of the virtio-blk devices with grow-to to their size, if smaller. Returns
+/// the paths of the images that were grown.
+pub fn grow_disks(devices: &[VirtioDeviceConfig]) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut grown = Vec::new();
+
+    for device in devices {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+        let Some(size) = blk.grow_to else {
+            continue;
+        };
+
+        let previous = grow_image(&blk.path, blk.format, size)
+            .context(format!("unable to grow {}", blk.path.display()))?;
+        if let Some(previous) = previous {
+            println!(
+                "Grew disk {} from {} to {}",
+                blk.path.display(),
+                format_bytes(previous),
+                format_bytes(size)
+            );
+            grown.push(blk.path.clone());
+        }
+    }
+
+    Ok(grown)
+}
+
+/// Grow the disk of an image to a size, returning its previous size if it was smaller. Disks are
+/// never shrunk.
+fn grow_image(
+    path: &Path,
+    format: DiskImageFormat,
+    size: u64,
+) -> Result<Option<u64>, anyhow::Error> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    // Growing a qcow2 image as a raw image would leave its disk unchanged.
+    let detected = detect_format(&file)?;
+    if detected != format {
+        return Err(anyhow!("image is a {detected} image, not a {format} image"));
+    }
+
+    let current = disk_size(&file, format)?;
+    if current >= size {
+        return Ok(None);
+    }
+
+    match format {
+        DiskImageFormat::Raw => {
+            file.set_len(size)?;
+            file.sync_all()?;
+        }
+        DiskImageFormat::Qcow2 => qcow2::grow(&file, size)?,
+    }
+
+    Ok(Some(current))
+}
+
+/// Size of the disk of an image in the given format.
+pub fn disk_size(file: &File, format: DiskImageFormat) -> Result<u64, anyhow::Error> {
+    match format {
+        DiskImageFormat::Raw => Ok(file.metadata()?.len()),
+        DiskImageFormat::Qcow2 => Ok(qcow2::Header::read(file)?.size),
+    }
+}
+
+/// Once the guest agent responds, have cloud-init extend the guest's root partition and
+/// filesystem into the space added to the grown disks.
+pub fn filesystem_grower(vm: Arc<VmHandle>, disks: Vec<PathBuf>) {
+    thread::spawn(move || {
+        let Some(agent) = &vm.agent else {
+            return;
+        };
+
+        let deadline = Instant::now() + AGENT_WAIT_TIMEOUT;
+        while agent.execute("guest-ping", None).is_err() {
+            if Instant::now() >= deadline {
+                println!("Guest agent did not respond, not extending the guest's filesystem");
+                return;
+            }
+            thread::sleep(AGENT_POLL_INTERVAL);
+        }
+
+        for module in CLOUD_INIT_MODULES {
+            let args = ["single", "--name", module, "--frequency", "always"];
+            match agent.exec_output("cloud-init", &args, Some(GROW_TIMEOUT)) {
+                Ok(output) if output.exit_code == Some(0) => (),
+                Ok(output) => {
+                    println!(
+                        "cloud-init {module} failed in the guest: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    return;
+                }
+                Err(e) => {
+                    println!("Unable to run cloud-init {module} in the guest: {e:#}");
+                    return;
+                }
+            }
+        }
+
+        let disks: Vec<String> = disks.iter().map(|d| d.display().to_string()).collect();
+        vm.events.publish(
+            EventKind::DiskGrown,
+            format!(
+                "guest filesystem extended into grown disk(s) {}",
+                disks.join(", ")
+            ),
+        );
+    });
+}
@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::{args_parse, duration_parse, val_parse},
+    events::EventKind,
+    vm::VmHandle,
+};
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Interval between heartbeats if not specified.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of consecutive missed heartbeats after which the guest is unresponsive, if not
+/// specified.
+const DEFAULT_THRESHOLD: u32 = 3;
+
+/// Longest time a heartbeat waits for the guest agent to respond.
+const PING_TIMEOUT_MAX: Duration = Duration::from_secs(10);
+
+/// Time given to the guest agent to respond to its first heartbeat once the VM runs. Heartbeats
+/// missed while the guest boots are not counted.
+const BOOT_GRACE: Duration = Duration::from_secs(300);
+
+/// Configuration of the periodic heartbeats sent to the guest agent.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatConfig {
+    /// Seconds between heartbeats.
+    pub interval_secs: u64,
+
+    /// Number of consecutive missed heartbeats after which the guest is unresponsive.
+    pub threshold: u32,
+}
+
+impl FromStr for HeartbeatConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut interval = DEFAULT_INTERVAL;
+        let mut threshold = DEFAULT_THRESHOLD;
+
+        for arg in args_parse(s.to_string(), "heartbeat", None)? {
+            match arg.split_once('=').map(|(label, _)| label) {
+                Some("interval") => {
+                    interval = duration_parse(&val_parse(&arg, "interval")?)
+                        .context("heartbeat interval argument invalid")?;
+                    if interval.as_secs() == 0 || interval.subsec_nanos() != 0 {
+                        return Err(anyhow!(
+                            "heartbeat interval must be a whole number of seconds, at least 1s"
+                        ));
+                    }
+                }
+                Some("threshold") => {
+                    threshold = u32::from_str(&val_parse(&arg, "threshold")?)
+                        .context("heartbeat threshold argument invalid")?;
+                    if threshold == 0 {
+                        return Err(anyhow!("heartbeat threshold must be greater than 0"));
+                    }
+                }
+                _ => return Err(anyhow!("invalid heartbeat argument: {arg}")),
+            }
+        }
+
+        Ok(Self {
+            interval_secs: interval.as_secs(),
+            threshold,
+        })
+    }
+}
+
+/// Behavior of krunkit when the guest stops responding to heartbeats.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnresponsivePolicy {
+    /// Only publish an event.
+    #[default]
+    Log,
+
+    /// Stop the VM and start it again with the same configuration.
+    Restart,
+
+    /// Stop the VM.
+    Stop,
+}
+
+impl FromStr for UnresponsivePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(Self::Log),
+            "restart" => Ok(Self::Restart),
+            "stop" => Ok(Self::Stop),
+            _ => Err(anyhow!("invalid --on-unresponsive option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for UnresponsivePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Log => write!(f, "log"),
+            Self::Restart => write!(f, "restart"),
+            Self::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+impl Serialize for UnresponsivePolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Health of the guest, as determined from the heartbeats it responded to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// The guest agent has not responded yet, as the guest boots.
+    Starting,
+
+    /// The guest agent responded to the last heartbeat.
+    Healthy,
+
+    /// The guest agent missed heartbeats, fewer than the threshold.
+    Degraded,
+
+    /// The guest agent missed at least the threshold of consecutive heartbeats.
+    Unresponsive,
+}
+
+/// Health of the guest, as served by the restful service.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub status: HealthStatus,
+
+    /// Number of heartbeats missed since the guest agent last responded.
+    pub consecutive_failures: u32,
+
+    pub threshold: u32,
+    pub interval_secs: u64,
+
+    /// Time the guest agent last responded, in seconds since the UNIX epoch.
+    pub last_success: Option<u64>,
+
+    /// Why the last missed heartbeat failed.
+    pub last_error: Option<String>,
+
+    /// Behavior when the guest becomes unresponsive.
+    pub on_unresponsive: UnresponsivePolicy,
+}
+
+/// Health of the guest, shared with the restful service. Only reported if heartbeats are
+/// configured.
+#[derive(Debug, Default)]
+pub struct Health {
+    report: Mutex<Option<HealthReport>>,
+}
+
+impl Health {
+    /// The health of the guest, if heartbeats are configured.
+    pub fn report(&self) -> Option<HealthReport> {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn start(&self, config: &HeartbeatConfig, policy: UnresponsivePolicy) {
+        *self.report.lock().unwrap() = Some(HealthReport {
+            status: HealthStatus::Starting,
+            consecutive_failures: 0,
+            threshold: config.threshold,
+            interval_secs: config.interval_secs,
+            last_success: None,
+            last_error: None,
+            on_unresponsive: policy,
+        });
+    }
+
+    /// Record the result of a heartbeat. Missed heartbeats are only counted once the guest agent
+    /// has responded, or once the boot grace period has passed. Returns the previous and new
+    /// statuses if the status changed.
+    fn record(
+        &self,
+        result: Result<(), anyhow::Error>,
+        booted: bool,
+    ) -> Option<(HealthStatus, HealthStatus)> {
+        let mut guard = self.report.lock().unwrap();
+        let report = guard.as_mut()?;
+        let previous = report.status;
+
+        match result {
+            Ok(()) => {
+                report.consecutive_failures = 0;
+                report.last_success = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs());
+                report.status = HealthStatus::Healthy;
+            }
+            Err(e) => {
+                report.last_error = Some(format!("{e:#}"));
+                if previous != HealthStatus::Starting || booted {
+                    report.consecutive_failures += 1;
+                    report.status = match report.consecutive_failures >= report.threshold {
+                        true => HealthStatus::Unresponsive,
+                        false => HealthStatus::Degraded,
+                    };
+                }
+            }
+        }
+
+        (report.status != previous).then_some((previous, report.status))
+    }
+}
+
+/// Ping the guest agent at an interval on a new thread until the VM exits, tracking the health of
+/// the guest and applying the policy once it becomes unresponsive.
+pub fn heartbeat_monitor(vm: Arc<VmHandle>, config: HeartbeatConfig, policy: UnresponsivePolicy) {
+    vm.health.start(&config, policy);
+
+    thread::spawn(move || {
+        let Some(agent) = &vm.agent else {
+            return;
+        };
+
+        let interval = Duration::from_secs(config.interval_secs);
+        let started = Instant::now();
+
+        while !vm.wait_exited(interval) {
+            if vm.stop_requested() {
+                return;
+            }
+
+            // A heartbeat is skipped rather than missed while another command is in flight,
+            // such as a file copy, as the agent serves one command at a time.
+            let result = match agent.ping(interval.min(PING_TIMEOUT_MAX)) {
+                Ok(false) => continue,
+                result => result.map(|_| ()),
+            };
+            let booted = started.elapsed() >= BOOT_GRACE;
+            match vm.health.record(result, booted) {
+                Some((_, HealthStatus::Degraded)) => {
+                    println!("Guest agent missed a heartbeat");
+                }
+                Some((_, HealthStatus::Unresponsive)) => {
+                    vm.events.publish(
+                        EventKind::GuestUnresponsive,
+                        format!(
+                            "guest agent missed {} consecutive heartbeats",
+                            config.threshold
+                        ),
+                    );
+                    if unresponsive(&vm, policy) {
+                        return;
+                    }
+                }
+                Some((HealthStatus::Degraded | HealthStatus::Unresponsive, _)) => {
+                    vm.events.publish(
+                        EventKind::GuestRecovered,
+                        "guest agent is responding to heartbeats again",
+                    );
+                }
+                _ => (),
+            }
+        }
+    });
+}
+
+/// Apply the policy to a guest that became unresponsive. Returns true if the VM is stopping.
+fn unresponsive(vm: &VmHandle, policy: UnresponsivePolicy) -> bool {
+    let result = match policy {
+        UnresponsivePolicy::Log => return false,
+        UnresponsivePolicy::Restart => {
+            vm.events.publish(
+                EventKind::Stopping,
+                "stopping unresponsive guest to restart it",
+            );
+            vm.reboot()
+        }
+        UnresponsivePolicy::Stop => {
+            vm.events
+                .publish(EventKind::Stopping, "stopping unresponsive guest");
+            vm.stop()
+        }
+    };
+
+    if let Err(e) = result {
+        println!("Unable to {policy} unresponsive guest: {e:#}");
+        return false;
+    }
+
+    true
+}
+
+mod tests {
+    #[test]
+    fn heartbeat_config_parse() {
+        use super::*;
+
+        assert_eq!(
+            HeartbeatConfig::from_str("interval=30s,threshold=5").unwrap(),
+            HeartbeatConfig {
+                interval_secs: 30,
+                threshold: 5,
+            }
+        );
+        assert_eq!(
+            HeartbeatConfig::from_str("threshold=2").unwrap(),
+            HeartbeatConfig {
+                interval_secs: 10,
+                threshold: 2,
+            }
+        );
+        assert!(HeartbeatConfig::from_str("interval=500ms").is_err());
+        assert!(HeartbeatConfig::from_str("interval=1500ms").is_err());
+        assert!(HeartbeatConfig::from_str("threshold=0").is_err());
+        assert!(HeartbeatConfig::from_str("timeout=5s").is_err());
+    }
+}
@@ -0,0 +1,563 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cleanup::{self, Resource},
+    cmdline::{args_parse, val_parse},
+    events::EventKind,
+    signal,
+    vm::VmHandle,
+};
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read},
+    net::IpAddr,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+/// Time given to a helper to create its ready socket.
+const HELPER_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time given to a helper to exit once asked to terminate, before it is killed.
+const HELPER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound of the delay before restarting a helper that exited.
+const HELPER_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Interval at which helpers are polled while waiting for them to become ready or exit.
+const HELPER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Interval at which the ready socket of a running helper is checked to still exist.
+const HELPER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configuration of a helper process (such as gvproxy, passt, or swtpm) supervised by krunkit
+/// for the lifetime of the VM.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelperConfig {
+    /// Name of the helper, prefixed to its output.
+    pub name: String,
+
+    /// Path of a UNIX socket the helper creates once it is ready to serve the VM.
+    pub ready_socket: Option<PathBuf>,
+
+    /// Program and arguments to run.
+    pub command: Vec<String>,
+}
+
+impl FromStr for HelperConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The command may contain commas, so it must be the last argument.
+        let (params, command) = match s.split_once("command=") {
+            Some((params, command)) => (params.trim_end_matches(','), command),
+            None => return Err(anyhow!("helper command argument not found")),
+        };
+
+        let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if command.is_empty() {
+            return Err(anyhow!("empty helper command"));
+        }
+
+        let mut name = None;
+        let mut ready_socket = None;
+        if !params.is_empty() {
+            for arg in args_parse(params.to_string(), "helper", None)? {
+                match arg.split_once('=').map(|(label, _)| label) {
+                    Some("name") => name = Some(val_parse(&arg, "name")?),
+                    Some("readySocket") => {
+                        ready_socket = Some(
+                            PathBuf::from_str(&val_parse(&arg, "readySocket")?)
+                                .context("readySocket argument not a valid path")?,
+                        )
+                    }
+                    _ => return Err(anyhow!("invalid helper argument: {arg}")),
+                }
+            }
+        }
+
+        // Default to the program's file name.
+        let name = name.unwrap_or_else(|| {
+            PathBuf::from(&command[0])
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| command[0].clone())
+        });
+
+        Ok(Self {
+            name,
+            ready_socket,
+            command,
+        })
+    }
+}
+
+impl HelperConfig {
+    /// Indicate if the helper runs passt.
+    fn is_passt(&self) -> bool {
+        Path::new(&self.command[0]).file_name() == Some("passt".as_ref())
+    }
+}
+
+/// Have the network backends started as helpers advertise the given DNS servers and search
+/// domains to the guest (see --dns and --dns-search). Only passt can be configured this way:
+/// gvproxy has no option setting the DNS servers of its guests, and krunkit cannot inject DHCP
+/// options, as libkrun passes frames straight to the backend's socket.
+pub fn configure_dns(
+    helpers: &mut [HelperConfig],
+    servers: &[IpAddr],
+    search: &[String],
+) -> Result<(), anyhow::Error> {
+    if servers.is_empty() && search.is_empty() {
+        return Ok(());
+    }
+
+    let mut configured = false;
+    for helper in helpers.iter_mut().filter(|h| h.is_passt()) {
+        for server in servers {
+            helper
+                .command
+                .extend(["--dns".to_string(), server.to_string()]);
+        }
+        if !search.is_empty() {
+            helper
+                .command
+                .extend(["--search".to_string(), search.join(" ")]);
+        }
+        configured = true;
+    }
+
+    match configured {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "--dns and --dns-search require passt to be started with --helper"
+        )),
+    }
+}
+
+/// Status of a supervised helper process.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HelperStatus {
+    Starting,
+    Running,
+    Exited,
+    Stopped,
+}
+
+/// Runtime state of a supervised helper process, as reported by the restful service.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelperReport {
+    #[serde(flatten)]
+    pub config: HelperConfig,
+
+    pub status: HelperStatus,
+
+    /// Process ID of the current instance of the helper.
+    pub pid: Option<u32>,
+
+    /// Number of times the helper was restarted after exiting.
+    pub restarts: u32,
+}
+
+/// A supervised helper process.
+struct Helper {
+    config: HelperConfig,
+    state: Mutex<HelperState>,
+    stopping: AtomicBool,
+}
+
+struct HelperState {
+    status: HelperStatus,
+    pid: Option<u32>,
+    restarts: u32,
+}
+
+/// Supervisor of the helper processes serving the VM. Helpers are started in order before the VM
+/// runs, each once the previous one is ready, and are restarted if they exit (or remove their
+/// ready socket) while the VM runs. They are stopped in reverse order before the VM is stopped, as
+/// libkrun exits the process once it has.
+#[derive(Default)]
+pub struct Supervisor {
+    helpers: Mutex<Vec<Arc<Helper>>>,
+}
+
+impl Supervisor {
+    /// Start each helper in order, waiting for it to be ready before starting the next one.
+    pub fn start(&self, vm: &Arc<VmHandle>, configs: &[HelperConfig]) -> Result<(), anyhow::Error> {
+        for config in configs {
+            let helper = Arc::new(Helper {
+                config: config.clone(),
+                state: Mutex::new(HelperState {
+                    status: HelperStatus::Starting,
+                    pid: None,
+                    restarts: 0,
+                }),
+                stopping: AtomicBool::new(false),
+            });
+            self.helpers.lock().unwrap().push(helper.clone());
+
+            let child = helper
+                .spawn()
+                .context(format!("unable to start helper {}", config.name))?;
+
+            let vm = vm.clone();
+            thread::spawn(move || helper.supervise(child, &vm));
+        }
+
+        Ok(())
+    }
+
+    /// Stop each helper in reverse order of start, killing those that do not exit in time.
+    pub fn stop(&self) {
+        let helpers = self.helpers.lock().unwrap();
+
+        for helper in helpers.iter().rev() {
+            helper.stop();
+        }
+    }
+
+    /// Name of the helper whose ready socket is at the given path, if any.
+    pub fn serving(&self, socket: &Path) -> Option<String> {
+        let helpers = self.helpers.lock().unwrap();
+
+        helpers
+            .iter()
+            .find(|helper| helper.config.ready_socket.as_deref() == Some(socket))
+            .map(|helper| helper.config.name.clone())
+    }
+
+    /// Restart a running helper, waiting for its new instance to be ready.
+    pub fn restart(&self, name: &str) -> Result<(), anyhow::Error> {
+        let helper = self
+            .helpers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|helper| helper.config.name == name)
+            .cloned()
+            .ok_or(anyhow!("unknown helper {name}"))?;
+
+        let (pid, restarts) = {
+            let state = helper.state.lock().unwrap();
+            (state.pid, state.restarts)
+        };
+        let Some(pid) = pid else {
+            return Err(anyhow!("helper {name} is not running"));
+        };
+
+        // The helper is restarted by its supervisor once it exits.
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+
+        let deadline = Instant::now() + HELPER_BACKOFF_MAX + HELPER_READY_TIMEOUT;
+        loop {
+            {
+                let state = helper.state.lock().unwrap();
+                if state.restarts > restarts && state.status == HelperStatus::Running {
+                    return Ok(());
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(anyhow!("helper {name} was not restarted in time"));
+            }
+            thread::sleep(HELPER_POLL_INTERVAL);
+        }
+    }
+
+    /// Report the runtime state of each helper.
+    pub fn report(&self) -> Vec<HelperReport> {
+        let helpers = self.helpers.lock().unwrap();
+
+        helpers
+            .iter()
+            .map(|helper| {
+                let state = helper.state.lock().unwrap();
+
+                HelperReport {
+                    config: helper.config.clone(),
+                    status: state.status,
+                    pid: state.pid,
+                    restarts: state.restarts,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Helper {
+    /// Start the helper, capturing its output, and wait for it to be ready.
+    fn spawn(&self) -> Result<Child, anyhow::Error> {
+        let name = &self.config.name;
+
+        // A socket left behind by a previous instance would be mistaken for readiness.
+        if let Some(socket) = &self.config.ready_socket {
+            if socket.exists() {
+                fs::remove_file(socket).context(format!(
+                    "unable to remove stale helper socket {}",
+                    socket.display()
+                ))?;
+            }
+        }
+
+        // Run the helper in its own process group, so that signals sent to krunkit's group (such
+        // as SIGINT from a terminal) do not terminate it before the VM has exited.
+        let mut command = Command::new(&self.config.command[0]);
+        command
+            .args(&self.config.command[1..])
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        signal::unblock_shutdown_signals(&mut command);
+        let mut child = command
+            .spawn()
+            .context(format!("unable to execute {}", self.config.command[0]))?;
+
+        cleanup::register(Resource::Process(child.id() as libc::pid_t));
+        if let Some(socket) = &self.config.ready_socket {
+            cleanup::register(Resource::File(socket.clone()));
+        }
+
+        if let Some(stdout) = child.stdout.take() {
+            capture_output(name.clone(), stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture_output(name.clone(), stderr);
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pid = Some(child.id());
+            state.status = HelperStatus::Starting;
+        }
+
+        if let Some(socket) = &self.config.ready_socket {
+            let deadline = Instant::now() + HELPER_READY_TIMEOUT;
+
+            while !socket.exists() {
+                if let Some(status) = child.try_wait()? {
+                    return Err(anyhow!("helper exited before becoming ready ({status})"));
+                }
+
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(anyhow!(
+                        "helper did not create {} within {} seconds",
+                        socket.display(),
+                        HELPER_READY_TIMEOUT.as_secs()
+                    ));
+                }
+
+                thread::sleep(HELPER_POLL_INTERVAL);
+            }
+        }
+
+        self.state.lock().unwrap().status = HelperStatus::Running;
+        println!("Helper {name} running (pid {})", child.id());
+
+        Ok(child)
+    }
+
+    /// Wait for the helper to exit, restarting it with an increasing delay unless it is being
+    /// stopped.
+    fn supervise(&self, mut child: Child, vm: &VmHandle) {
+        let name = &self.config.name;
+        let mut delay = Duration::from_secs(1);
+
+        loop {
+            let status = self.wait(&mut child);
+            cleanup::unregister(&Resource::Process(child.id() as libc::pid_t));
+
+            {
+                let mut state = self.state.lock().unwrap();
+                state.pid = None;
+                state.status = match self.stopping.load(Ordering::SeqCst) {
+                    true => HelperStatus::Stopped,
+                    false => HelperStatus::Exited,
+                };
+
+                if state.status == HelperStatus::Stopped {
+                    return;
+                }
+            }
+
+            vm.events.publish(
+                EventKind::HelperExited,
+                format!(
+                    "helper {name} exited ({status}), restarting in {} second(s)",
+                    delay.as_secs()
+                ),
+            );
+
+            loop {
+                thread::sleep(delay);
+                delay = (delay * 2).min(HELPER_BACKOFF_MAX);
+
+                if self.stopping.load(Ordering::SeqCst) {
+                    self.state.lock().unwrap().status = HelperStatus::Stopped;
+                    return;
+                }
+
+                match self.spawn() {
+                    Ok(new) => {
+                        child = new;
+                        self.state.lock().unwrap().restarts += 1;
+                        break;
+                    }
+                    Err(e) => {
+                        self.state.lock().unwrap().pid = None;
+                        println!("Unable to restart helper {name}: {e:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the helper to exit, returning why it did. A helper whose ready socket no longer
+    /// exists can no longer serve the VM, so it is killed, to be restarted.
+    fn wait(&self, child: &mut Child) -> String {
+        let mut checked = Instant::now();
+
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return status.to_string(),
+                Ok(None) => (),
+                Err(e) => return e.to_string(),
+            }
+
+            if let Some(socket) = &self.config.ready_socket {
+                if checked.elapsed() >= HELPER_CHECK_INTERVAL {
+                    checked = Instant::now();
+
+                    if !socket.exists() && !self.stopping.load(Ordering::SeqCst) {
+                        println!(
+                            "Helper {} removed its ready socket {}, killing it",
+                            self.config.name,
+                            socket.display()
+                        );
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return format!("ready socket {} removed", socket.display());
+                    }
+                }
+            }
+
+            thread::sleep(HELPER_POLL_INTERVAL);
+        }
+    }
+
+    /// Ask the helper to terminate and wait for it to exit, killing it if it does not in time.
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        let Some(pid) = self.state.lock().unwrap().pid else {
+            return;
+        };
+        let pid = pid as libc::pid_t;
+
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+
+        let deadline = Instant::now() + HELPER_STOP_TIMEOUT;
+        loop {
+            // Once the helper has exited, its PID may be reused by another process.
+            let state = self.state.lock().unwrap();
+            if state.status == HelperStatus::Stopped || state.pid.is_none() {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                println!("Helper {} did not exit, killing it", self.config.name);
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+                break;
+            }
+
+            drop(state);
+            thread::sleep(HELPER_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Write each line of a helper's output to krunkit's output, prefixed with the helper's name.
+pub fn capture_output<R: Read + Send + 'static>(name: String, output: R) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => println!("[{name}] {line}"),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+mod tests {
+    #[test]
+    fn helper_config_parse() {
+        use super::*;
+
+        let helper = HelperConfig::from_str(
+            "name=net,readySocket=/tmp/gv.sock,command=/usr/bin/gvproxy -listen-vfkit unixgram:///tmp/gv.sock,a",
+        )
+        .unwrap();
+        assert_eq!(helper.name, "net");
+        assert_eq!(helper.ready_socket, Some(PathBuf::from("/tmp/gv.sock")));
+        assert_eq!(
+            helper.command,
+            vec![
+                "/usr/bin/gvproxy",
+                "-listen-vfkit",
+                "unixgram:///tmp/gv.sock,a"
+            ]
+        );
+
+        let helper = HelperConfig::from_str("command=/usr/bin/swtpm socket").unwrap();
+        assert_eq!(helper.name, "swtpm");
+        assert_eq!(helper.ready_socket, None);
+
+        assert!(HelperConfig::from_str("name=net").is_err());
+        assert!(HelperConfig::from_str("command=").is_err());
+        assert!(HelperConfig::from_str("port=1,command=passt").is_err());
+    }
+
+    #[test]
+    fn helper_dns_configure() {
+        use super::*;
+
+        let mut helpers = vec![
+            HelperConfig::from_str(
+                "command=/usr/bin/gvproxy -listen-vfkit unixgram:///tmp/gv.sock",
+            )
+            .unwrap(),
+            HelperConfig::from_str("command=/usr/bin/passt --socket /tmp/passt.sock").unwrap(),
+        ];
+        let servers = [IpAddr::from_str("1.1.1.1").unwrap()];
+        let search = ["corp.example".to_string(), "example.com".to_string()];
+
+        configure_dns(&mut helpers, &[], &[]).unwrap();
+        assert_eq!(helpers[1].command.len(), 3);
+
+        configure_dns(&mut helpers, &servers, &search).unwrap();
+        assert_eq!(helpers[0].command.len(), 3);
+        assert_eq!(
+            helpers[1].command[3..],
+            ["--dns", "1.1.1.1", "--search", "corp.example example.com"]
+        );
+
+        assert!(configure_dns(&mut helpers[..1], &servers, &[]).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::{args_parse, val_parse},
+    config::VmConfig,
+    helper::capture_output,
+    signal,
+    vm::ExitReason,
+};
+
+use std::{
+    fmt,
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+/// Time given to a hook to complete before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval at which a hook is polled while waiting for it to complete.
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Point of the VM's lifecycle at which a hook runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookPoint {
+    /// Once the VM is configured, before helpers are started and the VM runs. krunkit exits if
+    /// the hook fails.
+    PreStart,
+
+    /// As the VM starts running, without waiting for the hook to complete.
+    PostStart,
+
+    /// As the VM is about to exit, once the helpers are stopped, before krunkit exits or restarts
+    /// it.
+    PostStop,
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PreStart => write!(f, "pre-start"),
+            Self::PostStart => write!(f, "post-start"),
+            Self::PostStop => write!(f, "post-stop"),
+        }
+    }
+}
+
+/// Scripts run on the host at points of the VM's lifecycle, to set up or tear down what the VM
+/// needs on the host.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookConfig {
+    pub pre_start: Option<PathBuf>,
+    pub post_start: Option<PathBuf>,
+    pub post_stop: Option<PathBuf>,
+}
+
+impl FromStr for HookConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+
+        for arg in args_parse(s.to_string(), "hook", None)? {
+            let Some((label, _)) = arg.split_once('=') else {
+                return Err(anyhow!("invalid hook argument: {arg}"));
+            };
+
+            let path = PathBuf::from(val_parse(&arg, label)?);
+            if path.as_os_str().is_empty() {
+                return Err(anyhow!("empty {label} hook path"));
+            }
+
+            match label {
+                "pre-start" => config.pre_start = Some(path),
+                "post-start" => config.post_start = Some(path),
+                "post-stop" => config.post_stop = Some(path),
+                _ => return Err(anyhow!("invalid hook argument: {arg}")),
+            }
+        }
+
+        if config == Self::default() {
+            return Err(anyhow!("no hook specified"));
+        }
+
+        Ok(config)
+    }
+}
+
+impl HookConfig {
+    fn script(&self, point: HookPoint) -> Option<&PathBuf> {
+        match point {
+            HookPoint::PreStart => self.pre_start.as_ref(),
+            HookPoint::PostStart => self.post_start.as_ref(),
+            HookPoint::PostStop => self.post_stop.as_ref(),
+        }
+    }
+
+    /// Indicate if a hook runs once the VM runs (and the sandbox is applied).
+    pub fn runs_after_start(&self) -> bool {
+        self.post_start.is_some() || self.post_stop.is_some()
+    }
+
+    /// Run the hook for a point of the lifecycle, if any, waiting for it to complete. Its output
+    /// is written to krunkit's output, prefixed with the point.
+    pub fn run(
+        &self,
+        point: HookPoint,
+        config: &VmConfig,
+        exit: Option<ExitReason>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(script) = self.script(point) else {
+            return Ok(());
+        };
+
+        let mut command = Command::new(script);
+        command
+            .env("KRUNKIT_HOOK", point.to_string())
+            .env("KRUNKIT_PID", process::id().to_string())
+            .env("KRUNKIT_CPUS", config.cpus.to_string())
+            .env("KRUNKIT_MEMORY_MIB", config.memory_mib.to_string())
+            .env("KRUNKIT_RESTFUL_URI", config.restful_uri.to_string())
+            .env(
+                "KRUNKIT_CONFIG",
+                serde_json::to_string(config).unwrap_or_default(),
+            );
+        if let Some(exit) = exit {
+            command
+                .env("KRUNKIT_EXIT_REASON", exit.to_string())
+                .env("KRUNKIT_EXIT_CODE", exit.exit_code().to_string());
+        }
+        signal::unblock_shutdown_signals(&mut command);
+
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!(
+                "unable to execute {point} hook {}",
+                script.display()
+            ))?;
+
+        let name = format!("hook {point}");
+        if let Some(stdout) = child.stdout.take() {
+            capture_output(name.clone(), stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture_output(name, stderr);
+        }
+
+        let deadline = Instant::now() + HOOK_TIMEOUT;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!(
+                    "{point} hook did not complete within {} seconds",
+                    HOOK_TIMEOUT.as_secs()
+                ));
+            }
+            thread::sleep(HOOK_POLL_INTERVAL);
+        };
+
+        match status.success() {
+            true => Ok(()),
+            false => Err(anyhow!("{point} hook failed ({status})")),
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn hook_config_parse() {
+        use super::*;
+
+        let hooks =
+            HookConfig::from_str("pre-start=/usr/local/bin/setup.sh,post-stop=/tmp/teardown.sh")
+                .unwrap();
+        assert_eq!(
+            hooks.script(HookPoint::PreStart),
+            Some(&PathBuf::from("/usr/local/bin/setup.sh"))
+        );
+        assert_eq!(hooks.script(HookPoint::PostStart), None);
+        assert_eq!(
+            hooks.script(HookPoint::PostStop),
+            Some(&PathBuf::from("/tmp/teardown.sh"))
+        );
+        assert!(hooks.runs_after_start());
+
+        assert!(HookConfig::from_str("pre-stop=/tmp/hook.sh").is_err());
+        assert!(HookConfig::from_str("pre-start=").is_err());
+        assert!(HookConfig::from_str("").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    lowpower::low_power_mode,
+    thermal::{thermal_pressure, ThermalPressure},
+    vm::VmHandle,
+};
+
+use std::{path::PathBuf, sync::Arc, thread, time::Duration};
+
+use serde::Serialize;
+
+/// Interval at which the host's power state is sampled to be propagated to the guest.
+const HOST_POWER_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Source of the host's power.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+    Ups,
+}
+
+/// Power state of the host.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostPower {
+    pub source: PowerSource,
+
+    /// Charge of the internal battery, if the host has one (percent).
+    pub battery_percent: Option<u8>,
+
+    /// The internal battery is charging, if the host has one.
+    pub charging: Option<bool>,
+
+    /// Thermal pressure of the host, if reported.
+    pub thermal_pressure: Option<ThermalPressure>,
+
+    /// The host is in Low Power Mode, if reported.
+    pub low_power_mode: Option<bool>,
+}
+
+/// Read the host's power state, if it can be determined.
+pub fn host_power() -> Option<HostPower> {
+    platform::host_power().map(|power| HostPower {
+        thermal_pressure: thermal_pressure(),
+        low_power_mode: low_power_mode(),
+        ..power
+    })
+}
+
+/// Write the host's power state as JSON to a file in the guest through the guest agent, whenever
+/// it changes, so that the guest can throttle background work while on battery.
+pub fn power_state_propagator(vm: Arc<VmHandle>, path: PathBuf) {
+    thread::spawn(move || {
+        let Some(agent) = &vm.agent else {
+            return;
+        };
+
+        let mut propagated = None;
+        let mut reported = false;
+        loop {
+            let power = host_power();
+            if power.is_some() && power != propagated {
+                let contents = serde_json::to_vec(&power).unwrap_or_default();
+                match agent.write_file(&path.to_string_lossy(), &contents) {
+                    Ok(()) => {
+                        propagated = power;
+                        reported = false;
+                    }
+                    // The guest agent may not be running yet, so retry at the next sample, but
+                    // only report the first of consecutive failures.
+                    Err(e) if !reported => {
+                        println!("Unable to propagate host power state to guest: {e:#}");
+                        reported = true;
+                    }
+                    Err(_) => (),
+                }
+            }
+
+            thread::sleep(HOST_POWER_POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{HostPower, PowerSource};
+
+    use std::{
+        ffi::{c_char, c_void, CStr, CString},
+        ptr,
+    };
+
+    type CfTypeRef = *const c_void;
+    type CfStringRef = *const c_void;
+    type CfArrayRef = *const c_void;
+    type CfDictionaryRef = *const c_void;
+
+    const CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+    const CF_NUMBER_SINT32_TYPE: isize = 3;
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFStringCreateWithCString(
+            alloc: *const c_void,
+            c_str: *const c_char,
+            encoding: u32,
+        ) -> CfStringRef;
+        fn CFStringGetCString(
+            string: CfStringRef,
+            buffer: *mut c_char,
+            size: isize,
+            encoding: u32,
+        ) -> bool;
+        fn CFArrayGetCount(array: CfArrayRef) -> isize;
+        fn CFArrayGetValueAtIndex(array: CfArrayRef, index: isize) -> CfTypeRef;
+        fn CFDictionaryGetValue(dict: CfDictionaryRef, key: CfTypeRef) -> CfTypeRef;
+        fn CFNumberGetValue(number: CfTypeRef, number_type: isize, value: *mut c_void) -> bool;
+        fn CFBooleanGetValue(boolean: CfTypeRef) -> bool;
+        fn CFRelease(cf: CfTypeRef);
+    }
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IOPSCopyPowerSourcesInfo() -> CfTypeRef;
+        fn IOPSCopyPowerSourcesList(blob: CfTypeRef) -> CfArrayRef;
+        fn IOPSGetPowerSourceDescription(blob: CfTypeRef, ps: CfTypeRef) -> CfDictionaryRef;
+        fn IOPSGetProvidingPowerSourceType(snapshot: CfTypeRef) -> CfStringRef;
+    }
+
+    pub fn host_power() -> Option<HostPower> {
+        let info = unsafe { IOPSCopyPowerSourcesInfo() };
+        if info.is_null() {
+            return None;
+        }
+
+        let source = match unsafe { string(IOPSGetProvidingPowerSourceType(info)) }.as_deref() {
+            Some("AC Power") => Some(PowerSource::Ac),
+            Some("Battery Power") => Some(PowerSource::Battery),
+            Some("UPS Power") => Some(PowerSource::Ups),
+            _ => None,
+        };
+
+        let mut battery_percent = None;
+        let mut charging = None;
+
+        let list = unsafe { IOPSCopyPowerSourcesList(info) };
+        if !list.is_null() {
+            for i in 0..unsafe { CFArrayGetCount(list) } {
+                let description =
+                    unsafe { IOPSGetPowerSourceDescription(info, CFArrayGetValueAtIndex(list, i)) };
+                if description.is_null()
+                    || unsafe { string(value(description, "Type")) }.as_deref()
+                        != Some("InternalBattery")
+                {
+                    continue;
+                }
+
+                let current = unsafe { number(value(description, "Current Capacity")) };
+                let max = unsafe { number(value(description, "Max Capacity")) };
+                if let (Some(current), Some(max)) = (current, max) {
+                    if max > 0 {
+                        battery_percent = Some((current * 100 / max).clamp(0, 100) as u8);
+                    }
+                }
+
+                let is_charging = unsafe { value(description, "Is Charging") };
+                if !is_charging.is_null() {
+                    charging = Some(unsafe { CFBooleanGetValue(is_charging) });
+                }
+            }
+            unsafe { CFRelease(list) };
+        }
+        unsafe { CFRelease(info) };
+
+        Some(HostPower {
+            source: source?,
+            battery_percent,
+            charging,
+            thermal_pressure: None,
+            low_power_mode: None,
+        })
+    }
+
+    /// Value of a power source description for the given key, or null if absent.
+    unsafe fn value(dict: CfDictionaryRef, key: &str) -> CfTypeRef {
+        let Ok(key) = CString::new(key) else {
+            return ptr::null();
+        };
+
+        let key = CFStringCreateWithCString(ptr::null(), key.as_ptr(), CF_STRING_ENCODING_UTF8);
+        if key.is_null() {
+            return ptr::null();
+        }
+        let value = CFDictionaryGetValue(dict, key);
+        CFRelease(key);
+
+        value
+    }
+
+    unsafe fn string(string: CfStringRef) -> Option<String> {
+        if string.is_null() {
+            return None;
+        }
+
+        let mut buf = [0 as c_char; 64];
+        if !CFStringGetCString(
+            string,
+            buf.as_mut_ptr(),
+            buf.len() as isize,
+            CF_STRING_ENCODING_UTF8,
+        ) {
+            return None;
+        }
+
+        Some(CStr::from_ptr(buf.as_ptr()).to_string_lossy().to_string())
+    }
+
+    unsafe fn number(number: CfTypeRef) -> Option<i32> {
+        if number.is_null() {
+            return None;
+        }
+
+        let mut value: i32 = 0;
+        match CFNumberGetValue(
+            number,
+            CF_NUMBER_SINT32_TYPE,
+            &mut value as *mut i32 as *mut c_void,
+        ) {
+            true => Some(value),
+            false => None,
+        }
+    }
+}
+
+/// The power supplies of other hosts are read from sysfs.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::{HostPower, PowerSource};
+
+    use std::{fs, path::Path};
+
+    const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+    pub fn host_power() -> Option<HostPower> {
+        let mut on_ac = None;
+        let mut battery_percent = None;
+        let mut charging = None;
+
+        for entry in fs::read_dir(POWER_SUPPLY_DIR).ok()?.flatten() {
+            let dir = entry.path();
+            match read(&dir, "type").as_deref() {
+                Some("Mains") => {
+                    on_ac =
+                        Some(on_ac.unwrap_or(false) || read(&dir, "online").as_deref() == Some("1"))
+                }
+                Some("Battery") if battery_percent.is_none() => {
+                    battery_percent = read(&dir, "capacity").and_then(|c| c.parse().ok());
+                    charging = read(&dir, "status").map(|s| s == "Charging");
+                }
+                _ => (),
+            }
+        }
+
+        let source = match (on_ac, battery_percent) {
+            (Some(true), _) => PowerSource::Ac,
+            (_, Some(_)) => PowerSource::Battery,
+            (Some(false), None) | (None, None) => return None,
+        };
+
+        Some(HostPower {
+            source,
+            battery_percent,
+            charging,
+            thermal_pressure: None,
+            low_power_mode: None,
+        })
+    }
+
+    fn read(dir: &Path, attribute: &str) -> Option<String> {
+        fs::read_to_string(dir.join(attribute))
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+}
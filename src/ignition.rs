@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cleanup::{self, Resource},
+    cmdline::{args_parse, val_parse},
+    events::EventKind,
+    libkrun::{self, libkrun},
+    statedir,
+    virtio::KrunContextSet,
+    vm::VmHandle,
+};
+
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{ffi::OsStrExt, net::UnixListener},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// vsock port the guest fetches its Ignition config from over HTTP, as served by vfkit.
+pub const IGNITION_VSOCK_PORT: u32 = 1024;
+
+/// Message the guest sends once it has booted (as sent by the ready.service unit of Fedora CoreOS
+/// images built for podman machine).
+const GUEST_READY_MESSAGE: &str = "Ready";
+
+/// Ignition config served to the guest, compatible with vfkit's --ignition.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct IgnitionConfig {
+    pub path: PathBuf,
+}
+
+impl FromStr for IgnitionConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            path: PathBuf::from_str(s).context("ignition argument not a valid path")?,
+        })
+    }
+}
+
+/// Forward guest connections on the Ignition vsock port to a UNIX socket krunkit serves the
+/// config on.
+impl KrunContextSet for IgnitionConfig {
+    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+        add_vsock_port(id, IGNITION_VSOCK_PORT, "ignition")
+    }
+}
+
+/// Channel the guest reports it has booted on.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestReadyConfig {
+    /// vsock port the guest connects to.
+    pub port: u32,
+}
+
+impl FromStr for GuestReadyConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = args_parse(s.to_string(), "guest-ready", Some(1))?;
+
+        let port = u32::from_str(&val_parse(&args[0], "port")?)
+            .context("guest ready port argument invalid")?;
+
+        Ok(Self { port })
+    }
+}
+
+/// Forward guest connections on the ready vsock port to a UNIX socket krunkit listens on.
+impl KrunContextSet for GuestReadyConfig {
+    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+        add_vsock_port(id, self.port, "ready")
+    }
+}
+
+/// Path of the host UNIX socket guest connections to a vsock port are forwarded to.
+fn socket_path(name: &str) -> PathBuf {
+    statedir::socket_path(name)
+        .unwrap_or_else(|| env::temp_dir().join(format!("krunkit-{name}-{}.sock", process::id())))
+}
+
+unsafe fn add_vsock_port(id: u32, port: u32, name: &str) -> Result<(), anyhow::Error> {
+    let path = socket_path(name);
+    let path_cstr = CString::new(path.as_os_str().as_bytes()).context(format!(
+        "unable to convert {name} socket path into C string"
+    ))?;
+
+    libkrun::check((libkrun().krun_add_vsock_port)(
+        id,
+        port,
+        path_cstr.as_ptr(),
+    ))
+    .context(format!(
+        "unable to add {name} vsock port {port} for path {}",
+        path.display()
+    ))?;
+
+    Ok(())
+}
+
+/// Listen on the host UNIX socket of a vsock port, replacing any stale socket.
+fn bind(name: &str) -> Result<UnixListener, anyhow::Error> {
+    let path = socket_path(name);
+    if path.exists() {
+        fs::remove_file(&path).context(format!(
+            "unable to remove stale {name} socket {}",
+            path.display()
+        ))?;
+    }
+
+    let listener = UnixListener::bind(&path)
+        .context(format!("unable to bind {name} socket {}", path.display()))?;
+    cleanup::register(Resource::File(path));
+
+    Ok(listener)
+}
+
+/// Serve the Ignition config to the guest on a new thread. Every request is answered with the
+/// config, read before the VM starts.
+pub fn serve_ignition(config: &IgnitionConfig) -> Result<(), anyhow::Error> {
+    let contents = ignition_contents(&config.path)?;
+    let listener = bind("ignition")?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Error accepting Ignition connection: {e}");
+                    continue;
+                }
+            };
+
+            // The request itself is not needed, as there is only one document to serve.
+            let mut buf = [0u8; 4096];
+            if let Err(e) = stream.read(&mut buf) {
+                println!("Error reading Ignition request: {e}");
+                continue;
+            }
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                contents.len()
+            );
+            match stream
+                .write_all(header.as_bytes())
+                .and_then(|_| stream.write_all(&contents))
+            {
+                Ok(()) => println!("Served Ignition config to guest"),
+                Err(e) => println!("Error writing Ignition config: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn ignition_contents(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    let contents =
+        fs::read(path).context(format!("unable to read Ignition config {}", path.display()))?;
+    serde_json::from_slice::<serde_json::Value>(&contents).context(format!(
+        "Ignition config {} is not valid JSON",
+        path.display()
+    ))?;
+
+    Ok(contents)
+}
+
+/// Wait for the guest to report that it has booted on a new thread, recording it in the VM's
+/// state and publishing an event.
+pub fn guest_ready_listener(vm: Arc<VmHandle>) -> Result<(), anyhow::Error> {
+    let listener = bind("ready")?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Error accepting guest ready connection: {e}");
+                    continue;
+                }
+            };
+
+            let mut line = String::new();
+            if let Err(e) = BufReader::new(stream).read_line(&mut line) {
+                println!("Error reading guest ready message: {e}");
+                continue;
+            }
+
+            match line.trim() == GUEST_READY_MESSAGE {
+                true if vm.set_guest_ready() => {
+                    vm.events.publish(EventKind::GuestReady, "guest is ready")
+                }
+                true => (),
+                false => println!("Unexpected guest ready message: {}", line.trim()),
+            }
+        }
+    });
+
+    Ok(())
+}
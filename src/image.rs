@@ -0,0 +1,824 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    copy::{format_bytes, Progress},
+    qcow2,
+    snapshot::clone_image,
+    virtio::DiskImageFormat,
+};
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+
+/// Size of the chunks images are copied in.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Arguments of the image subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct ImageArgs {
+    #[command(subcommand)]
+    pub command: ImageCommand,
+}
+
+/// Disk image operations.
+#[derive(Clone, Debug, Subcommand)]
+pub enum ImageCommand {
+    /// Copy a raw or qcow2 disk image, optionally skipping unallocated and zeroed regions and
+    /// limiting the rate of the copy.
+    Clone(CloneArgs),
+
+    /// Convert a disk image between the raw and qcow2 formats.
+    Convert(ConvertArgs),
+
+    /// Report the format, sizes, and backing file of a disk image, and whether krunkit can boot
+    /// it.
+    Inspect(InspectArgs),
+}
+
+/// Arguments of the image clone subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct CloneArgs {
+    /// Do not write regions of the image that are unallocated or only contain zeroes, leaving
+    /// holes in the copy instead.
+    #[arg(long, default_value_t = false)]
+    pub sparse: bool,
+
+    /// Maximum rate of the copy, in bytes per second, with an optional unit (for example,
+    /// 200MB/s or 1GiB/s).
+    #[arg(long)]
+    pub rate: Option<Rate>,
+
+    /// Do not report the progress of the copy.
+    #[arg(long, short, default_value_t = false)]
+    pub quiet: bool,
+
+    /// Image to copy.
+    pub source: PathBuf,
+
+    /// Path of the copy, which must not exist.
+    pub destination: PathBuf,
+}
+
+/// Arguments of the image convert subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct ConvertArgs {
+    /// Format of the image to convert (raw, qcow2). Detected if not specified.
+    #[arg(long)]
+    pub from: Option<DiskImageFormat>,
+
+    /// Format to convert the image to (raw, qcow2).
+    #[arg(long)]
+    pub to: DiskImageFormat,
+
+    /// Do not report the progress of the conversion.
+    #[arg(long, short, default_value_t = false)]
+    pub quiet: bool,
+
+    /// Image to convert.
+    pub source: PathBuf,
+
+    /// Path of the converted image, which must not exist.
+    pub destination: PathBuf,
+}
+
+/// Arguments of the image inspect subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct InspectArgs {
+    /// Print the report as JSON.
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+
+    /// Image to inspect.
+    pub image: PathBuf,
+}
+
+/// A rate, in bytes per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = s.strip_suffix("/s").unwrap_or(s);
+        let split = rate
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rate.len());
+        let (value, unit) = rate.split_at(split);
+
+        let multiplier: u64 = match unit.to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "kib" => 1 << 10,
+            "m" | "mb" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => return Err(anyhow!("invalid rate unit in {s}")),
+        };
+
+        let value = f64::from_str(value).context(format!("invalid rate {s}"))?;
+        let bytes = (value * multiplier as f64) as u64;
+        if bytes == 0 {
+            return Err(anyhow!("rate must be at least 1 byte per second"));
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+/// Detect the format of an image from its first bytes. Anything that is not a qcow2 image is
+/// a raw image.
+pub fn detect_format(file: &File) -> Result<DiskImageFormat, io::Error> {
+    let mut magic = [0; 4];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) if &magic == qcow2::MAGIC => Ok(DiskImageFormat::Qcow2),
+        Ok(()) => Ok(DiskImageFormat::Raw),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(DiskImageFormat::Raw),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn image(args: &ImageArgs) -> Result<(), anyhow::Error> {
+    match &args.command {
+        ImageCommand::Clone(args) => clone(args),
+        ImageCommand::Convert(args) => convert(args),
+        ImageCommand::Inspect(args) => inspect(args),
+    }
+}
+
+/// Copy a disk image. Without a rate limit, the image is cloned where the filesystem supports it
+/// (APFS), which is nearly instant. Otherwise, it is copied in chunks, and qcow2 images are copied
+/// as they are, metadata included.
+fn clone(args: &CloneArgs) -> Result<(), anyhow::Error> {
+    let source =
+        File::open(&args.source).context(format!("unable to open {}", args.source.display()))?;
+    let len = source.metadata()?.len();
+
+    let format = detect_format(&source)?;
+    if format == DiskImageFormat::Qcow2 {
+        let header = qcow2::Header::read(&source)
+            .context(format!("unable to read {}", args.source.display()))?;
+        check_backing_file(&args.source, &args.destination, &header)?;
+    }
+
+    if args.destination.exists() {
+        return Err(anyhow!("{} already exists", args.destination.display()));
+    }
+
+    if args.rate.is_none() && clone_image(&args.source, &args.destination)? {
+        println!(
+            "Cloned {} to {} ({format}, {})",
+            args.source.display(),
+            args.destination.display(),
+            format_bytes(len)
+        );
+        return Ok(());
+    }
+
+    let destination = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&args.destination)
+        .context(format!("unable to create {}", args.destination.display()))?;
+
+    let progress = Progress {
+        quiet: args.quiet,
+        total: Some(len),
+    };
+    let written = copy_data(&source, &destination, len, args, &progress);
+    progress.finish();
+
+    let written = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = fs::remove_file(&args.destination);
+            return Err(e.context(format!(
+                "unable to copy {} to {}",
+                args.source.display(),
+                args.destination.display()
+            )));
+        }
+    };
+
+    println!(
+        "Copied {} to {} ({format}, {}, {} written)",
+        args.source.display(),
+        args.destination.display(),
+        format_bytes(len),
+        format_bytes(written)
+    );
+
+    Ok(())
+}
+
+/// Check that the copy of a qcow2 overlay will find its backing file. A relative backing file is
+/// resolved from the overlay's directory, so the copy must be in the same directory.
+fn check_backing_file(
+    source: &Path,
+    destination: &Path,
+    header: &qcow2::Header,
+) -> Result<(), anyhow::Error> {
+    let Some(backing_file) = &header.backing_file else {
+        return Ok(());
+    };
+    if Path::new(backing_file).is_absolute() {
+        return Ok(());
+    }
+
+    let dir = |path: &Path| match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize(),
+        _ => Path::new(".").canonicalize(),
+    };
+    if dir(source)? != dir(destination)? {
+        return Err(anyhow!(
+            "the backing file of {} ({backing_file}) is relative to its directory, so the copy \
+             must be in the same directory",
+            source.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Convert a disk image between the raw and qcow2 formats. Unallocated and zeroed regions of the
+/// disk are left unallocated in the converted image.
+fn convert(args: &ConvertArgs) -> Result<(), anyhow::Error> {
+    let source =
+        File::open(&args.source).context(format!("unable to open {}", args.source.display()))?;
+
+    let format = detect_format(&source)?;
+    if let Some(from) = args.from {
+        if from != format {
+            return Err(anyhow!(
+                "{} is a {format} image, not a {from} image",
+                args.source.display()
+            ));
+        }
+    }
+    if format == args.to {
+        return Err(anyhow!(
+            "{} is already a {format} image (use krunkit image clone to copy it)",
+            args.source.display()
+        ));
+    }
+
+    if args.destination.exists() {
+        return Err(anyhow!("{} already exists", args.destination.display()));
+    }
+    let destination = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&args.destination)
+        .context(format!("unable to create {}", args.destination.display()))?;
+
+    let mut progress = Progress {
+        quiet: args.quiet,
+        total: None,
+    };
+    let size = match args.to {
+        DiskImageFormat::Qcow2 => raw_to_qcow2(&source, &destination, &mut progress),
+        DiskImageFormat::Raw => qcow2_to_raw(&source, &destination, &mut progress),
+    };
+    progress.finish();
+
+    let size = match size {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_file(&args.destination);
+            return Err(e.context(format!(
+                "unable to convert {} to {}",
+                args.source.display(),
+                args.destination.display()
+            )));
+        }
+    };
+
+    let allocated = destination
+        .metadata()
+        .map(|m| m.blocks() * 512)
+        .unwrap_or(0);
+    println!(
+        "Converted {} ({format}) to {} ({}, {} disk, {} allocated)",
+        args.source.display(),
+        args.destination.display(),
+        args.to,
+        format_bytes(size),
+        format_bytes(allocated)
+    );
+
+    Ok(())
+}
+
+/// Write a qcow2 image with the clusters of a raw image that contain data. Returns the size of
+/// the disk.
+fn raw_to_qcow2(
+    source: &File,
+    destination: &File,
+    progress: &mut Progress,
+) -> Result<u64, anyhow::Error> {
+    let len = source.metadata()?.len();
+    progress.total = Some(len);
+
+    let cluster_size = qcow2::Writer::cluster_size();
+    let mut writer = qcow2::Writer::new(destination, len);
+    let mut buf = vec![0; cluster_size as usize];
+
+    // Clusters before this offset have been written, and data regions may share a cluster.
+    let mut next = 0;
+    while let Some((start, end)) = next_data(source, next, len, true)? {
+        let mut cluster = (start / cluster_size * cluster_size).max(next);
+        while cluster < end {
+            let n = cluster_size.min(len - cluster) as usize;
+            source.read_exact_at(&mut buf[..n], cluster)?;
+            if buf[..n].iter().any(|b| *b != 0) {
+                writer.write_cluster(cluster, &buf[..n])?;
+            }
+
+            cluster += cluster_size;
+            progress.report(cluster.min(len));
+        }
+        next = cluster;
+    }
+
+    writer.finish()?;
+
+    Ok(len)
+}
+
+/// Write the allocated clusters of a qcow2 image to a raw image, leaving holes for the others.
+/// Returns the size of the disk.
+fn qcow2_to_raw(
+    source: &File,
+    destination: &File,
+    progress: &mut Progress,
+) -> Result<u64, anyhow::Error> {
+    let mut reader = qcow2::Reader::new(source)?;
+    let size = reader.size();
+    progress.total = Some(size);
+
+    let cluster_size = reader.cluster_size();
+    let mut buf = vec![0; cluster_size as usize];
+
+    let mut cluster = 0;
+    while cluster < size {
+        if reader.read_cluster(cluster, &mut buf)? && buf.iter().any(|b| *b != 0) {
+            let n = cluster_size.min(size - cluster) as usize;
+            destination.write_all_at(&buf[..n], cluster)?;
+        }
+
+        cluster += cluster_size;
+        progress.report(cluster.min(size));
+    }
+
+    destination.set_len(size)?;
+    destination.sync_all()?;
+
+    Ok(size)
+}
+
+/// Partition table of a disk.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTable {
+    Gpt,
+    Mbr,
+}
+
+impl fmt::Display for PartitionTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Gpt => write!(f, "GPT"),
+            Self::Mbr => write!(f, "MBR"),
+        }
+    }
+}
+
+/// What krunkit finds about a disk image.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageReport {
+    path: PathBuf,
+    format: DiskImageFormat,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qcow2_version: Option<u32>,
+
+    /// Size of the disk seen by the guest, in bytes.
+    virtual_size: u64,
+
+    /// Space the image uses on the host, in bytes.
+    allocated_size: u64,
+
+    backing_file: Option<String>,
+
+    /// Partition table of the disk, if one was found.
+    partition_table: Option<PartitionTable>,
+
+    /// Whether the disk has an EFI system partition, or null if its contents could not be read or
+    /// its GPT header is invalid.
+    efi_system_partition: Option<bool>,
+
+    /// Whether krunkit can boot the disk, which is the case if no problem was found.
+    bootable: bool,
+
+    problems: Vec<String>,
+
+    /// --device argument to attach the image with.
+    device: String,
+}
+
+/// Report what krunkit finds about a disk image, and whether it can boot it.
+fn inspect(args: &InspectArgs) -> Result<(), anyhow::Error> {
+    let report = image_report(&args.image)?;
+
+    match args.json {
+        true => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("unable to serialize image report")?
+        ),
+        false => print_image_report(&report),
+    }
+
+    Ok(())
+}
+
+fn image_report(path: &Path) -> Result<ImageReport, anyhow::Error> {
+    let file = File::open(path).context(format!("unable to open {}", path.display()))?;
+    let metadata = file.metadata()?;
+    let format = detect_format(&file)?;
+
+    let mut report = ImageReport {
+        path: path.to_path_buf(),
+        format,
+        qcow2_version: None,
+        virtual_size: metadata.len(),
+        allocated_size: metadata.blocks() * 512,
+        backing_file: None,
+        partition_table: None,
+        efi_system_partition: None,
+        bootable: false,
+        problems: Vec::new(),
+        device: format!("virtio-blk,path={},format={format}", path.display()),
+    };
+
+    let partitions = match format {
+        DiskImageFormat::Raw => {
+            partitions(|offset, buf| read_raw(&file, offset, buf).map_err(anyhow::Error::from))
+        }
+        DiskImageFormat::Qcow2 => match qcow2::Header::read(&file) {
+            Ok(header) => {
+                check_qcow2(path, &header, &mut report);
+                qcow2::Reader::new(&file)
+                    .and_then(|mut reader| partitions(|offset, buf| reader.read_at(offset, buf)))
+            }
+            Err(e) => {
+                report.problems.push(format!("invalid qcow2 image: {e:#}"));
+                Err(e)
+            }
+        },
+    };
+
+    if report.virtual_size == 0 {
+        report.problems.push(String::from("the disk is empty"));
+    } else if !report.virtual_size.is_multiple_of(512) {
+        report.problems.push(format!(
+            "the disk size ({} bytes) is not a multiple of the 512-byte sector size",
+            report.virtual_size
+        ));
+    }
+
+    // Disks are booted through the EFI firmware bundled with libkrun-efi.
+    match partitions {
+        Ok(None) => {
+            report.efi_system_partition = Some(false);
+            report.problems.push(String::from(
+                "no partition table found (the EFI firmware boots from an EFI system partition)",
+            ));
+        }
+        Ok(Some((table, esp))) => {
+            report.partition_table = Some(table);
+            report.efi_system_partition = esp;
+            if esp == Some(false) {
+                report
+                    .problems
+                    .push(String::from("no EFI system partition found"));
+            }
+        }
+        // The contents of the disk could not be read by krunkit, though libkrun may.
+        Err(_) => (),
+    }
+
+    report.bootable = report.problems.is_empty();
+
+    Ok(report)
+}
+
+/// Check the features of a qcow2 image that libkrun needs to support to boot it.
+fn check_qcow2(path: &Path, header: &qcow2::Header, report: &mut ImageReport) {
+    report.qcow2_version = Some(header.version);
+    report.virtual_size = header.size;
+    report.backing_file = header.backing_file.clone();
+
+    if !(2..=3).contains(&header.version) {
+        report
+            .problems
+            .push(format!("unsupported qcow2 version {}", header.version));
+    }
+    if header.crypt_method != 0 {
+        report
+            .problems
+            .push(String::from("the qcow2 image is encrypted"));
+    }
+
+    if let Some(backing_file) = &header.backing_file {
+        let resolved = match path.parent() {
+            Some(dir) => dir.join(backing_file),
+            None => PathBuf::from(backing_file),
+        };
+        if !resolved.exists() {
+            report.problems.push(format!(
+                "backing file {backing_file} not found ({})",
+                resolved.display()
+            ));
+        }
+    }
+}
+
+/// Read a raw image, reading zeroes past its end.
+fn read_raw(file: &File, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    buf[read..].fill(0);
+
+    Ok(())
+}
+
+/// GUID of EFI system partitions, as stored in GPT partition entries.
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// MBR partition type of EFI system partitions.
+const ESP_MBR_TYPE: u8 = 0xef;
+
+/// Largest number of GPT partition entries read.
+const GPT_ENTRIES_MAX: usize = 1024;
+
+/// Largest size of a GPT partition entry accepted, as in EFI firmware.
+const GPT_ENTRY_SIZE_MAX: usize = 4096;
+
+/// Find the partition table of a disk, and whether it has an EFI system partition, or None if the
+/// partition entries of its GPT header are out of range.
+fn partitions(
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<(), anyhow::Error>,
+) -> Result<Option<(PartitionTable, Option<bool>)>, anyhow::Error> {
+    // The GPT header is in the second logical block, of 512 or 4096 bytes.
+    for block_size in [512, 4096] {
+        let mut header = [0; 92];
+        read(block_size, &mut header)?;
+        if &header[..8] != b"EFI PART" {
+            continue;
+        }
+
+        // The header comes from the image being inspected, so its values are not trusted.
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        let entries = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+        let offset = entries_lba.checked_mul(block_size);
+        let (Some(offset), 16..=GPT_ENTRY_SIZE_MAX) = (offset, entry_size) else {
+            return Ok(Some((PartitionTable::Gpt, None)));
+        };
+
+        let mut table = vec![0; entries.min(GPT_ENTRIES_MAX) * entry_size];
+        read(offset, &mut table)?;
+        let esp = table
+            .chunks_exact(entry_size)
+            .any(|entry| entry[..16] == ESP_TYPE_GUID);
+
+        return Ok(Some((PartitionTable::Gpt, Some(esp))));
+    }
+
+    let mut mbr = [0; 512];
+    read(0, &mut mbr)?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+    let esp = mbr[446..510]
+        .chunks_exact(16)
+        .any(|entry| entry[4] == ESP_MBR_TYPE);
+
+    Ok(Some((PartitionTable::Mbr, Some(esp))))
+}
+
+fn print_image_report(report: &ImageReport) {
+    println!("{}:", report.path.display());
+    match report.qcow2_version {
+        Some(version) => println!("  format: {} (version {version})", report.format),
+        None => println!("  format: {}", report.format),
+    }
+    println!(
+        "  virtual size: {} ({} bytes)",
+        format_bytes(report.virtual_size),
+        report.virtual_size
+    );
+    println!("  allocated size: {}", format_bytes(report.allocated_size));
+    println!(
+        "  backing file: {}",
+        report.backing_file.as_deref().unwrap_or("none")
+    );
+
+    let partitions = match (report.partition_table, report.efi_system_partition) {
+        (Some(table), Some(true)) => format!("{table}, with an EFI system partition"),
+        (Some(table), Some(false)) => format!("{table}, without an EFI system partition"),
+        (Some(table), None) => format!("{table}, partition entries unknown (invalid header)"),
+        (None, Some(_)) => String::from("none"),
+        (None, None) => String::from("unknown (the disk's contents could not be read)"),
+    };
+    println!("  partition table: {partitions}");
+
+    println!("  bootable: {}", if report.bootable { "yes" } else { "no" });
+    for problem in &report.problems {
+        println!("  problem: {problem}");
+    }
+    println!("  device: --device {}", report.device);
+}
+
+/// Copy the contents of an image, returning the number of bytes written.
+fn copy_data(
+    source: &File,
+    destination: &File,
+    len: u64,
+    args: &CloneArgs,
+    progress: &Progress,
+) -> Result<u64, anyhow::Error> {
+    let mut throttle = args.rate.map(Throttle::new);
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut written = 0;
+
+    let mut offset = 0;
+    while let Some((start, end)) = next_data(source, offset, len, args.sparse)? {
+        let mut pos = start;
+        while pos < end {
+            let n = (end - pos).min(COPY_CHUNK_SIZE as u64) as usize;
+            source.read_exact_at(&mut buf[..n], pos)?;
+
+            if !(args.sparse && buf[..n].iter().all(|b| *b == 0)) {
+                destination.write_all_at(&buf[..n], pos)?;
+                written += n as u64;
+            }
+            pos += n as u64;
+
+            if let Some(throttle) = &mut throttle {
+                throttle.wait(n as u64);
+            }
+            progress.report(pos);
+        }
+        offset = end;
+    }
+
+    // The image ends with a hole if its last region was not written.
+    destination.set_len(len)?;
+    destination.sync_all()?;
+
+    Ok(written)
+}
+
+/// The next region of the file at or after the offset to copy, as its start and end offsets. If
+/// sparse, unallocated regions of the file are skipped, where the filesystem reports them.
+fn next_data(
+    file: &File,
+    offset: u64,
+    len: u64,
+    sparse: bool,
+) -> Result<Option<(u64, u64)>, io::Error> {
+    if offset >= len {
+        return Ok(None);
+    }
+    if !sparse {
+        return Ok(Some((offset, len)));
+    }
+
+    let fd = file.as_raw_fd();
+    let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // There is no data past the offset.
+            Some(libc::ENXIO) => Ok(None),
+            // The filesystem does not report holes.
+            Some(libc::EINVAL) | Some(libc::ENOTSUP) => Ok(Some((offset, len))),
+            _ => Err(e),
+        };
+    }
+
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    let end = match end < 0 {
+        true => len,
+        false => (end as u64).min(len),
+    };
+
+    Ok(Some((start as u64, end)))
+}
+
+/// Limits the rate of a copy by sleeping as long as it is ahead of the rate.
+struct Throttle {
+    rate: Rate,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn wait(&mut self, bytes: u64) {
+        self.bytes += bytes;
+
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate.0 as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn rate_parse() {
+        use super::*;
+
+        assert_eq!(Rate::from_str("200MB/s").unwrap(), Rate(200_000_000));
+        assert_eq!(Rate::from_str("1GiB/s").unwrap(), Rate(1 << 30));
+        assert_eq!(Rate::from_str("1.5m").unwrap(), Rate(1_500_000));
+        assert_eq!(Rate::from_str("4096").unwrap(), Rate(4096));
+        assert!(Rate::from_str("0").is_err());
+        assert!(Rate::from_str("10 furlongs").is_err());
+        assert!(Rate::from_str("MB/s").is_err());
+    }
+
+    #[test]
+    fn partition_table_detect() {
+        use super::*;
+
+        let mut disk = vec![0u8; 64 * 1024];
+        let read = |disk: &Vec<u8>| {
+            partitions(|offset, buf| {
+                let start = offset as usize;
+                buf.copy_from_slice(&disk[start..start + buf.len()]);
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(read(&disk), None);
+
+        // An MBR with a Linux partition, then with an EFI system partition.
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+        disk[446 + 4] = 0x83;
+        assert_eq!(read(&disk), Some((PartitionTable::Mbr, Some(false))));
+        disk[446 + 16 + 4] = ESP_MBR_TYPE;
+        assert_eq!(read(&disk), Some((PartitionTable::Mbr, Some(true))));
+
+        // A GPT with its partition entries from the third block, the second of which is an EFI
+        // system partition.
+        disk[512..520].copy_from_slice(b"EFI PART");
+        disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        assert_eq!(read(&disk), Some((PartitionTable::Gpt, Some(false))));
+        disk[1024 + 128..1024 + 144].copy_from_slice(&ESP_TYPE_GUID);
+        assert_eq!(read(&disk), Some((PartitionTable::Gpt, Some(true))));
+
+        // Out-of-range entry sizes and table locations.
+        disk[512 + 84..512 + 88].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(&disk), Some((PartitionTable::Gpt, None)));
+        disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        disk[512 + 72..512 + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(read(&disk), Some((PartitionTable::Gpt, None)));
+    }
+}
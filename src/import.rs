@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use roxmltree::{Document, Node};
+use serde_json::Value;
+
+/// Arguments of the import subcommand.
+#[derive(Clone, Debug, Parser)]
+pub struct ImportArgs {
+    /// vfkit VM definition (JSON) to import.
+    #[arg(long = "vfkit-json", required_unless_present = "libvirt_xml")]
+    pub vfkit_json: Option<PathBuf>,
+
+    /// libvirt domain definition (XML) to import.
+    #[arg(long = "libvirt-xml", conflicts_with = "vfkit_json")]
+    pub libvirt_xml: Option<PathBuf>,
+
+    /// Path of the krunkit config file to write (defaults to standard output).
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// A krunkit config file being generated, with a line per option. Settings that krunkit cannot
+/// represent are written as comments, so that they can be reviewed.
+#[derive(Debug, Default)]
+struct ConfigFile {
+    lines: Vec<String>,
+}
+
+impl ConfigFile {
+    fn option(&mut self, option: &str, value: impl AsRef<str>) {
+        self.lines.push(format!("--{option} {}", value.as_ref()));
+    }
+
+    fn device(&mut self, device: impl AsRef<str>) {
+        self.option("device", device);
+    }
+
+    fn unsupported(&mut self, what: impl AsRef<str>) {
+        self.lines
+            .push(format!("# not imported: {}", what.as_ref()));
+    }
+}
+
+/// Convert a vfkit or libvirt VM definition into a krunkit config file.
+pub fn import(args: &ImportArgs) -> Result<(), anyhow::Error> {
+    let (path, config) = match (&args.vfkit_json, &args.libvirt_xml) {
+        (Some(path), _) => (path, from_vfkit_json(&read(path)?)?),
+        (_, Some(path)) => (path, from_libvirt_xml(&read(path)?)?),
+        (None, None) => unreachable!(),
+    };
+
+    let mut contents = format!("# krunkit config imported from {}\n", path.display());
+    for line in config.lines {
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+
+    match &args.output {
+        Some(output) => fs::write(output, contents)
+            .context(format!("unable to write config file {}", output.display()))?,
+        None => io::stdout().write_all(contents.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String, anyhow::Error> {
+    fs::read_to_string(path).context(format!("unable to read {}", path.display()))
+}
+
+/// Convert a VM definition serialized by vfkit (its config.VirtualMachine type).
+fn from_vfkit_json(contents: &str) -> Result<ConfigFile, anyhow::Error> {
+    let vm: Value = serde_json::from_str(contents).context("invalid vfkit VM definition")?;
+    let mut config = ConfigFile::default();
+
+    let str_field = |value: &Value, name: &str| value[name].as_str().map(String::from);
+
+    let cpus = vm["vcpus"]
+        .as_u64()
+        .ok_or(anyhow!("vfkit VM definition has no vcpus"))?;
+    config.option("cpus", cpus.to_string());
+
+    let memory = vm["memoryBytes"]
+        .as_u64()
+        .ok_or(anyhow!("vfkit VM definition has no memoryBytes"))?;
+    config.option("memory", (memory / (1024 * 1024)).to_string());
+
+    match (str_field(&vm["bootloader"], "kind"), &vm["bootloader"]) {
+        (Some(kind), bootloader) if kind == "efiBootloader" => {
+            match str_field(bootloader, "efiVariableStorePath") {
+                Some(vstore) => {
+                    config.option("bootloader", format!("efi,variable-store={vstore},create"))
+                }
+                None => config.unsupported("EFI bootloader without a variable store"),
+            }
+        }
+        (Some(kind), _) => config.unsupported(format!("bootloader {kind}")),
+        (None, _) => (),
+    }
+
+    for device in vm["devices"].as_array().into_iter().flatten() {
+        let kind = str_field(device, "kind").unwrap_or_default();
+
+        match kind.as_str() {
+            "virtioblk" => match str_field(device, "imagePath") {
+                Some(path) => config.device(format!("virtio-blk,path={path},format=raw")),
+                None => config.unsupported("virtio-blk device without an image"),
+            },
+            "virtiofs" => match (
+                str_field(device, "sharedDir"),
+                str_field(device, "mountTag"),
+            ) {
+                (Some(dir), Some(tag)) => {
+                    config.device(format!("virtio-fs,sharedDir={dir},mountTag={tag}"))
+                }
+                _ => config.unsupported("virtio-fs device without a shared directory"),
+            },
+            "virtiovsock" => match (
+                device["port"].as_u64(),
+                str_field(device, "socketURL"),
+                device["listen"].as_bool(),
+            ) {
+                (Some(port), Some(url), Some(true)) => {
+                    let path = url.strip_prefix("unix://").unwrap_or(&url);
+                    config.device(format!("virtio-vsock,port={port},socketURL={path},listen"))
+                }
+                (port, _, _) => config.unsupported(format!(
+                    "virtio-vsock port {} connecting to the host (only listening ports are supported)",
+                    port.unwrap_or_default()
+                )),
+            },
+            "virtionet" => match (
+                str_field(device, "unixSocketPath"),
+                str_field(device, "macAddress"),
+            ) {
+                (Some(path), Some(mac)) => {
+                    config.device(format!("virtio-net,unixSocketPath={path},mac={mac}"))
+                }
+                (_, mac) => config.unsupported(format!(
+                    "virtio-net device {} using NAT or a file descriptor (use a gvproxy socket instead)",
+                    mac.unwrap_or_default()
+                )),
+            },
+            "virtioserial" => match str_field(device, "logFile") {
+                Some(path) => config.device(format!("virtio-serial,logFilePath={path}")),
+                None => config.unsupported("virtio-serial device not logging to a file"),
+            },
+            "virtiorng" => config.device("virtio-rng"),
+            "virtiogpu" => match (device["width"].as_u64(), device["height"].as_u64()) {
+                (Some(width), Some(height)) => {
+                    config.device(format!("virtio-gpu,width={width},height={height}"))
+                }
+                _ => config.unsupported("virtio-gpu device without a resolution"),
+            },
+            "virtioinput" => match str_field(device, "inputType").as_deref() {
+                Some(input @ ("keyboard" | "pointing")) => {
+                    config.device(format!("virtio-input,{input}"))
+                }
+                _ => config.unsupported("virtio-input device"),
+            },
+            kind => config.unsupported(format!("{kind} device")),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Convert a libvirt domain definition.
+fn from_libvirt_xml(contents: &str) -> Result<ConfigFile, anyhow::Error> {
+    let document = Document::parse(contents).context("invalid libvirt domain definition")?;
+    let domain = document.root_element();
+    if !domain.has_tag_name("domain") {
+        return Err(anyhow!("libvirt domain definition has no domain element"));
+    }
+
+    let mut config = ConfigFile::default();
+
+    let cpus = child_text(domain, "vcpu")
+        .ok_or(anyhow!("libvirt domain definition has no vcpu element"))?;
+    config.option("cpus", cpus.trim());
+
+    let memory = child(domain, "memory")
+        .ok_or(anyhow!("libvirt domain definition has no memory element"))?;
+    config.option("memory", libvirt_memory_mib(memory)?.to_string());
+
+    if let Some(os) = child(domain, "os") {
+        match child_text(os, "nvram") {
+            Some(nvram) => config.option(
+                "bootloader",
+                format!("efi,variable-store={},create", nvram.trim()),
+            ),
+            None if child(os, "kernel").is_some() => config.unsupported("direct kernel boot"),
+            None => (),
+        }
+    }
+
+    let devices = child(domain, "devices")
+        .into_iter()
+        .flat_map(|d| d.children());
+    for device in devices.filter(|d| d.is_element()) {
+        let source = child(device, "source");
+        let source_attr = |name| source.and_then(|s| s.attribute(name));
+
+        match device.tag_name().name() {
+            "disk" => match (device.attribute("device"), source_attr("file")) {
+                (Some("disk") | None, Some(path)) => {
+                    let format = child(device, "driver")
+                        .and_then(|d| d.attribute("type"))
+                        .unwrap_or("raw");
+                    match format {
+                        "raw" | "qcow2" => {
+                            config.device(format!("virtio-blk,path={path},format={format}"))
+                        }
+                        format => config.unsupported(format!("{format} disk {path}")),
+                    }
+                }
+                (kind, path) => config.unsupported(format!(
+                    "{} {}",
+                    kind.unwrap_or("disk"),
+                    path.unwrap_or("not backed by a file")
+                )),
+            },
+            "filesystem" => match (
+                source_attr("dir"),
+                child(device, "target").and_then(|t| t.attribute("dir")),
+            ) {
+                (Some(dir), Some(tag)) => {
+                    config.device(format!("virtio-fs,sharedDir={dir},mountTag={tag}"))
+                }
+                _ => config.unsupported("filesystem not backed by a directory"),
+            },
+            "interface" => {
+                let mac = child(device, "mac")
+                    .and_then(|m| m.attribute("address"))
+                    .unwrap_or_default();
+                config.unsupported(format!(
+                    "{} interface {mac} (use a gvproxy socket: --device virtio-net,unixSocketPath=<path>,mac={mac})",
+                    device.attribute("type").unwrap_or("network")
+                ));
+            }
+            "serial" | "console" => match source_attr("path") {
+                Some(path) if device.attribute("type") == Some("file") => {
+                    config.device(format!("virtio-serial,logFilePath={path}"))
+                }
+                _ => config.unsupported(format!(
+                    "{} of type {}",
+                    device.tag_name().name(),
+                    device.attribute("type").unwrap_or("pty")
+                )),
+            },
+            "rng" => config.device("virtio-rng"),
+            // Only the emulator and devices libvirt always adds to a domain are silently ignored.
+            "emulator" | "controller" | "memballoon" => (),
+            name => config.unsupported(format!("{name} device")),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Amount of memory of a libvirt memory element, in MiB.
+fn libvirt_memory_mib(memory: Node) -> Result<u64, anyhow::Error> {
+    let value: u64 = memory
+        .text()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .context("invalid libvirt memory amount")?;
+
+    let bytes = match memory.attribute("unit").unwrap_or("KiB") {
+        "b" | "bytes" => value,
+        "k" | "KiB" => value * 1024,
+        "KB" => value * 1000,
+        "M" | "MiB" => value * 1024 * 1024,
+        "MB" => value * 1000 * 1000,
+        "G" | "GiB" => value * 1024 * 1024 * 1024,
+        "GB" => value * 1000 * 1000 * 1000,
+        unit => return Err(anyhow!("unsupported libvirt memory unit {unit}")),
+    };
+
+    Ok(bytes / (1024 * 1024))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|c| c.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|c| c.text())
+}
+
+mod tests {
+    #[test]
+    fn import_libvirt_xml() {
+        use super::*;
+
+        let xml = r#"
+            <domain type='kvm'>
+              <name>fedora</name>
+              <memory unit='GiB'>4</memory>
+              <vcpu placement='static'>2</vcpu>
+              <os>
+                <type arch='aarch64'>hvm</type>
+                <nvram>/var/lib/libvirt/nvram/fedora_VARS.fd</nvram>
+              </os>
+              <devices>
+                <emulator>/usr/bin/qemu-system-aarch64</emulator>
+                <disk type='file' device='disk'>
+                  <driver name='qemu' type='qcow2'/>
+                  <source file='/var/lib/libvirt/images/fedora.qcow2'/>
+                  <target dev='vda' bus='virtio'/>
+                </disk>
+                <disk type='file' device='cdrom'>
+                  <source file='/tmp/seed.iso'/>
+                </disk>
+                <filesystem type='mount'>
+                  <source dir='/home/user/src'/>
+                  <target dir='src'/>
+                </filesystem>
+                <interface type='network'>
+                  <mac address='52:54:00:12:34:56'/>
+                </interface>
+                <rng model='virtio'/>
+              </devices>
+            </domain>"#;
+
+        let config = from_libvirt_xml(xml).unwrap();
+        assert_eq!(config.lines[0], "--cpus 2");
+        assert_eq!(config.lines[1], "--memory 4096");
+        assert_eq!(
+            config.lines[2],
+            "--bootloader efi,variable-store=/var/lib/libvirt/nvram/fedora_VARS.fd,create"
+        );
+        assert_eq!(
+            config.lines[3],
+            "--device virtio-blk,path=/var/lib/libvirt/images/fedora.qcow2,format=qcow2"
+        );
+        assert_eq!(config.lines[4], "# not imported: cdrom /tmp/seed.iso");
+        assert_eq!(
+            config.lines[5],
+            "--device virtio-fs,sharedDir=/home/user/src,mountTag=src"
+        );
+        assert!(config.lines[6].contains("mac=52:54:00:12:34:56"));
+        assert_eq!(config.lines[7], "--device virtio-rng");
+        assert_eq!(config.lines.len(), 8);
+    }
+}
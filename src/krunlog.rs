@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    os::fd::{FromRawFd, RawFd},
+    sync::{
+        mpsc::{self, Receiver},
+        Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+/// Number of lines libkrun logged most recently that are kept to explain its failures.
+const RECENT_LINES: usize = 10;
+
+/// Time given to the lines still in the pipe to be passed on when the capture stops.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Time given to the lines already written to the pipe to be read when syncing.
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Line written to the pipe to find out when the lines written before it have been read. It is not
+/// passed on.
+const SYNC_MARKER: &[u8] = b"\0krunkit-log-sync";
+
+/// Lines most recently written to standard error.
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Number of sync markers read from the pipe. Signalled through the condition variable.
+static SYNCED: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// Original standard error, and a receiver signalled once the pipe has been drained, while the
+/// log is captured.
+static CAPTURE: Mutex<Option<(RawFd, Receiver<()>)>> = Mutex::new(None);
+
+/// Capture libkrun's log, which it writes to standard error, keeping the most recent lines to
+/// explain libkrun's failures. Standard error is replaced with a pipe, and each line written to it
+/// is passed on to the original standard error.
+pub fn capture() -> Result<(), anyhow::Error> {
+    let mut capture = CAPTURE.lock().unwrap();
+    if capture.is_some() {
+        return Ok(());
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(anyhow!(
+            "unable to create libkrun log pipe: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let [read, write] = fds;
+
+    let original = unsafe { libc::dup(libc::STDERR_FILENO) };
+    if original < 0 || unsafe { libc::dup2(write, libc::STDERR_FILENO) } < 0 {
+        let e = std::io::Error::last_os_error();
+        unsafe {
+            libc::close(read);
+            libc::close(write);
+        }
+        return Err(anyhow!("unable to capture libkrun log: {e}"));
+    }
+    unsafe { libc::close(write) };
+
+    // Neither end of the pipe is inherited by helper processes.
+    unsafe {
+        libc::fcntl(read, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(original, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    let (drained, receiver) = mpsc::channel();
+    let mut output = unsafe { File::from_raw_fd(libc::fcntl(original, libc::F_DUPFD_CLOEXEC, 0)) };
+    let reader = BufReader::new(unsafe { File::from_raw_fd(read) });
+    thread::spawn(move || {
+        for line in reader.split(b'\n').map_while(Result::ok) {
+            if line == SYNC_MARKER {
+                let (synced, cvar) = &SYNCED;
+                *synced.lock().unwrap() += 1;
+                cvar.notify_all();
+                continue;
+            }
+
+            let _ = output
+                .write_all(&line)
+                .and_then(|_| output.write_all(b"\n"));
+
+            let mut recent = RECENT.lock().unwrap();
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+
+        let _ = drained.send(());
+    });
+
+    *capture = Some((original, receiver));
+
+    Ok(())
+}
+
+/// Restore the original standard error, waiting briefly for the lines still in the pipe to be
+/// passed on. Called before krunkit exits or is replaced to restart the VM.
+pub fn stop() {
+    let Some((original, drained)) = CAPTURE.lock().unwrap().take() else {
+        return;
+    };
+
+    unsafe {
+        libc::dup2(original, libc::STDERR_FILENO);
+        libc::close(original);
+    }
+
+    // The pipe may still be open in a child process, in which case it is never drained.
+    let _ = drained.recv_timeout(DRAIN_TIMEOUT);
+}
+
+/// Wait for the lines written to standard error so far to have been read, so that they are
+/// included in recent_lines().
+pub fn sync() {
+    if CAPTURE.lock().unwrap().is_none() {
+        return;
+    }
+
+    let (synced, cvar) = &SYNCED;
+    let target = *synced.lock().unwrap() + 1;
+
+    let mut marker = SYNC_MARKER.to_vec();
+    marker.push(b'\n');
+    if unsafe { libc::write(libc::STDERR_FILENO, marker.as_ptr().cast(), marker.len()) } < 0 {
+        return;
+    }
+
+    let guard = synced.lock().unwrap();
+    let _ = cvar.wait_timeout_while(guard, SYNC_TIMEOUT, |s| *s < target);
+}
+
+/// The lines libkrun (or anything else) most recently wrote to standard error.
+pub fn recent_lines() -> Vec<String> {
+    RECENT
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|l| !l.is_empty())
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::krunlog;
+
+use std::{
+    env,
+    ffi::{c_char, c_void, CStr, CString},
+    fmt, fs, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Environment variable overriding the path of the libkrun library to load.
+const LIBKRUN_ENV: &str = "KRUNKIT_LIBKRUN";
+
+/// File name of the libkrun library. krunkit requires the EFI variant of libkrun.
+#[cfg(target_os = "macos")]
+const LIBKRUN_NAME: &str = "libkrun-efi.dylib";
+#[cfg(not(target_os = "macos"))]
+const LIBKRUN_NAME: &str = "libkrun-efi.so";
+
+/// Directories libkrun is commonly installed to, searched if the dynamic linker cannot find it.
+/// Homebrew's prefix on Apple silicon is not searched by default.
+const LIBKRUN_DIRS: [&str; 2] = ["/opt/homebrew/lib", "/usr/local/lib"];
+
+/// The loaded libkrun library.
+static LIBKRUN: OnceLock<Libkrun> = OnceLock::new();
+
+/// Declare the libkrun functions krunkit calls. Required functions must be exported by the
+/// library for it to be loaded. Optional functions were added in later versions of libkrun, and
+/// are only needed by some features.
+macro_rules! libkrun_functions {
+    (
+        required { $($name:ident: fn($($arg:ty),*) -> $ret:ty;)* }
+        optional { $($opt_name:ident: fn($($opt_arg:ty),*) -> $opt_ret:ty;)* }
+    ) => {
+        /// The functions of the loaded libkrun library.
+        pub struct Libkrun {
+            /// Path of the library, with symbolic links resolved.
+            pub path: PathBuf,
+
+            $(pub $name: unsafe extern "C" fn($($arg),*) -> $ret,)*
+            $(pub $opt_name: Option<unsafe extern "C" fn($($opt_arg),*) -> $opt_ret>,)*
+        }
+
+        impl Libkrun {
+            /// Resolve each function from the library's handle.
+            unsafe fn resolve(handle: *mut c_void, path: PathBuf) -> Result<Self, anyhow::Error> {
+                Ok(Self {
+                    path: path.clone(),
+                    $($name: mem::transmute::<*mut c_void, unsafe extern "C" fn($($arg),*) -> $ret>(
+                        symbol(handle, stringify!($name)).ok_or(anyhow!(
+                            "libkrun at {} is too old: {} not found",
+                            path.display(),
+                            stringify!($name)
+                        ))?,
+                    ),)*
+                    $($opt_name: symbol(handle, stringify!($opt_name)).map(|s| {
+                        mem::transmute::<*mut c_void, unsafe extern "C" fn($($opt_arg),*) -> $opt_ret>(s)
+                    }),)*
+                })
+            }
+
+            /// Names of the optional functions, along with whether the library exports them.
+            pub fn optional_functions(&self) -> Vec<(&'static str, bool)> {
+                vec![$((stringify!($opt_name), self.$opt_name.is_some()),)*]
+            }
+        }
+    };
+}
+
+libkrun_functions! {
+    required {
+        krun_create_ctx: fn() -> i32;
+        krun_set_log_level: fn(u32) -> i32;
+        krun_set_vm_config: fn(u32, u8, u32) -> i32;
+        krun_start_enter: fn(u32) -> i32;
+        krun_get_shutdown_eventfd: fn(u32) -> i32;
+        krun_add_disk2: fn(u32, *const c_char, *const c_char, u32, bool) -> i32;
+        krun_add_vsock_port: fn(u32, u32, *const c_char) -> i32;
+        krun_add_virtiofs: fn(u32, *const c_char, *const c_char) -> i32;
+        krun_set_gvproxy_path: fn(u32, *const c_char) -> i32;
+        krun_set_net_mac: fn(u32, *const u8) -> i32;
+        krun_set_console_output: fn(u32, *const c_char) -> i32;
+    }
+    optional {
+        krun_add_vsock_port2: fn(u32, u32, *const c_char, bool) -> i32;
+        krun_set_gpu_options2: fn(u32, u32, u64) -> i32;
+        krun_set_smbios_oem_strings: fn(u32, *const *const c_char) -> i32;
+        krun_add_net_unixgram: fn(u32, *const c_char, i32, *const u8, u32, u32) -> i32;
+        krun_add_net_unixstream: fn(u32, *const c_char, i32, *const u8, u32, u32) -> i32;
+    }
+}
+
+impl Libkrun {
+    /// Return an optional function, or an error explaining that the loaded libkrun is too old for
+    /// the feature needing it.
+    pub fn require<F>(
+        &self,
+        function: Option<F>,
+        name: &str,
+        feature: &str,
+    ) -> Result<F, anyhow::Error> {
+        function.ok_or(anyhow!(
+            "libkrun at {} is too old for {feature} ({name} not found), please upgrade libkrun",
+            self.path.display()
+        ))
+    }
+}
+
+/// Check the return value of a libkrun function, which is a negated errno value on failure.
+/// Failures are explained, along with the last lines libkrun logged.
+pub fn check(ret: i32) -> Result<u32, anyhow::Error> {
+    if ret >= 0 {
+        return Ok(ret as u32);
+    }
+
+    let mut message = describe_error(ret);
+    krunlog::sync();
+    let log = krunlog::recent_lines();
+    if !log.is_empty() {
+        message = format!("{message}\nlast libkrun log lines:\n  {}", log.join("\n  "));
+    }
+
+    Err(anyhow!(message))
+}
+
+/// Explain a negated errno value returned by libkrun.
+pub fn describe_error(ret: i32) -> String {
+    let errno = ret.saturating_neg();
+    let explanation = match errno {
+        libc::EINVAL => "libkrun rejected the configuration as invalid",
+        libc::ENOENT => "a file, or the libkrun context, does not exist",
+        libc::EEXIST => "the resource is already configured",
+        libc::EBUSY => "the resource is in use",
+        libc::ENOMEM => "not enough memory",
+        libc::E2BIG => "too many values",
+        libc::EPERM | libc::EACCES => {
+            "permission denied (check file permissions, and that krunkit is signed with the hypervisor entitlement)"
+        }
+        libc::ENOTSUP | libc::ENOSYS => "not supported by this build of libkrun",
+        _ => "",
+    };
+    let error = std::io::Error::from_raw_os_error(errno);
+
+    match explanation {
+        "" => format!("libkrun error: {error}"),
+        explanation => format!("{explanation} (libkrun error {ret})"),
+    }
+}
+
+/// Architecture of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuestArch {
+    Aarch64,
+    X86_64,
+}
+
+impl GuestArch {
+    /// Architecture of the host.
+    pub fn host() -> Self {
+        match cfg!(target_arch = "x86_64") {
+            true => Self::X86_64,
+            false => Self::Aarch64,
+        }
+    }
+}
+
+impl FromStr for GuestArch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aarch64" | "arm64" => Ok(Self::Aarch64),
+            "x86_64" | "amd64" => Ok(Self::X86_64),
+            _ => Err(anyhow!("invalid architecture (expected aarch64 or x86_64)")),
+        }
+    }
+}
+
+impl fmt::Display for GuestArch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Aarch64 => write!(f, "aarch64"),
+            Self::X86_64 => write!(f, "x86_64"),
+        }
+    }
+}
+
+impl Serialize for GuestArch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Libkrun {
+    /// Check that the library can run guests of the given architecture. libkrun runs guests with
+    /// the host's hypervisor, and has no emulated (TCG) backend, so that only guests of the
+    /// host's architecture are supported, booted with the EFI firmware bundled in libkrun-efi.
+    pub fn check_arch(&self, arch: GuestArch) -> Result<(), anyhow::Error> {
+        if arch == GuestArch::host() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "libkrun at {} cannot run {arch} guests: only {} guests are supported, as libkrun has no emulated backend",
+            self.path.display(),
+            GuestArch::host()
+        ))
+    }
+}
+
+/// Load libkrun, if not already loaded. The library is searched for next to krunkit (in ../lib),
+/// by the dynamic linker, and then in common installation directories, unless its path is given
+/// with the KRUNKIT_LIBKRUN environment variable.
+pub fn load() -> Result<&'static Libkrun, anyhow::Error> {
+    if let Some(libkrun) = LIBKRUN.get() {
+        return Ok(libkrun);
+    }
+
+    let candidates: Vec<PathBuf> = match env::var_os(LIBKRUN_ENV) {
+        Some(path) => vec![PathBuf::from(path)],
+        None => {
+            let bundled = env::current_exe()
+                .ok()
+                .and_then(|exe| Some(exe.parent()?.join("../lib").join(LIBKRUN_NAME)));
+
+            bundled
+                .into_iter()
+                .chain([PathBuf::from(LIBKRUN_NAME)])
+                .chain(
+                    LIBKRUN_DIRS
+                        .iter()
+                        .map(|d| PathBuf::from(d).join(LIBKRUN_NAME)),
+                )
+                .collect()
+        }
+    };
+
+    let mut errors = Vec::new();
+    for candidate in candidates {
+        match open(&candidate) {
+            Ok(libkrun) => return Ok(LIBKRUN.get_or_init(|| libkrun)),
+            Err(e) => errors.push(format!("{e:#}")),
+        }
+    }
+
+    Err(anyhow!("unable to load libkrun:\n{}", errors.join("\n")))
+}
+
+/// The loaded libkrun library. libkrun must have been loaded with load() first.
+pub fn libkrun() -> &'static Libkrun {
+    LIBKRUN.get().expect("libkrun not loaded")
+}
+
+/// Open a candidate libkrun library and resolve its functions.
+fn open(path: &Path) -> Result<Libkrun, anyhow::Error> {
+    let c_path = CString::new(path.as_os_str().as_encoded_bytes())
+        .context(format!("invalid libkrun path {}", path.display()))?;
+
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        let error = unsafe { libc::dlerror() };
+        let message = match error.is_null() {
+            true => String::from("unknown error"),
+            false => unsafe { CStr::from_ptr(error) }
+                .to_string_lossy()
+                .to_string(),
+        };
+        return Err(anyhow!("{message}"));
+    }
+
+    // The library is never unloaded.
+    let resolved = unsafe { symbol(handle, "krun_create_ctx") }
+        .and_then(library_path)
+        .unwrap_or_else(|| path.to_path_buf());
+
+    unsafe { Libkrun::resolve(handle, resolved) }
+}
+
+/// Look up a function of the library.
+unsafe fn symbol(handle: *mut c_void, name: &str) -> Option<*mut c_void> {
+    let name = CString::new(name).ok()?;
+    let symbol = libc::dlsym(handle, name.as_ptr());
+
+    (!symbol.is_null()).then_some(symbol)
+}
+
+/// Path of the file a symbol was loaded from, with symbolic links resolved. libkrun does not
+/// report its version, but it is part of the file name of the installed library.
+fn library_path(symbol: *mut c_void) -> Option<PathBuf> {
+    let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+    if unsafe { libc::dladdr(symbol, &mut info) } == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+
+    let path = PathBuf::from(
+        unsafe { CStr::from_ptr(info.dli_fname) }
+            .to_string_lossy()
+            .to_string(),
+    );
+
+    Some(fs::canonicalize(&path).unwrap_or(path))
+}
+
+mod tests {
+    #[test]
+    fn guest_arch_parse() {
+        use super::*;
+
+        assert_eq!(GuestArch::from_str("arm64").unwrap(), GuestArch::Aarch64);
+        assert_eq!(GuestArch::from_str("amd64").unwrap(), GuestArch::X86_64);
+        assert_eq!(GuestArch::X86_64.to_string(), "x86_64");
+        assert!(GuestArch::from_str("riscv64").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    events::EventKind,
+    vm::{VmHandle, GUEST_SHUTDOWN_TIMEOUT},
+};
+
+use std::{
+    mem,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Interval at which the CPU usage of the VM is sampled to judge whether it is idle.
+const IDLE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The VM is considered idle while its vCPUs use less than this share of a single host CPU.
+const IDLE_CPU_THRESHOLD: f64 = 0.05;
+
+/// Shut the VM down gracefully once it has been running for the given duration.
+pub fn max_runtime_monitor(vm: Arc<VmHandle>, max_runtime: Duration) {
+    thread::spawn(move || {
+        if vm.wait_exited(max_runtime) {
+            return;
+        }
+
+        stop(
+            &vm,
+            format!("maximum runtime of {}s reached", max_runtime.as_secs()),
+        );
+    });
+}
+
+/// Shut the VM down gracefully once it has been idle for the given duration. Idleness is judged
+/// by the host CPU time consumed by krunkit, which is almost entirely spent running vCPUs.
+pub fn idle_monitor(vm: Arc<VmHandle>, idle_timeout: Duration) {
+    thread::spawn(move || {
+        let mut idle_since: Option<Instant> = None;
+        let mut last = cpu_time();
+
+        while !vm.wait_exited(IDLE_SAMPLE_INTERVAL) {
+            let now = cpu_time();
+            let usage = (now - last).as_secs_f64() / IDLE_SAMPLE_INTERVAL.as_secs_f64();
+            last = now;
+
+            if usage >= IDLE_CPU_THRESHOLD {
+                idle_since = None;
+                continue;
+            }
+
+            let since = idle_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= idle_timeout {
+                stop(
+                    &vm,
+                    format!("VM idle for more than {}s", idle_timeout.as_secs()),
+                );
+                return;
+            }
+        }
+    });
+}
+
+fn stop(vm: &VmHandle, reason: String) {
+    vm.events
+        .publish(EventKind::Stopping, format!("{reason}, shutting down VM"));
+
+    if let Err(e) = vm.stop_gracefully(GUEST_SHUTDOWN_TIMEOUT) {
+        println!("Error shutting down VM: {e}");
+    }
+}
+
+/// User and system CPU time consumed by the krunkit process.
+pub fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+
+    let timeval = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+
+    timeval(usage.ru_utime) + timeval(usage.ru_stime)
+}
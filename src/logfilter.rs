@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{env, fmt, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Serialize, Serializer};
+
+/// Levels of env_logger directives, in the order of libkrun's log levels (0=off to 5=trace).
+const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// libkrun log level from which informational messages are logged.
+pub const LOG_LEVEL_INFO: u32 = 3;
+
+/// A per-module log filter for libkrun, in env_logger's directive syntax (for example,
+/// "devices::virtio::net=trace,vmm=warn"). A directive without a module sets the level of all
+/// modules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    directives: Vec<(Option<String>, usize)>,
+}
+
+impl LogFilter {
+    /// Have libkrun filter its logs with this filter. libkrun's logger reads its filter from the
+    /// RUST_LOG environment variable, only falling back to the level set with
+    /// krun_set_log_level() if unset, so this must be called before that. Modules without a
+    /// directive are logged at the given libkrun log level, unless the filter sets a level for
+    /// all modules.
+    pub fn apply(&self, default_level: u32) {
+        let mut filter = self.to_string();
+        if self.directives.iter().all(|(module, _)| module.is_some()) {
+            let default = LEVELS[(default_level as usize).min(LEVELS.len() - 1)];
+            filter = format!("{default},{filter}");
+        }
+
+        env::set_var("RUST_LOG", filter);
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut directives = Vec::new();
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.split_once('=') {
+                Some((module, level)) => (Some(module.trim().to_string()), level.trim()),
+                None => (None, directive),
+            };
+
+            if module.as_ref().is_some_and(|m| m.is_empty()) {
+                return Err(anyhow!(
+                    "missing module in log filter directive: {directive}"
+                ));
+            }
+
+            let level = LEVELS
+                .iter()
+                .position(|l| l.eq_ignore_ascii_case(level))
+                .ok_or_else(|| anyhow!("invalid log level in log filter directive: {directive}"))?;
+
+            directives.push((module, level));
+        }
+
+        if directives.is_empty() {
+            return Err(anyhow!("empty log filter"));
+        }
+
+        Ok(Self { directives })
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let directives: Vec<String> = self
+            .directives
+            .iter()
+            .map(|(module, level)| match module {
+                Some(module) => format!("{module}={}", LEVELS[*level]),
+                None => LEVELS[*level].to_string(),
+            })
+            .collect();
+
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+impl Serialize for LogFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+mod tests {
+    #[test]
+    fn log_filter_parse() {
+        use super::*;
+
+        let filter = LogFilter::from_str("devices::virtio::net=TRACE, vmm=warn,error").unwrap();
+        assert_eq!(
+            filter.to_string(),
+            "devices::virtio::net=trace,vmm=warn,error"
+        );
+
+        filter.apply(2);
+        assert_eq!(
+            env::var("RUST_LOG").unwrap(),
+            "devices::virtio::net=trace,vmm=warn,error"
+        );
+
+        LogFilter::from_str("vmm=info").unwrap().apply(9);
+        assert_eq!(env::var("RUST_LOG").unwrap(), "trace,vmm=info");
+
+        assert!(LogFilter::from_str("").is_err());
+        assert!(LogFilter::from_str("vmm=loud").is_err());
+        assert!(LogFilter::from_str("=warn").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    events::EventKind,
+    priority::{self, BackgroundReason},
+    vm::VmHandle,
+};
+
+use std::{str::FromStr, sync::Arc, thread, time::Duration};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+/// Interval at which the host's Low Power Mode is checked.
+const LOW_POWER_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Behavior of krunkit while the host is in Low Power Mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LowPowerPolicy {
+    /// Leave the VM as is. Changes of Low Power Mode are still published as events.
+    #[default]
+    Ignore,
+
+    /// Run krunkit, including the vCPUs, in the background priority band.
+    Background,
+}
+
+impl FromStr for LowPowerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(Self::Ignore),
+            "background" => Ok(Self::Background),
+            _ => Err(anyhow!("invalid --low-power-policy option: {s}")),
+        }
+    }
+}
+
+/// Indicate if the host is in Low Power Mode, if reported.
+pub fn low_power_mode() -> Option<bool> {
+    platform::low_power_mode()
+}
+
+/// Publish changes of the host's Low Power Mode as events, applying the policy while it is active
+/// and restoring normal settings once it ends.
+pub fn low_power_monitor(vm: Arc<VmHandle>, policy: LowPowerPolicy) {
+    thread::spawn(move || {
+        let mut active = false;
+
+        loop {
+            let enabled = low_power_mode().unwrap_or(false);
+            if enabled != active {
+                let message = match enabled {
+                    true => "host entered Low Power Mode",
+                    false => "host left Low Power Mode",
+                };
+                vm.events.publish(EventKind::LowPowerMode, message);
+
+                if policy == LowPowerPolicy::Background {
+                    if let Err(e) =
+                        priority::set_background(BackgroundReason::LowPowerMode, enabled)
+                    {
+                        println!("Unable to change VM priority: {e}");
+                    }
+                }
+
+                active = enabled;
+            }
+
+            thread::sleep(LOW_POWER_POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{
+        ffi::{c_char, c_void},
+        mem,
+    };
+
+    type Id = *mut c_void;
+    type Sel = *mut c_void;
+
+    #[link(name = "Foundation", kind = "framework")]
+    extern "C" {}
+
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const c_char) -> Id;
+        fn sel_registerName(name: *const c_char) -> Sel;
+        fn objc_msgSend();
+    }
+
+    /// NSProcessInfo.isLowPowerModeEnabled, available from macOS 12.
+    pub fn low_power_mode() -> Option<bool> {
+        unsafe {
+            let class = objc_getClass(c"NSProcessInfo".as_ptr());
+            if class.is_null() {
+                return None;
+            }
+
+            let get: extern "C" fn(Id, Sel) -> Id =
+                mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            let process_info = get(class, sel_registerName(c"processInfo".as_ptr()));
+
+            let selector = sel_registerName(c"isLowPowerModeEnabled".as_ptr());
+            let responds: extern "C" fn(Id, Sel, Sel) -> bool =
+                mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            if !responds(
+                process_info,
+                sel_registerName(c"respondsToSelector:".as_ptr()),
+                selector,
+            ) {
+                return None;
+            }
+
+            let enabled: extern "C" fn(Id, Sel) -> bool =
+                mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            Some(enabled(process_info, selector))
+        }
+    }
+}
+
+/// Other hosts report a low power platform profile (as set by power-profiles-daemon).
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use std::fs;
+
+    const PLATFORM_PROFILE: &str = "/sys/firmware/acpi/platform_profile";
+
+    pub fn low_power_mode() -> Option<bool> {
+        fs::read_to_string(PLATFORM_PROFILE)
+            .ok()
+            .map(|profile| profile.trim() == "low-power")
+    }
+}
@@ -2,24 +2,144 @@
 
 #![allow(dead_code)]
 
+mod activation;
+mod agent;
+mod boot;
+mod caffeinate;
+mod capabilities;
+mod cleanup;
 mod cmdline;
+mod config;
+mod connection;
+mod console;
 mod context;
+mod copy;
+mod crash;
+mod daemon;
+mod diagnose;
+mod events;
+mod exec;
+mod grow;
+mod health;
+mod helper;
+mod hook;
+mod hostpower;
+mod ignition;
+mod image;
+mod import;
+mod krunlog;
+mod libkrun;
+mod limits;
+mod logfilter;
+mod lowpower;
+mod network;
+mod notify;
+mod oem;
+mod operation;
+mod otel;
+mod preflight;
+mod priority;
+mod privsep;
+mod qcow2;
+mod quota;
+mod sandbox;
+mod secret;
+mod shares;
+mod signal;
+mod snapshot;
+mod state;
+mod statedir;
+mod stats;
 mod status;
+mod thermal;
+mod timesync;
 mod virtio;
+mod vm;
+mod websocket;
 
-use cmdline::Args;
+use cmdline::{Args, Command, CommandArgs};
+use config::VmConfig;
 use context::KrunContext;
 
-use clap::Parser;
+use std::{env, process};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 
 fn main() -> Result<(), anyhow::Error> {
+    // Subcommands are parsed on their own, as the VM arguments are required otherwise.
+    if env::args()
+        .nth(1)
+        .is_some_and(|arg| Command::has_subcommand(&arg))
+    {
+        return match CommandArgs::parse().command {
+            Command::Capabilities(args) => capabilities::capabilities(&args),
+            Command::Cp(args) => copy::cp(&args),
+            Command::Diagnose(args) => diagnose::diagnose(&args),
+            Command::Exec(args) => process::exit(exec::exec(&args)?),
+            Command::Image(args) => image::image(&args),
+            Command::Import(args) => import::import(&args),
+            Command::RestfulProxy(args) => privsep::restful_proxy(&args),
+        };
+    }
+
+    boot::start();
+    let mut args = Args::parse_from(cmdline::expand_config_file(env::args_os().collect())?);
+    statedir::resolve(&mut args)?;
+    helper::configure_dns(&mut args.helpers, &args.dns, &args.dns_search)?;
+
+    // Print the resolved configuration without configuring the workload, if requested. Nothing
+    // else may be written to stdout, so that the output is valid JSON.
+    if args.print_config {
+        let config = serde_json::to_string_pretty(&VmConfig::from(&args))
+            .context("unable to serialize VM configuration")?;
+        println!("{config}");
+
+        return Ok(());
+    }
+    boot::mark("argsParsed");
+
+    // Release the host resources created for the VM if krunkit exits on its own, such as when the
+    // VM could not be started. Stopping the VM releases them before libkrun exits the process.
+    cleanup::install_panic_hook();
+    let result = run(args);
+    krunlog::stop();
+    cleanup::run();
+    otel::flush();
+
+    process::exit(result?)
+}
+
+/// Configure and run the VM, returning the exit status of krunkit.
+fn run(args: Args) -> Result<i32, anyhow::Error> {
+    // Shutdown signals are handled by a dedicated thread once the VM is running. They must be
+    // blocked before any other thread is created.
+    signal::block_shutdown_signals()?;
+
+    // Detach from the terminal if requested. Only the daemonized child process returns.
+    let daemon = if args.daemonize {
+        daemon::daemonize(args.log_file.as_deref())?
+    } else {
+        None
+    };
+
+    // The exporter thread must inherit the blocked shutdown signals, and run in the daemonized
+    // child process.
+    if let Some(endpoint) = &args.otel_endpoint {
+        otel::init(endpoint.clone());
+    }
+
+    statedir::create()?;
+
+    // Keep the last lines libkrun logs, to explain its failures.
+    krunlog::capture()?;
+
     // Gather the krun context from the command line arguments and configure the workload
     // accordingly.
-    let ctx = KrunContext::try_from(Args::parse())?;
+    let ctx = KrunContext::try_from(args)?;
 
-    // Run the workload. If behaving properly, the main thread will not return from this
-    // function.
-    ctx.run()?;
+    // Run the workload. Once the VM exits, exit with a status indicating why.
+    let reason = ctx.run(daemon)?;
 
-    Ok(())
+    Ok(reason.exit_code())
 }
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{events::EventKind, vm::VmHandle};
+
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+};
+
+/// Check that the backend of each virtio-net device (such as gvproxy) still accepts packets on its
+/// socket, as backends are frequently found dead after the host sleeps. A dead backend run as a
+/// helper is restarted. Backends that cannot be re-established are reported as events.
+pub fn revalidate_backends(vm: &VmHandle, sockets: &[PathBuf]) {
+    for socket in sockets {
+        let Err(e) = probe(socket) else {
+            continue;
+        };
+
+        let Some(name) = vm.helpers.serving(socket) else {
+            vm.events.publish(
+                EventKind::NetBackendFailed,
+                format!("network backend {} not responding: {e}", socket.display()),
+            );
+            continue;
+        };
+
+        println!(
+            "Network backend {} not responding ({e}), restarting helper {name}",
+            socket.display()
+        );
+        let result = vm
+            .helpers
+            .restart(&name)
+            .and_then(|()| probe(socket).map_err(anyhow::Error::from));
+
+        match result {
+            Ok(()) => vm.events.publish(
+                EventKind::NetBackendRestarted,
+                format!(
+                    "network backend {} restarted (helper {name})",
+                    socket.display()
+                ),
+            ),
+            Err(e) => vm.events.publish(
+                EventKind::NetBackendFailed,
+                format!(
+                    "unable to re-establish network backend {} (helper {name}): {e:#}",
+                    socket.display()
+                ),
+            ),
+        }
+    }
+}
+
+/// Check that a socket is bound at the path, without sending it anything. Connecting a datagram
+/// socket fails if no socket is bound to the path.
+fn probe(socket: &Path) -> io::Result<()> {
+    UnixDatagram::unbound()?.connect(socket)
+}
+
+mod tests {
+    #[test]
+    fn network_probe() {
+        use super::*;
+
+        let path = std::env::temp_dir().join(format!("krunkit-probe-{}.sock", std::process::id()));
+        assert!(probe(&path).is_err());
+
+        let socket = UnixDatagram::bind(&path).unwrap();
+        assert!(probe(&path).is_ok());
+
+        drop(socket);
+        assert!(probe(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::vm;
+
+use std::{
+    fs::File,
+    io::Write,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::PathBuf,
+    process,
+};
+
+use anyhow::{anyhow, Context};
+
+/// Means of notifying an orchestrator that the VM is configured and about to run.
+#[derive(Clone, Debug, Default)]
+pub struct ReadyNotify {
+    /// Path of a UNIX datagram socket to send an sd_notify(3) READY message to.
+    pub socket: Option<PathBuf>,
+
+    /// File descriptor (inherited from the parent process) to write a READY message to. The file
+    /// descriptor is closed once written to.
+    pub fd: Option<RawFd>,
+}
+
+impl ReadyNotify {
+    /// Send the READY message to each configured destination.
+    pub fn notify(&self) -> Result<(), anyhow::Error> {
+        if let Some(path) = &self.socket {
+            let msg = format!("READY=1\nMAINPID={}\n", process::id());
+
+            let socket = UnixDatagram::unbound().context("unable to create notify socket")?;
+            socket
+                .send_to(msg.as_bytes(), path)
+                .context(format!("unable to notify socket {}", path.display()))?;
+        }
+
+        // The file descriptor was already written to and closed before the VM was restarted.
+        if let Some(fd) = self.fd.filter(|_| !vm::restarted()) {
+            if fd < 0 {
+                return Err(anyhow!("invalid ready file descriptor {fd}"));
+            }
+
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            file.write_all(b"READY=1\n")
+                .context(format!("unable to write to ready file descriptor {fd}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{fmt, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Prefix added to the keys of key=value OEM strings that are not already namespaced.
+const KEY_PREFIX: &str = "io.containers.";
+
+/// Maximum combined size of the OEM strings, including their NUL terminators. The SMBIOS
+/// structure table is limited to 64 KiB, and the other structures libkrun provides need the rest.
+const MAX_OEM_STRINGS_SIZE: usize = 63 * 1024;
+
+/// Where the value of an OEM string (or of its key=value pair) is taken from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OemValue {
+    Inline(String),
+
+    /// Contents of a file (@path), without a trailing newline.
+    File(PathBuf),
+}
+
+impl OemValue {
+    fn parse(s: &str) -> Self {
+        match s.strip_prefix('@') {
+            Some(path) if !path.is_empty() => Self::File(PathBuf::from(path)),
+            _ => Self::Inline(s.to_string()),
+        }
+    }
+
+    fn read(&self) -> Result<String, anyhow::Error> {
+        let path = match self {
+            Self::Inline(value) => return Ok(value.clone()),
+            Self::File(path) => path,
+        };
+
+        let value = fs::read_to_string(path)
+            .context(format!("unable to read OEM string from {}", path.display()))?;
+
+        Ok(value.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl fmt::Display for OemValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inline(value) => write!(f, "{value}"),
+            Self::File(path) => write!(f, "@{}", path.display()),
+        }
+    }
+}
+
+/// An SMBIOS OEM string passed to the guest, given as the string itself, a key=value pair, or
+/// either of them read from a file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OemString {
+    /// The whole string (or @path of a file containing it).
+    Value(OemValue),
+
+    /// A key=value pair. Keys without a namespace (a dot or a colon) are prefixed with
+    /// io.containers., so that podman=... is passed as io.containers.podman=....
+    Pair { key: String, value: OemValue },
+}
+
+impl FromStr for OemString {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(anyhow!("OEM strings cannot be empty"));
+        }
+
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !key.starts_with('@') => Ok(Self::Pair {
+                key: key.to_string(),
+                value: OemValue::parse(value),
+            }),
+            _ => Ok(Self::Value(OemValue::parse(s))),
+        }
+    }
+}
+
+impl fmt::Display for OemString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{value}"),
+            Self::Pair { key, value } => write!(f, "{key}={value}"),
+        }
+    }
+}
+
+/// OEM strings read from files are reported by path, not by value.
+impl Serialize for OemString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl OemString {
+    /// The key, with its io.containers. prefix if it is not namespaced.
+    fn full_key(key: &str) -> String {
+        match key.contains(['.', ':']) {
+            true => key.to_string(),
+            false => format!("{KEY_PREFIX}{key}"),
+        }
+    }
+
+    /// The string passed to the guest, reading it from its file if needed.
+    pub fn resolve(&self) -> Result<String, anyhow::Error> {
+        let string = match self {
+            Self::Value(value) => value.read()?,
+            Self::Pair { key, value } => format!("{}={}", Self::full_key(key), value.read()?),
+        };
+
+        if string.is_empty() {
+            return Err(anyhow!("OEM string {self} is empty"));
+        }
+        if string.contains('\0') {
+            return Err(anyhow!(
+                "OEM string {self} contains a NUL byte (use --secret to pass binary values)"
+            ));
+        }
+
+        Ok(string)
+    }
+}
+
+/// Check that the OEM strings fit in the SMBIOS tables.
+pub fn check_size(oem_strings: &[String]) -> Result<(), anyhow::Error> {
+    if oem_strings.len() > u8::MAX as usize {
+        return Err(anyhow!(
+            "too many SMBIOS OEM strings ({}, at most {})",
+            oem_strings.len(),
+            u8::MAX
+        ));
+    }
+
+    let size: usize = oem_strings.iter().map(|s| s.len() + 1).sum();
+    if size > MAX_OEM_STRINGS_SIZE {
+        return Err(anyhow!(
+            "SMBIOS OEM strings (including secrets) total {size} bytes, more than the {MAX_OEM_STRINGS_SIZE} bytes available"
+        ));
+    }
+
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn oem_string_parse() {
+        use super::*;
+
+        let oem = OemString::from_str("podman=machine").unwrap();
+        assert_eq!(oem.resolve().unwrap(), "io.containers.podman=machine");
+
+        let oem = OemString::from_str("io.systemd.credential:user=core").unwrap();
+        assert_eq!(oem.resolve().unwrap(), "io.systemd.credential:user=core");
+
+        let oem = OemString::from_str("ignition=@/tmp/ignition.b64").unwrap();
+        assert_eq!(
+            oem,
+            OemString::Pair {
+                key: String::from("ignition"),
+                value: OemValue::File(PathBuf::from("/tmp/ignition.b64")),
+            }
+        );
+        assert_eq!(oem.to_string(), "ignition=@/tmp/ignition.b64");
+
+        assert_eq!(
+            OemString::from_str("@/tmp/oem.txt").unwrap(),
+            OemString::Value(OemValue::File(PathBuf::from("/tmp/oem.txt")))
+        );
+        assert_eq!(
+            OemString::from_str("plain string")
+                .unwrap()
+                .resolve()
+                .unwrap(),
+            "plain string"
+        );
+        assert!(OemString::from_str("").is_err());
+
+        assert!(check_size(&vec![String::from("a"); 255]).is_ok());
+        assert!(check_size(&vec![String::from("a"); 256]).is_err());
+        assert!(check_size(&[String::from("a").repeat(MAX_OEM_STRINGS_SIZE)]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Maximum number of operations kept. Once reached, the oldest finished operation is dropped
+/// when a new one is started.
+const OPERATIONS_MAX: usize = 100;
+
+/// Status of a long-running operation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A long-running operation requested through the restful service. Slow actions are run on their
+/// own thread, so that the client can be answered immediately and poll for the result.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Operation {
+    pub id: u64,
+
+    /// The action being performed (for example, "shutdown").
+    pub kind: String,
+
+    pub status: OperationStatus,
+
+    /// Description of the outcome. For failed operations, this is the error.
+    pub message: Option<String>,
+
+    /// Seconds since the UNIX epoch at which the operation started.
+    pub started: u64,
+
+    /// Seconds since the UNIX epoch at which the operation finished.
+    pub finished: Option<u64>,
+}
+
+/// Registry of all operations started.
+#[derive(Debug, Default)]
+pub struct Operations {
+    inner: Mutex<OperationsInner>,
+}
+
+#[derive(Debug, Default)]
+struct OperationsInner {
+    next_id: u64,
+    operations: VecDeque<Operation>,
+}
+
+impl Operations {
+    /// Run an action on a new thread, returning the operation tracking it. On success, the action
+    /// returns a description of its outcome.
+    pub fn start<F>(self: &Arc<Self>, kind: &str, action: F) -> Operation
+    where
+        F: FnOnce() -> Result<String, anyhow::Error> + Send + 'static,
+    {
+        let operation = {
+            let mut inner = self.inner.lock().unwrap();
+
+            inner.next_id += 1;
+            let operation = Operation {
+                id: inner.next_id,
+                kind: kind.to_string(),
+                status: OperationStatus::Running,
+                message: None,
+                started: now(),
+                finished: None,
+            };
+
+            if inner.operations.len() == OPERATIONS_MAX {
+                if let Some(idx) = inner
+                    .operations
+                    .iter()
+                    .position(|o| o.status != OperationStatus::Running)
+                {
+                    inner.operations.remove(idx);
+                }
+            }
+            inner.operations.push_back(operation.clone());
+
+            operation
+        };
+
+        let operations = self.clone();
+        let id = operation.id;
+        thread::spawn(move || {
+            let (status, message) = match action() {
+                Ok(message) => (OperationStatus::Succeeded, message),
+                Err(e) => (OperationStatus::Failed, format!("{e:#}")),
+            };
+
+            let mut inner = operations.inner.lock().unwrap();
+            if let Some(operation) = inner.operations.iter_mut().find(|o| o.id == id) {
+                operation.status = status;
+                operation.message = Some(message);
+                operation.finished = Some(now());
+            }
+        });
+
+        operation
+    }
+
+    /// Retrieve an operation by its ID.
+    pub fn get(&self, id: u64) -> Option<Operation> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.operations.iter().find(|o| o.id == id).cloned()
+    }
+
+    /// Retrieve all operations, oldest first.
+    pub fn list(&self) -> Vec<Operation> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.operations.iter().cloned().collect()
+    }
+}
+
+/// Seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+mod tests {
+    #[test]
+    fn operation_lifecycle() {
+        use super::*;
+
+        use std::time::Duration;
+
+        let operations = Arc::new(Operations::default());
+
+        let ok = operations.start("ok", || Ok(String::from("done")));
+        let failed = operations.start("failed", || Err(anyhow::anyhow!("broken")));
+        assert_eq!(ok.id, 1);
+        assert_eq!(failed.id, 2);
+
+        for _ in 0..100 {
+            if operations
+                .list()
+                .iter()
+                .all(|o| o.status != OperationStatus::Running)
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let ok = operations.get(1).unwrap();
+        assert_eq!(ok.status, OperationStatus::Succeeded);
+        assert_eq!(ok.message.as_deref(), Some("done"));
+
+        let failed = operations.get(2).unwrap();
+        assert_eq!(failed.status, OperationStatus::Failed);
+        assert_eq!(failed.message.as_deref(), Some("broken"));
+
+        assert!(operations.get(3).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::boot::BootPhase;
+
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Write},
+    net::TcpStream,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+/// Spans are exported in batches, at most this long after they end.
+const OTEL_EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time given to the collector to accept an export, and to pending spans to be exported when
+/// krunkit exits.
+const OTEL_EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Exporter of the spans recorded by krunkit, if an OpenTelemetry endpoint is configured.
+static EXPORTER: OnceLock<Mutex<Sender<Message>>> = OnceLock::new();
+
+/// Address of an OpenTelemetry collector accepting OTLP over HTTP with JSON encoding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtelEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for OtelEndpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(address) = s.strip_prefix("http://") else {
+            return Err(anyhow!(
+                "invalid OpenTelemetry endpoint {s}: only http:// is supported"
+            ));
+        };
+        let address = address.trim_end_matches('/');
+
+        let (host, port) = match address.strip_prefix('[') {
+            Some(address) => match address.split_once(']') {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => return Err(anyhow!("invalid OpenTelemetry endpoint {s}")),
+            },
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+
+        // Default to the port of OTLP over HTTP.
+        let port = port
+            .map(u16::from_str)
+            .transpose()
+            .context("OpenTelemetry endpoint port invalid")?
+            .unwrap_or(4318);
+
+        if host.is_empty() || host.contains('/') {
+            return Err(anyhow!("invalid OpenTelemetry endpoint {s}"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for OtelEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "http://[{}]:{}", self.host, self.port),
+            false => write!(f, "http://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Serialize for OtelEndpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Kind of span, as defined by OTLP.
+#[derive(Clone, Copy, Debug)]
+enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+/// A finished span.
+#[derive(Clone, Debug)]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, String)>,
+    error: bool,
+}
+
+impl Span {
+    /// OTLP/JSON representation of the span.
+    fn to_json(&self) -> Value {
+        let nanos = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+                .to_string()
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": attributes,
+            // STATUS_CODE_ERROR or STATUS_CODE_UNSET.
+            "status": { "code": if self.error { 2 } else { 0 } },
+        })
+    }
+}
+
+enum Message {
+    Span(Span),
+    Flush(Sender<()>),
+}
+
+/// Start exporting spans to the collector at the given endpoint.
+pub fn init(endpoint: OtelEndpoint) {
+    let (sender, receiver) = mpsc::channel();
+    if EXPORTER.set(Mutex::new(sender)).is_err() {
+        return;
+    }
+
+    thread::spawn(move || export_loop(endpoint, receiver));
+}
+
+/// Export the spans of krunkit's startup, from the boot phases reached so far: a root span
+/// covering the whole startup, with a child span for each phase.
+pub fn export_startup(phases: &[BootPhase]) {
+    if EXPORTER.get().is_none() {
+        return;
+    }
+
+    let now = SystemTime::now();
+    let last_ms = phases.last().map(|p| p.since_start_ms).unwrap_or(0);
+    let start = now - Duration::from_millis(last_ms);
+
+    let trace_id = random_id(16);
+    let root_id = random_id(8);
+    for phase in phases {
+        // Phases of the same kind (such as each device being configured) share a name, and are
+        // distinguished by an attribute.
+        let (name, attributes) = match phase.name.split_once(':') {
+            Some((name, id)) => (name, vec![(String::from("krunkit.id"), id.to_string())]),
+            None => (phase.name.as_str(), Vec::new()),
+        };
+
+        record(Span {
+            trace_id: trace_id.clone(),
+            span_id: random_id(8),
+            parent_span_id: Some(root_id.clone()),
+            name: name.to_string(),
+            kind: SpanKind::Internal,
+            start: start + Duration::from_millis(phase.since_start_ms - phase.duration_ms),
+            end: start + Duration::from_millis(phase.since_start_ms),
+            attributes,
+            error: false,
+        });
+    }
+
+    record(Span {
+        trace_id,
+        span_id: root_id,
+        parent_span_id: None,
+        name: String::from("vm.startup"),
+        kind: SpanKind::Internal,
+        start,
+        end: now,
+        attributes: Vec::new(),
+        error: false,
+    });
+}
+
+/// Export the span of a request handled by the restful service, given the response sent.
+pub fn export_request(method: &str, path: &str, response: &str, start: SystemTime) {
+    if EXPORTER.get().is_none() {
+        return;
+    }
+
+    // The status code follows the protocol version in the status line.
+    let status = response.split_whitespace().nth(1).unwrap_or("").to_string();
+
+    record(Span {
+        trace_id: random_id(16),
+        span_id: random_id(8),
+        parent_span_id: None,
+        name: format!("{method} {path}"),
+        kind: SpanKind::Server,
+        start,
+        end: SystemTime::now(),
+        error: status.starts_with('5'),
+        attributes: vec![
+            (String::from("http.request.method"), method.to_string()),
+            (String::from("url.path"), path.to_string()),
+            (String::from("http.response.status_code"), status),
+        ],
+    });
+}
+
+/// Wait for the spans recorded so far to be exported.
+pub fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    if exporter
+        .lock()
+        .unwrap()
+        .send(Message::Flush(sender))
+        .is_ok()
+    {
+        let _ = receiver.recv_timeout(OTEL_EXPORT_TIMEOUT);
+    }
+}
+
+fn record(span: Span) {
+    if let Some(exporter) = EXPORTER.get() {
+        let _ = exporter.lock().unwrap().send(Message::Span(span));
+    }
+}
+
+/// Export spans in batches until krunkit exits.
+fn export_loop(endpoint: OtelEndpoint, receiver: Receiver<Message>) {
+    let mut batch = Vec::new();
+    let mut reported = false;
+
+    loop {
+        let flushed = match receiver.recv_timeout(OTEL_EXPORT_INTERVAL) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                continue;
+            }
+            Ok(Message::Flush(ack)) => Some(ack),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if !batch.is_empty() {
+            match export(&endpoint, &batch) {
+                Ok(()) => reported = false,
+                // Only report the first of consecutive failures, so that an unavailable collector
+                // does not flood the log.
+                Err(e) if !reported => {
+                    println!("Unable to export spans to {endpoint}: {e:#}");
+                    reported = true;
+                }
+                Err(_) => (),
+            }
+            batch.clear();
+        }
+
+        if let Some(ack) = flushed {
+            let _ = ack.send(());
+        }
+    }
+}
+
+/// Send spans to the collector with OTLP over HTTP, JSON encoded.
+fn export(endpoint: &OtelEndpoint, spans: &[Span]) -> Result<(), anyhow::Error> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": "krunkit" } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "krunkit" },
+                "spans": spans.iter().map(Span::to_json).collect::<Vec<Value>>(),
+            }],
+        }],
+    })
+    .to_string();
+
+    let mut stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port))
+        .context("unable to connect to collector")?;
+    stream.set_read_timeout(Some(OTEL_EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(OTEL_EXPORT_TIMEOUT))?;
+
+    let request = format!(
+        "POST /v1/traces HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        endpoint.host,
+        endpoint.port,
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .context("unable to send spans")?;
+
+    let mut response = [0u8; 256];
+    let sz = stream
+        .read(&mut response)
+        .context("no response from collector")?;
+    let response = String::from_utf8_lossy(&response[..sz]);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        return Err(anyhow!("collector responded with status {status}"));
+    }
+
+    Ok(())
+}
+
+/// A random identifier of the given size in bytes, hex encoded.
+fn random_id(size: usize) -> String {
+    let mut bytes = vec![0u8; size];
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .is_err()
+    {
+        // Fall back to the current time, which is unique enough for tracing.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (nanos >> ((i % 16) * 8)) as u8;
+        }
+    }
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+mod tests {
+    #[test]
+    fn otel_endpoint_parse() {
+        use super::*;
+
+        let endpoint = OtelEndpoint::from_str("http://collector:4318/").unwrap();
+        assert_eq!(endpoint.host, "collector");
+        assert_eq!(endpoint.port, 4318);
+
+        let endpoint = OtelEndpoint::from_str("http://[::1]:14318").unwrap();
+        assert_eq!(endpoint.to_string(), "http://[::1]:14318");
+
+        assert_eq!(
+            OtelEndpoint::from_str("http://localhost").unwrap().port,
+            4318
+        );
+
+        assert!(OtelEndpoint::from_str("https://collector:4318").is_err());
+        assert!(OtelEndpoint::from_str("http://collector:4318/v1/traces").is_err());
+    }
+}
@@ -0,0 +1,471 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::Args,
+    copy::format_bytes,
+    grow::disk_size,
+    virtio::{SocketPolicy, VirtioDeviceConfig},
+};
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io, mem,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+/// A problem preventing the VM from running, with a hint to resolve it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    pub message: String,
+    pub hint: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n  hint: {}", self.message, self.hint)
+    }
+}
+
+/// Check that the host allows krunkit to run the VM and to access the files it is configured
+/// with, before libkrun fails with a less specific error (or only once the VM starts).
+///
+/// Each check runs on its own thread, as opening disk images and shared directories on network
+/// or external volumes can be slow. All problems found are reported together.
+pub fn check(args: &Args) -> Result<(), anyhow::Error> {
+    // Network backends are expected to be running unless a helper is started to serve them.
+    let helpers_serve_sockets = !args.helpers.is_empty();
+
+    let problems: Vec<Problem> = thread::scope(|s| {
+        let host = s.spawn(host_problems);
+        let resources = s.spawn(|| resource_problems(args));
+        let devices: Vec<_> = args
+            .devices
+            .iter()
+            .map(|device| s.spawn(move || device_problem(device, helpers_serve_sockets)))
+            .collect();
+
+        let mut problems = host.join().unwrap_or_default();
+        problems.extend(devices.into_iter().filter_map(|d| d.join().ok().flatten()));
+
+        // With --force, a lack of resources is only reported.
+        for problem in resources.join().unwrap_or_default() {
+            match args.force {
+                true => println!("Warning: {problem}"),
+                false => problems.push(problem),
+            }
+        }
+
+        problems
+    });
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+    Err(anyhow!(
+        "unable to run the VM on this host:\n{}",
+        problems.join("\n")
+    ))
+}
+
+/// Interval in which a missing device socket is checked for (see wait-for-socket).
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait for the host sockets of the virtio-net and virtio-vsock devices with wait-for-socket to
+/// be created, concurrently, and remove the devices with optional=true whose socket is still
+/// missing. Nothing is awaited if helpers are configured, as they are only started once the VM
+/// is configured, and krunkit waits for their ready sockets then.
+pub fn await_sockets(args: &mut Args) {
+    if !args.helpers.is_empty() {
+        return;
+    }
+
+    let present: Vec<bool> = thread::scope(|s| {
+        let waits: Vec<_> = args
+            .devices
+            .iter()
+            .map(|device| s.spawn(move || socket_present(device)))
+            .collect();
+
+        waits
+            .into_iter()
+            .map(|w| w.join().unwrap_or(true))
+            .collect()
+    });
+
+    let mut present = present.into_iter();
+    args.devices.retain(|device| {
+        let present = present.next().unwrap_or(true);
+        match device_socket(device) {
+            Some((path, policy)) if !present && policy.optional => {
+                println!(
+                    "Skipping optional {} device: socket {} does not exist",
+                    device.label(),
+                    path.display()
+                );
+                false
+            }
+            _ => true,
+        }
+    });
+}
+
+/// Host socket of a virtio-net or virtio-vsock device, with the policy applied if it is missing.
+fn device_socket(device: &VirtioDeviceConfig) -> Option<(&Path, SocketPolicy)> {
+    match device {
+        VirtioDeviceConfig::Net(net) => Some((&net.unix_socket_path, net.socket_policy)),
+        VirtioDeviceConfig::Vsock(vsock) => Some((&vsock.socket_url, vsock.socket_policy)),
+        _ => None,
+    }
+}
+
+/// Indicate if the host socket of a device exists, waiting for it to be created if the device
+/// has wait-for-socket. Devices without a socket policy are not checked.
+fn socket_present(device: &VirtioDeviceConfig) -> bool {
+    let Some((path, policy)) = device_socket(device) else {
+        return true;
+    };
+    if policy == SocketPolicy::default() {
+        return true;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(policy.wait_for_socket_secs.unwrap_or(0));
+    let mut waiting = false;
+    loop {
+        if path.exists() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        if !waiting {
+            println!(
+                "Waiting for {} device socket {}",
+                device.label(),
+                path.display()
+            );
+            waiting = true;
+        }
+        thread::sleep(SOCKET_POLL_INTERVAL);
+    }
+}
+
+/// Problem with access to the file or socket a device is configured with, if any.
+fn device_problem(device: &VirtioDeviceConfig, helpers_serve_sockets: bool) -> Option<Problem> {
+    match device {
+        VirtioDeviceConfig::Blk(blk) => check_access(
+            &blk.path,
+            "disk image",
+            OpenOptions::new().read(true).write(true).open(&blk.path),
+        ),
+        VirtioDeviceConfig::Fs(fs) => check_access(
+            &fs.shared_dir,
+            "shared directory",
+            fs::read_dir(&fs.shared_dir),
+        ),
+        VirtioDeviceConfig::Net(net)
+            if !helpers_serve_sockets && !net.unix_socket_path.exists() =>
+        {
+            Some(Problem {
+                message: format!(
+                    "network backend socket {} does not exist",
+                    net.unix_socket_path.display()
+                ),
+                hint: String::from(
+                    "start the network backend (such as gvproxy) first, wait for it with wait-for-socket, or have krunkit start it with --helper",
+                ),
+            })
+        }
+        // libkrun only connects to a vsock device's socket once the guest connects to its port,
+        // so the socket is only required to exist if waited for.
+        VirtioDeviceConfig::Vsock(vsock)
+            if !helpers_serve_sockets
+                && vsock.socket_policy.wait_for_socket_secs.is_some()
+                && !vsock.socket_url.exists() =>
+        {
+            Some(Problem {
+                message: format!(
+                    "vsock socket {} does not exist",
+                    vsock.socket_url.display()
+                ),
+                hint: String::from(
+                    "start the service listening on the socket first, or make the device optional with optional=true",
+                ),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Lack of free memory for the VM's RAM, or of free disk space for its disks to be grown (see
+/// grow-to), on the host. The GPU's shared memory region (VRAM) is not counted: it is sized to
+/// the host's total memory (see vram_size), but only backed by host memory as the guest uses it,
+/// so counting it would fail on every host.
+fn resource_problems(args: &Args) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let mut sys = sysinfo::System::new();
+    sys.refresh_memory();
+    let ram = args.memory as u64 * 1024 * 1024;
+    let available = sys.available_memory();
+    if ram > available {
+        problems.push(Problem {
+            message: format!(
+                "the VM's RAM ({}) exceeds the memory available on the host ({} of {})",
+                format_bytes(ram),
+                format_bytes(available),
+                format_bytes(sys.total_memory())
+            ),
+            hint: String::from(
+                "lower --memory, quit applications or other VMs, or start the VM anyway with --force",
+            ),
+        });
+    }
+
+    // Disks being grown, and the space they grow by, per file system.
+    let mut growth: BTreeMap<u64, (PathBuf, u64)> = BTreeMap::new();
+    for device in &args.devices {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+        let Some(size) = blk.grow_to else {
+            continue;
+        };
+
+        // Images that cannot be opened are reported by the device checks.
+        let Ok(file) = File::open(&blk.path) else {
+            continue;
+        };
+        let (Ok(current), Ok(metadata)) = (disk_size(&file, blk.format), file.metadata()) else {
+            continue;
+        };
+
+        let entry = growth
+            .entry(metadata.dev())
+            .or_insert((blk.path.clone(), 0));
+        entry.1 += size.saturating_sub(current);
+    }
+
+    for (path, needed) in growth.into_values() {
+        let Some(free) = free_space(&path) else {
+            continue;
+        };
+        if needed > free {
+            problems.push(Problem {
+                message: format!(
+                    "growing the disks on the file system of {} needs {}, but only {} is free",
+                    path.display(),
+                    format_bytes(needed),
+                    format_bytes(free)
+                ),
+                hint: String::from(
+                    "free up disk space, lower grow-to, or start the VM anyway with --force",
+                ),
+            });
+        }
+    }
+
+    problems
+}
+
+/// Free space of the file system a file is on, available to unprivileged users.
+fn free_space(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Problems preventing any VM from running on the host, regardless of its configuration.
+pub fn host_problems() -> Vec<Problem> {
+    platform::hypervisor_problems()
+}
+
+/// Describe the failure to access a file the VM is configured with, if any.
+fn check_access<T>(path: &Path, what: &str, result: io::Result<T>) -> Option<Problem> {
+    let e = result.err()?;
+
+    let hint = match (e.kind(), e.raw_os_error()) {
+        (io::ErrorKind::NotFound, _) => String::from("check the path, or create the file first"),
+        // macOS privacy protections (TCC) deny access to folders such as Documents, Desktop,
+        // Downloads, and external volumes with EPERM rather than EACCES.
+        (_, Some(libc::EPERM)) => String::from(
+            "grant Full Disk Access (or access to the folder) to the application launching krunkit, such as the terminal, in System Settings > Privacy & Security",
+        ),
+        (io::ErrorKind::PermissionDenied, _) => String::from(
+            "check that the user running krunkit can read and write the path (ls -l)",
+        ),
+        _ => String::from("check that the path is accessible"),
+    };
+
+    Some(Problem {
+        message: format!("unable to access {what} {}: {e}", path.display()),
+        hint,
+    })
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::Problem;
+
+    use std::{
+        env,
+        ffi::{c_char, c_void},
+        mem, ptr,
+    };
+
+    /// Entitlement required to use Hypervisor.framework.
+    const HYPERVISOR_ENTITLEMENT: &[u8] = b"com.apple.security.hypervisor\0";
+
+    /// sysctl indicating if the host supports Hypervisor.framework.
+    const HV_SUPPORT_SYSCTL: &[u8] = b"kern.hv_support\0";
+
+    const CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFStringCreateWithCString(
+            alloc: *const c_void,
+            c_str: *const c_char,
+            encoding: u32,
+        ) -> *const c_void;
+        fn CFRelease(cf: *const c_void);
+    }
+
+    #[link(name = "Security", kind = "framework")]
+    extern "C" {
+        fn SecTaskCreateFromSelf(allocator: *const c_void) -> *const c_void;
+        fn SecTaskCopyValueForEntitlement(
+            task: *const c_void,
+            entitlement: *const c_void,
+            error: *mut *const c_void,
+        ) -> *const c_void;
+    }
+
+    pub fn hypervisor_problems() -> Vec<Problem> {
+        let mut problems = Vec::new();
+
+        if !hypervisor_supported() {
+            problems.push(Problem {
+                message: String::from("Hypervisor.framework is not supported on this host"),
+                hint: String::from(
+                    "krunkit cannot run inside a virtual machine without nested virtualization, and requires an Apple silicon Mac",
+                ),
+            });
+        }
+
+        if hypervisor_entitled() == Some(false) {
+            let exe = env::current_exe()
+                .map(|e| e.display().to_string())
+                .unwrap_or_else(|_| String::from("krunkit"));
+            problems.push(Problem {
+                message: String::from(
+                    "krunkit is not signed with the com.apple.security.hypervisor entitlement",
+                ),
+                hint: format!(
+                    "sign it with: codesign --entitlements krunkit.entitlements --force -s - {exe}"
+                ),
+            });
+        }
+
+        problems
+    }
+
+    /// Indicate if the host supports Hypervisor.framework.
+    fn hypervisor_supported() -> bool {
+        let mut supported: i32 = 0;
+        let mut size = mem::size_of::<i32>();
+        let ret = unsafe {
+            libc::sysctlbyname(
+                HV_SUPPORT_SYSCTL.as_ptr() as *const c_char,
+                &mut supported as *mut i32 as *mut c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+
+        ret == 0 && supported == 1
+    }
+
+    /// Indicate if the krunkit process has the hypervisor entitlement, if it can be determined.
+    fn hypervisor_entitled() -> Option<bool> {
+        let task = unsafe { SecTaskCreateFromSelf(ptr::null()) };
+        if task.is_null() {
+            return None;
+        }
+
+        let entitlement = unsafe {
+            CFStringCreateWithCString(
+                ptr::null(),
+                HYPERVISOR_ENTITLEMENT.as_ptr() as *const c_char,
+                CF_STRING_ENCODING_UTF8,
+            )
+        };
+        if entitlement.is_null() {
+            unsafe { CFRelease(task) };
+            return None;
+        }
+
+        let value = unsafe { SecTaskCopyValueForEntitlement(task, entitlement, ptr::null_mut()) };
+        let entitled = !value.is_null();
+
+        unsafe {
+            if entitled {
+                CFRelease(value);
+            }
+            CFRelease(entitlement);
+            CFRelease(task);
+        }
+
+        Some(entitled)
+    }
+}
+
+/// Hypervisor.framework only exists on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::Problem;
+
+    pub fn hypervisor_problems() -> Vec<Problem> {
+        Vec::new()
+    }
+}
+
+mod tests {
+    #[test]
+    fn preflight_access_hint() {
+        use super::*;
+
+        let path = Path::new("/Users/user/Documents/share");
+        assert_eq!(check_access(path, "shared directory", Ok(())), None);
+
+        let problem = check_access::<()>(
+            path,
+            "shared directory",
+            Err(io::Error::from_raw_os_error(libc::EPERM)),
+        )
+        .unwrap();
+        assert!(problem
+            .message
+            .starts_with("unable to access shared directory /Users/user/Documents/share"));
+        assert!(problem.hint.contains("Full Disk Access"));
+
+        let problem =
+            check_access::<()>(path, "disk image", Err(io::ErrorKind::NotFound.into())).unwrap();
+        assert!(problem.hint.contains("create the file"));
+    }
+}
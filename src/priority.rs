@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{fmt, io, str::FromStr, sync::Mutex};
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+/// Quality of service class of the threads running the VM, which macOS schedules them (and
+/// throttles their I/O) by.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QosClass {
+    Background,
+    Utility,
+    UserInitiated,
+    UserInteractive,
+}
+
+impl FromStr for QosClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "background" => Ok(Self::Background),
+            "utility" => Ok(Self::Utility),
+            "userinitiated" => Ok(Self::UserInitiated),
+            "userinteractive" => Ok(Self::UserInteractive),
+            _ => Err(anyhow!("invalid --qos option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for QosClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let class = match self {
+            Self::Background => "background",
+            Self::Utility => "utility",
+            Self::UserInitiated => "userinitiated",
+            Self::UserInteractive => "userinteractive",
+        };
+
+        write!(f, "{class}")
+    }
+}
+
+/// Reason for krunkit to run at background priority.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackgroundReason {
+    ThermalPressure,
+    LowPowerMode,
+}
+
+/// Reasons currently requiring background priority. krunkit runs at background priority as long
+/// as any remains.
+static REASONS: Mutex<Vec<BackgroundReason>> = Mutex::new(Vec::new());
+
+/// Request (or stop requesting) that krunkit, including the vCPUs, runs in the background
+/// priority band, in which its CPU and I/O are throttled.
+pub fn set_background(reason: BackgroundReason, background: bool) -> io::Result<()> {
+    let mut reasons = REASONS.lock().unwrap();
+    let was_background = !reasons.is_empty();
+
+    reasons.retain(|r| *r != reason);
+    if background {
+        reasons.push(reason);
+    }
+
+    match (was_background, !reasons.is_empty()) {
+        (false, true) => {
+            platform::set_background(true)?;
+            println!("Running VM at background priority ({reason:?})");
+        }
+        (true, false) => {
+            platform::set_background(false)?;
+            println!("Running VM at normal priority");
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Apply a QoS class and nice value to the calling thread before it starts the VM, so that the
+/// vCPU and I/O threads libkrun creates from it inherit them. On macOS, the nice value applies to
+/// the whole krunkit process.
+pub fn apply(qos: Option<QosClass>, nice: Option<i32>) -> Result<(), anyhow::Error> {
+    if let Some(qos) = qos {
+        platform::set_qos_class(qos).context(format!("unable to set QoS class {qos}"))?;
+    }
+
+    if let Some(nice) = nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(anyhow!(
+                "unable to set nice value {nice}: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::QosClass;
+
+    use std::io;
+
+    /// Set the QoS class of the calling thread, which threads it creates inherit.
+    pub fn set_qos_class(qos: QosClass) -> io::Result<()> {
+        let class = match qos {
+            QosClass::Background => libc::qos_class_t::QOS_CLASS_BACKGROUND,
+            QosClass::Utility => libc::qos_class_t::QOS_CLASS_UTILITY,
+            QosClass::UserInitiated => libc::qos_class_t::QOS_CLASS_USER_INITIATED,
+            QosClass::UserInteractive => libc::qos_class_t::QOS_CLASS_USER_INTERACTIVE,
+        };
+
+        match unsafe { libc::pthread_set_qos_class_self_np(class, 0) } {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    /// Move the whole process in or out of the background priority band.
+    pub fn set_background(background: bool) -> io::Result<()> {
+        let priority = match background {
+            true => libc::PRIO_DARWIN_BG,
+            false => 0,
+        };
+
+        match unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, priority) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// QoS classes and the background priority band only exist on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::QosClass;
+
+    use std::io;
+
+    pub fn set_qos_class(_qos: QosClass) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn set_background(_background: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+mod tests {
+    #[test]
+    fn qos_class_parse() {
+        use super::*;
+
+        assert_eq!(
+            QosClass::from_str("background").unwrap(),
+            QosClass::Background
+        );
+        assert_eq!(
+            QosClass::from_str("UserInteractive").unwrap(),
+            QosClass::UserInteractive
+        );
+        assert_eq!(QosClass::Utility.to_string(), "utility");
+        assert!(QosClass::from_str("realtime").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cleanup::{self, Resource},
+    sandbox,
+    status::Request,
+};
+
+use std::{
+    env,
+    ffi::CStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::{
+            net::{UnixListener, UnixStream},
+            process::CommandExt,
+        },
+    },
+    process::{self, Command},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+
+/// File descriptors of the listener and the channel to the VM process in the restful proxy.
+const PROXY_LISTENER_FD: RawFd = 3;
+const PROXY_CHANNEL_FD: RawFd = 4;
+
+/// User the restful proxy runs as if krunkit is run as root.
+const PROXY_USER: &CStr = c"nobody";
+
+/// Arguments of the (hidden) restful-proxy subcommand, run by krunkit itself.
+#[derive(Clone, Debug, Parser)]
+pub struct RestfulProxyArgs {
+    /// The inherited listener is a UNIX socket rather than a TCP socket.
+    #[arg(long, default_value_t = false)]
+    pub unix: bool,
+}
+
+/// Start a process serving the restful service on the listener, which handles the connections of
+/// clients and parses their requests, and return the channel it sends the requests over. The
+/// process has no more privileges than needed to do so, so that a bug in handling untrusted
+/// input cannot compromise the VM.
+///
+/// The channel is a line-based exchange of JSON: the proxy sends a request, the VM process
+/// returns the response, and the proxy sends an empty line once the response has been written to
+/// the client. The proxy exits once the channel is closed.
+pub fn spawn_proxy(listener: &impl AsRawFd, unix: bool) -> Result<UnixStream, anyhow::Error> {
+    let (channel, proxy_channel) = UnixStream::pair().context("unable to create proxy channel")?;
+
+    let exe = env::current_exe().context("unable to find krunkit executable")?;
+    let mut command = Command::new(exe);
+    command.arg("restful-proxy");
+    if unix {
+        command.arg("--unix");
+    }
+
+    let listener = listener.as_raw_fd();
+    let proxy_channel_fd = proxy_channel.as_raw_fd();
+    unsafe {
+        command.pre_exec(move || {
+            inherit_fd(listener, PROXY_LISTENER_FD)?;
+            inherit_fd(proxy_channel_fd, PROXY_CHANNEL_FD)
+        });
+    }
+
+    let child = command
+        .spawn()
+        .context("unable to start restful proxy process")?;
+    cleanup::register(Resource::Process(child.id() as libc::pid_t));
+    println!("Restful service handled by proxy process {}", child.id());
+
+    Ok(channel)
+}
+
+/// Duplicate a file descriptor to a given number, to be inherited across exec. Only calls
+/// async-signal-safe functions, as this runs between fork and exec.
+fn inherit_fd(fd: RawFd, target: RawFd) -> io::Result<()> {
+    // dup2 does not clear close-on-exec if the descriptors are the same.
+    let ret = match fd == target {
+        true => unsafe { libc::fcntl(fd, libc::F_SETFD, 0) },
+        false => unsafe { libc::dup2(fd, target) },
+    };
+
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Run the restful proxy, relaying the requests of clients to the VM process.
+pub fn restful_proxy(args: &RestfulProxyArgs) -> Result<(), anyhow::Error> {
+    let channel = unsafe { UnixStream::from_raw_fd(PROXY_CHANNEL_FD) };
+
+    drop_privileges()?;
+    sandbox::restrict()?;
+
+    match args.unix {
+        true => {
+            let listener = unsafe { UnixListener::from_raw_fd(PROXY_LISTENER_FD) };
+            proxy(&channel, || listener.accept().map(|(s, _)| s))
+        }
+        false => {
+            let listener = unsafe { TcpListener::from_raw_fd(PROXY_LISTENER_FD) };
+            proxy(&channel, || listener.accept().map(|(s, _)| s))
+        }
+    }
+}
+
+/// Relay each request until the channel to the VM process is closed.
+fn proxy<S: Read + Write>(
+    channel: &UnixStream,
+    accept: impl Fn() -> io::Result<S>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(channel.try_clone()?);
+    let mut writer = channel.try_clone()?;
+
+    // The VM process only writes to the channel in response to a request, so the channel
+    // becoming readable while waiting for a client means that it was closed.
+    while wait_for_client()? {
+        let mut stream = match accept() {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Error accepting connection: {e}");
+                continue;
+            }
+        };
+
+        let request = match Request::read(&mut stream) {
+            Ok(request) => serde_json::to_string(&request)?,
+            Err(e) => {
+                println!("Error reading stream: {}", e);
+                continue;
+            }
+        };
+
+        writeln!(writer, "{request}")?;
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let response: String =
+            serde_json::from_str(&line).context("malformed response from VM process")?;
+
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            println!("Error writting response: {e}");
+        }
+        drop(stream);
+
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+/// Wait for a client to connect, returning false if the channel to the VM process was closed
+/// instead.
+fn wait_for_client() -> io::Result<bool> {
+    let mut fds = [
+        libc::pollfd {
+            fd: PROXY_LISTENER_FD,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: PROXY_CHANNEL_FD,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        return Ok(fds[1].revents == 0);
+    }
+}
+
+/// Run as an unprivileged user if started as root.
+fn drop_privileges() -> Result<(), anyhow::Error> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let user = unsafe { libc::getpwnam(PROXY_USER.as_ptr()) };
+    if user.is_null() {
+        return Err(anyhow!("unable to find user {PROXY_USER:?}"));
+    }
+
+    let (uid, gid) = unsafe { ((*user).pw_uid, (*user).pw_gid) };
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) < 0
+            || libc::setgid(gid) < 0
+            || libc::setuid(uid) < 0
+        {
+            return Err(anyhow!(
+                "unable to drop privileges of restful proxy process {}: {}",
+                process::id(),
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
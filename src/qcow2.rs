@@ -0,0 +1,499 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::BTreeMap, fs::File, os::unix::fs::FileExt};
+
+use anyhow::{anyhow, Context};
+
+/// First bytes of a qcow2 image.
+pub const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Size of the part of the header common to versions 2 and 3.
+const HEADER_SIZE: usize = 72;
+
+/// Size of the version 3 header written by krunkit, without extensions.
+const V3_HEADER_SIZE: usize = 104;
+
+/// Cluster size of the images written by krunkit (64 KiB, as with qemu-img).
+const CLUSTER_BITS: u32 = 16;
+
+/// Width of the refcounts of the images written by krunkit (2^4 = 16 bits).
+const REFCOUNT_ORDER: u32 = 4;
+
+/// Flag of L1 and L2 entries of clusters with a refcount of exactly one.
+const OFLAG_COPIED: u64 = 1 << 63;
+
+/// Flag of L2 entries of compressed clusters.
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+
+/// Flag of L2 entries of clusters that read as zeroes (version 3).
+const OFLAG_ZERO: u64 = 1;
+
+/// Bits of L1 and L2 entries holding the host offset of a table or standard cluster.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// Incompatible feature bit indicating that the refcounts may be inconsistent. Data is still read
+/// correctly, so it is the only incompatible feature images can be read with.
+const INCOMPAT_DIRTY: u64 = 1;
+
+/// Fields of a qcow2 image header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u32,
+
+    /// Path of the image this one is an overlay of, which may be relative to the image's
+    /// directory.
+    pub backing_file: Option<String>,
+
+    pub cluster_bits: u32,
+
+    /// Size of the disk, in bytes.
+    pub size: u64,
+
+    /// Encryption method (0 if the image is not encrypted).
+    pub crypt_method: u32,
+
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+
+    /// Features the image cannot be read without supporting (version 3).
+    pub incompatible_features: u64,
+}
+
+impl Header {
+    pub fn read(file: &File) -> Result<Self, anyhow::Error> {
+        let mut header = [0; V3_HEADER_SIZE];
+        file.read_exact_at(&mut header[..HEADER_SIZE], 0)
+            .context("qcow2 header is truncated")?;
+
+        if &header[..4] != MAGIC {
+            return Err(anyhow!("not a qcow2 image"));
+        }
+
+        let version = be_u32(&header, 4);
+        if version >= 3 {
+            file.read_exact_at(&mut header[HEADER_SIZE..], HEADER_SIZE as u64)
+                .context("qcow2 header is truncated")?;
+        }
+
+        let backing_file_offset = be_u64(&header, 8);
+        let backing_file_size = be_u32(&header, 16);
+        let backing_file = match backing_file_offset {
+            0 => None,
+            offset => {
+                let mut name = vec![0; backing_file_size as usize];
+                file.read_exact_at(&mut name, offset)
+                    .context("qcow2 backing file name is truncated")?;
+                Some(String::from_utf8_lossy(&name).to_string())
+            }
+        };
+
+        Ok(Self {
+            version,
+            backing_file,
+            cluster_bits: be_u32(&header, 20),
+            size: be_u64(&header, 24),
+            crypt_method: be_u32(&header, 32),
+            l1_size: be_u32(&header, 36),
+            l1_table_offset: be_u64(&header, 40),
+            incompatible_features: match version >= 3 {
+                true => be_u64(&header, 72),
+                false => 0,
+            },
+        })
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read the big-endian 64-bit entries of an L1 or L2 table.
+fn read_table(file: &File, offset: u64, entries: usize) -> Result<Vec<u64>, anyhow::Error> {
+    let mut buf = vec![0; entries * 8];
+    file.read_exact_at(&mut buf, offset)
+        .context(format!("qcow2 table at offset {offset} is truncated"))?;
+
+    Ok(buf.chunks_exact(8).map(|e| be_u64(e, 0)).collect())
+}
+
+/// Grow the disk of a qcow2 image to a larger size. The L1 table is not moved, so the disk can
+/// only grow as far as the clusters of the L1 table have room for entries (4 TiB per cluster of a
+/// table of 64 KiB clusters).
+pub fn grow(file: &File, size: u64) -> Result<(), anyhow::Error> {
+    let header = Header::read(file)?;
+    if size <= header.size {
+        return Ok(());
+    }
+    if header.incompatible_features & !INCOMPAT_DIRTY != 0 {
+        return Err(anyhow!(
+            "qcow2 image uses unsupported features ({:#x})",
+            header.incompatible_features
+        ));
+    }
+
+    let cluster_size = header.cluster_size();
+    let l2_entries = cluster_size / 8;
+    let l1_size = size.div_ceil(cluster_size).div_ceil(l2_entries);
+
+    if l1_size > u64::from(header.l1_size) {
+        let capacity = match header.l1_table_offset {
+            0 => 0,
+            _ => (u64::from(header.l1_size) * 8).div_ceil(cluster_size) * l2_entries,
+        };
+        if l1_size > capacity {
+            return Err(anyhow!(
+                "the L1 table of the image only has room for a disk of {} bytes",
+                capacity * l2_entries * cluster_size
+            ));
+        }
+
+        // The entries past the end of the table, in its last cluster, must be unused.
+        let unused = read_table(
+            file,
+            header.l1_table_offset + u64::from(header.l1_size) * 8,
+            (l1_size - u64::from(header.l1_size)) as usize,
+        )?;
+        if unused.iter().any(|e| *e != 0) {
+            return Err(anyhow!("the L1 table of the image is followed by data"));
+        }
+
+        file.write_all_at(&(l1_size as u32).to_be_bytes(), 36)?;
+    }
+
+    file.write_all_at(&size.to_be_bytes(), 24)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Reads the contents of a standalone, uncompressed and unencrypted qcow2 image, as seen by the
+/// guest.
+pub struct Reader<'a> {
+    file: &'a File,
+    header: Header,
+    l1: Vec<u64>,
+
+    /// The L2 table read last, with its index in the L1 table.
+    l2: Option<(usize, Vec<u64>)>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(file: &'a File) -> Result<Self, anyhow::Error> {
+        let header = Header::read(file)?;
+
+        if !(2..=3).contains(&header.version) {
+            return Err(anyhow!("unsupported qcow2 version {}", header.version));
+        }
+        if !(9..=21).contains(&header.cluster_bits) {
+            return Err(anyhow!(
+                "invalid qcow2 cluster size (2^{} bytes)",
+                header.cluster_bits
+            ));
+        }
+        if header.crypt_method != 0 {
+            return Err(anyhow!("encrypted qcow2 images are not supported"));
+        }
+        if let Some(backing_file) = &header.backing_file {
+            return Err(anyhow!(
+                "qcow2 image is an overlay of {backing_file}, which is not supported"
+            ));
+        }
+        if header.incompatible_features & !INCOMPAT_DIRTY != 0 {
+            return Err(anyhow!(
+                "qcow2 image uses unsupported features ({:#x})",
+                header.incompatible_features
+            ));
+        }
+
+        let l1 = read_table(file, header.l1_table_offset, header.l1_size as usize)?;
+
+        Ok(Self {
+            file,
+            header,
+            l1,
+            l2: None,
+        })
+    }
+
+    /// Size of the disk, in bytes.
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    /// Read the cluster at a (cluster-aligned) offset of the disk into a buffer of the cluster
+    /// size. Returns false, leaving the buffer untouched, if the cluster is unallocated or reads
+    /// as zeroes.
+    pub fn read_cluster(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, anyhow::Error> {
+        let l2_entries = self.cluster_size() / 8;
+        let cluster = offset >> self.header.cluster_bits;
+        let l1_index = (cluster / l2_entries) as usize;
+        let l2_index = (cluster % l2_entries) as usize;
+
+        let l2_offset = match self.l1.get(l1_index) {
+            Some(entry) => entry & OFFSET_MASK,
+            None => return Ok(false),
+        };
+        if l2_offset == 0 {
+            return Ok(false);
+        }
+
+        if self.l2.as_ref().map(|(i, _)| *i) != Some(l1_index) {
+            let table = read_table(self.file, l2_offset, l2_entries as usize)?;
+            self.l2 = Some((l1_index, table));
+        }
+        let entry = self.l2.as_ref().map(|(_, t)| t[l2_index]).unwrap_or(0);
+
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(anyhow!(
+                "qcow2 image has compressed clusters, which are not supported"
+            ));
+        }
+        let host_offset = entry & OFFSET_MASK;
+        if host_offset == 0 || (self.header.version >= 3 && entry & OFLAG_ZERO != 0) {
+            return Ok(false);
+        }
+
+        // The last cluster of the file may be shorter than a cluster, the rest reading as zeroes.
+        let mut read = 0;
+        while read < buf.len() {
+            match self
+                .file
+                .read_at(&mut buf[read..], host_offset + read as u64)?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+
+        Ok(true)
+    }
+
+    /// Read the disk from an offset, reading zeroes from unallocated clusters and past its end.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), anyhow::Error> {
+        let cluster_size = self.cluster_size();
+        let mut cluster_buf = vec![0; cluster_size as usize];
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let cluster = pos / cluster_size * cluster_size;
+            let start = (pos - cluster) as usize;
+            let n = (cluster_size as usize - start).min(buf.len() - done);
+
+            match pos < self.size() && self.read_cluster(cluster, &mut cluster_buf)? {
+                true => buf[done..done + n].copy_from_slice(&cluster_buf[start..start + n]),
+                false => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a standalone qcow2 (version 3) image, one cluster at a time in increasing order of disk
+/// offsets. Clusters that are not written read as zeroes.
+pub struct Writer<'a> {
+    file: &'a File,
+    size: u64,
+    l1: Vec<u64>,
+
+    /// L2 tables, by index in the L1 table, with their host offset.
+    l2: BTreeMap<usize, (u64, Vec<u64>)>,
+
+    /// Host offset of the next cluster to allocate.
+    next: u64,
+}
+
+impl<'a> Writer<'a> {
+    /// Start writing an image of a disk of the given size to an empty file.
+    pub fn new(file: &'a File, size: u64) -> Self {
+        let cluster_size = Self::cluster_size();
+        let clusters = size.div_ceil(cluster_size);
+        let l1_size = clusters.div_ceil(cluster_size / 8);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        Self {
+            file,
+            size,
+            l1: vec![0; l1_size as usize],
+            l2: BTreeMap::new(),
+            // The header is followed by the L1 table.
+            next: (1 + l1_clusters) * cluster_size,
+        }
+    }
+
+    pub fn cluster_size() -> u64 {
+        1 << CLUSTER_BITS
+    }
+
+    /// Allocate a cluster at the end of the file, returning its host offset.
+    fn allocate(&mut self) -> u64 {
+        let offset = self.next;
+        self.next += Self::cluster_size();
+
+        offset
+    }
+
+    /// Write the cluster at a (cluster-aligned) offset of the disk. Data shorter than a cluster
+    /// is padded with zeroes.
+    pub fn write_cluster(&mut self, offset: u64, data: &[u8]) -> Result<(), anyhow::Error> {
+        let cluster_size = Self::cluster_size();
+        let l2_entries = cluster_size / 8;
+        let cluster = offset / cluster_size;
+        let l1_index = (cluster / l2_entries) as usize;
+        let l2_index = (cluster % l2_entries) as usize;
+
+        if !self.l2.contains_key(&l1_index) {
+            let l2_offset = self.allocate();
+            self.l1[l1_index] = l2_offset | OFLAG_COPIED;
+            self.l2
+                .insert(l1_index, (l2_offset, vec![0; l2_entries as usize]));
+        }
+
+        let host_offset = self.allocate();
+        self.file.write_all_at(data, host_offset)?;
+        if let Some((_, table)) = self.l2.get_mut(&l1_index) {
+            table[l2_index] = host_offset | OFLAG_COPIED;
+        }
+
+        Ok(())
+    }
+
+    /// Write the tables and the header, completing the image.
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        let cluster_size = Self::cluster_size();
+
+        for (offset, table) in self.l2.values() {
+            self.file.write_all_at(&table_bytes(table), *offset)?;
+        }
+        self.file
+            .write_all_at(&table_bytes(&self.l1), cluster_size)?;
+
+        // Every cluster, including the refcount blocks and table themselves, has a refcount of
+        // one. Their number depends on the number of clusters they cover, including their own.
+        let refcounts_per_block = cluster_size * 8 / (1 << REFCOUNT_ORDER);
+        let used = self.next / cluster_size;
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let total = used + blocks + table_clusters;
+            let needed_blocks = total.div_ceil(refcounts_per_block);
+            let needed_table_clusters = (needed_blocks * 8).div_ceil(cluster_size);
+            if (needed_blocks, needed_table_clusters) == (blocks, table_clusters) {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table_clusters);
+        }
+        let total = used + blocks + table_clusters;
+
+        let mut refcount_table = Vec::new();
+        for block in 0..blocks {
+            let offset = self.allocate();
+            refcount_table.push(offset);
+
+            let first = block * refcounts_per_block;
+            let refcounts: Vec<u8> = (first..first + refcounts_per_block)
+                .flat_map(|cluster| u16::from(cluster < total).to_be_bytes())
+                .collect();
+            self.file.write_all_at(&refcounts, offset)?;
+        }
+        let refcount_table_offset = self.next;
+        self.file
+            .write_all_at(&table_bytes(&refcount_table), refcount_table_offset)?;
+        self.next += table_clusters * cluster_size;
+
+        let mut header = Vec::with_capacity(V3_HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&3u32.to_be_bytes());
+        header.extend_from_slice(&0u64.to_be_bytes()); // backing file offset
+        header.extend_from_slice(&0u32.to_be_bytes()); // backing file size
+        header.extend_from_slice(&CLUSTER_BITS.to_be_bytes());
+        header.extend_from_slice(&self.size.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // encryption method
+        header.extend_from_slice(&(self.l1.len() as u32).to_be_bytes());
+        header.extend_from_slice(&cluster_size.to_be_bytes()); // L1 table offset
+        header.extend_from_slice(&refcount_table_offset.to_be_bytes());
+        header.extend_from_slice(&(table_clusters as u32).to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // number of snapshots
+        header.extend_from_slice(&0u64.to_be_bytes()); // snapshots offset
+        header.extend_from_slice(&0u64.to_be_bytes()); // incompatible features
+        header.extend_from_slice(&0u64.to_be_bytes()); // compatible features
+        header.extend_from_slice(&0u64.to_be_bytes()); // autoclear features
+        header.extend_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+        header.extend_from_slice(&(V3_HEADER_SIZE as u32).to_be_bytes());
+        self.file.write_all_at(&header, 0)?;
+
+        // The header extensions that follow are terminated by the zeroes the header cluster is
+        // padded with.
+        self.file.set_len(self.next)?;
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+fn table_bytes(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+mod tests {
+    #[test]
+    fn qcow2_write_read() {
+        use super::*;
+
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("krunkit-qcow2-test-{}.qcow2", process::id()));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        // A disk of three L2 tables' worth of clusters and a partial one, with data in the
+        // first and last clusters and in the middle of the second L2 table.
+        let cluster_size = Writer::cluster_size();
+        let size = cluster_size * 8192 * 3 + 512;
+        let clusters = [0, cluster_size * 8192 + cluster_size * 7, size - 512];
+
+        let mut writer = Writer::new(&file, size);
+        for (i, offset) in clusters.iter().enumerate() {
+            writer
+                .write_cluster(*offset, &vec![i as u8 + 1; 512])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::new(&file).unwrap();
+        assert_eq!(reader.size(), size);
+        assert_eq!(reader.cluster_size(), cluster_size);
+
+        let mut buf = vec![0; cluster_size as usize];
+        for (i, offset) in clusters.iter().enumerate() {
+            assert!(reader.read_cluster(*offset, &mut buf).unwrap());
+            assert!(buf[..512].iter().all(|b| *b == i as u8 + 1));
+            assert!(buf[512..].iter().all(|b| *b == 0));
+        }
+        assert!(!reader.read_cluster(cluster_size, &mut buf).unwrap());
+        assert!(!reader
+            .read_cluster(cluster_size * 8192 * 2, &mut buf)
+            .unwrap());
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::vm::VmHandle;
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Period over which the CPU time of the vCPUs is limited.
+const QUOTA_PERIOD: Duration = Duration::from_millis(100);
+
+/// Interval at which the CPU time used by the vCPUs in the current period is checked.
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Host CPU time the vCPUs may use, in percent of a host CPU (200% is two host CPUs).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuQuota {
+    pub percent: u32,
+}
+
+impl CpuQuota {
+    /// CPU time the vCPUs may use in each period.
+    fn budget(&self) -> Duration {
+        QUOTA_PERIOD * self.percent / 100
+    }
+}
+
+impl FromStr for CpuQuota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let percent = u32::from_str(s.strip_suffix('%').unwrap_or(s))
+            .context(format!("invalid --cpu-quota option: {s}"))?;
+        if percent == 0 {
+            return Err(anyhow!("--cpu-quota must be greater than 0%"));
+        }
+
+        Ok(Self { percent })
+    }
+}
+
+impl fmt::Display for CpuQuota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}%", self.percent)
+    }
+}
+
+impl Serialize for CpuQuota {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Limit the host CPU time used by the vCPUs on a new thread until the VM exits. Once the vCPUs
+/// have used up their budget for the current period, their threads are suspended until the next
+/// period starts.
+pub fn cpu_quota_limiter(vm: Arc<VmHandle>, quota: CpuQuota) -> Result<(), anyhow::Error> {
+    platform::check()?;
+
+    thread::spawn(move || loop {
+        let start = Instant::now();
+
+        // vCPU threads are only created once the VM starts, so look for them each period.
+        let vcpus = platform::vcpu_threads();
+        let used = |vcpus: &[platform::VcpuThread]| -> Duration {
+            vcpus.iter().map(|v| v.cpu_time()).sum()
+        };
+        let base = used(&vcpus);
+
+        while start.elapsed() < QUOTA_PERIOD {
+            if vm.wait_exited(QUOTA_CHECK_INTERVAL) {
+                vcpus.iter().for_each(|v| v.set_suspended(false));
+                return;
+            }
+
+            if used(&vcpus).saturating_sub(base) >= quota.budget() {
+                vcpus.iter().for_each(|v| v.set_suspended(true));
+
+                let remaining = QUOTA_PERIOD.saturating_sub(start.elapsed());
+                let exited = vm.wait_exited(remaining);
+                vcpus.iter().for_each(|v| v.set_suspended(false));
+                if exited {
+                    return;
+                }
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use crate::stats::VCPU_THREAD_PREFIX;
+
+    use std::{ffi::CStr, mem, ptr, time::Duration};
+
+    extern "C" {
+        fn thread_suspend(target_act: libc::thread_act_t) -> libc::kern_return_t;
+        fn thread_resume(target_act: libc::thread_act_t) -> libc::kern_return_t;
+        fn mach_port_deallocate(
+            task: libc::mach_port_t,
+            name: libc::mach_port_t,
+        ) -> libc::kern_return_t;
+    }
+
+    /// Threads are suspended through their Mach ports.
+    pub fn check() -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// A vCPU thread of the VM, identified by its Mach port.
+    pub struct VcpuThread {
+        port: libc::thread_act_t,
+    }
+
+    impl VcpuThread {
+        /// User and system CPU time consumed by the thread.
+        pub fn cpu_time(&self) -> Duration {
+            match extended_info(self.port) {
+                Some(info) => Duration::from_nanos(info.pth_user_time + info.pth_system_time),
+                None => Duration::ZERO,
+            }
+        }
+
+        pub fn set_suspended(&self, suspended: bool) {
+            let ret = match suspended {
+                true => unsafe { thread_suspend(self.port) },
+                false => unsafe { thread_resume(self.port) },
+            };
+
+            if ret != libc::KERN_SUCCESS {
+                println!("Unable to suspend or resume vCPU thread: error {ret}");
+            }
+        }
+    }
+
+    impl Drop for VcpuThread {
+        fn drop(&mut self) {
+            unsafe { mach_port_deallocate(libc::mach_task_self(), self.port) };
+        }
+    }
+
+    /// The vCPU threads of the VM, found by the names libkrun gives them.
+    pub fn vcpu_threads() -> Vec<VcpuThread> {
+        let mut threads: libc::thread_act_array_t = ptr::null_mut();
+        let mut count: libc::mach_msg_type_number_t = 0;
+        if unsafe { libc::task_threads(libc::mach_task_self(), &mut threads, &mut count) }
+            != libc::KERN_SUCCESS
+        {
+            return Vec::new();
+        }
+
+        let ports = unsafe { std::slice::from_raw_parts(threads, count as usize) }.to_vec();
+        unsafe {
+            libc::vm_deallocate(
+                libc::mach_task_self(),
+                threads as libc::vm_address_t,
+                ports.len() * mem::size_of::<libc::thread_act_t>(),
+            )
+        };
+
+        // Ports of the other threads are released as they are dropped.
+        ports
+            .into_iter()
+            .map(|port| VcpuThread { port })
+            .filter(|thread| {
+                extended_info(thread.port).is_some_and(|info| {
+                    let name = unsafe { CStr::from_ptr(info.pth_name.as_ptr()) };
+                    name.to_string_lossy().starts_with(VCPU_THREAD_PREFIX)
+                })
+            })
+            .collect()
+    }
+
+    fn extended_info(port: libc::thread_act_t) -> Option<libc::thread_extended_info> {
+        let mut info: libc::thread_extended_info = unsafe { mem::zeroed() };
+        let mut count = libc::THREAD_EXTENDED_INFO_COUNT;
+
+        let ret = unsafe {
+            libc::thread_info(
+                port,
+                libc::THREAD_EXTENDED_INFO as libc::thread_flavor_t,
+                &mut info as *mut libc::thread_extended_info as libc::thread_info_t,
+                &mut count,
+            )
+        };
+
+        match ret {
+            libc::KERN_SUCCESS => Some(info),
+            _ => None,
+        }
+    }
+}
+
+/// Suspending individual threads of a process is only possible on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    pub fn check() -> Result<(), anyhow::Error> {
+        Err(anyhow!("--cpu-quota is only supported on macOS"))
+    }
+
+    pub struct VcpuThread;
+
+    impl VcpuThread {
+        pub fn cpu_time(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        pub fn set_suspended(&self, _suspended: bool) {}
+    }
+
+    pub fn vcpu_threads() -> Vec<VcpuThread> {
+        Vec::new()
+    }
+}
+
+mod tests {
+    #[test]
+    fn cpu_quota_parse() {
+        use super::*;
+
+        let quota = CpuQuota::from_str("200%").unwrap();
+        assert_eq!(quota.percent, 200);
+        assert_eq!(quota.budget(), Duration::from_millis(200));
+        assert_eq!(quota.to_string(), "200%");
+
+        assert_eq!(CpuQuota::from_str("50").unwrap().percent, 50);
+        assert!(CpuQuota::from_str("0%").is_err());
+        assert!(CpuQuota::from_str("half").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{cmdline::Args, statedir, virtio::VirtioDeviceConfig};
+
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use serde::{Serialize, Serializer};
+
+/// Directories holding the system libraries and frameworks, configuration, and the libkrun and
+/// libkrunfw libraries (commonly installed with Homebrew), which remain readable in the sandbox.
+const SANDBOX_READABLE_DIRS: [&str; 7] = [
+    "/System",
+    "/Library",
+    "/usr",
+    "/opt",
+    "/private/etc",
+    "/private/var/db",
+    "/dev",
+];
+
+/// Restrictions applied to the krunkit process once the VM is about to run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SandboxMode {
+    /// No restrictions.
+    #[default]
+    Off,
+
+    /// Restrict file access to the disks, shares, sockets, and files given on the command line,
+    /// and the execution of programs to krunkit itself and its helpers.
+    Strict,
+}
+
+impl FromStr for SandboxMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "strict" => Ok(Self::Strict),
+            _ => Err(anyhow!("invalid --sandbox option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for SandboxMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+impl Serialize for SandboxMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Paths the sandboxed krunkit process may access.
+#[derive(Debug, Default)]
+struct SandboxPaths {
+    /// Files (or UNIX sockets) that can be read and written.
+    files: Vec<PathBuf>,
+
+    /// Directories whose contents can be read and written.
+    dirs: Vec<PathBuf>,
+
+    /// Programs that can be executed.
+    programs: Vec<PathBuf>,
+}
+
+impl SandboxPaths {
+    /// Paths declared on the command line, resolved as the sandbox matches real paths (for
+    /// example, /tmp is /private/tmp).
+    fn from_args(args: &Args) -> Self {
+        let mut paths = Self::default();
+
+        for device in &args.devices {
+            match device {
+                VirtioDeviceConfig::Blk(blk) => paths.files.push(resolve(&blk.path)),
+                VirtioDeviceConfig::Serial(serial) => {
+                    paths.files.push(resolve(&serial.log_file_path))
+                }
+                VirtioDeviceConfig::Vsock(vsock) => paths.files.push(resolve(&vsock.socket_url)),
+                VirtioDeviceConfig::Net(net) => paths.files.push(resolve(&net.unix_socket_path)),
+                VirtioDeviceConfig::Fs(fs) => paths.dirs.push(resolve(&fs.shared_dir)),
+                _ => (),
+            }
+        }
+
+        let files = [
+            args.bootloader.as_ref().map(|b| b.vstore.clone()),
+            args.pidfile.clone(),
+            args.log_file.clone(),
+            args.crash_file.clone(),
+            args.stats_log.clone(),
+            args.notify_socket.clone(),
+            args.restful_uri.as_ref().and_then(|u| u.socket_path()),
+            args.guest_agent.as_ref().map(|a| a.socket_path()),
+            args.timesync.as_ref().map(|t| t.socket_path()),
+        ];
+        paths
+            .files
+            .extend(files.iter().flatten().map(|p| resolve(p)));
+
+        // Sockets of the restful service, guest agent, and timesync channels are created in the
+        // temporary directory by default.
+        paths.dirs.push(resolve(&env::temp_dir()));
+        if let Some(dir) = statedir::path() {
+            paths.dirs.push(resolve(dir));
+        }
+
+        // krunkit replaces itself with a new instance to restart the VM, and restarts helpers
+        // that exit.
+        if let Ok(exe) = env::current_exe() {
+            paths.programs.push(resolve(&exe));
+        }
+        for helper in &args.helpers {
+            if let Some(program) = find_program(&helper.command[0]) {
+                paths.programs.push(resolve(&program));
+            }
+        }
+
+        paths
+    }
+
+    /// Sandbox profile, in the sandbox profile language (SBPL), allowing access to these paths.
+    fn profile(&self) -> String {
+        let mut profile = String::from(
+            "(version 1)\n\
+             (allow default)\n\
+             (deny file-read* file-write*)\n\
+             (deny process-exec*)\n\
+             (allow file-read-metadata)\n\
+             (allow file-read* (literal \"/\"))\n",
+        );
+
+        for dir in SANDBOX_READABLE_DIRS {
+            profile.push_str(&format!("(allow file-read* (subpath {}))\n", quote(dir)));
+        }
+        profile.push_str("(allow file-write* (subpath \"/dev\"))\n");
+
+        for file in &self.files {
+            profile.push_str(&format!(
+                "(allow file-read* file-write* (literal {}))\n",
+                quote(&file.to_string_lossy())
+            ));
+        }
+
+        for dir in &self.dirs {
+            profile.push_str(&format!(
+                "(allow file-read* file-write* (subpath {}))\n",
+                quote(&dir.to_string_lossy())
+            ));
+        }
+
+        for program in &self.programs {
+            let program = quote(&program.to_string_lossy());
+            profile.push_str(&format!(
+                "(allow file-read* process-exec* (literal {program}))\n"
+            ));
+        }
+
+        profile
+    }
+}
+
+/// Ensure that the sandbox mode can be applied on this host.
+pub fn check(mode: SandboxMode) -> Result<(), anyhow::Error> {
+    match (mode, cfg!(target_os = "macos")) {
+        (SandboxMode::Strict, false) => Err(anyhow!("--sandbox strict is only supported on macOS")),
+        _ => Ok(()),
+    }
+}
+
+/// Restrict the krunkit process to the paths declared on the command line. This must be done once
+/// all other files and sockets krunkit uses have been opened, before the VM runs.
+pub fn apply(mode: SandboxMode, args: &Args) -> Result<(), anyhow::Error> {
+    if mode == SandboxMode::Off {
+        return Ok(());
+    }
+
+    platform::init(&SandboxPaths::from_args(args).profile())?;
+    println!("Sandbox applied to krunkit process");
+
+    Ok(())
+}
+
+/// Restrict a process which needs no files beyond system libraries, such as the restful proxy, to
+/// reading them. The sandbox is only available on macOS; elsewhere the process is left as is.
+pub fn restrict() -> Result<(), anyhow::Error> {
+    if !cfg!(target_os = "macos") {
+        return Ok(());
+    }
+
+    platform::init(&SandboxPaths::default().profile())
+}
+
+/// Resolve a path to an absolute path with symbolic links resolved. Files which do not exist yet
+/// (such as sockets) are resolved through their parent directory.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    match (fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => env::current_dir().map(|d| d.join(path)).unwrap_or_default(),
+    }
+}
+
+/// Find a program as executed by a helper, searching PATH if it is given by name.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Quote a string as an SBPL string literal.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{
+        ffi::{c_char, CStr, CString},
+        ptr,
+    };
+
+    use anyhow::{anyhow, Context};
+
+    extern "C" {
+        fn sandbox_init(profile: *const c_char, flags: u64, errorbuf: *mut *mut c_char) -> i32;
+        fn sandbox_free_error(errorbuf: *mut c_char);
+    }
+
+    pub fn init(profile: &str) -> Result<(), anyhow::Error> {
+        let profile = CString::new(profile).context("invalid sandbox profile")?;
+
+        let mut error = ptr::null_mut();
+        if unsafe { sandbox_init(profile.as_ptr(), 0, &mut error) } == 0 {
+            return Ok(());
+        }
+
+        let message = match error.is_null() {
+            true => String::from("unknown error"),
+            false => {
+                let message = unsafe { CStr::from_ptr(error) }
+                    .to_string_lossy()
+                    .to_string();
+                unsafe { sandbox_free_error(error) };
+                message
+            }
+        };
+
+        Err(anyhow!("unable to apply sandbox: {message}"))
+    }
+}
+
+/// The sandbox is only available on macOS, which is ensured by check().
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use anyhow::anyhow;
+
+    pub fn init(_profile: &str) -> Result<(), anyhow::Error> {
+        Err(anyhow!("sandboxing is not supported on this platform"))
+    }
+}
+
+mod tests {
+    #[test]
+    fn sandbox_profile() {
+        use super::*;
+
+        assert_eq!(SandboxMode::from_str("off").unwrap(), SandboxMode::Off);
+        assert_eq!(
+            SandboxMode::from_str("strict").unwrap(),
+            SandboxMode::Strict
+        );
+        assert!(SandboxMode::from_str("relaxed").is_err());
+
+        let paths = SandboxPaths {
+            files: vec![PathBuf::from("/Users/user/disk \"1\".img")],
+            dirs: vec![PathBuf::from("/Users/user/share")],
+            programs: vec![PathBuf::from("/opt/homebrew/bin/gvproxy")],
+        };
+        let profile = paths.profile();
+
+        assert!(profile.starts_with("(version 1)\n"));
+        assert!(profile.contains(
+            "(allow file-read* file-write* (literal \"/Users/user/disk \\\"1\\\".img\"))"
+        ));
+        assert!(profile.contains("(allow file-read* file-write* (subpath \"/Users/user/share\"))"));
+        assert!(profile
+            .contains("(allow file-read* process-exec* (literal \"/opt/homebrew/bin/gvproxy\"))"));
+    }
+}
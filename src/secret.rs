@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{fmt, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Serialize, Serializer};
+
+/// Prefix of the SMBIOS OEM strings systemd imports as credentials in the guest, with the value
+/// as text or, with the binary prefix, base64-encoded.
+const CREDENTIAL_PREFIX: &str = "io.systemd.credential:";
+const CREDENTIAL_BINARY_PREFIX: &str = "io.systemd.credential.binary:";
+
+/// Where the value of a secret is read from. Values are never given on the command line, where
+/// they would be visible to other users in the process list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecretSource {
+    /// Contents of a file (@path).
+    File(PathBuf),
+
+    /// Password of a generic password item of the user's macOS Keychain, found by its service
+    /// name (keychain:service).
+    Keychain(String),
+}
+
+impl FromStr for SecretSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix('@') {
+            if !path.is_empty() {
+                return Ok(Self::File(PathBuf::from(path)));
+            }
+        }
+
+        if let Some(item) = s.strip_prefix("keychain:") {
+            if !item.is_empty() {
+                return Ok(Self::Keychain(item.to_string()));
+            }
+        }
+
+        Err(anyhow!(
+            "secrets must be read from a file (@file) or the Keychain (keychain:item), not given inline"
+        ))
+    }
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "@{}", path.display()),
+            Self::Keychain(item) => write!(f, "keychain:{item}"),
+        }
+    }
+}
+
+impl Serialize for SecretSource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl SecretSource {
+    /// Read the value of the secret.
+    pub fn read(&self) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            Self::File(path) => {
+                fs::read(path).context(format!("unable to read secret from {}", path.display()))
+            }
+            Self::Keychain(item) => platform::keychain_password(item)
+                .context(format!("unable to read secret from Keychain item {item}")),
+        }
+    }
+
+    /// Read the value of the secret as text, without a trailing newline.
+    pub fn read_string(&self) -> Result<String, anyhow::Error> {
+        let value = String::from_utf8(self.read()?).context("secret is not valid UTF-8")?;
+
+        Ok(value.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// A secret provisioned into the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretConfig {
+    /// Name of the credential the secret is imported as in the guest.
+    pub name: String,
+
+    /// Where the value is read from.
+    pub source: SecretSource,
+}
+
+impl FromStr for SecretConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, source) = s.split_once('=').ok_or(anyhow!(
+            "expected --secret argument of the form name=@file or name=keychain:item"
+        ))?;
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            return Err(anyhow!("invalid secret name: {name}"));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            source: SecretSource::from_str(source).context(format!("invalid secret {name}"))?,
+        })
+    }
+}
+
+impl fmt::Display for SecretConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.source)
+    }
+}
+
+/// Only the name and source of a secret are reported, never its value.
+impl Serialize for SecretConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl SecretConfig {
+    /// SMBIOS OEM string delivering the secret to the guest as a systemd credential. Values that
+    /// cannot be represented as a C string are base64-encoded.
+    pub fn oem_string(&self) -> Result<String, anyhow::Error> {
+        let value = self
+            .source
+            .read()
+            .context(format!("secret {}", self.name))?;
+
+        match String::from_utf8(value) {
+            Ok(value) if !value.contains('\0') => {
+                Ok(format!("{CREDENTIAL_PREFIX}{}={value}", self.name))
+            }
+            Ok(value) => Ok(format!(
+                "{CREDENTIAL_BINARY_PREFIX}{}={}",
+                self.name,
+                STANDARD.encode(value)
+            )),
+            Err(e) => Ok(format!(
+                "{CREDENTIAL_BINARY_PREFIX}{}={}",
+                self.name,
+                STANDARD.encode(e.into_bytes())
+            )),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{ffi::c_void, io, ptr, slice};
+
+    const ERR_SEC_SUCCESS: i32 = 0;
+    const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+
+    #[link(name = "Security", kind = "framework")]
+    extern "C" {
+        fn SecKeychainFindGenericPassword(
+            keychain_or_array: *const c_void,
+            service_name_length: u32,
+            service_name: *const u8,
+            account_name_length: u32,
+            account_name: *const u8,
+            password_length: *mut u32,
+            password_data: *mut *mut c_void,
+            item_ref: *mut *const c_void,
+        ) -> i32;
+        fn SecKeychainItemFreeContent(attr_list: *const c_void, data: *mut c_void) -> i32;
+    }
+
+    /// Password of the first generic password item with the given service name, in the user's
+    /// default keychain search list.
+    pub fn keychain_password(service: &str) -> io::Result<Vec<u8>> {
+        let mut length = 0;
+        let mut data = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                service.len() as u32,
+                service.as_ptr(),
+                0,
+                ptr::null(),
+                &mut length,
+                &mut data,
+                ptr::null_mut(),
+            )
+        };
+
+        match status {
+            ERR_SEC_SUCCESS => (),
+            ERR_SEC_ITEM_NOT_FOUND => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            status => {
+                return Err(io::Error::other(format!(
+                    "Keychain Services error {status}"
+                )))
+            }
+        }
+
+        let password =
+            unsafe { slice::from_raw_parts(data as *const u8, length as usize) }.to_vec();
+        unsafe { SecKeychainItemFreeContent(ptr::null(), data) };
+
+        Ok(password)
+    }
+}
+
+/// The Keychain only exists on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use std::io;
+
+    pub fn keychain_password(_service: &str) -> io::Result<Vec<u8>> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+mod tests {
+    #[test]
+    fn secret_config_parse() {
+        use super::*;
+
+        let secret = SecretConfig::from_str("registry-auth=@/Users/user/auth.json").unwrap();
+        assert_eq!(secret.name, "registry-auth");
+        assert_eq!(
+            secret.source,
+            SecretSource::File(PathBuf::from("/Users/user/auth.json"))
+        );
+        assert_eq!(secret.to_string(), "registry-auth=@/Users/user/auth.json");
+
+        let secret = SecretConfig::from_str("token=keychain:krunkit-token").unwrap();
+        assert_eq!(
+            secret.source,
+            SecretSource::Keychain(String::from("krunkit-token"))
+        );
+
+        assert!(SecretConfig::from_str("token=hunter2").is_err());
+        assert!(SecretConfig::from_str("token=@").is_err());
+        assert!(SecretConfig::from_str("token=keychain:").is_err());
+        assert!(SecretConfig::from_str("bad name=@/tmp/token").is_err());
+        assert!(SecretConfig::from_str("@/tmp/token").is_err());
+    }
+}
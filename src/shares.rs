@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    events::EventKind,
+    virtio::{FsConfig, VirtioDeviceConfig},
+    vm::VmHandle,
+};
+
+use std::{fs, os::unix::fs::MetadataExt, path::Path, sync::Arc, thread, time::Duration};
+
+/// Interval in which the shared directories are checked.
+const SHARE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Identity of a directory: the device and inode numbers it is on.
+type DirIdentity = (u64, u64);
+
+/// A change of the directory found at the path of a share, compared to the previous check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShareChange {
+    /// Nothing is found at the path anymore.
+    Missing,
+
+    /// Another directory is found at the path.
+    Replaced,
+
+    /// The directory shared when the VM started is found at the path again.
+    Restored,
+}
+
+/// Determine how the directory at the path of a share changed since the previous check, given
+/// the identity of the directory shared when the VM started.
+fn share_change(
+    original: DirIdentity,
+    previous: Option<DirIdentity>,
+    current: Option<DirIdentity>,
+) -> Option<ShareChange> {
+    if current == previous {
+        return None;
+    }
+
+    match current {
+        None => Some(ShareChange::Missing),
+        Some(id) if id == original => Some(ShareChange::Restored),
+        Some(_) => Some(ShareChange::Replaced),
+    }
+}
+
+fn dir_identity(path: &Path) -> Option<DirIdentity> {
+    let metadata = fs::metadata(path).ok()?;
+
+    metadata.is_dir().then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// Watch the host directories shared through virtio-fs devices on a new thread, publishing an
+/// event when one is moved, removed, or replaced. libkrun keeps sharing the directory it opened
+/// when the VM started, so the guest would otherwise silently lose access to the files at the
+/// path (or keep accessing the directory at its new location).
+pub fn share_watcher(vm: Arc<VmHandle>, devices: &[VirtioDeviceConfig]) {
+    let shares: Vec<(FsConfig, DirIdentity)> = devices
+        .iter()
+        .filter_map(|d| match d {
+            VirtioDeviceConfig::Fs(fs) => Some((fs.clone(), dir_identity(&fs.shared_dir)?)),
+            _ => None,
+        })
+        .collect();
+    if shares.is_empty() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut previous: Vec<Option<DirIdentity>> =
+            shares.iter().map(|(_, id)| Some(*id)).collect();
+
+        while !vm.wait_exited(SHARE_POLL_INTERVAL) {
+            for ((share, original), previous) in shares.iter().zip(previous.iter_mut()) {
+                let current = dir_identity(&share.shared_dir);
+                let Some(change) = share_change(*original, *previous, current) else {
+                    continue;
+                };
+                *previous = current;
+
+                let dir = share.shared_dir.display();
+                let tag = share.mount_tag.display();
+                let message = match change {
+                    ShareChange::Missing => format!(
+                        "shared directory {dir} (mount tag {tag}) was moved or removed, guest I/O on it may fail"
+                    ),
+                    ShareChange::Replaced => format!(
+                        "shared directory {dir} (mount tag {tag}) was replaced, the guest still sees the directory shared when the VM started"
+                    ),
+                    ShareChange::Restored => {
+                        format!("shared directory {dir} (mount tag {tag}) is back in place")
+                    }
+                };
+                vm.events.publish(EventKind::SharedDirChanged, message);
+            }
+        }
+    });
+}
+
+mod tests {
+    #[test]
+    fn shared_dir_change() {
+        use super::*;
+
+        let original = (1, 100);
+        let other = (1, 200);
+
+        assert_eq!(share_change(original, Some(original), Some(original)), None);
+        assert_eq!(
+            share_change(original, Some(original), None),
+            Some(ShareChange::Missing)
+        );
+        assert_eq!(share_change(original, None, None), None);
+        assert_eq!(
+            share_change(original, None, Some(other)),
+            Some(ShareChange::Replaced)
+        );
+        assert_eq!(share_change(original, Some(other), Some(other)), None);
+        assert_eq!(
+            share_change(original, Some(other), Some(original)),
+            Some(ShareChange::Restored)
+        );
+    }
+}
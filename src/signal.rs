@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::vm::{VmHandle, GUEST_SHUTDOWN_TIMEOUT};
+
+use std::{io, mem, os::unix::process::CommandExt, process::Command, ptr, sync::Arc, thread};
+
+use anyhow::anyhow;
+
+/// Signals that request the VM to be shut down.
+const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+fn shutdown_sigset() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for sig in SHUTDOWN_SIGNALS {
+            libc::sigaddset(&mut set, sig);
+        }
+
+        set
+    }
+}
+
+/// Block the shutdown signals in the calling thread. Threads inherit the signal mask of their
+/// creator, so this must be called before any other thread (including those created by libkrun)
+/// is spawned, ensuring the signals are only ever received by the signal listener.
+pub fn block_shutdown_signals() -> Result<(), anyhow::Error> {
+    let set = shutdown_sigset();
+
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if ret != 0 {
+        return Err(anyhow!(
+            "unable to block shutdown signals: {}",
+            io::Error::from_raw_os_error(ret)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Unblock the shutdown signals in the process a command spawns. The signal mask is inherited
+/// across fork and exec, so the program would otherwise not be terminated by them, such as when
+/// krunkit stops a helper.
+pub fn unblock_shutdown_signals(command: &mut Command) {
+    let set = shutdown_sigset();
+
+    unsafe {
+        command.pre_exec(move || {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+            Ok(())
+        });
+    }
+}
+
+/// Wait for shutdown signals on a new thread. The first signal shuts the VM down gracefully (if
+/// possible) and forcefully if the guest does not power off in time. Any subsequent signal stops
+/// the VM immediately.
+pub fn signal_listener(vm: Arc<VmHandle>) {
+    thread::spawn(move || {
+        let set = shutdown_sigset();
+        let mut received = false;
+
+        loop {
+            let mut sig: libc::c_int = 0;
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                continue;
+            }
+
+            if received {
+                println!("Received signal {sig} again, stopping VM");
+                if let Err(e) = vm.stop() {
+                    println!("Error stopping VM: {e}");
+                }
+                continue;
+            }
+            received = true;
+
+            println!("Received signal {sig}, shutting down VM");
+            let vm = vm.clone();
+            thread::spawn(move || {
+                if let Err(e) = vm.stop_gracefully(GUEST_SHUTDOWN_TIMEOUT) {
+                    println!("Error shutting down VM: {e}");
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::agent::GuestAgent;
+
+use std::{fs, io, path::Path};
+
+use anyhow::{anyhow, Context};
+
+/// Write a consistent copy of a disk image of the running VM. The guest's filesystems are frozen
+/// first, which flushes them to the disk (and has libkrun flush the image to the host file), and
+/// are thawed once the image has been copied, whether or not the copy succeeded.
+///
+/// The copy is a clone of the image where the host filesystem supports it (APFS), so that it is
+/// nearly instant and the guest is frozen as briefly as possible.
+pub fn snapshot_disk(
+    agent: &GuestAgent,
+    image: &Path,
+    destination: &Path,
+) -> Result<String, anyhow::Error> {
+    if destination.exists() {
+        return Err(anyhow!("{} already exists", destination.display()));
+    }
+
+    let frozen = agent
+        .freeze_filesystems()
+        .context("unable to freeze guest filesystems")?;
+
+    let copied = copy_image(image, destination);
+
+    let thawed = agent.thaw_filesystems();
+    let method = copied.context(format!(
+        "unable to copy {} to {}",
+        image.display(),
+        destination.display()
+    ))?;
+    thawed.context("snapshot written, but unable to thaw guest filesystems")?;
+
+    Ok(format!(
+        "{} {} to {} with {frozen} guest filesystem(s) frozen",
+        method,
+        image.display(),
+        destination.display()
+    ))
+}
+
+/// Clone the image if possible, or copy it otherwise. Returns how it was copied.
+fn copy_image(image: &Path, destination: &Path) -> Result<&'static str, io::Error> {
+    if clone_image(image, destination)? {
+        return Ok("cloned");
+    }
+
+    fs::copy(image, destination)?;
+
+    Ok("copied")
+}
+
+/// Clone an image, sharing its blocks with the destination. Returns false if the filesystem does
+/// not support it.
+pub fn clone_image(image: &Path, destination: &Path) -> Result<bool, io::Error> {
+    match platform::clone_file(image, destination) {
+        Ok(()) => Ok(true),
+        Err(e) if !is_clone_unsupported(&e) => Err(e),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Indicate if cloning failed because the filesystem (or the pair of filesystems) does not
+/// support it, rather than because of the files themselves.
+fn is_clone_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOTSUP) | Some(libc::EXDEV) | Some(libc::ENOSYS)
+    ) || e.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+    /// Clone a file with clonefile(2), which shares the blocks of the file on APFS.
+    pub fn clone_file(source: &Path, destination: &Path) -> io::Result<()> {
+        let source = CString::new(source.as_os_str().as_bytes())?;
+        let destination = CString::new(destination.as_os_str().as_bytes())?;
+
+        match unsafe { libc::clonefile(source.as_ptr(), destination.as_ptr(), 0) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Files are only cloned on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use std::{io, path::Path};
+
+    pub fn clone_file(_source: &Path, _destination: &Path) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::operation::now;
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// State of the VM in its lifecycle. libkrun cannot pause a running VM, so there are no paused
+/// states.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum VmState {
+    /// The krun context is being configured from the command line arguments.
+    Configuring,
+
+    /// The VM is configured, and krunkit is starting the services and helpers it needs.
+    Starting,
+
+    /// The vCPUs are running.
+    Running,
+
+    /// The VM is being shut down or stopped by the host.
+    Stopping,
+
+    /// The VM exited normally.
+    Stopped,
+
+    /// The VM terminated abnormally, or the guest kernel panicked.
+    Crashed,
+}
+
+impl VmState {
+    /// Name of the state reported by GET /vm/state, as reported by vfkit.
+    pub fn vfkit_name(&self) -> &'static str {
+        match self {
+            Self::Configuring | Self::Starting => "VirtualMachineStateStarting",
+            Self::Running => "VirtualMachineStateRunning",
+            Self::Stopping => "VirtualMachineStateStopping",
+            Self::Stopped => "VirtualMachineStateStopped",
+            Self::Crashed => "VirtualMachineStateError",
+        }
+    }
+
+    /// Indicate if the VM can go from this state to the given one. Stopped and Crashed are final.
+    fn can_become(&self, next: VmState) -> bool {
+        use VmState::*;
+
+        matches!(
+            (self, next),
+            (Configuring, Starting)
+                | (Starting, Running | Stopping | Crashed)
+                | (Running, Stopping | Stopped | Crashed)
+                | (Stopping, Stopped | Crashed)
+        )
+    }
+}
+
+/// A change of the VM's state.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateTransition {
+    pub state: VmState,
+
+    /// Seconds since the UNIX epoch at which the VM entered the state.
+    pub time: u64,
+
+    /// Why the VM entered the state.
+    pub reason: String,
+}
+
+/// Current state of the VM, and the states it went through, oldest first.
+#[derive(Debug)]
+pub struct StateHistory {
+    transitions: Mutex<Vec<StateTransition>>,
+}
+
+/// The history of a VM being configured.
+impl Default for StateHistory {
+    fn default() -> Self {
+        Self {
+            transitions: Mutex::new(vec![StateTransition {
+                state: VmState::Configuring,
+                time: now(),
+                reason: String::from("krunkit started"),
+            }]),
+        }
+    }
+}
+
+impl StateHistory {
+    /// Move the VM to a new state, writing the transition to the log. Returns false, leaving the
+    /// state unchanged, if the VM cannot go to that state from its current one (for example, once
+    /// it is already stopping).
+    pub fn set(&self, state: VmState, reason: impl Into<String>) -> bool {
+        let mut transitions = self.transitions.lock().unwrap();
+
+        let current = transitions.last().map(|t| t.state);
+        if !current.is_some_and(|c| c.can_become(state)) {
+            return false;
+        }
+
+        let transition = StateTransition {
+            state,
+            time: now(),
+            reason: reason.into(),
+        };
+        println!(
+            "VM state changed to {:?}: {}",
+            transition.state, transition.reason
+        );
+        transitions.push(transition);
+
+        true
+    }
+
+    pub fn current(&self) -> VmState {
+        self.transitions
+            .lock()
+            .unwrap()
+            .last()
+            .map(|t| t.state)
+            .unwrap_or(VmState::Configuring)
+    }
+
+    pub fn transitions(&self) -> Vec<StateTransition> {
+        self.transitions.lock().unwrap().clone()
+    }
+}
+
+mod tests {
+    #[test]
+    fn state_transitions() {
+        use super::*;
+
+        let history = StateHistory::default();
+        assert_eq!(history.current(), VmState::Configuring);
+        assert!(!history.set(VmState::Running, "not started yet"));
+
+        assert!(history.set(VmState::Starting, "configured"));
+        assert!(history.set(VmState::Running, "vCPUs started"));
+        assert!(history.set(VmState::Stopping, "stop requested"));
+        assert!(!history.set(VmState::Stopping, "stop requested again"));
+        assert!(history.set(VmState::Stopped, "VM stopped by host"));
+        assert!(!history.set(VmState::Running, "already stopped"));
+
+        let states: Vec<VmState> = history.transitions().iter().map(|t| t.state).collect();
+        assert_eq!(
+            states,
+            [
+                VmState::Configuring,
+                VmState::Starting,
+                VmState::Running,
+                VmState::Stopping,
+                VmState::Stopped
+            ]
+        );
+        assert_eq!(history.current().vfkit_name(), "VirtualMachineStateStopped");
+    }
+}
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{cmdline::Args, timesync::SOCKET_PATH_MAX};
+
+use std::{
+    env, fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Context};
+
+/// Name of the pidfile written to the state directory if --pidfile is not specified.
+const PIDFILE: &str = "krunkit.pid";
+
+/// Name of the file the console output is saved to if the guest kernel panics, if --crash-file is
+/// not specified.
+const CRASH_FILE: &str = "crash.log";
+
+/// Name of the EFI variable store, if --bootloader is not specified.
+const EFI_VARIABLE_STORE: &str = "efi-variable-store";
+
+/// Longest name of a socket created in the state directory (that of a vsock restful URI).
+const LONGEST_SOCKET_NAME: &str = "restful-4294967295.sock";
+
+/// Directory of the VM's runtime files, once resolved from the command line arguments.
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Parse a VM name, which is used as the name of its state directory.
+pub fn name_parse(s: &str) -> Result<String, anyhow::Error> {
+    if s.is_empty() || s.starts_with('.') {
+        return Err(anyhow!("VM name cannot be empty or start with a dot"));
+    }
+    if !s
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(anyhow!(
+            "invalid VM name {s} (only letters, digits, '.', '_' and '-' are allowed)"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Directory the state directories of named VMs are created in.
+#[cfg(target_os = "macos")]
+fn base_dir() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+
+    Some(PathBuf::from(home).join("Library/Application Support/krunkit"))
+}
+
+/// Directory the state directories of named VMs are created in.
+#[cfg(not(target_os = "macos"))]
+fn base_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("krunkit")),
+        _ => Some(PathBuf::from(env::var_os("HOME")?).join(".local/state/krunkit")),
+    }
+}
+
+/// Resolve the state directory from --state-dir, or from --name if not specified, and have the
+/// files krunkit keeps for the VM default to paths in it. Without either option, there is no state
+/// directory and runtime files are created in the temporary directory.
+pub fn resolve(args: &mut Args) -> Result<(), anyhow::Error> {
+    let dir = match (&args.state_dir, &args.name) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(name)) => base_dir()
+            .ok_or(anyhow!("unable to find the home directory for --name"))?
+            .join(name),
+        (None, None) => return Ok(()),
+    };
+
+    let dir = match dir.is_absolute() {
+        true => dir,
+        false => env::current_dir()
+            .context("unable to resolve relative state directory")?
+            .join(dir),
+    };
+
+    let socket_len = dir.join(LONGEST_SOCKET_NAME).as_os_str().len();
+    if socket_len >= SOCKET_PATH_MAX {
+        return Err(anyhow!(
+            "state directory {} is too long for socket paths ({socket_len} bytes, at most {})",
+            dir.display(),
+            SOCKET_PATH_MAX - 1
+        ));
+    }
+
+    if args.pidfile.is_none() {
+        args.pidfile = Some(dir.join(PIDFILE));
+    }
+    if args.crash_file.is_none() {
+        args.crash_file = Some(dir.join(CRASH_FILE));
+    }
+    if args.bootloader.is_none() {
+        let vstore = dir.join(EFI_VARIABLE_STORE);
+        args.bootloader = Some(FromStr::from_str(&format!(
+            "efi,variable-store={},create",
+            vstore.display()
+        ))?);
+    }
+
+    let _ = STATE_DIR.set(dir);
+
+    Ok(())
+}
+
+/// Create the state directory (only accessible to the user) if it does not exist. Fails if
+/// another krunkit instance is using it, and otherwise removes the sockets left behind by an
+/// instance that did not exit cleanly.
+pub fn create() -> Result<(), anyhow::Error> {
+    let Some(dir) = path() else {
+        return Ok(());
+    };
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .context(format!(
+            "unable to create state directory {}",
+            dir.display()
+        ))?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).context(format!(
+        "unable to set permissions of state directory {}",
+        dir.display()
+    ))?;
+
+    if let Some(pid) = running_instance(dir) {
+        return Err(anyhow!(
+            "state directory {} is in use by krunkit instance {pid}",
+            dir.display()
+        ));
+    }
+
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "sock") {
+            fs::remove_file(&path)
+                .context(format!("unable to remove stale socket {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Process ID of another running krunkit instance whose pidfile is in the state directory.
+fn running_instance(dir: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(dir.join(PIDFILE))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    // krunkit replaces itself with a new instance to restart the VM, keeping its process ID.
+    if pid == std::process::id() {
+        return None;
+    }
+
+    match unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        true => Some(pid),
+        false => None,
+    }
+}
+
+/// The state directory, if any.
+pub fn path() -> Option<&'static PathBuf> {
+    STATE_DIR.get()
+}
+
+/// Path of a UNIX socket created by krunkit in the state directory, if any.
+pub fn socket_path(name: &str) -> Option<PathBuf> {
+    path().map(|dir| dir.join(format!("{name}.sock")))
+}
+
+mod tests {
+    #[test]
+    fn vm_name_parse() {
+        use super::*;
+
+        assert_eq!(
+            name_parse("podman-machine-default").unwrap(),
+            "podman-machine-default"
+        );
+        assert!(name_parse("fedora_41.x86").is_ok());
+        assert!(name_parse("").is_err());
+        assert!(name_parse("..").is_err());
+        assert!(name_parse("a/b").is_err());
+        assert!(name_parse("my vm").is_err());
+    }
+}
@@ -0,0 +1,371 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{limits, vm::VmHandle};
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// Prefix of the names libkrun gives its vCPU threads, followed by the index of the vCPU.
+pub const VCPU_THREAD_PREFIX: &str = "fc_vcpu";
+
+/// Host-side resource usage of the krunkit process and the vCPUs of the VM.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostStats {
+    /// Time of the sample, in seconds since the UNIX epoch.
+    pub time: u64,
+
+    /// User and system CPU time consumed by the krunkit process, in milliseconds.
+    pub cpu_time_ms: u64,
+
+    /// CPU usage of the krunkit process since the previous sample, in percent of a host CPU.
+    pub cpu_percent: f64,
+
+    /// Memory of the krunkit process resident in host RAM, including the guest's.
+    pub resident_bytes: u64,
+
+    /// Memory of the krunkit process that has been written to and cannot be reclaimed without
+    /// being swapped out (the physical footprint on macOS).
+    pub dirty_bytes: u64,
+
+    /// Usage of each vCPU, ordered by index.
+    pub vcpus: Vec<VcpuStats>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VcpuStats {
+    pub index: u32,
+    pub cpu_time_ms: u64,
+    pub cpu_percent: f64,
+}
+
+/// CPU time of a thread of the krunkit process.
+struct ThreadTime {
+    name: String,
+    cpu_time: Duration,
+}
+
+/// Most recent sample of the host-side resource usage, shared with the restful service.
+#[derive(Debug, Default)]
+pub struct StatsSampler {
+    latest: Mutex<Option<HostStats>>,
+}
+
+impl StatsSampler {
+    /// The most recent sample, if one has been taken.
+    pub fn latest(&self) -> Option<HostStats> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    fn set(&self, stats: HostStats) {
+        *self.latest.lock().unwrap() = Some(stats);
+    }
+}
+
+/// Sample the host-side resource usage of the VM at an interval on a new thread until the VM
+/// exits, appending each sample to a CSV file if given. The file is opened before the VM runs, so
+/// that it is not subject to the sandbox.
+pub fn stats_sampler(
+    vm: Arc<VmHandle>,
+    interval: Duration,
+    cpus: u8,
+    log: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let mut log = log.map(|path| StatsLog::open(path, cpus)).transpose()?;
+
+    thread::spawn(move || {
+        let mut previous: Option<(Instant, HostStats)> = None;
+
+        loop {
+            let stats = sample(previous.as_ref());
+
+            if let Some(log) = &mut log {
+                if let Err(e) = log.write(&stats) {
+                    println!("Error writing stats log: {e:#}");
+                }
+            }
+
+            vm.stats.set(stats.clone());
+            previous = Some((Instant::now(), stats));
+
+            if vm.wait_exited(interval) {
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Take a sample, computing CPU usage since the previous one.
+fn sample(previous: Option<&(Instant, HostStats)>) -> HostStats {
+    let (resident_bytes, dirty_bytes) = platform::memory();
+    let cpu_time_ms = limits::cpu_time().as_millis() as u64;
+
+    let mut vcpus: Vec<VcpuStats> = platform::thread_times()
+        .into_iter()
+        .filter_map(|thread| {
+            let index = thread
+                .name
+                .strip_prefix(VCPU_THREAD_PREFIX)?
+                .trim()
+                .parse()
+                .ok()?;
+
+            Some(VcpuStats {
+                index,
+                cpu_time_ms: thread.cpu_time.as_millis() as u64,
+                cpu_percent: 0.0,
+            })
+        })
+        .collect();
+    vcpus.sort_by_key(|v| v.index);
+
+    let mut stats = HostStats {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        cpu_time_ms,
+        cpu_percent: 0.0,
+        resident_bytes,
+        dirty_bytes,
+        vcpus,
+    };
+
+    if let Some((at, previous)) = previous {
+        let elapsed_ms = at.elapsed().as_millis() as f64;
+        let percent = |now: u64, before: u64| match elapsed_ms > 0.0 {
+            true => (now.saturating_sub(before) as f64 * 100.0 / elapsed_ms * 10.0).round() / 10.0,
+            false => 0.0,
+        };
+
+        stats.cpu_percent = percent(stats.cpu_time_ms, previous.cpu_time_ms);
+        for vcpu in &mut stats.vcpus {
+            let before = previous
+                .vcpus
+                .iter()
+                .find(|v| v.index == vcpu.index)
+                .map(|v| v.cpu_time_ms)
+                .unwrap_or(0);
+            vcpu.cpu_percent = percent(vcpu.cpu_time_ms, before);
+        }
+    }
+
+    stats
+}
+
+/// CSV file samples are appended to, with a column for the CPU usage of each vCPU.
+struct StatsLog {
+    file: File,
+    cpus: u8,
+}
+
+impl StatsLog {
+    /// Open the file for appending, writing the header if it is empty. A restarted VM appends to
+    /// the log of the previous instance.
+    fn open(path: &Path, cpus: u8) -> Result<Self, anyhow::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("unable to open stats log {}", path.display()))?;
+
+        if file.metadata().map(|m| m.len() == 0).unwrap_or(false) {
+            let vcpus: String = (0..cpus).map(|i| format!(",vcpu{i}_cpu_percent")).collect();
+            writeln!(
+                file,
+                "time,cpu_time_ms,cpu_percent,resident_bytes,dirty_bytes{vcpus}"
+            )
+            .context(format!("unable to write stats log {}", path.display()))?;
+        }
+
+        Ok(Self { file, cpus })
+    }
+
+    fn write(&mut self, stats: &HostStats) -> Result<(), anyhow::Error> {
+        writeln!(self.file, "{}", csv_line(stats, self.cpus)).context("unable to append sample")
+    }
+}
+
+/// A sample as a line of the stats log. vCPUs without a thread yet are left empty.
+fn csv_line(stats: &HostStats, cpus: u8) -> String {
+    let vcpus: String = (0..cpus as u32)
+        .map(|i| match stats.vcpus.iter().find(|v| v.index == i) {
+            Some(vcpu) => format!(",{}", vcpu.cpu_percent),
+            None => String::from(","),
+        })
+        .collect();
+
+    format!(
+        "{},{},{},{},{}{vcpus}",
+        stats.time, stats.cpu_time_ms, stats.cpu_percent, stats.resident_bytes, stats.dirty_bytes
+    )
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::ThreadTime;
+
+    use std::{ffi::CStr, mem, time::Duration};
+
+    /// proc_pidinfo(2) flavor listing the threads of a process, not defined by the libc crate.
+    const PROC_PIDLISTTHREADS: libc::c_int = 6;
+
+    /// Resident size and physical footprint of the krunkit process.
+    pub fn memory() -> (u64, u64) {
+        let mut info: libc::rusage_info_v2 = unsafe { mem::zeroed() };
+        let ret = unsafe {
+            libc::proc_pid_rusage(
+                libc::getpid(),
+                libc::RUSAGE_INFO_V2,
+                &mut info as *mut libc::rusage_info_v2 as *mut libc::rusage_info_t,
+            )
+        };
+
+        match ret {
+            0 => (info.ri_resident_size, info.ri_phys_footprint),
+            _ => (0, 0),
+        }
+    }
+
+    /// CPU time of each thread of the krunkit process.
+    pub fn thread_times() -> Vec<ThreadTime> {
+        let pid = unsafe { libc::getpid() };
+
+        let mut handles = vec![0u64; 256];
+        let size = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                PROC_PIDLISTTHREADS,
+                0,
+                handles.as_mut_ptr() as *mut libc::c_void,
+                (handles.len() * mem::size_of::<u64>()) as libc::c_int,
+            )
+        };
+        if size <= 0 {
+            return Vec::new();
+        }
+        handles.truncate(size as usize / mem::size_of::<u64>());
+
+        handles
+            .into_iter()
+            .filter_map(|handle| {
+                let mut info: libc::proc_threadinfo = unsafe { mem::zeroed() };
+                let size = mem::size_of::<libc::proc_threadinfo>() as libc::c_int;
+                let ret = unsafe {
+                    libc::proc_pidinfo(
+                        pid,
+                        libc::PROC_PIDTHREADINFO,
+                        handle,
+                        &mut info as *mut libc::proc_threadinfo as *mut libc::c_void,
+                        size,
+                    )
+                };
+                if ret != size {
+                    return None;
+                }
+
+                let name = unsafe { CStr::from_ptr(info.pth_name.as_ptr()) };
+
+                // Thread times are reported in nanoseconds.
+                Some(ThreadTime {
+                    name: name.to_string_lossy().to_string(),
+                    cpu_time: Duration::from_nanos(info.pth_user_time + info.pth_system_time),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Usage is read from procfs on Linux.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::ThreadTime;
+
+    use std::{fs, time::Duration};
+
+    /// Resident size and dirty memory of the krunkit process.
+    pub fn memory() -> (u64, u64) {
+        let Ok(rollup) = fs::read_to_string("/proc/self/smaps_rollup") else {
+            return (0, 0);
+        };
+
+        let field = |name: &str| -> u64 {
+            rollup
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kib| kib * 1024)
+                .unwrap_or(0)
+        };
+
+        (
+            field("Rss:"),
+            field("Private_Dirty:") + field("Shared_Dirty:"),
+        )
+    }
+
+    /// CPU time of each thread of the krunkit process.
+    pub fn thread_times() -> Vec<ThreadTime> {
+        let Ok(tasks) = fs::read_dir("/proc/self/task") else {
+            return Vec::new();
+        };
+
+        tasks
+            .flatten()
+            .filter_map(|task| {
+                let name = fs::read_to_string(task.path().join("comm")).ok()?;
+                let stat = fs::read_to_string(task.path().join("stat")).ok()?;
+
+                Some(ThreadTime {
+                    name: name.trim().to_string(),
+                    cpu_time: stat_cpu_time(&stat)?,
+                })
+            })
+            .collect()
+    }
+
+    /// CPU time from the utime and stime fields of a stat file, which follow the command name.
+    fn stat_cpu_time(stat: &str) -> Option<Duration> {
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+
+        let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+
+        Some(Duration::from_millis(ticks * 1000 / hz.max(1) as u64))
+    }
+}
+
+mod tests {
+    #[test]
+    fn stats_csv_line() {
+        use super::*;
+
+        let stats = HostStats {
+            time: 1718000000,
+            cpu_time_ms: 1500,
+            cpu_percent: 12.5,
+            resident_bytes: 4096,
+            dirty_bytes: 2048,
+            vcpus: vec![VcpuStats {
+                index: 1,
+                cpu_time_ms: 700,
+                cpu_percent: 50.0,
+            }],
+        };
+
+        assert_eq!(csv_line(&stats, 2), "1718000000,1500,12.5,4096,2048,,50");
+    }
+}
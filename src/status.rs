@@ -1,78 +1,279 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::{
+    boot,
+    cleanup::{self, Resource},
+    config::VmConfig,
+    connection::connection,
+    console::console_log_path,
+    hostpower,
+    libkrun::{self, libkrun},
+    operation::Operation,
+    otel,
+    privsep::spawn_proxy,
+    snapshot::snapshot_disk,
+    state::VmState,
+    statedir,
+    virtio::{KrunContextSet, VirtioDeviceConfig},
+    vm::{VmHandle, GUEST_SHUTDOWN_TIMEOUT},
+    websocket::{console_websocket, handshake_response, WebSocketStream},
+};
+
 use std::{
-    fs::File,
-    io::{Read, Write},
-    net::{Ipv4Addr, TcpListener},
-    os::fd::{FromRawFd, RawFd},
+    env,
+    ffi::CString,
+    fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv6Addr, TcpListener, ToSocketAddrs},
+    os::unix::{
+        ffi::OsStrExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process,
     str::FromStr,
+    sync::Arc,
+    thread,
+    time::SystemTime,
 };
 
 use anyhow::{anyhow, Context};
-use clap::Parser;
+use serde::{Deserialize, Serialize, Serializer};
 
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32;
-}
+const HTTP_GUEST_PANICKED: &str =
+    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateGuestPanicked\"}\0";
+
+const HTTP_REBOOTING: &str =
+    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateRebooting\"}\0";
 
-const HTTP_RUNNING: &str =
-    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateRunning\"}\0";
+const HTTP_SWITCHING_PROTOCOLS: &str = "HTTP/1.1 101 ";
 
-const HTTP_STOPPING: &str =
-    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateStopping\"}\0";
+/// Largest request read from a client of the restful service, headers included.
+const REQUEST_SIZE_MAX: usize = 64 * 1024;
 
-/// Socket address in which the restful URI socket should listen on. Identical to Rust's
-/// SocketAddrV4, but requires a modified FromStr implementation due to how the address is
-/// presented on the command line.
-#[derive(Clone, Debug, Parser)]
-pub struct RestfulUriAddr {
-    pub ip_addr: Ipv4Addr,
-    pub port: u16,
+/// Endpoints served by the restful service, as reported by krunkit capabilities.
+pub const RESTFUL_ENDPOINTS: [&str; 16] = [
+    "GET /vm/state",
+    "GET /vm/state/history",
+    "GET /vm/inspect",
+    "GET /vm/console",
+    "GET /vm/console/ws",
+    "GET /vm/guest/stats",
+    "GET /vm/connection",
+    "GET /vm/health",
+    "GET /vm/host/power",
+    "GET /vm/stats/boot",
+    "GET /vm/stats/host",
+    "GET /vm/events",
+    "GET /vm/operations",
+    "GET /vm/operations/{id}",
+    "POST /vm/state",
+    "POST /vm/disks/{id}/snapshot",
+];
+
+/// Number of lines of console output returned if not specified by the client.
+const DEFAULT_CONSOLE_LINES: usize = 100;
+
+/// URI in which the restful service should listen on. Hostnames are stored as given and resolved
+/// when the listener is bound, allowing for IPv4 and IPv6 literals as well as names.
+///
+/// A vsock URI exposes the service to the guest on the given vsock port rather than to the host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestfulUri {
+    Tcp { host: String, port: u16 },
+    Vsock { port: u32 },
 }
 
-impl FromStr for RestfulUriAddr {
+impl FromStr for RestfulUri {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut string = String::from(s);
+        if let Some(port) = s.strip_prefix("vsock://") {
+            let port =
+                u32::from_str(port).context("restful URI vsock port formatted incorrectly")?;
 
-        if let Some(removed) = string.strip_prefix("tcp://") {
-            string = String::from(removed);
+            return Ok(Self::Vsock { port });
         }
 
-        let mut parts: Vec<String> = string.split(':').map(|s| s.to_string()).collect();
-        if parts.len() != 2 {
-            return Err(anyhow!("restful URI formatted incorrectly"));
-        }
+        let string = s.strip_prefix("tcp://").unwrap_or(s);
 
-        // Ipv4Address's FromStr does not understand that the "localhost" IP address translates to
-        // 127.0.0.1, this must be manually translated.
-        if &parts[0][..] == "localhost" {
-            parts[0] = String::from("127.0.0.1");
-        }
+        // IPv6 literals are enclosed in brackets to separate the address from the port number
+        // (for example, [::1]:8081).
+        let (host, port) = if let Some(rest) = string.strip_prefix('[') {
+            let (host, port) = rest
+                .split_once("]:")
+                .ok_or_else(|| anyhow!("restful URI formatted incorrectly"))?;
+            Ipv6Addr::from_str(host).context("restful URI IPv6 address formatted incorrectly")?;
+
+            (host, port)
+        } else {
+            let (host, port) = string
+                .split_once(':')
+                .ok_or_else(|| anyhow!("restful URI formatted incorrectly"))?;
+            if host.is_empty() || port.contains(':') {
+                return Err(anyhow!("restful URI host formatted incorrectly"));
+            }
+
+            (host, port)
+        };
 
-        let ip_addr = Ipv4Addr::from_str(&parts[0])
-            .context("restful URI IP address formatted incorrectly")?;
-        let port =
-            u16::from_str(&parts[1]).context("restful URI port number formatted incorrectly")?;
+        // "localhost" could resolve to either the IPv4 or IPv6 loopback address. Clients expect
+        // the IPv4 address, so it must be manually translated.
+        let host = match host {
+            "localhost" => String::from("127.0.0.1"),
+            _ => host.to_string(),
+        };
+        let port = u16::from_str(port).context("restful URI port number formatted incorrectly")?;
 
-        Ok(Self { ip_addr, port })
+        Ok(Self::Tcp { host, port })
     }
 }
 
-impl Default for RestfulUriAddr {
+impl fmt::Display for RestfulUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp { host, port } if host.contains(':') => write!(f, "tcp://[{host}]:{port}"),
+            Self::Tcp { host, port } => write!(f, "tcp://{host}:{port}"),
+            Self::Vsock { port } => write!(f, "vsock://{port}"),
+        }
+    }
+}
+
+impl Serialize for RestfulUri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Default for RestfulUri {
     fn default() -> Self {
-        Self {
-            ip_addr: Ipv4Addr::new(127, 0, 0, 1),
+        Self::Tcp {
+            host: String::from("127.0.0.1"),
             port: 8081,
         }
     }
 }
 
+/// Expose the restful service to the guest. libkrun proxies connections made by the guest on the
+/// vsock port to a UNIX socket on the host, which the status listener accepts connections from.
+impl KrunContextSet for RestfulUri {
+    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+        let Self::Vsock { port } = self else {
+            return Ok(());
+        };
+
+        let path = vsock_socket_path(*port);
+        let path_cstr = CString::new(path.as_os_str().as_bytes())
+            .context("unable to convert restful URI socket path into C string")?;
+
+        libkrun::check((libkrun().krun_add_vsock_port)(
+            id,
+            *port,
+            path_cstr.as_ptr(),
+        ))
+        .context(format!(
+            "unable to add restful URI vsock port {} for path {}",
+            port,
+            path.display()
+        ))?;
+
+        Ok(())
+    }
+}
+
+impl RestfulUri {
+    /// Ensure that the service is only exposed beyond the host if explicitly allowed, as it is not
+    /// authenticated. Hostnames are resolved, and must only resolve to loopback addresses.
+    pub fn check_bind(&self, insecure: bool) -> Result<(), anyhow::Error> {
+        let Self::Tcp { host, port } = self else {
+            return Ok(());
+        };
+        if insecure {
+            return Ok(());
+        }
+
+        let addrs: Vec<_> = (host.as_str(), *port)
+            .to_socket_addrs()
+            .context(format!("unable to resolve restful URI host {host}"))?
+            .collect();
+        if addrs.is_empty() || addrs.iter().any(|a| !a.ip().is_loopback()) {
+            return Err(anyhow!(
+                "restful URI {self} is not a loopback address (use --restful-insecure-bind to allow)"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Path of the host UNIX socket created for the restful service, if any.
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        match self {
+            Self::Tcp { .. } => None,
+            Self::Vsock { port } => Some(vsock_socket_path(*port)),
+        }
+    }
+}
+
+/// Path of the host UNIX socket backing a vsock restful URI, in the state directory if krunkit has
+/// one. Otherwise it is in the temporary directory, with the process ID included so that multiple
+/// krunkit instances can expose the service on the same guest port.
+fn vsock_socket_path(port: u32) -> PathBuf {
+    statedir::socket_path(&format!("restful-{port}")).unwrap_or_else(|| {
+        env::temp_dir().join(format!("krunkit-restful-{}-{}.sock", process::id(), port))
+    })
+}
+
+/// Requests clients of the restful service are allowed to make.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RestfulAccess {
+    /// Only retrieve the VM's state and information (GET requests).
+    StatusOnly,
+
+    /// Also change the VM's state, such as stopping it.
+    #[default]
+    FullControl,
+}
+
+impl RestfulAccess {
+    /// Indicate if requests with the given method are allowed.
+    fn allows(&self, method: &str) -> bool {
+        match self {
+            Self::StatusOnly => method == "GET",
+            Self::FullControl => true,
+        }
+    }
+}
+
+impl FromStr for RestfulAccess {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "status-only" => Ok(Self::StatusOnly),
+            "full-control" => Ok(Self::FullControl),
+            _ => Err(anyhow!("invalid --restful-access option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for RestfulAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::StatusOnly => write!(f, "status-only"),
+            Self::FullControl => write!(f, "full-control"),
+        }
+    }
+}
+
+impl Serialize for RestfulAccess {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 /// Retrieve the shutdown event file descriptor initialized by libkrun.
 pub unsafe fn get_shutdown_eventfd(ctx_id: u32) -> i32 {
-    let fd = krun_get_shutdown_eventfd(ctx_id);
+    let fd = (libkrun().krun_get_shutdown_eventfd)(ctx_id);
     if fd < 0 {
         panic!("unable to retrieve krun shutdown file descriptor");
     }
@@ -80,41 +281,680 @@ pub unsafe fn get_shutdown_eventfd(ctx_id: u32) -> i32 {
 }
 
 /// Listen for status and shutdown requests from the client. Shut down the krun VM when prompted.
+/// If a token is given, clients must present it as a bearer token.
 pub fn status_listener(
-    shutdown_eventfd: RawFd,
-    addr: Option<RestfulUriAddr>,
+    vm: Arc<VmHandle>,
+    mut config: VmConfig,
+    token: Option<String>,
 ) -> Result<(), anyhow::Error> {
-    // VM is shut down by writing to the shutdown event file.
-    let mut shutdown = unsafe { File::from_raw_fd(shutdown_eventfd) };
-
-    let addr = addr.unwrap_or_default();
-
-    let listener = TcpListener::bind((addr.ip_addr, addr.port)).unwrap();
-
-    for stream in listener.incoming() {
-        let mut buf = [0u8; 4096];
-        let mut stream = stream.unwrap();
-
-        match stream.read(&mut buf) {
-            Ok(_sz) => {
-                let request = String::from_utf8_lossy(&buf);
-                if request.contains("POST") {
-                    // Send a VirtualMachineStateStopping message to the client.
-                    if let Err(e) = stream.write_all(HTTP_STOPPING.as_bytes()) {
-                        println!("Error writting POST response: {e}");
-                    }
-
-                    // Shut down the VM.
-                    if let Err(e) = shutdown.write_all(&1u64.to_le_bytes()) {
-                        println!("Error writting to shutdown fd: {e}");
-                    }
-                } else if let Err(e) = stream.write_all(HTTP_RUNNING.as_bytes()) {
-                    println!("Error writting GET response: {e}");
-                }
+    match config.restful_uri.clone() {
+        RestfulUri::Tcp { host, port } => {
+            // Hostnames are resolved when binding. If port 0 is given, the OS chooses an available
+            // port, so always report the address that is actually being listened on.
+            let listener = TcpListener::bind((host.as_str(), port))
+                .context(format!("unable to bind restful URI {host}:{port}"))?;
+            let local_addr = listener
+                .local_addr()
+                .context("unable to retrieve restful URI listener address")?;
+            println!("Restful service listening on tcp://{local_addr}");
+
+            config.restful_uri = RestfulUri::Tcp {
+                host: local_addr.ip().to_string(),
+                port: local_addr.port(),
+            };
+
+            match config.restful_privsep {
+                true => serve_proxied(spawn_proxy(&listener, false)?, &vm, &config, token)?,
+                false => serve(listener.incoming(), &vm, &config, token.as_deref()),
+            }
+        }
+        RestfulUri::Vsock { port } => {
+            let path = vsock_socket_path(port);
+
+            // Remove a stale socket left behind by a previous instance.
+            if path.exists() {
+                fs::remove_file(&path).context(format!(
+                    "unable to remove stale restful URI socket {}",
+                    path.display()
+                ))?;
+            }
+
+            let listener = UnixListener::bind(&path).context(format!(
+                "unable to bind restful URI socket {}",
+                path.display()
+            ))?;
+            cleanup::register(Resource::File(path.clone()));
+            println!(
+                "Restful service listening on vsock port {port} (host socket {})",
+                path.display()
+            );
+
+            match config.restful_privsep {
+                true => serve_proxied(spawn_proxy(&listener, true)?, &vm, &config, token)?,
+                false => serve(listener.incoming(), &vm, &config, token.as_deref()),
             }
-            Err(e) => println!("Error reading stream: {}", e),
         }
     }
 
     Ok(())
 }
+
+/// Handle each request from a connected client, regardless of the underlying transport.
+fn serve<S: WebSocketStream + 'static>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    vm: &Arc<VmHandle>,
+    config: &VmConfig,
+    token: Option<&str>,
+) {
+    for stream in incoming {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Error accepting connection: {e}");
+                continue;
+            }
+        };
+
+        let request = match Request::read(&mut stream) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Error reading stream: {}", e);
+                continue;
+            }
+        };
+
+        let started = SystemTime::now();
+        let (response, change) = respond(&request, vm, config, token);
+
+        // Send the response before changing the VM's state, as the process may exit (or be
+        // replaced) once the VM is shut down.
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            println!("Error writting {} response: {e}", request.method);
+        }
+        otel::export_request(&request.method, &request.path, &response, started);
+
+        // The connection was upgraded to a WebSocket streaming the console output.
+        if response.starts_with(HTTP_SWITCHING_PROTOCOLS) {
+            if let (Some(console), Some(path)) = (
+                &vm.console,
+                console_log_path(config.devices.iter().map(|d| &d.config)),
+            ) {
+                let recent = console.tail(DEFAULT_CONSOLE_LINES);
+                console_websocket(stream, path, recent, vm.clone());
+            }
+            continue;
+        }
+
+        if let Some(change) = change {
+            change_state(vm, change);
+        }
+    }
+}
+
+/// Handle each request relayed by the restful proxy process (see --restful-privsep), until it
+/// exits.
+fn serve_proxied(
+    channel: UnixStream,
+    vm: &Arc<VmHandle>,
+    config: &VmConfig,
+    token: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(channel.try_clone()?);
+    let mut writer = channel;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("restful proxy process exited"));
+        }
+
+        let started = SystemTime::now();
+        let request: Request =
+            serde_json::from_str(&line).context("malformed request from restful proxy")?;
+        let (response, change) = respond(&request, vm, config, token.as_deref());
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+
+        // Wait for the response to be sent to the client before changing the VM's state.
+        line.clear();
+        reader.read_line(&mut line)?;
+        otel::export_request(&request.method, &request.path, &response, started);
+
+        if let Some(change) = change {
+            change_state(vm, change);
+        }
+    }
+}
+
+/// Build the response to a request, along with the change of the VM's state to make once the
+/// response has been sent, if any.
+fn respond(
+    request: &Request,
+    vm: &Arc<VmHandle>,
+    config: &VmConfig,
+    token: Option<&str>,
+) -> (String, Option<StateChange>) {
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        _ if !request.authorized(token) => {
+            error_response("401 Unauthorized", "missing or invalid bearer token")
+        }
+        (method, _) if !config.restful_access.allows(method) => error_response(
+            "403 Forbidden",
+            &format!(
+                "{method} requests not allowed with {} access",
+                config.restful_access
+            ),
+        ),
+        ("GET", "/vm/state") if vm.guest_panicked() => String::from(HTTP_GUEST_PANICKED),
+        ("GET", "/vm/state/history") => serialized_response("200 OK", &vm.state.transitions()),
+        ("GET", "/vm/inspect") => inspect_response(config, vm),
+        ("GET", "/vm/console") => match &vm.console {
+            Some(console) => match request.query_usize("lines") {
+                Ok(lines) => {
+                    let lines = console.tail(lines.unwrap_or(DEFAULT_CONSOLE_LINES));
+                    json_response("200 OK", &serde_json::json!({ "lines": lines }).to_string())
+                }
+                Err(e) => error_response("400 Bad Request", &e.to_string()),
+            },
+            None => error_response("404 Not Found", "no virtio-serial device configured"),
+        },
+        ("GET", "/vm/console/ws") => match (&vm.console, &request.websocket_key) {
+            (None, _) => error_response("404 Not Found", "no virtio-serial device configured"),
+            // The restful proxy process relays requests and responses, not connections.
+            _ if config.restful_privsep => error_response(
+                "501 Not Implemented",
+                "console WebSocket not supported with --restful-privsep",
+            ),
+            (Some(_), None) => error_response("400 Bad Request", "WebSocket upgrade required"),
+            (Some(_), Some(key)) => handshake_response(key),
+        },
+        ("GET", "/vm/guest/stats") => match &vm.agent {
+            Some(agent) => match agent.stats() {
+                Ok(stats) => serialized_response("200 OK", &stats),
+                Err(e) => error_response("502 Bad Gateway", &format!("{e:#}")),
+            },
+            None => error_response("404 Not Found", "no guest agent configured"),
+        },
+        ("GET", "/vm/connection") => {
+            serialized_response("200 OK", &connection(config, vm.agent.as_ref()))
+        }
+        ("GET", "/vm/health") => match vm.health.report() {
+            Some(health) => serialized_response("200 OK", &health),
+            None => error_response("404 Not Found", "no heartbeat configured"),
+        },
+        ("GET", "/vm/host/power") => match hostpower::host_power() {
+            Some(power) => serialized_response("200 OK", &power),
+            None => error_response("404 Not Found", "host power state unavailable"),
+        },
+        ("GET", "/vm/stats/boot") => {
+            serialized_response("200 OK", &serde_json::json!({ "phases": boot::phases() }))
+        }
+        ("GET", "/vm/stats/host") => match vm.stats.latest() {
+            Some(stats) => serialized_response("200 OK", &stats),
+            None => error_response("503 Service Unavailable", "no sample taken yet"),
+        },
+        ("GET", "/vm/events") => match request.query_usize("since") {
+            Ok(since) => {
+                let events = vm.events.since(since.unwrap_or(0) as u64);
+                serialized_response("200 OK", &events)
+            }
+            Err(e) => error_response("400 Bad Request", &e.to_string()),
+        },
+        ("GET", "/vm/operations") => serialized_response("200 OK", &vm.operations.list()),
+        ("GET", path) if path.starts_with("/vm/operations/") => {
+            let id = &path["/vm/operations/".len()..];
+            match u64::from_str(id).ok().and_then(|id| vm.operations.get(id)) {
+                Some(operation) => serialized_response("200 OK", &operation),
+                None => error_response("404 Not Found", &format!("unknown operation: {id}")),
+            }
+        }
+        ("POST", "/vm/state") => match StateChange::parse(&request.body) {
+            StateChange::Start => match vm.activate() {
+                true => {
+                    println!("Start requested through the restful service, starting the VM");
+                    state_response(VmState::Starting)
+                }
+                false => error_response("409 Conflict", "VM is not awaiting activation"),
+            },
+            StateChange::Shutdown => {
+                if vm.agent.is_none() {
+                    error_response("409 Conflict", "no guest agent configured")
+                } else {
+                    let shutdown_vm = vm.clone();
+                    let operation = vm.operations.start("shutdown", move || {
+                        shutdown_vm.shutdown_guest(GUEST_SHUTDOWN_TIMEOUT)?;
+                        Ok(String::from("guest powered off"))
+                    });
+
+                    accepted_response(&operation)
+                }
+            }
+            change => {
+                let response = match change {
+                    StateChange::Reboot => String::from(HTTP_REBOOTING),
+                    _ => state_response(VmState::Stopping),
+                };
+
+                return (response, Some(change));
+            }
+        },
+        ("POST", path) if path.starts_with("/vm/disks/") && path.ends_with("/snapshot") => {
+            let id = &path["/vm/disks/".len()..path.len() - "/snapshot".len()];
+            snapshot_response(vm, config, id, &request.body)
+        }
+        ("POST", _) => error_response("404 Not Found", "unknown endpoint"),
+        _ => state_response(vm.state.current()),
+    };
+
+    (response, None)
+}
+
+/// Stop or reboot the VM as requested by a client. With a guest agent, the guest is first asked to
+/// power off (unless a hard stop is requested), on a new thread so that requests are still served
+/// in the meantime, and the VM is only stopped if it does not within the timeout. Another stop
+/// request then stops it right away.
+fn change_state(vm: &Arc<VmHandle>, change: StateChange) {
+    if change == StateChange::Stop && vm.agent.is_some() && !vm.stop_requested() {
+        let vm = vm.clone();
+        thread::spawn(move || {
+            if let Err(e) = vm.stop_gracefully(GUEST_SHUTDOWN_TIMEOUT) {
+                println!("Error stopping VM: {e}");
+            }
+        });
+        return;
+    }
+
+    let result = match change {
+        StateChange::Reboot => vm.reboot(),
+        _ => vm.stop(),
+    };
+    if let Err(e) = result {
+        println!("Error changing VM state: {e}");
+    }
+}
+
+/// Respond with the VM configuration, including the runtime state of helper processes.
+fn inspect_response(config: &VmConfig, vm: &VmHandle) -> String {
+    let mut inspect = match serde_json::to_value(config) {
+        Ok(inspect) => inspect,
+        Err(e) => return error_response("500 Internal Server Error", &e.to_string()),
+    };
+    inspect["helpers"] = serde_json::json!(vm.helpers.report());
+    inspect["guestReady"] = serde_json::json!(vm.guest_ready());
+
+    json_response("200 OK", &inspect.to_string())
+}
+
+/// Start a snapshot of a disk image as a long-running operation.
+fn snapshot_response(vm: &Arc<VmHandle>, config: &VmConfig, id: &str, body: &str) -> String {
+    #[derive(Deserialize)]
+    struct SnapshotRequest {
+        path: PathBuf,
+    }
+
+    let image = config.devices.iter().find_map(|d| match &d.config {
+        VirtioDeviceConfig::Blk(blk) if d.id == id => Some(blk.path.clone()),
+        _ => None,
+    });
+    let Some(image) = image else {
+        return error_response("404 Not Found", &format!("unknown disk: {id}"));
+    };
+
+    let destination = match serde_json::from_str::<SnapshotRequest>(body) {
+        Ok(request) if request.path.is_absolute() => request.path,
+        Ok(_) => return error_response("400 Bad Request", "snapshot path must be absolute"),
+        Err(e) => {
+            return error_response("400 Bad Request", &format!("invalid snapshot request: {e}"))
+        }
+    };
+
+    let Some(agent) = vm.agent.clone() else {
+        return error_response("409 Conflict", "no guest agent configured");
+    };
+
+    let operation = vm.operations.start("snapshot", move || {
+        snapshot_disk(&agent, &image, &destination)
+    });
+
+    accepted_response(&operation)
+}
+
+/// The parts of an HTTP request used by the restful service.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+
+    /// Key of a WebSocket handshake (Sec-WebSocket-Key).
+    websocket_key: Option<String>,
+
+    body: String,
+}
+
+impl Request {
+    /// Read an HTTP request from a client. The headers and body may arrive in several segments,
+    /// so the stream is read until the headers, and as many bytes of body as given by their
+    /// Content-Length, have been received.
+    pub fn read(stream: &mut impl Read) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let sz = stream.read(&mut chunk)?;
+            buf.extend_from_slice(&chunk[..sz]);
+
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buf[..end]);
+                let length = head
+                    .lines()
+                    .skip(1)
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("content-length")
+                            .then(|| usize::from_str(value.trim()).ok())?
+                    })
+                    .unwrap_or(0);
+
+                if buf.len() >= end + 4 + length {
+                    break;
+                }
+            }
+
+            // The client closed the connection before sending the whole request.
+            if sz == 0 {
+                break;
+            }
+
+            if buf.len() > REQUEST_SIZE_MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("request larger than {REQUEST_SIZE_MAX} bytes"),
+                ));
+            }
+        }
+
+        Ok(Self::parse(&buf))
+    }
+
+    /// Parse the request line, authorization, WebSocket key, and body of an HTTP request. Other
+    /// headers are not needed by the service and are ignored.
+    pub fn parse(buf: &[u8]) -> Self {
+        let request = String::from_utf8_lossy(buf);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap_or((&request, ""));
+
+        let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+        let method = request_line.next().unwrap_or("").to_uppercase();
+        let target = request_line.next().unwrap_or("");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let header = |header: &str| {
+            head.lines().skip(1).find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case(header)
+                    .then(|| value.trim().to_string())
+            })
+        };
+
+        Self {
+            method,
+            path: path.to_string(),
+            query: query.to_string(),
+            authorization: header("authorization"),
+            websocket_key: header("sec-websocket-key"),
+            body: body.trim_end_matches('\0').to_string(),
+        }
+    }
+
+    /// Indicate if the request presents the expected bearer token, if any. Tokens are compared
+    /// in constant time.
+    fn authorized(&self, token: Option<&str>) -> bool {
+        let Some(token) = token else {
+            return true;
+        };
+
+        let presented = self
+            .authorization
+            .as_deref()
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .unwrap_or("");
+
+        presented.len() == token.len()
+            && presented
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Retrieve the value of a query parameter.
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Retrieve the value of a query parameter expected to be an unsigned integer.
+    fn query_usize(&self, name: &str) -> Result<Option<usize>, anyhow::Error> {
+        self.query_param(name)
+            .map(|v| usize::from_str(v).context(format!("invalid {name} query parameter: {v}")))
+            .transpose()
+    }
+}
+
+/// A state change requested with POST /vm/state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StateChange {
+    /// Start a VM awaiting activation (see --activate-on).
+    Start,
+
+    /// Stop the VM, through the guest agent if configured.
+    Stop,
+
+    /// Stop the VM immediately, even with a guest agent.
+    HardStop,
+
+    /// Stop the VM and start it again.
+    Reboot,
+
+    /// Ask the guest to power off, as a long-running operation.
+    Shutdown,
+}
+
+impl StateChange {
+    /// Parse the requested state from a request body. Any POST request used to stop the VM, so
+    /// for compatibility with clients that do not send a body, or request states krunkit does not
+    /// support (such as vfkit's Pause), anything else is a request to stop the VM.
+    fn parse(body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct StateRequest {
+            state: String,
+        }
+
+        let state = serde_json::from_str::<StateRequest>(body).map(|r| r.state);
+        match state.as_deref() {
+            Ok("Start") => Self::Start,
+            Ok("HardStop") => Self::HardStop,
+            Ok("Reboot") => Self::Reboot,
+            Ok("Shutdown") => Self::Shutdown,
+            _ => Self::Stop,
+        }
+    }
+}
+
+/// Build the response reporting a state of the VM, in the format of vfkit's.
+fn state_response(state: VmState) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{{\"state\": \"{}\"}}\0",
+        state.vfkit_name()
+    )
+}
+
+/// Build an HTTP response with a JSON body.
+fn json_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Build an HTTP response with a value serialized as the JSON body.
+fn serialized_response<T: Serialize>(status: &str, value: &T) -> String {
+    match serde_json::to_string(value) {
+        Ok(json) => json_response(status, &json),
+        Err(e) => error_response("500 Internal Server Error", &e.to_string()),
+    }
+}
+
+/// Build an HTTP response indicating that a long-running operation was started. The client can
+/// poll the operation's status at the returned location.
+fn accepted_response(operation: &Operation) -> String {
+    let body = serde_json::to_string(operation).unwrap_or_default();
+
+    format!(
+        "HTTP/1.1 202 Accepted\r\nContent-type: application/json\r\nLocation: /vm/operations/{}\r\nContent-Length: {}\r\n\r\n{body}",
+        operation.id,
+        body.len()
+    )
+}
+
+/// Build an HTTP response describing an error.
+fn error_response(status: &str, message: &str) -> String {
+    json_response(status, &serde_json::json!({ "error": message }).to_string())
+}
+
+mod tests {
+    #[test]
+    fn restful_uri_parse() {
+        use super::*;
+
+        let uri = RestfulUri::from_str("tcp://localhost:49573").unwrap();
+        assert_eq!(
+            uri,
+            RestfulUri::Tcp {
+                host: String::from("127.0.0.1"),
+                port: 49573
+            }
+        );
+
+        let uri = RestfulUri::from_str("tcp://[::1]:8081").unwrap();
+        assert_eq!(
+            uri,
+            RestfulUri::Tcp {
+                host: String::from("::1"),
+                port: 8081
+            }
+        );
+
+        let uri = RestfulUri::from_str("tcp://vm.example.com:0").unwrap();
+        assert_eq!(
+            uri,
+            RestfulUri::Tcp {
+                host: String::from("vm.example.com"),
+                port: 0
+            }
+        );
+
+        let uri = RestfulUri::from_str("vsock://1027").unwrap();
+        assert_eq!(uri, RestfulUri::Vsock { port: 1027 });
+
+        assert!(RestfulUri::from_str("vsock://").is_err());
+        assert!(RestfulUri::from_str("tcp://::1:8081").is_err());
+        assert!(RestfulUri::from_str("tcp://[::1]").is_err());
+        assert!(RestfulUri::from_str("tcp://localhost").is_err());
+        assert!(RestfulUri::from_str("tcp://:8081").is_err());
+    }
+
+    #[test]
+    fn restful_uri_check_bind() {
+        use super::*;
+
+        let uri = RestfulUri::from_str("tcp://localhost:8081").unwrap();
+        assert!(uri.check_bind(false).is_ok());
+
+        let uri = RestfulUri::from_str("tcp://[::1]:8081").unwrap();
+        assert!(uri.check_bind(false).is_ok());
+
+        let uri = RestfulUri::from_str("tcp://0.0.0.0:8081").unwrap();
+        assert!(uri.check_bind(false).is_err());
+        assert!(uri.check_bind(true).is_ok());
+
+        let uri = RestfulUri::from_str("vsock://1027").unwrap();
+        assert!(uri.check_bind(false).is_ok());
+
+        assert!(RestfulAccess::StatusOnly.allows("GET"));
+        assert!(!RestfulAccess::StatusOnly.allows("POST"));
+        assert!(RestfulAccess::FullControl.allows("POST"));
+    }
+
+    #[test]
+    fn request_authorized() {
+        use super::*;
+
+        let request = Request::parse(
+            b"GET /vm/state HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\r\n",
+        );
+        assert!(request.authorized(None));
+        assert!(request.authorized(Some("s3cret")));
+        assert!(!request.authorized(Some("s3cre")));
+
+        let request = Request::parse(b"GET /vm/state HTTP/1.1\r\n\r\n");
+        assert!(request.authorized(None));
+        assert!(!request.authorized(Some("s3cret")));
+    }
+
+    #[test]
+    fn request_read() {
+        use super::*;
+
+        let head = b"POST /vm/state HTTP/1.1\r\nContent-Length: 18\r\n\r\n";
+        let body = b"{\"state\":\"Reboot\"}";
+        let request = Request::read(&mut head.chain(&body[..])).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body, "{\"state\":\"Reboot\"}");
+
+        let request = Request::read(&mut &b"GET /vm/state HTTP/1.1\r\n\r\n"[..]).unwrap();
+        assert_eq!(request.path, "/vm/state");
+        assert_eq!(request.body, "");
+
+        let large = format!("GET /{} HTTP/1.1\r\n", "a".repeat(REQUEST_SIZE_MAX));
+        assert!(Request::read(&mut large.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn state_change_parse() {
+        use super::*;
+
+        assert_eq!(StateChange::parse(""), StateChange::Stop);
+        assert_eq!(
+            StateChange::parse("{\"state\": \"Stop\"}"),
+            StateChange::Stop
+        );
+        assert_eq!(
+            StateChange::parse("{\"state\": \"HardStop\"}"),
+            StateChange::HardStop
+        );
+        assert_eq!(
+            StateChange::parse("{\"state\": \"Reboot\"}"),
+            StateChange::Reboot
+        );
+        assert_eq!(
+            StateChange::parse("{\"state\": \"Shutdown\"}"),
+            StateChange::Shutdown
+        );
+        assert_eq!(
+            StateChange::parse("{\"state\": \"Start\"}"),
+            StateChange::Start
+        );
+        assert_eq!(
+            StateChange::parse("{\"state\": \"Pause\"}"),
+            StateChange::Stop
+        );
+        assert_eq!(StateChange::parse("stop"), StateChange::Stop);
+    }
+}
@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    events::EventKind,
+    priority::{self, BackgroundReason},
+    vm::VmHandle,
+};
+
+use std::{fmt, str::FromStr, sync::Arc, thread, time::Duration};
+
+use anyhow::anyhow;
+use serde::{Serialize, Serializer};
+
+/// Interval at which the host's thermal pressure is checked.
+const THERMAL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Thermal pressure of the host, as reported by macOS. Ordered from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermalPressure {
+    Nominal,
+    Moderate,
+    Heavy,
+    Trapping,
+    Sleeping,
+}
+
+impl FromStr for ThermalPressure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nominal" => Ok(Self::Nominal),
+            "moderate" => Ok(Self::Moderate),
+            "heavy" => Ok(Self::Heavy),
+            "trapping" => Ok(Self::Trapping),
+            "sleeping" => Ok(Self::Sleeping),
+            _ => Err(anyhow!("invalid thermal pressure level: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for ThermalPressure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self {
+            Self::Nominal => "nominal",
+            Self::Moderate => "moderate",
+            Self::Heavy => "heavy",
+            Self::Trapping => "trapping",
+            Self::Sleeping => "sleeping",
+        };
+
+        write!(f, "{level}")
+    }
+}
+
+/// Behavior of krunkit when the host is under thermal pressure.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ThermalPolicy {
+    /// Leave the VM as is. Changes of thermal pressure are still published as events.
+    #[default]
+    Ignore,
+
+    /// Run krunkit, including the vCPUs, in the background priority band while the thermal
+    /// pressure is at least the given level, so that the host's own work takes precedence.
+    Background(ThermalPressure),
+}
+
+impl FromStr for ThermalPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, level) = match s.split_once(':') {
+            Some((policy, level)) => (policy, Some(level)),
+            None => (s, None),
+        };
+
+        match (policy.to_lowercase().as_str(), level) {
+            ("ignore", None) => Ok(Self::Ignore),
+            ("background", level) => Ok(Self::Background(
+                level
+                    .map(ThermalPressure::from_str)
+                    .transpose()?
+                    .unwrap_or(ThermalPressure::Heavy),
+            )),
+            // libkrun cannot pause or duty-cycle vCPUs.
+            ("pause", _) => Err(anyhow!(
+                "pausing vCPUs under thermal pressure is not supported by libkrun"
+            )),
+            _ => Err(anyhow!("invalid --thermal-policy option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for ThermalPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ignore => write!(f, "ignore"),
+            Self::Background(level) => write!(f, "background:{level}"),
+        }
+    }
+}
+
+impl Serialize for ThermalPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Current thermal pressure of the host, if reported.
+pub fn thermal_pressure() -> Option<ThermalPressure> {
+    platform::thermal_pressure()
+}
+
+/// Publish changes of the host's thermal pressure as events, applying the thermal policy.
+pub fn thermal_monitor(vm: Arc<VmHandle>, policy: ThermalPolicy) {
+    thread::spawn(move || {
+        let mut last = ThermalPressure::Nominal;
+        let mut background = false;
+
+        loop {
+            let pressure = thermal_pressure().unwrap_or(ThermalPressure::Nominal);
+            if pressure != last {
+                vm.events.publish(
+                    EventKind::ThermalPressure,
+                    format!("host thermal pressure {pressure}"),
+                );
+                last = pressure;
+            }
+
+            if let ThermalPolicy::Background(level) = policy {
+                let throttle = pressure >= level;
+                if throttle != background {
+                    if let Err(e) =
+                        priority::set_background(BackgroundReason::ThermalPressure, throttle)
+                    {
+                        println!("Unable to change VM priority: {e}");
+                    }
+                    background = throttle;
+                }
+            }
+
+            thread::sleep(THERMAL_POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::ThermalPressure;
+
+    use std::{ffi::c_char, sync::OnceLock};
+
+    /// Darwin notification carrying the thermal pressure level as its state.
+    const THERMAL_PRESSURE_NOTIFICATION: &[u8] = b"com.apple.system.thermalpressurelevel\0";
+
+    const NOTIFY_STATUS_OK: u32 = 0;
+
+    extern "C" {
+        fn notify_register_check(name: *const c_char, out_token: *mut i32) -> u32;
+        fn notify_get_state(token: i32, state: *mut u64) -> u32;
+    }
+
+    /// Registration for the thermal pressure notification, made on first use.
+    static TOKEN: OnceLock<Option<i32>> = OnceLock::new();
+
+    pub fn thermal_pressure() -> Option<ThermalPressure> {
+        let token = (*TOKEN.get_or_init(|| {
+            let mut token = 0;
+            match unsafe {
+                notify_register_check(
+                    THERMAL_PRESSURE_NOTIFICATION.as_ptr() as *const c_char,
+                    &mut token,
+                )
+            } {
+                NOTIFY_STATUS_OK => Some(token),
+                _ => None,
+            }
+        }))?;
+
+        let mut state = 0;
+        if unsafe { notify_get_state(token, &mut state) } != NOTIFY_STATUS_OK {
+            return None;
+        }
+
+        // OSThermalPressureLevel values on macOS.
+        match state {
+            0 => Some(ThermalPressure::Nominal),
+            1 => Some(ThermalPressure::Moderate),
+            2 => Some(ThermalPressure::Heavy),
+            3 => Some(ThermalPressure::Trapping),
+            4 => Some(ThermalPressure::Sleeping),
+            _ => None,
+        }
+    }
+}
+
+/// Thermal pressure is only reported on macOS.
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::ThermalPressure;
+
+    pub fn thermal_pressure() -> Option<ThermalPressure> {
+        None
+    }
+}
+
+mod tests {
+    #[test]
+    fn thermal_policy_parse() {
+        use super::*;
+
+        assert_eq!(
+            ThermalPolicy::from_str("ignore").unwrap(),
+            ThermalPolicy::Ignore
+        );
+        assert_eq!(
+            ThermalPolicy::from_str("background").unwrap(),
+            ThermalPolicy::Background(ThermalPressure::Heavy)
+        );
+
+        let policy = ThermalPolicy::from_str("background:moderate").unwrap();
+        assert_eq!(policy, ThermalPolicy::Background(ThermalPressure::Moderate));
+        assert_eq!(policy.to_string(), "background:moderate");
+
+        assert!(ThermalPressure::Trapping > ThermalPressure::Heavy);
+        assert!(ThermalPolicy::from_str("background:hot").is_err());
+        assert!(ThermalPolicy::from_str("pause").is_err());
+    }
+}
@@ -0,0 +1,676 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    agent::GuestAgent,
+    cmdline::{args_parse, duration_parse, val_parse},
+    events::EventKind,
+    libkrun::{self, libkrun},
+    network::revalidate_backends,
+    statedir,
+    virtio::KrunContextSet,
+    vm::VmHandle,
+};
+
+use std::{
+    env,
+    ffi::CString,
+    fmt, fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, net::UnixStream},
+    path::PathBuf,
+    process,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Time to wait for the guest to set its clock over a krunkit timesync channel.
+const TIMESYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum length of a UNIX socket path, including the NUL terminator (the size of sun_path on
+/// macOS).
+pub const SOCKET_PATH_MAX: usize = 104;
+
+/// Offset of the guest's clock below which it is slewed rather than stepped, if not specified.
+const DEFAULT_SLEW_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Host power state changes relevant to the VM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerEvent {
+    /// The host is about to sleep.
+    WillSleep,
+
+    /// The host woke from sleep.
+    HasPoweredOn,
+}
+
+/// Behavior of krunkit when the host goes to sleep.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostSleepPolicy {
+    /// Leave the VM as is. The guest's clock is still resynchronized on wake.
+    #[default]
+    Ignore,
+
+    /// Quiesce the guest by freezing its filesystems before the host sleeps, and thaw them on
+    /// wake.
+    Suspend,
+}
+
+impl FromStr for HostSleepPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(Self::Ignore),
+            "suspend" => Ok(Self::Suspend),
+            "pause" => Err(anyhow!(
+                "pausing vCPUs on host sleep is not supported by libkrun"
+            )),
+            _ => Err(anyhow!("invalid --on-host-sleep option: {s}")),
+        }
+    }
+}
+
+/// Protocol spoken by the guest on the timesync channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimesyncProtocol {
+    /// qemu-guest-agent, with its guest-set-time command.
+    #[default]
+    Qga,
+
+    /// A line-oriented protocol (also named krunkit-agent) simple enough to implement with a shell
+    /// script: krunkit sends the host's time as "<seconds>.<nanoseconds>" since the UNIX epoch,
+    /// and the guest responds "OK" once it has set its clock.
+    Krunkit,
+}
+
+impl FromStr for TimesyncProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "qga" => Ok(Self::Qga),
+            "krunkit" | "krunkit-agent" => Ok(Self::Krunkit),
+            _ => Err(anyhow!(
+                "invalid timesync protocol: {s} (expected qga or krunkit-agent)"
+            )),
+        }
+    }
+}
+
+/// Configuration of a channel dedicated to synchronizing the guest's clock, as an alternative to
+/// the guest agent channel.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimesyncConfig {
+    /// vsock port the guest listens on.
+    pub port: u32,
+
+    /// Path of the host UNIX socket proxied to the vsock port.
+    pub socket: Option<PathBuf>,
+
+    pub protocol: TimesyncProtocol,
+
+    /// Seconds between periodic resynchronizations of the guest's clock, if any. The clock is
+    /// otherwise only resynchronized when the host wakes from sleep.
+    pub interval_secs: Option<u64>,
+}
+
+impl FromStr for TimesyncConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut port = None;
+        let mut socket = None;
+        let mut protocol = TimesyncProtocol::default();
+        let mut interval_secs = None;
+
+        for arg in args_parse(s.to_string(), "timesync", None)? {
+            match arg.split_once('=').map(|(label, _)| label) {
+                Some("port") => {
+                    let value = u32::from_str(&val_parse(&arg, "port")?)
+                        .context("timesync port argument invalid")?;
+                    if value == 0 {
+                        return Err(anyhow!("timesync port must be greater than 0"));
+                    }
+                    port = Some(value);
+                }
+                Some("socket") => {
+                    let path = PathBuf::from(val_parse(&arg, "socket")?);
+                    if path.as_os_str().len() >= SOCKET_PATH_MAX {
+                        return Err(anyhow!(
+                            "timesync socket path longer than {} bytes",
+                            SOCKET_PATH_MAX - 1
+                        ));
+                    }
+                    match path.parent() {
+                        Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => (),
+                        _ => {
+                            return Err(anyhow!(
+                                "timesync socket directory of {} does not exist",
+                                path.display()
+                            ))
+                        }
+                    }
+                    socket = Some(path);
+                }
+                Some(label @ ("protocol" | "mode")) => {
+                    protocol = TimesyncProtocol::from_str(&val_parse(&arg, label)?)?
+                }
+                Some("interval") => {
+                    let interval = duration_parse(&val_parse(&arg, "interval")?)
+                        .context("timesync interval argument invalid")?;
+                    if interval.as_secs() == 0 || interval.subsec_nanos() != 0 {
+                        return Err(anyhow!(
+                            "timesync interval must be a whole number of seconds, at least 1s"
+                        ));
+                    }
+                    interval_secs = Some(interval.as_secs());
+                }
+                _ => return Err(anyhow!("invalid timesync argument: {arg}")),
+            }
+        }
+
+        Ok(Self {
+            port: port.ok_or(anyhow!("timesync port argument not found"))?,
+            socket,
+            protocol,
+            interval_secs,
+        })
+    }
+}
+
+impl TimesyncConfig {
+    /// Path of the host UNIX socket proxied to the guest's timesync vsock port.
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .or_else(|| statedir::socket_path("timesync"))
+            .unwrap_or_else(|| {
+                env::temp_dir().join(format!("krunkit-timesync-{}.sock", process::id()))
+            })
+    }
+}
+
+/// Have libkrun listen for host connections on the timesync socket, forwarding each to the
+/// guest's timesync vsock port.
+impl KrunContextSet for TimesyncConfig {
+    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+        let path = self.socket_path();
+
+        // Remove a stale socket left behind by a previous instance, but never another file.
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!(
+                    "timesync socket path {} exists and is not a socket",
+                    path.display()
+                ));
+            }
+            fs::remove_file(&path).context(format!(
+                "unable to remove stale timesync socket {}",
+                path.display()
+            ))?;
+        }
+
+        let path_cstr = CString::new(path.as_os_str().as_bytes())
+            .context("unable to convert timesync socket path into C string")?;
+
+        let krun = libkrun();
+        let add_vsock_port2 = krun.require(
+            krun.krun_add_vsock_port2,
+            "krun_add_vsock_port2",
+            "the timesync channel",
+        )?;
+        libkrun::check(add_vsock_port2(id, self.port, path_cstr.as_ptr(), true)).context(
+            format!(
+                "unable to add timesync vsock port {} for path {}",
+                self.port,
+                path.display()
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Channel through which the guest's clock is set.
+#[derive(Clone, Debug)]
+pub enum GuestClock {
+    /// A qemu-guest-agent, either the guest agent or a dedicated timesync channel.
+    Agent(GuestAgent),
+
+    /// A timesync channel speaking the krunkit protocol, through the given socket.
+    Krunkit(PathBuf),
+}
+
+impl GuestClock {
+    /// The channel to use given the timesync and guest agent configuration, preferring a
+    /// dedicated timesync channel.
+    pub fn new(timesync: Option<&TimesyncConfig>, agent: Option<&GuestAgent>) -> Option<Self> {
+        match timesync {
+            Some(config) => match config.protocol {
+                TimesyncProtocol::Qga => Some(Self::Agent(GuestAgent::new(config.socket_path()))),
+                TimesyncProtocol::Krunkit => Some(Self::Krunkit(config.socket_path())),
+            },
+            None => agent.cloned().map(Self::Agent),
+        }
+    }
+
+    /// Set the guest's clock to the given time.
+    pub fn set_time(&self, time: SystemTime) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Agent(agent) => agent.set_time(time),
+            Self::Krunkit(path) => {
+                let since_epoch = time
+                    .duration_since(UNIX_EPOCH)
+                    .context("host time before UNIX epoch")?;
+
+                let mut stream = UnixStream::connect(path).context(format!(
+                    "unable to connect to timesync socket {}",
+                    path.display()
+                ))?;
+                stream.set_read_timeout(Some(TIMESYNC_TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMESYNC_TIMEOUT))?;
+
+                stream.write_all(
+                    format!(
+                        "{}.{:09}\n",
+                        since_epoch.as_secs(),
+                        since_epoch.subsec_nanos()
+                    )
+                    .as_bytes(),
+                )?;
+
+                let mut line = String::new();
+                BufReader::new(stream).read_line(&mut line)?;
+                match line.trim() {
+                    "OK" => Ok(()),
+                    "" => Err(anyhow!("no response from guest timesync channel")),
+                    response => Err(anyhow!("guest unable to set its clock: {response}")),
+                }
+            }
+        }
+    }
+}
+
+/// How the guest's clock is corrected when resynchronized with the host's.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimeCorrection {
+    /// Set the guest's clock to the host's time.
+    #[default]
+    Step,
+
+    /// Have chrony in the guest gradually correct offsets up to the threshold, so that the
+    /// guest's clock never jumps. Larger offsets are stepped.
+    Slew { threshold: Duration },
+}
+
+impl FromStr for TimeCorrection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, threshold) = match s.split_once(':') {
+            Some((mode, threshold)) => (mode, Some(threshold)),
+            None => (s, None),
+        };
+
+        match (mode.to_lowercase().as_str(), threshold) {
+            ("step", None) => Ok(Self::Step),
+            ("slew", threshold) => Ok(Self::Slew {
+                threshold: threshold
+                    .map(duration_parse)
+                    .transpose()
+                    .context("invalid slew threshold")?
+                    .unwrap_or(DEFAULT_SLEW_THRESHOLD),
+            }),
+            _ => Err(anyhow!("invalid --time-correction option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for TimeCorrection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Step => write!(f, "step"),
+            Self::Slew { threshold } => write!(f, "slew:{}ms", threshold.as_millis()),
+        }
+    }
+}
+
+impl Serialize for TimeCorrection {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Resynchronize the guest's clock with the host's at an interval on a new thread until the VM
+/// exits, so that it does not drift while the host stays awake. Failures are only logged once
+/// until a resynchronization succeeds, as the guest may not be listening yet.
+pub fn clock_resync_scheduler(vm: Arc<VmHandle>, interval: Duration, correction: TimeCorrection) {
+    thread::spawn(move || {
+        let mut failing = false;
+
+        while !vm.wait_exited(interval) {
+            let Some(clock) = &vm.clock else {
+                return;
+            };
+
+            match resync_clock(clock, correction) {
+                Ok(()) if failing => {
+                    println!("Resynchronized guest clock");
+                    failing = false;
+                }
+                Ok(()) => (),
+                Err(e) if !failing => {
+                    println!("Unable to resynchronize guest clock, will retry: {e:#}");
+                    failing = true;
+                }
+                Err(_) => (),
+            }
+        }
+    });
+}
+
+/// Resynchronize the guest's clock with the host's, applying the correction policy.
+pub fn resync_clock(clock: &GuestClock, correction: TimeCorrection) -> Result<(), anyhow::Error> {
+    // Slewing relies on running chrony through a guest agent.
+    let (TimeCorrection::Slew { threshold }, GuestClock::Agent(agent)) = (correction, clock) else {
+        return clock.set_time(SystemTime::now());
+    };
+
+    let guest = agent.get_time()?;
+    let host = SystemTime::now();
+    let offset = host
+        .duration_since(guest)
+        .or_else(|_| guest.duration_since(host))
+        .unwrap_or_default();
+
+    if offset > threshold {
+        println!(
+            "Guest clock off by {} ms, above the slew threshold, stepping it",
+            offset.as_millis()
+        );
+        return agent.set_time(SystemTime::now());
+    }
+
+    // chrony slews offsets below its step threshold. Have it measure its sources right away
+    // rather than at its next polling interval.
+    match agent.exec("chronyc", &["-a", "burst", "4/4"]) {
+        Ok(0) => {
+            println!("Guest clock off by {} ms, slewing it", offset.as_millis());
+            Ok(())
+        }
+        result => {
+            let reason = match result {
+                Ok(code) => format!("chronyc exited with status {code}"),
+                Err(e) => format!("{e:#}"),
+            };
+            println!("Unable to slew guest clock ({reason}), stepping it");
+            agent.set_time(SystemTime::now())
+        }
+    }
+}
+
+/// Apply the host sleep policy to the VM as the host sleeps and wakes. On wake, resynchronize the
+/// guest's clock with the host's and re-validate the network backends at the given sockets.
+/// Actions in the guest require a guest agent.
+pub fn power_monitor(
+    vm: Arc<VmHandle>,
+    policy: HostSleepPolicy,
+    correction: TimeCorrection,
+    networks: Vec<PathBuf>,
+) {
+    let networks = Arc::new(networks);
+
+    watch_power_events(move |event| match event {
+        // The host only goes to sleep once the guest has confirmed it is quiesced (or failed to),
+        // so handle the event before returning.
+        PowerEvent::WillSleep => handle_power_event(&vm, policy, correction, &networks, event),
+        PowerEvent::HasPoweredOn => {
+            let vm = vm.clone();
+            let networks = networks.clone();
+            thread::spawn(move || handle_power_event(&vm, policy, correction, &networks, event));
+        }
+    });
+}
+
+fn handle_power_event(
+    vm: &VmHandle,
+    policy: HostSleepPolicy,
+    correction: TimeCorrection,
+    networks: &[PathBuf],
+    event: PowerEvent,
+) {
+    match event {
+        PowerEvent::WillSleep => {
+            vm.events
+                .publish(EventKind::HostSleep, "host going to sleep");
+
+            if let (HostSleepPolicy::Suspend, Some(agent)) = (policy, &vm.agent) {
+                match agent.freeze_filesystems() {
+                    Ok(n) => println!("Froze {n} guest filesystem(s), allowing host sleep"),
+                    Err(e) => println!(
+                        "Unable to freeze guest filesystems, allowing host sleep anyway: {e:#}"
+                    ),
+                }
+            }
+        }
+        PowerEvent::HasPoweredOn => {
+            vm.events
+                .publish(EventKind::HostWake, "host woke from sleep");
+
+            if let (HostSleepPolicy::Suspend, Some(agent)) = (policy, &vm.agent) {
+                match agent.thaw_filesystems() {
+                    Ok(n) => println!("Thawed {n} guest filesystem(s)"),
+                    Err(e) => println!("Unable to thaw guest filesystems: {e:#}"),
+                }
+            }
+
+            if let Some(clock) = &vm.clock {
+                if let Err(e) = resync_clock(clock, correction) {
+                    println!("Unable to resynchronize guest clock: {e:#}");
+                }
+            }
+
+            revalidate_backends(vm, networks);
+        }
+    }
+}
+
+/// Call the handler on a dedicated thread for each host power event. The host does not go to
+/// sleep until the handler returns for PowerEvent::WillSleep (or macOS gives up waiting, after 30
+/// seconds).
+#[cfg(target_os = "macos")]
+fn watch_power_events<F: Fn(PowerEvent) + Send + 'static>(handler: F) {
+    thread::spawn(move || {
+        if let Err(e) = iokit::run_power_notifications(Box::new(handler)) {
+            println!("Unable to watch host power events: {e}");
+        }
+    });
+}
+
+/// Host power events are only reported on macOS.
+#[cfg(not(target_os = "macos"))]
+fn watch_power_events<F: Fn(PowerEvent) + Send + 'static>(_handler: F) {}
+
+#[cfg(target_os = "macos")]
+mod iokit {
+    use super::PowerEvent;
+
+    use std::{
+        ffi::c_void,
+        ptr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use anyhow::anyhow;
+
+    type IoConnect = u32;
+    type IoObject = u32;
+    type IoNotificationPortRef = *mut c_void;
+    type CfRunLoopRef = *mut c_void;
+    type CfRunLoopSourceRef = *mut c_void;
+    type CfStringRef = *const c_void;
+    type IoServiceInterestCallback = extern "C" fn(*mut c_void, IoObject, u32, *mut c_void);
+
+    const IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe000_0270;
+    const IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe000_0280;
+    const IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe000_0300;
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IORegisterForSystemPower(
+            refcon: *mut c_void,
+            port: *mut IoNotificationPortRef,
+            callback: IoServiceInterestCallback,
+            notifier: *mut IoObject,
+        ) -> IoConnect;
+        fn IOAllowPowerChange(kernel_port: IoConnect, notification_id: isize) -> i32;
+        fn IONotificationPortGetRunLoopSource(port: IoNotificationPortRef) -> CfRunLoopSourceRef;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFRunLoopCommonModes: CfStringRef;
+
+        fn CFRunLoopGetCurrent() -> CfRunLoopRef;
+        fn CFRunLoopAddSource(rl: CfRunLoopRef, source: CfRunLoopSourceRef, mode: CfStringRef);
+        fn CFRunLoopRun();
+    }
+
+    /// State passed to the power notification callback.
+    struct Monitor {
+        root_port: AtomicU32,
+        handler: Box<dyn Fn(PowerEvent) + Send>,
+    }
+
+    /// Register for system power notifications and run the current thread's run loop to
+    /// receive them. Only returns on error.
+    pub fn run_power_notifications(
+        handler: Box<dyn Fn(PowerEvent) + Send>,
+    ) -> Result<(), anyhow::Error> {
+        // The monitor must live as long as the run loop, which never returns.
+        let monitor = Box::leak(Box::new(Monitor {
+            root_port: AtomicU32::new(0),
+            handler,
+        }));
+
+        let mut port: IoNotificationPortRef = ptr::null_mut();
+        let mut notifier: IoObject = 0;
+        let root_port = unsafe {
+            IORegisterForSystemPower(
+                monitor as *mut Monitor as *mut c_void,
+                &mut port,
+                power_callback,
+                &mut notifier,
+            )
+        };
+        if root_port == 0 {
+            return Err(anyhow!("IORegisterForSystemPower failed"));
+        }
+        monitor.root_port.store(root_port, Ordering::SeqCst);
+
+        unsafe {
+            CFRunLoopAddSource(
+                CFRunLoopGetCurrent(),
+                IONotificationPortGetRunLoopSource(port),
+                kCFRunLoopCommonModes,
+            );
+            CFRunLoopRun();
+        }
+
+        Err(anyhow!("power notification run loop exited"))
+    }
+
+    extern "C" fn power_callback(
+        refcon: *mut c_void,
+        _service: IoObject,
+        message_type: u32,
+        message_argument: *mut c_void,
+    ) {
+        let monitor = unsafe { &*(refcon as *const Monitor) };
+        let root_port = monitor.root_port.load(Ordering::SeqCst);
+
+        match message_type {
+            IO_MESSAGE_CAN_SYSTEM_SLEEP => unsafe {
+                IOAllowPowerChange(root_port, message_argument as isize);
+            },
+            IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+                (monitor.handler)(PowerEvent::WillSleep);
+                unsafe { IOAllowPowerChange(root_port, message_argument as isize) };
+            }
+            IO_MESSAGE_SYSTEM_HAS_POWERED_ON => (monitor.handler)(PowerEvent::HasPoweredOn),
+            _ => (),
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn time_correction_parse() {
+        use super::*;
+
+        assert_eq!(
+            TimeCorrection::from_str("step").unwrap(),
+            TimeCorrection::Step
+        );
+        assert_eq!(
+            TimeCorrection::from_str("slew").unwrap(),
+            TimeCorrection::Slew {
+                threshold: Duration::from_secs(1)
+            }
+        );
+
+        let correction = TimeCorrection::from_str("slew:500ms").unwrap();
+        assert_eq!(
+            correction,
+            TimeCorrection::Slew {
+                threshold: Duration::from_millis(500)
+            }
+        );
+        assert_eq!(correction.to_string(), "slew:500ms");
+
+        assert!(TimeCorrection::from_str("step:1s").is_err());
+        assert!(TimeCorrection::from_str("slew:1d").is_err());
+        assert!(TimeCorrection::from_str("smear").is_err());
+    }
+
+    #[test]
+    fn timesync_config_parse() {
+        use super::*;
+
+        let config = TimesyncConfig::from_str("port=1027").unwrap();
+        assert_eq!(config.port, 1027);
+        assert_eq!(config.protocol, TimesyncProtocol::Qga);
+        assert!(config
+            .socket_path()
+            .ends_with(format!("krunkit-timesync-{}.sock", process::id())));
+
+        let config =
+            TimesyncConfig::from_str("protocol=krunkit,port=1027,socket=/tmp/timesync.sock")
+                .unwrap();
+        assert_eq!(config.protocol, TimesyncProtocol::Krunkit);
+        assert_eq!(config.socket_path(), PathBuf::from("/tmp/timesync.sock"));
+
+        let config = TimesyncConfig::from_str("port=1027,interval=5m,mode=krunkit-agent").unwrap();
+        assert_eq!(config.protocol, TimesyncProtocol::Krunkit);
+        assert_eq!(config.interval_secs, Some(300));
+
+        assert!(TimesyncConfig::from_str("port=1027,interval=500ms").is_err());
+        assert!(TimesyncConfig::from_str("port=1027,interval=1500ms").is_err());
+        assert!(TimesyncConfig::from_str("port=0").is_err());
+        assert!(TimesyncConfig::from_str("socket=/tmp/timesync.sock").is_err());
+        assert!(TimesyncConfig::from_str("port=1027,protocol=ntp").is_err());
+        assert!(TimesyncConfig::from_str("port=1027,socket=/nonexistent/timesync.sock").is_err());
+        assert!(
+            TimesyncConfig::from_str(&format!("port=1027,socket=/tmp/{}", "a".repeat(100)))
+                .is_err()
+        );
+    }
+}
@@ -1,9 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::cmdline::{args_parse, val_parse};
+use crate::{
+    cmdline::{args_parse, duration_parse, size_parse, val_parse},
+    libkrun::{self, libkrun},
+};
 
 use std::{
-    ffi::{c_char, CString},
+    ffi::CString,
+    fmt,
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     str::FromStr,
@@ -11,25 +15,11 @@ use std::{
 
 use anyhow::{anyhow, Context, Result};
 use mac_address::MacAddress;
-
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_add_disk2(
-        ctx_id: u32,
-        c_block_id: *const c_char,
-        c_disk_path: *const c_char,
-        disk_format: u32,
-        read_only: bool,
-    ) -> i32;
-    fn krun_add_vsock_port(ctx_id: u32, port: u32, c_filepath: *const c_char) -> i32;
-    fn krun_add_virtiofs(ctx_id: u32, c_tag: *const c_char, c_path: *const c_char) -> i32;
-    fn krun_set_gvproxy_path(ctx_id: u32, c_path: *const c_char) -> i32;
-    fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32;
-    fn krun_set_console_output(ctx_id: u32, c_filepath: *const c_char) -> i32;
-}
+use serde::Serialize;
 
 #[repr(u32)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DiskImageFormat {
     Raw = 0,
     Qcow2 = 1,
@@ -47,6 +37,15 @@ impl FromStr for DiskImageFormat {
     }
 }
 
+impl fmt::Display for DiskImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Raw => write!(f, "raw"),
+            Self::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
 /// Each virito device configures itself with krun differently. This is used by each virtio device
 /// to set their respective configurations with libkrun.
 pub trait KrunContextSet {
@@ -54,18 +53,90 @@ pub trait KrunContextSet {
 }
 
 /// virtio device configurations.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "config")]
 pub enum VirtioDeviceConfig {
+    #[serde(rename = "virtio-blk")]
     Blk(BlkConfig),
+    #[serde(rename = "virtio-rng")]
     Rng,
+    #[serde(rename = "virtio-serial")]
     Serial(SerialConfig),
+    #[serde(rename = "virtio-vsock")]
     Vsock(VsockConfig),
+    #[serde(rename = "virtio-net")]
     Net(NetConfig),
+    #[serde(rename = "virtio-fs")]
     Fs(FsConfig),
+    #[serde(rename = "virtio-gpu")]
     Gpu(GpuConfig),
+    #[serde(rename = "virtio-input")]
     Input(InputConfig),
 }
 
+impl VirtioDeviceConfig {
+    /// The label identifying the type of the device on the command line.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Blk(_) => "virtio-blk",
+            Self::Rng => "virtio-rng",
+            Self::Serial(_) => "virtio-serial",
+            Self::Vsock(_) => "virtio-vsock",
+            Self::Net(_) => "virtio-net",
+            Self::Fs(_) => "virtio-fs",
+            Self::Gpu(_) => "virtio-gpu",
+            Self::Input(_) => "virtio-input",
+        }
+    }
+}
+
+/// The device as given on the command line (with --device).
+impl fmt::Display for VirtioDeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.label())?;
+
+        match self {
+            Self::Blk(blk) => {
+                write!(f, ",path={},format={}", blk.path.display(), blk.format)?;
+                match blk.grow_to {
+                    Some(size) => write!(f, ",grow-to={size}"),
+                    None => Ok(()),
+                }
+            }
+            Self::Rng => Ok(()),
+            Self::Serial(serial) => write!(f, ",logFilePath={}", serial.log_file_path.display()),
+            Self::Vsock(vsock) => write!(
+                f,
+                ",port={},socketURL={},listen{}",
+                vsock.port,
+                vsock.socket_url.display(),
+                vsock.socket_policy
+            ),
+            Self::Net(net) => write!(
+                f,
+                ",{}={},mac={}{}",
+                match net.api {
+                    NetApi::Unixgram => "unixSocketPath",
+                    NetApi::Unixstream => "unixStreamPath",
+                    NetApi::Gvproxy => "gvproxySocket",
+                },
+                net.unix_socket_path.display(),
+                net.mac_address,
+                net.socket_policy
+            ),
+            Self::Fs(fs) => write!(
+                f,
+                ",sharedDir={},mountTag={}",
+                fs.shared_dir.display(),
+                fs.mount_tag.display()
+            ),
+            Self::Gpu(gpu) => write!(f, ",width={},height={}", gpu.width, gpu.height),
+            Self::Input(InputConfig::Keyboard) => write!(f, ",keyboard"),
+            Self::Input(InputConfig::Pointing) => write!(f, ",pointing"),
+        }
+    }
+}
+
 /// Parse a virtio device configuration with its respective information/data.
 impl FromStr for VirtioDeviceConfig {
     type Err = anyhow::Error;
@@ -116,59 +187,82 @@ impl KrunContextSet for VirtioDeviceConfig {
 }
 
 /// Configuration of a virtio-blk device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct BlkConfig {
     /// Path of the file to store as the root disk.
     pub path: PathBuf,
 
     /// Format of the disk image.
     pub format: DiskImageFormat,
+
+    /// Size the disk is grown to at startup, if smaller, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grow_to: Option<u64>,
 }
 
 impl FromStr for BlkConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-blk", Some(2))?;
+        let args = args_parse(s.to_string(), "virtio-blk", None)?;
+        if !(2..=3).contains(&args.len()) {
+            return Err(anyhow!(
+                "expected --virtio-blk argument to have 2 or 3 comma-separated sub-arguments, found {}",
+                args.len()
+            ));
+        }
+
+        let grow_to = match args.get(2) {
+            Some(arg) => Some(size_parse(&val_parse(arg, "grow-to")?)?),
+            None => None,
+        };
 
         Ok(Self {
             path: PathBuf::from_str(&val_parse(&args[0], "path")?)
                 .context("path argument not a valid path")?,
             format: DiskImageFormat::from_str(val_parse(&args[1], "format")?.as_str())?,
+            grow_to,
         })
     }
 }
 
+impl BlkConfig {
+    /// Identifier of the disk in libkrun, which is the file name of the image.
+    pub fn block_id(&self) -> &str {
+        match self.path.file_name() {
+            Some(osstr) => osstr.to_str().unwrap_or("disk"),
+            None => "disk",
+        }
+    }
+}
+
 /// Set the virtio-blk device to be the krun VM's root disk.
 impl KrunContextSet for BlkConfig {
     unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
-        let basename = match self.path.file_name() {
-            Some(osstr) => osstr.to_str().unwrap_or("disk"),
-            None => "disk",
-        };
-        let block_id_cstr = CString::new(basename).context("can't convert basename to cstring")?;
+        let block_id_cstr =
+            CString::new(self.block_id()).context("can't convert basename to cstring")?;
         let path_cstr = path_to_cstring(&self.path)?;
 
-        if krun_add_disk2(
+        libkrun::check((libkrun().krun_add_disk2)(
             id,
             block_id_cstr.as_ptr(),
             path_cstr.as_ptr(),
             self.format as u32,
             false,
-        ) < 0
-        {
-            return Err(anyhow!(format!(
-                "unable to set virtio-blk disk for {}",
-                self.path.display()
-            )));
-        }
+        ))
+        .context(format!(
+            "unable to set virtio-blk disk for {}",
+            self.path.display()
+        ))?;
 
         Ok(())
     }
 }
 
 /// Configuration of a virtio-serial device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SerialConfig {
     /// Path of a file to use as the device's log.
     pub log_file_path: PathBuf,
@@ -192,44 +286,54 @@ impl KrunContextSet for SerialConfig {
     unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
         let path_cstr = path_to_cstring(&self.log_file_path)?;
 
-        if krun_set_console_output(id, path_cstr.as_ptr()) < 0 {
-            return Err(anyhow!(
-                "unable to set krun console output redirection to virtio-serial log file"
-            ));
-        }
+        libkrun::check((libkrun().krun_set_console_output)(id, path_cstr.as_ptr()))
+            .context("unable to set krun console output redirection to virtio-serial log file")?;
 
         Ok(())
     }
 }
 
 /// Configuration of a virtio-vsock device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct VsockConfig {
     /// Port to connect to on VM.
     pub port: u32,
 
     /// Path of underlying socket.
+    #[serde(rename = "socketURL")]
     pub socket_url: PathBuf,
 
     /// Action of socket.
     pub action: VsockAction,
+
+    #[serde(flatten)]
+    pub socket_policy: SocketPolicy,
 }
 
 impl FromStr for VsockConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-vsock", Some(3))?;
+        let args = args_parse(s.to_string(), "virtio-vsock", None)?;
+        if args.len() < 3 {
+            return Err(anyhow!(
+                "expected --virtio-vsock argument to have at least 3 comma-separated sub-arguments, found {}",
+                args.len()
+            ));
+        }
 
         let port = u32::from_str(&val_parse(&args[0], "port")?).context("port argument invalid")?;
         let socket_url = PathBuf::from_str(&val_parse(&args[1], "socketURL")?)
             .context("socketURL argument not a valid path")?;
         let action = VsockAction::from_str(&args[2])?;
+        let socket_policy = SocketPolicy::parse(&args[3..], "virtio-vsock")?;
 
         Ok(Self {
             port,
             socket_url,
             action,
+            socket_policy,
         })
     }
 }
@@ -240,20 +344,24 @@ impl KrunContextSet for VsockConfig {
     unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
         let path_cstr = path_to_cstring(&self.socket_url)?;
 
-        if krun_add_vsock_port(id, self.port, path_cstr.as_ptr()) < 0 {
-            return Err(anyhow!(format!(
-                "unable to add vsock port {} for path {}",
-                self.port,
-                &self.socket_url.display()
-            )));
-        }
+        libkrun::check((libkrun().krun_add_vsock_port)(
+            id,
+            self.port,
+            path_cstr.as_ptr(),
+        ))
+        .context(format!(
+            "unable to add vsock port {} for path {}",
+            self.port,
+            &self.socket_url.display()
+        ))?;
 
         Ok(())
     }
 }
 
 /// virtio-vsock action.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum VsockAction {
     Listen,
 }
@@ -271,57 +379,218 @@ impl FromStr for VsockAction {
     }
 }
 
+/// How krunkit handles the host socket of a virtio-net or virtio-vsock device being missing at
+/// startup, such as when the network backend is started concurrently with krunkit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketPolicy {
+    /// Seconds to wait for the socket to be created, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_for_socket_secs: Option<u64>,
+
+    /// Start the VM without the device if the socket is missing, rather than failing.
+    pub optional: bool,
+}
+
+impl SocketPolicy {
+    /// Parse the optional sub-arguments of a device configuring the policy (wait-for-socket,
+    /// optional).
+    fn parse(args: &[String], label: &str) -> Result<Self> {
+        let mut policy = Self::default();
+
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("wait-for-socket", value)) => {
+                    let wait = duration_parse(value)
+                        .context(format!("{label} wait-for-socket argument invalid"))?;
+                    policy.wait_for_socket_secs = Some(wait.as_secs().max(1));
+                }
+                Some(("optional", value)) => {
+                    policy.optional = bool::from_str(value)
+                        .context(format!("{label} optional argument invalid"))?;
+                }
+                _ => return Err(anyhow!("invalid {label} argument: {arg}")),
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for SocketPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(secs) = self.wait_for_socket_secs {
+            write!(f, ",wait-for-socket={secs}s")?;
+        }
+        if self.optional {
+            write!(f, ",optional=true")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// virtio-net features offered with the unixgram and unixstream APIs, matching those of the
+/// legacy gvproxy API (checksum offload, TSO, and UFO in both directions).
+const NET_FEATURES_COMPAT: u32 = 1 << NET_FEATURE_CSUM
+    | 1 << NET_FEATURE_GUEST_CSUM
+    | 1 << NET_FEATURE_GUEST_TSO4
+    | 1 << NET_FEATURE_GUEST_UFO
+    | 1 << NET_FEATURE_HOST_TSO4
+    | 1 << NET_FEATURE_HOST_UFO;
+const NET_FEATURE_CSUM: u32 = 0;
+const NET_FEATURE_GUEST_CSUM: u32 = 1;
+const NET_FEATURE_GUEST_TSO4: u32 = 7;
+const NET_FEATURE_GUEST_UFO: u32 = 10;
+const NET_FEATURE_HOST_TSO4: u32 = 11;
+const NET_FEATURE_HOST_UFO: u32 = 14;
+
+/// Send the vfkit magic when connecting to the socket, as gvproxy's --listen-vfkit expects.
+const NET_FLAG_VFKIT: u32 = 1 << 0;
+
+/// libkrun API a virtio-net device is attached to its socket with, chosen by the argument naming
+/// the socket.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NetApi {
+    /// A UNIX datagram socket, such as gvproxy's (unixSocketPath), attached with
+    /// krun_add_net_unixgram, or the legacy gvproxy API if libkrun predates it.
+    Unixgram,
+
+    /// A UNIX stream socket, such as passt's (unixStreamPath), attached with
+    /// krun_add_net_unixstream.
+    Unixstream,
+
+    /// The legacy gvproxy API, krun_set_gvproxy_path (gvproxySocket).
+    Gvproxy,
+}
+
 /// Configuration of a virtio-net device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct NetConfig {
-    /// Path to underlying gvproxy socket.
+    /// Path to underlying network backend socket.
     pub unix_socket_path: PathBuf,
 
     /// Network MAC address.
+    #[serde(rename = "mac")]
     pub mac_address: MacAddress,
+
+    /// API the interface is attached to the socket with.
+    pub api: NetApi,
+
+    #[serde(flatten)]
+    pub socket_policy: SocketPolicy,
 }
 
 impl FromStr for NetConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-net", Some(2))?;
+        let args = args_parse(s.to_string(), "virtio-net", None)?;
+        if args.len() < 2 {
+            return Err(anyhow!(
+                "expected --virtio-net argument to have at least 2 comma-separated sub-arguments, found {}",
+                args.len()
+            ));
+        }
+
+        let (unix_socket_path, api) = match args[0].split_once('=') {
+            Some(("gvproxySocket", path)) => (path.to_string(), NetApi::Gvproxy),
+            Some(("unixStreamPath", path)) => (path.to_string(), NetApi::Unixstream),
+            _ => (val_parse(&args[0], "unixSocketPath")?, NetApi::Unixgram),
+        };
 
         Ok(Self {
-            unix_socket_path: PathBuf::from_str(&val_parse(&args[0], "unixSocketPath")?)
+            unix_socket_path: PathBuf::from_str(&unix_socket_path)
                 .context("unixSocketPath argument not a valid path")?,
             mac_address: MacAddress::from_str(&val_parse(&args[1], "mac")?)
                 .context("unable to parse mac address from argument")?,
+            api,
+            socket_policy: SocketPolicy::parse(&args[2..], "virtio-net")?,
         })
     }
 }
 
-/// Set the gvproxy's path and network MAC address.
+impl NetConfig {
+    /// Indicate if the device is configured with the legacy gvproxy API, either as requested or
+    /// because the loaded libkrun predates the unixgram API.
+    pub fn uses_legacy_api(&self) -> bool {
+        match self.api {
+            NetApi::Unixgram => libkrun().krun_add_net_unixgram.is_none(),
+            NetApi::Unixstream => false,
+            NetApi::Gvproxy => true,
+        }
+    }
+}
+
+/// Connect the network interface to the gvproxy socket with the given MAC address.
 impl KrunContextSet for NetConfig {
     unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
         let path_cstr = path_to_cstring(&self.unix_socket_path)?;
         let mac = self.mac_address.bytes();
 
-        if krun_set_gvproxy_path(id, path_cstr.as_ptr()) < 0 {
-            return Err(anyhow!(format!(
-                "unable to set gvproxy path {}",
-                &self.unix_socket_path.display()
-            )));
+        if self.api == NetApi::Unixstream {
+            let krun = libkrun();
+            let add_net_unixstream = krun.require(
+                krun.krun_add_net_unixstream,
+                "krun_add_net_unixstream",
+                "virtio-net with unixStreamPath",
+            )?;
+            libkrun::check(add_net_unixstream(
+                id,
+                path_cstr.as_ptr(),
+                -1,
+                mac.as_ptr(),
+                NET_FEATURES_COMPAT,
+                0,
+            ))
+            .context(format!(
+                "unable to add network interface for stream socket {}",
+                self.unix_socket_path.display()
+            ))?;
+
+            return Ok(());
         }
 
-        if krun_set_net_mac(id, mac.as_ptr()) < 0 {
-            return Err(anyhow!(format!(
-                "unable to set net MAC address {}",
-                self.mac_address
-            )));
+        if let (NetApi::Unixgram, Some(add_net_unixgram)) =
+            (self.api, libkrun().krun_add_net_unixgram)
+        {
+            libkrun::check(add_net_unixgram(
+                id,
+                path_cstr.as_ptr(),
+                -1,
+                mac.as_ptr(),
+                NET_FEATURES_COMPAT,
+                NET_FLAG_VFKIT,
+            ))
+            .context(format!(
+                "unable to add network interface for socket {}",
+                self.unix_socket_path.display()
+            ))?;
+
+            return Ok(());
         }
 
+        libkrun::check((libkrun().krun_set_gvproxy_path)(id, path_cstr.as_ptr())).context(
+            format!(
+                "unable to set gvproxy path {}",
+                &self.unix_socket_path.display()
+            ),
+        )?;
+
+        libkrun::check((libkrun().krun_set_net_mac)(id, mac.as_ptr())).context(format!(
+            "unable to set net MAC address {}",
+            self.mac_address
+        ))?;
+
         Ok(())
     }
 }
 
 /// Configuration of a virtio-fs device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct FsConfig {
     /// Shared directory with the host.
     pub shared_dir: PathBuf,
@@ -361,20 +630,24 @@ impl KrunContextSet for FsConfig {
         let shared_dir_cstr = path_to_cstring(&self.shared_dir)?;
         let mount_tag_cstr = path_to_cstring(&self.mount_tag)?;
 
-        if krun_add_virtiofs(id, mount_tag_cstr.as_ptr(), shared_dir_cstr.as_ptr()) < 0 {
-            return Err(anyhow!(format!(
-                "unable to add virtiofs shared directory {} with mount tag {}",
-                &self.shared_dir.display(),
-                &self.mount_tag.display()
-            )));
-        }
+        libkrun::check((libkrun().krun_add_virtiofs)(
+            id,
+            mount_tag_cstr.as_ptr(),
+            shared_dir_cstr.as_ptr(),
+        ))
+        .context(format!(
+            "unable to add virtiofs shared directory {} with mount tag {}",
+            &self.shared_dir.display(),
+            &self.mount_tag.display()
+        ))?;
 
         Ok(())
     }
 }
 
 /// Configuration of a virtio-gpu device.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GpuConfig {
     /// Width (pixels).
     pub width: u32,
@@ -400,7 +673,8 @@ impl FromStr for GpuConfig {
 
 /// Configuration of a virtio-input device. This is an enum indicating which virtio-input device a
 /// user would like to include with the VM.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InputConfig {
     Keyboard,
     Pointing,
@@ -0,0 +1,601 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    env, fmt,
+    fs::File,
+    io::Write,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::Command,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    agent::GuestAgent,
+    cleanup,
+    console::ConsoleBuffer,
+    events::{EventKind, Events},
+    health::Health,
+    helper::Supervisor,
+    krunlog,
+    operation::Operations,
+    state::{StateHistory, VmState},
+    stats::StatsSampler,
+    timesync::GuestClock,
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Serializer};
+
+/// Set in the environment of a krunkit process that replaced a previous instance to restart the
+/// VM.
+const RESTARTED_ENV: &str = "KRUNKIT_RESTARTED";
+
+/// Set in the environment of a krunkit process that replaced a previous instance to restart the
+/// VM, with the number of consecutive times the VM terminated abnormally.
+const FAILURES_ENV: &str = "KRUNKIT_FAILURES";
+
+/// Upper bound of the delay before restarting a VM that terminated abnormally.
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Time given to the guest to power off when asked to shut down.
+pub const GUEST_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Behavior of krunkit when the VM terminates abnormally.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RestartPolicy {
+    /// Exit krunkit with an error.
+    #[default]
+    No,
+
+    /// Start the VM again with the same configuration, optionally a limited number of times.
+    OnFailure { max_retries: Option<u32> },
+}
+
+impl RestartPolicy {
+    /// Delay before restarting the VM after it terminated abnormally a given number of
+    /// consecutive times, or None if it should not be restarted. The delay doubles with each
+    /// failure, starting at one second.
+    pub fn backoff(&self, failures: u32) -> Option<Duration> {
+        match self {
+            Self::No => None,
+            Self::OnFailure { max_retries } => {
+                if max_retries.is_some_and(|max| failures > max) {
+                    return None;
+                }
+
+                let delay = Duration::from_secs(1 << failures.saturating_sub(1).min(6));
+
+                Some(delay.min(RESTART_BACKOFF_MAX))
+            }
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, max_retries) = match s.split_once(':') {
+            Some((policy, max)) => (policy, Some(max)),
+            None => (s, None),
+        };
+
+        match (policy.to_lowercase().as_str(), max_retries) {
+            ("no", None) => Ok(Self::No),
+            ("on-failure", max_retries) => {
+                let max_retries = max_retries
+                    .map(u32::from_str)
+                    .transpose()
+                    .context("invalid --restart maximum retries")?;
+
+                Ok(Self::OnFailure { max_retries })
+            }
+            _ => Err(anyhow!("invalid --restart option: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::No => write!(f, "no"),
+            Self::OnFailure { max_retries: None } => write!(f, "on-failure"),
+            Self::OnFailure {
+                max_retries: Some(max),
+            } => write!(f, "on-failure:{max}"),
+        }
+    }
+}
+
+impl Serialize for RestartPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Reason for the VM to have exited, determining the exit status of krunkit.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExitReason {
+    /// The guest powered itself off.
+    PoweredOff,
+
+    /// The guest powered itself off after being asked to shut down by the host.
+    ShutDown,
+
+    /// The VM was stopped by the host without the guest powering off.
+    Stopped,
+
+    /// The VM was rebooted by the host, and is started again by a new krunkit instance.
+    Rebooted,
+
+    /// The VM terminated abnormally.
+    Failed,
+
+    /// The VM exited after the guest kernel panicked.
+    GuestPanicked,
+}
+
+impl ExitReason {
+    /// Exit status of krunkit. 1 and 2 are used for krunkit and command line errors. A VM the host
+    /// stopped exits with 0, as libkrun exits the process with it once the VM stops.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::PoweredOff | Self::ShutDown | Self::Stopped | Self::Rebooted => 0,
+            Self::Failed => 4,
+            Self::GuestPanicked => 5,
+        }
+    }
+
+    /// Write the final record of the VM's execution to the log, as a single line of JSON.
+    pub fn log(&self, uptime: Duration) {
+        let record = serde_json::json!({
+            "reason": self,
+            "exitCode": self.exit_code(),
+            "message": self.to_string(),
+            "uptimeSecs": uptime.as_secs(),
+        });
+
+        println!("{record}");
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PoweredOff => write!(f, "guest powered off"),
+            Self::ShutDown => write!(f, "guest shut down by host through the guest agent"),
+            Self::Stopped => write!(f, "VM stopped by host"),
+            Self::Rebooted => write!(f, "VM rebooted by host"),
+            Self::Failed => write!(f, "VM terminated abnormally"),
+            Self::GuestPanicked => write!(f, "guest kernel panicked"),
+        }
+    }
+}
+
+/// Tears down what was set up on the host for the VM, given the reason for the VM to exit.
+pub type Teardown = Box<dyn FnOnce(ExitReason) + Send>;
+
+/// A handle to the running VM, shared between the thread running the workload and the threads
+/// serving control requests.
+pub struct VmHandle {
+    /// VM is shut down by writing to the shutdown event file.
+    shutdown: Mutex<File>,
+
+    /// The VM was asked to stop by the host.
+    stop_requested: AtomicBool,
+
+    /// The VM was stopped by the host without the guest powering off.
+    forced_stop: AtomicBool,
+
+    /// The guest kernel panicked.
+    guest_panicked: AtomicBool,
+
+    /// The guest reported that it has booted.
+    guest_ready: AtomicBool,
+
+    /// The VM has exited. Signalled through the condition variable.
+    exited: (Mutex<bool>, Condvar),
+
+    /// krunkit prepared for the VM to exit (see prepare_exit()).
+    exiting: AtomicBool,
+
+    /// Run as the VM is about to exit, once the helpers are stopped.
+    teardown: Mutex<Option<Teardown>>,
+
+    /// Time the VM started running, if it did.
+    started: OnceLock<Instant>,
+
+    /// krunkit waits for the VM to be activated before starting it.
+    awaiting_activation: AtomicBool,
+
+    /// The VM was activated. Signalled through the condition variable.
+    activated: (Mutex<bool>, Condvar),
+
+    /// Recent guest console output, if the VM has a virtio-serial device.
+    pub console: Option<Arc<ConsoleBuffer>>,
+
+    /// Client of the guest agent, if a guest agent channel is configured.
+    pub agent: Option<GuestAgent>,
+
+    /// Channel through which the guest's clock is set, if any.
+    pub clock: Option<GuestClock>,
+
+    /// Long-running operations requested through the restful service.
+    pub operations: Arc<Operations>,
+
+    /// Lifecycle events of the VM.
+    pub events: Events,
+
+    /// Helper processes serving the VM.
+    pub helpers: Supervisor,
+
+    /// Host-side resource usage of the VM.
+    pub stats: StatsSampler,
+
+    /// Health of the guest, as determined from heartbeats sent to the guest agent.
+    pub health: Health,
+
+    /// Lifecycle state of the VM, shared with the krun context.
+    pub state: Arc<StateHistory>,
+}
+
+impl VmHandle {
+    pub fn new(
+        shutdown_eventfd: RawFd,
+        console: Option<Arc<ConsoleBuffer>>,
+        agent: Option<GuestAgent>,
+        clock: Option<GuestClock>,
+        state: Arc<StateHistory>,
+    ) -> Self {
+        Self {
+            shutdown: Mutex::new(unsafe { File::from_raw_fd(shutdown_eventfd) }),
+            stop_requested: AtomicBool::new(false),
+            forced_stop: AtomicBool::new(false),
+            guest_panicked: AtomicBool::new(false),
+            guest_ready: AtomicBool::new(false),
+            exited: (Mutex::new(false), Condvar::new()),
+            exiting: AtomicBool::new(false),
+            teardown: Mutex::new(None),
+            started: OnceLock::new(),
+            awaiting_activation: AtomicBool::new(false),
+            activated: (Mutex::new(false), Condvar::new()),
+            console,
+            agent,
+            clock,
+            operations: Arc::new(Operations::default()),
+            events: Events::default(),
+            helpers: Supervisor::default(),
+            stats: StatsSampler::default(),
+            health: Health::default(),
+            state,
+        }
+    }
+
+    /// Stop the VM.
+    pub fn stop(&self) -> Result<(), anyhow::Error> {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.forced_stop.store(true, Ordering::SeqCst);
+        self.state.set(VmState::Stopping, "VM stopped by host");
+        self.prepare_exit(ExitReason::Stopped);
+        self.shut_down()
+    }
+
+    /// Stop the VM and start it again, by replacing the krunkit process with a new instance (see
+    /// restart()). libkrun exits the process once the VM stops, so the new instance is executed
+    /// instead of stopping the VM. This only returns if it could not be, once the VM was stopped.
+    pub fn reboot(&self) -> Result<(), anyhow::Error> {
+        self.state.set(VmState::Stopping, "VM rebooting");
+        if !self.prepare_exit(ExitReason::Rebooted) {
+            return Err(anyhow!("VM is already exiting"));
+        }
+        self.events
+            .publish(EventKind::Restarting, "VM rebooting, restarting");
+
+        let e = restart(0);
+        println!("{e:#}, stopping it");
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.forced_stop.store(true, Ordering::SeqCst);
+        self.shut_down()?;
+
+        Err(e)
+    }
+
+    /// Ask the guest to power itself off through the guest agent, and wait for the VM to exit.
+    pub fn shutdown_guest(&self, timeout: Duration) -> Result<(), anyhow::Error> {
+        let Some(agent) = &self.agent else {
+            return Err(anyhow!("no guest agent configured"));
+        };
+
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.state.set(
+            VmState::Stopping,
+            "guest asked to power off through the guest agent",
+        );
+        self.prepare_exit(ExitReason::ShutDown);
+        agent.send("guest-shutdown", None)?;
+
+        if !self.wait_exited(timeout) {
+            return Err(anyhow!(
+                "guest did not power off within {} seconds",
+                timeout.as_secs()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Shut the VM down gracefully through the guest agent (if configured), and stop it if the
+    /// guest does not power off within the timeout. The mechanism used is published as events.
+    pub fn stop_gracefully(&self, timeout: Duration) -> Result<(), anyhow::Error> {
+        if self.agent.is_some() {
+            self.events.publish(
+                EventKind::Stopping,
+                "asking guest to power off through the guest agent",
+            );
+
+            match self.shutdown_guest(timeout) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    println!("Unable to shut down guest gracefully, stopping VM: {e:#}");
+                    self.events.publish(
+                        EventKind::Stopping,
+                        format!("guest did not power off ({e:#}), stopping VM"),
+                    );
+                }
+            }
+        }
+
+        self.stop()
+    }
+
+    /// Have the VM wait to be activated (see --activate-on) before it is started.
+    pub fn await_activation(&self) {
+        self.awaiting_activation.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for the VM to be activated, if it awaits activation. Returns false if the host asked
+    /// for the VM to be stopped in the meantime.
+    pub fn wait_activated(&self) -> bool {
+        if !self.awaiting_activation.load(Ordering::SeqCst) {
+            return true;
+        }
+        let (activated, cvar) = &self.activated;
+
+        let mut guard = activated.lock().unwrap();
+        while !*guard {
+            if self.stop_requested() {
+                return false;
+            }
+            guard = cvar
+                .wait_timeout(guard, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+
+        !self.stop_requested()
+    }
+
+    /// Activate a VM that awaits activation, starting it. Returns false if it does not await
+    /// activation, or was already activated.
+    pub fn activate(&self) -> bool {
+        if !self.awaiting_activation.load(Ordering::SeqCst) {
+            return false;
+        }
+        let (activated, cvar) = &self.activated;
+
+        let mut guard = activated.lock().unwrap();
+        if *guard {
+            return false;
+        }
+        *guard = true;
+        cvar.notify_all();
+
+        true
+    }
+
+    /// Mark the VM as exited, waking any thread waiting for it to exit.
+    pub fn set_exited(&self) {
+        let (exited, cvar) = &self.exited;
+
+        *exited.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    /// Wait for the VM to exit. Returns false if it did not exit within the timeout.
+    pub fn wait_exited(&self, timeout: Duration) -> bool {
+        let (exited, cvar) = &self.exited;
+
+        let guard = exited.lock().unwrap();
+        let (guard, _) = cvar.wait_timeout_while(guard, timeout, |e| !*e).unwrap();
+
+        *guard
+    }
+
+    /// Record that the guest kernel panicked. Returns false if it was already recorded.
+    pub fn set_guest_panicked(&self) -> bool {
+        self.state.set(VmState::Crashed, "guest kernel panicked");
+        !self.guest_panicked.swap(true, Ordering::SeqCst)
+    }
+
+    /// Indicate if the guest kernel panicked.
+    pub fn guest_panicked(&self) -> bool {
+        self.guest_panicked.load(Ordering::SeqCst)
+    }
+
+    /// Record that the guest reported it has booted. Returns false if it was already recorded.
+    pub fn set_guest_ready(&self) -> bool {
+        !self.guest_ready.swap(true, Ordering::SeqCst)
+    }
+
+    /// Indicate if the guest reported it has booted.
+    pub fn guest_ready(&self) -> bool {
+        self.guest_ready.load(Ordering::SeqCst)
+    }
+
+    /// Reason for the VM to have exited normally.
+    pub fn exit_reason(&self) -> ExitReason {
+        if self.forced_stop.load(Ordering::SeqCst) {
+            ExitReason::Stopped
+        } else if self.stop_requested() {
+            ExitReason::ShutDown
+        } else {
+            ExitReason::PoweredOff
+        }
+    }
+
+    /// Set what to tear down on the host as the VM is about to exit.
+    pub fn on_exit(&self, teardown: Teardown) {
+        *self.teardown.lock().unwrap() = Some(teardown);
+    }
+
+    /// Record that the VM started running, to report its uptime once it exits.
+    pub fn set_started(&self) {
+        let _ = self.started.set(Instant::now());
+    }
+
+    /// Prepare for the VM to exit for the given reason: stop the helper processes, run the
+    /// teardown set with on_exit(), release the host resources krunkit created, and report why
+    /// the VM exits. libkrun exits the process as soon as the VM stops, without returning from
+    /// krun_start_enter(), so this is done before asking it to stop the VM. Returns false if it
+    /// was already done, as several exit paths may race.
+    pub fn prepare_exit(&self, reason: ExitReason) -> bool {
+        if self.exiting.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        self.helpers.stop();
+        if let Some(teardown) = self.teardown.lock().unwrap().take() {
+            teardown(reason);
+        }
+        cleanup::run();
+
+        let (state, kind) = match reason {
+            ExitReason::Failed | ExitReason::GuestPanicked => (VmState::Crashed, EventKind::Failed),
+            _ => (VmState::Stopped, EventKind::Stopped),
+        };
+        self.state.set(state, reason.to_string());
+        self.events.publish(kind, reason.to_string());
+        reason.log(self.started.get().map(Instant::elapsed).unwrap_or_default());
+
+        true
+    }
+
+    /// Handle the VM terminating abnormally: prepare for it to exit, and restart it if the restart
+    /// policy allows, by replacing the krunkit process with a new instance. The new instance
+    /// waits for the backoff delay before starting the VM. Returns false if the VM was already
+    /// exiting, and otherwise only returns if it is not restarted.
+    pub fn fail(&self, reason: ExitReason, policy: RestartPolicy) -> bool {
+        if !self.prepare_exit(reason) {
+            return false;
+        }
+
+        let failures = failures() + 1;
+        if !self.stop_requested() && policy.backoff(failures).is_some() {
+            self.events.publish(
+                EventKind::Restarting,
+                format!("VM failed {failures} consecutive time(s), restarting"),
+            );
+            println!("{:#}", restart(failures));
+        }
+
+        true
+    }
+
+    /// Indicate if the host asked for the VM to be stopped.
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    /// Wait before restarting the VM. Returns false if the host asked for the VM to be stopped in
+    /// the meantime.
+    pub fn restart_delay(&self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+
+        while Instant::now() < deadline {
+            if self.stop_requested() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        !self.stop_requested()
+    }
+
+    fn shut_down(&self) -> Result<(), anyhow::Error> {
+        let mut shutdown = self.shutdown.lock().unwrap();
+
+        shutdown
+            .write_all(&1u64.to_le_bytes())
+            .context("unable to write to shutdown fd")
+    }
+}
+
+/// Recreate the VM by replacing the krunkit process with a new instance started with the same
+/// arguments. The number of consecutive abnormal terminations of the VM is passed on to the new
+/// instance. This only returns if the new instance could not be executed.
+pub fn restart(failures: u32) -> anyhow::Error {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return anyhow!("unable to find krunkit executable to restart VM: {e}"),
+    };
+
+    // The new instance captures libkrun's log again.
+    krunlog::stop();
+
+    let e = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(RESTARTED_ENV, "1")
+        .env(FAILURES_ENV, failures.to_string())
+        .exec();
+
+    anyhow!("unable to restart VM: {e}")
+}
+
+/// Indicate if this krunkit process replaced a previous instance to restart the VM.
+pub fn restarted() -> bool {
+    env::var_os(RESTARTED_ENV).is_some()
+}
+
+/// Number of consecutive times the VM terminated abnormally before this krunkit process was
+/// started to restart it.
+pub fn failures() -> u32 {
+    env::var(FAILURES_ENV)
+        .ok()
+        .and_then(|f| u32::from_str(&f).ok())
+        .unwrap_or(0)
+}
+
+mod tests {
+    #[test]
+    fn restart_policy_backoff() {
+        use super::*;
+
+        assert_eq!(RestartPolicy::from_str("no").unwrap(), RestartPolicy::No);
+        assert!(RestartPolicy::from_str("no:3").is_err());
+        assert!(RestartPolicy::from_str("always").is_err());
+        assert!(RestartPolicy::from_str("on-failure:x").is_err());
+
+        let unlimited = RestartPolicy::from_str("on-failure").unwrap();
+        assert_eq!(unlimited.to_string(), "on-failure");
+        assert_eq!(unlimited.backoff(1), Some(Duration::from_secs(1)));
+        assert_eq!(unlimited.backoff(3), Some(Duration::from_secs(4)));
+        assert_eq!(unlimited.backoff(100), Some(RESTART_BACKOFF_MAX));
+
+        let limited = RestartPolicy::from_str("on-failure:2").unwrap();
+        assert_eq!(limited.to_string(), "on-failure:2");
+        assert_eq!(limited.backoff(2), Some(Duration::from_secs(2)));
+        assert_eq!(limited.backoff(3), None);
+
+        assert_eq!(RestartPolicy::No.backoff(1), None);
+    }
+}
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{console::file_len, vm::VmHandle};
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// GUID appended to the client's key to compute the accept key of the handshake (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Interval in which the console log file is checked for new output and the client for frames.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Largest frame accepted from the client. The console does not take input, so clients only
+/// send control frames.
+const FRAME_MAX: u64 = 64 * 1024;
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Status code of a close frame sent once the VM exits ("going away").
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// A connection to the restful service that can be upgraded to a WebSocket.
+pub trait WebSocketStream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl WebSocketStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl WebSocketStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// A frame received from the client.
+#[derive(Debug, PartialEq)]
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Build the response accepting a client's WebSocket handshake, given its Sec-WebSocket-Key.
+pub fn handshake_response(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{key}{WEBSOCKET_GUID}")).digest();
+
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        STANDARD.encode(digest.bytes())
+    )
+}
+
+/// Encode a frame sent to the client. Frames sent by a server are not masked.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// Decode a frame received from the client at the start of a buffer, returning it along with its
+/// length, or None if the buffer does not hold a complete frame yet. Fragmented messages are not
+/// reassembled, as the frames are not used beyond their opcode.
+fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, anyhow::Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let opcode = buf[0] & 0x0f;
+    if buf[1] & 0x80 == 0 {
+        return Err(anyhow!("client sent an unmasked frame"));
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into()?), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > FRAME_MAX {
+        return Err(anyhow!("client sent a frame of {len} bytes"));
+    }
+
+    let end = pos + 4 + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let mask = &buf[pos..pos + 4];
+    pos += 4;
+
+    let payload = buf[pos..end]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { opcode, payload }, end)))
+}
+
+/// Stream the guest's console output to a client whose connection was upgraded to a WebSocket, on
+/// a new thread, until the client closes the connection or the VM exits. The recent output is
+/// sent first, followed by the output written to the console log file from then on, as binary
+/// frames. The console only carries the guest's output, so data sent by the client is ignored.
+pub fn console_websocket<S: WebSocketStream + 'static>(
+    stream: S,
+    path: PathBuf,
+    recent: Vec<String>,
+    vm: Arc<VmHandle>,
+) {
+    thread::spawn(move || {
+        if let Err(e) = serve_console(stream, path, recent, &vm) {
+            println!("Console WebSocket closed: {e:#}");
+        }
+    });
+}
+
+fn serve_console<S: WebSocketStream>(
+    mut stream: S,
+    path: PathBuf,
+    recent: Vec<String>,
+    vm: &VmHandle,
+) -> Result<(), anyhow::Error> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut offset = file_len(&path);
+    if !recent.is_empty() {
+        stream.write_all(&encode_frame(OPCODE_BINARY, recent.join("\r\n").as_bytes()))?;
+    }
+
+    let mut output = [0u8; 4096];
+    let mut input = [0u8; 4096];
+    let mut pending = Vec::new();
+
+    loop {
+        if vm.wait_exited(Duration::ZERO) {
+            let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+            payload.extend_from_slice(b"VM exited");
+            stream.write_all(&encode_frame(OPCODE_CLOSE, &payload))?;
+            return Ok(());
+        }
+
+        // The file was truncated (for example, recreated by libkrun). Start from the beginning.
+        let len = file_len(&path);
+        if len < offset {
+            offset = 0;
+        }
+        if len > offset {
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            loop {
+                let sz = file.read(&mut output)?;
+                if sz == 0 {
+                    break;
+                }
+                stream.write_all(&encode_frame(OPCODE_BINARY, &output[..sz]))?;
+                offset += sz as u64;
+            }
+        }
+
+        match stream.read(&mut input) {
+            Ok(0) => return Ok(()),
+            Ok(sz) => pending.extend_from_slice(&input[..sz]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some((frame, len)) = decode_frame(&pending)? {
+            pending.drain(..len);
+
+            match frame.opcode {
+                OPCODE_CLOSE => {
+                    stream.write_all(&encode_frame(OPCODE_CLOSE, &frame.payload))?;
+                    return Ok(());
+                }
+                OPCODE_PING => stream.write_all(&encode_frame(OPCODE_PONG, &frame.payload))?,
+                _ => (),
+            }
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn websocket_frames() {
+        use super::*;
+
+        // Example handshake from RFC 6455.
+        assert!(handshake_response("dGhlIHNhbXBsZSBub25jZQ==")
+            .contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        assert_eq!(encode_frame(OPCODE_BINARY, b"hi"), b"\x82\x02hi");
+        assert_eq!(
+            &encode_frame(OPCODE_BINARY, &[0; 300])[..4],
+            b"\x82\x7e\x01\x2c"
+        );
+
+        // Masked "Hello" text frame from RFC 6455.
+        let masked = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        assert_eq!(decode_frame(&masked[..6]).unwrap(), None);
+        assert_eq!(
+            decode_frame(masked).unwrap(),
+            Some((
+                Frame {
+                    opcode: 0x1,
+                    payload: b"Hello".to_vec(),
+                },
+                11
+            ))
+        );
+        assert!(decode_frame(b"\x81\x05Hello").is_err());
+    }
+}